@@ -3,11 +3,20 @@ use candid::{
     utils::{encode_args, ArgumentEncoder},
     CandidType, Decode, Principal,
 };
-use futures::try_join;
-use ic_agent::Agent;
+use futures::{
+    stream::{self, BoxStream},
+    try_join, StreamExt,
+};
+use ic_agent::{
+    hash_tree::{HashTree, LookupResult},
+    Agent, Certificate,
+};
 use ic_auth_types::{SignInResponse, SignedDelegation};
 use ic_cose_types::{
-    cose::{ecdh::try_ecdh_x25519, encrypt0::cose_decrypt0, get_cose_key_secret, CoseKey},
+    cose::{
+        ecdh::try_ecdh_x25519, encrypt0::cose_decrypt0, get_cose_key_secret, kek_key_version,
+        sha256, CoseKey,
+    },
     format_error,
     types::namespace::*,
     types::setting::*,
@@ -18,28 +27,200 @@ use ic_cose_types::{
     BoxError, CanisterCaller,
 };
 use serde_bytes::{ByteArray, ByteBuf};
-use std::{collections::BTreeSet, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use x25519_dalek::{PublicKey, StaticSecret};
 
+use crate::dek::{open, seal};
 use crate::rand_bytes;
-use crate::vetkeys::{DerivedPublicKey, EncryptedVetKey, TransportSecretKey, VetKey};
+use crate::vetkeys::{
+    ibe_decrypt, ibe_encrypt, vetkey_kek, DerivedPublicKey, EncryptedVetKey, TransportSecretKey,
+    VetKey,
+};
+
+/// Default max age of the certificates of query results, see [`Client::with_max_certificate_age`].
+pub const DEFAULT_MAX_CERTIFICATE_AGE: Duration = Duration::from_secs(300);
 
 #[derive(Clone)]
 pub struct Client {
     agent: Arc<Agent>,
     canister: Principal,
+    max_certificate_age: Duration,
 }
 
 impl Client {
     pub fn new(agent: Arc<Agent>, canister: Principal) -> Client {
-        Client { agent, canister }
+        Client {
+            agent,
+            canister,
+            max_certificate_age: DEFAULT_MAX_CERTIFICATE_AGE,
+        }
     }
+
+    /// Sets the max age of the certificates of query results, older certificates are rejected.
+    pub fn with_max_certificate_age(mut self, max_age: Duration) -> Client {
+        self.max_certificate_age = max_age;
+        self
+    }
+
+    /// Verifies the certificate and witness of a setting returned by a query call
+    /// against the root key of the agent. The certificate should not be older than the max age,
+    /// and the setting subject should be the agent's principal if the path has no subject.
+    pub fn verify_setting(
+        &self,
+        path: &SettingPath,
+        info: &SettingInfo,
+        with_payload: bool,
+    ) -> Result<(), String> {
+        let certificate = info
+            .certificate
+            .as_ref()
+            .ok_or_else(|| "setting is not certified".to_string())?;
+        let certificate: Certificate =
+            cbor2::from_slice(certificate.as_slice()).map_err(format_error)?;
+        self.agent
+            .verify(&certificate, self.canister)
+            .map_err(format_error)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(format_error)?;
+        verify_certificate_time(&certificate.tree, self.max_certificate_age, now)?;
+        let certified_data = match certificate.tree.lookup_path([
+            b"canister".as_slice(),
+            self.canister.as_slice(),
+            b"certified_data".as_slice(),
+        ]) {
+            LookupResult::Found(data) => data,
+            _ => Err("certified data not found in certificate".to_string())?,
+        };
+        let subject = match path.subject {
+            Some(subject) => subject,
+            None => self.agent.get_principal()?,
+        };
+        let path = SettingPath {
+            subject: Some(subject),
+            ..path.clone()
+        };
+        verify_setting_witness(certified_data, &path, info, with_payload)
+    }
+}
+
+/// Verifies that the time of the certificate is not older than `max_age` before `now`,
+/// both durations since the unix epoch.
+pub fn verify_certificate_time(
+    tree: &HashTree<Vec<u8>>,
+    max_age: Duration,
+    now: Duration,
+) -> Result<(), String> {
+    let time = match tree.lookup_path([b"time".as_slice()]) {
+        LookupResult::Found(time) => decode_leb128(time)?,
+        _ => Err("time not found in certificate".to_string())?,
+    };
+    if now.as_nanos().saturating_sub(time as u128) > max_age.as_nanos() {
+        Err(format!("certificate is older than {:?}", max_age))?;
+    }
+    Ok(())
 }
 
+fn decode_leb128(data: &[u8]) -> Result<u64, String> {
+    let mut value = 0u64;
+    for (i, byte) in data.iter().take(10).enumerate() {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("invalid time in certificate".to_string())
+}
+
+/// Verifies that the witness of the setting matches the certified data of the canister,
+/// and proves the setting metadata, along with the payload if `with_payload` is true.
+pub fn verify_setting_witness(
+    certified_data: &[u8],
+    path: &SettingPath,
+    info: &SettingInfo,
+    with_payload: bool,
+) -> Result<(), String> {
+    let witness = info
+        .witness
+        .as_ref()
+        .ok_or_else(|| "setting is not certified".to_string())?;
+    let witness: HashTree<Vec<u8>> = cbor2::from_slice(witness.as_slice()).map_err(format_error)?;
+    if witness.digest().as_slice() != certified_data {
+        Err("witness does not match the certified data".to_string())?;
+    }
+    if info.key != path.key || path.subject.is_some_and(|subject| subject != info.subject) {
+        Err("setting does not match the path".to_string())?;
+    }
+
+    let key = certified_setting_key(&path.ns, path.user_owned, &info.subject, &info.key);
+    let check = |label: &[u8], hash: [u8; 32]| match witness.lookup_path([
+        LABEL_SETTINGS,
+        key.as_slice(),
+        label,
+    ]) {
+        LookupResult::Found(value) if value == hash.as_slice() => Ok(()),
+        _ => Err(format!(
+            "setting {} is not certified",
+            String::from_utf8_lossy(label)
+        )),
+    };
+    check(LABEL_SETTING_META, info.meta_hash())?;
+    if with_payload {
+        check(LABEL_SETTING_PAYLOAD, info.payload_hash())?;
+    }
+    Ok(())
+}
+
+#[async_trait]
 impl CoseSDK for Client {
     fn canister(&self) -> &Principal {
         &self.canister
     }
+
+    /// The result is verified with its certificate and witness.
+    async fn setting_get_info(&self, path: &SettingPath) -> Result<SettingInfo, String> {
+        let info: Result<SettingInfo, String> = self
+            .canister_query(self.canister(), "setting_get_info", (path,))
+            .await
+            .map_err(format_error)?;
+        let info = info?;
+        self.verify_setting(path, &info, false)?;
+        Ok(info)
+    }
+
+    /// The metadata of the result is verified with its certificate and witness,
+    /// the DEK is authenticated when it is unwrapped.
+    /// The witness also proves the payload hash, see [`CoseSDK::verify_chunked_setting`].
+    async fn setting_get_dek(&self, path: &SettingPath) -> Result<SettingInfo, String> {
+        let info: Result<SettingInfo, String> = self
+            .canister_query(self.canister(), "setting_get_dek", (path,))
+            .await
+            .map_err(format_error)?;
+        let info = info?;
+        self.verify_setting(path, &info, false)?;
+        Ok(info)
+    }
+
+    /// The result is verified with its certificate and witness.
+    async fn setting_get(&self, path: &SettingPath) -> Result<SettingInfo, String> {
+        let info: Result<SettingInfo, String> = self
+            .canister_query(self.canister(), "setting_get", (path,))
+            .await
+            .map_err(format_error)?;
+        let info = info?;
+        self.verify_setting(path, &info, true)?;
+        Ok(info)
+    }
+
+    /// The assembled payload and DEK are verified with the payload hash in the witness.
+    fn verify_chunked_setting(&self, path: &SettingPath, info: &SettingInfo) -> Result<(), String> {
+        self.verify_setting(path, info, true)
+    }
 }
 
 impl CanisterCaller for Client {
@@ -136,6 +317,67 @@ pub trait CoseSDK: CanisterCaller + Sized {
             .map_err(format_error)?
     }
 
+    /// the caller of agent should be canister controller
+    async fn admin_set_gas_policy(&self, policy: &GasPolicy) -> Result<(), String> {
+        self.canister_update(self.canister(), "admin_set_gas_policy", (policy,))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// the caller of agent should be canister controller
+    async fn admin_set_export_source(
+        &self,
+        canister: &Principal,
+        public_key: Option<&ByteBuf>,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "admin_set_export_source",
+            (canister, public_key),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn admin_set_gas_exempt(&self, namespace: &str, exempt: bool) -> Result<(), String> {
+        self.canister_update(self.canister(), "admin_set_gas_exempt", (namespace, exempt))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// the caller of agent should be canister controller or manager
+    async fn admin_add_group_members(
+        &self,
+        name: &str,
+        members: &BTreeSet<Principal>,
+    ) -> Result<(), String> {
+        self.canister_update(self.canister(), "admin_add_group_members", (name, members))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// the caller of agent should be canister controller or manager
+    async fn admin_remove_group_members(
+        &self,
+        name: &str,
+        members: &BTreeSet<Principal>,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "admin_remove_group_members",
+            (name, members),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    /// the caller of agent should be canister manager or auditor
+    async fn admin_list_groups(&self) -> Result<BTreeMap<String, BTreeSet<Principal>>, String> {
+        self.canister_query(self.canister(), "admin_list_groups", ())
+            .await
+            .map_err(format_error)?
+    }
+
     /// the caller of agent should be canister controller
     async fn admin_create_namespace(
         &self,
@@ -211,7 +453,20 @@ pub trait CoseSDK: CanisterCaller + Sized {
             .map_err(format_error)?
     }
 
+    /// Returns the partial KEK of the current KEK version.
+    /// See [`crate::key_manager::KeyManager`] to combine it with a local secret into the full KEK.
     async fn get_cose_encrypted_key(&self, path: &SettingPath) -> Result<ByteArray<32>, String> {
+        let (secret, _) = self.get_versioned_cose_encrypted_key(path, None).await?;
+        Ok(secret)
+    }
+
+    /// Returns the partial KEK of the KEK version and the version, the current version if None.
+    /// The DEK wrapped with it should record the version in its kid, see [`ic_cose_types::cose::kek_key_id`].
+    async fn get_versioned_cose_encrypted_key(
+        &self,
+        path: &SettingPath,
+        kek_version: Option<u32>,
+    ) -> Result<(ByteArray<32>, u32), String> {
         let nonce: [u8; 12] = rand_bytes();
         let secret: [u8; 32] = rand_bytes();
         let secret = StaticSecret::from(secret);
@@ -219,26 +474,37 @@ pub trait CoseSDK: CanisterCaller + Sized {
         let subject = path
             .subject
             .ok_or_else(|| "subject is required for get_cose_encrypted_key".to_string())?;
-        let res = self
-            .ecdh_cose_encrypted_key(
-                path,
-                &ECDHInput {
-                    nonce: nonce.into(),
-                    public_key: public.to_bytes().into(),
-                },
+        let ecdh = ECDHInput {
+            nonce: nonce.into(),
+            public_key: public.to_bytes().into(),
+        };
+        let res: Result<ECDHOutput<ByteBuf>, String> = self
+            .canister_update(
+                self.canister(),
+                "ecdh_cose_encrypted_key",
+                (path, &ecdh, kek_version),
             )
-            .await?;
+            .await
+            .map_err(format_error)?;
+        let res = res?;
 
         let (shared_secret, _) = try_ecdh_x25519(secret.to_bytes(), *res.public_key)?;
         let add = subject.as_slice();
         let kek = cose_decrypt0(&res.payload, &shared_secret.to_bytes(), add)?;
         let key =
             CoseKey::from_slice(&kek).map_err(|err| format!("invalid COSE key: {:?}", err))?;
+        let kid = key
+            .kid()
+            .map_err(format_error)?
+            .unwrap_or_default()
+            .to_vec();
         let secret = get_cose_key_secret(key)?;
         let secret: [u8; 32] = secret.try_into().map_err(|val: Vec<u8>| {
             format!("invalid COSE secret, expected 32 bytes, got {}", val.len())
         })?;
-        Ok(secret.into())
+        let version = kek_key_version(&path.key, &kid)
+            .ok_or_else(|| "kid mismatch, the KEK belongs to another setting".to_string())?;
+        Ok((secret.into(), version))
     }
 
     async fn vetkd_public_key(&self, path: &SettingPath) -> Result<ByteBuf, String> {
@@ -276,6 +542,150 @@ pub trait CoseSDK: CanisterCaller + Sized {
         Ok((vk, dpk))
     }
 
+    /// Returns the namespace-level vetKD public key for identity-based encryption to principals.
+    /// It is an update call so that the key is not served by a single replica.
+    async fn vetkd_ibe_public_key(&self, namespace: &str) -> Result<ByteBuf, String> {
+        self.canister_update(self.canister(), "vetkd_ibe_public_key", (namespace,))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn vetkd_ibe_encrypted_key(
+        &self,
+        namespace: &str,
+        transport_public_key: &ByteBuf,
+    ) -> Result<ByteBuf, String> {
+        self.canister_update(
+            self.canister(),
+            "vetkd_ibe_encrypted_key",
+            (namespace, transport_public_key),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    /// Returns the IBE vetKey of the caller in the namespace, `recipient` should be the caller.
+    async fn ibe_vetkey(
+        &self,
+        namespace: &str,
+        recipient: &Principal,
+    ) -> Result<(VetKey, DerivedPublicKey), String> {
+        let seed: [u8; 32] = rand_bytes();
+        let tsk = TransportSecretKey::from_seed(seed.into())?;
+        let tpk = tsk.public_key().into();
+
+        let (pk, ek) = try_join!(
+            self.vetkd_ibe_public_key(namespace),
+            self.vetkd_ibe_encrypted_key(namespace, &tpk)
+        )?;
+        let dpk = DerivedPublicKey::deserialize(&pk).map_err(|err| format!("{err:?}"))?;
+        let evk = EncryptedVetKey::deserialize(&ek).map_err(|err| format!("{err:?}"))?;
+        let vk = evk.decrypt_and_verify(&tsk, &dpk, recipient.as_slice())?;
+        Ok((vk, dpk))
+    }
+
+    /// Encrypts the message to the recipient in the namespace, it does not need the recipient to publish a key.
+    /// The recipient decrypts it with [`CoseSDK::ibe_decrypt_from`].
+    async fn ibe_encrypt_to(
+        &self,
+        namespace: &str,
+        recipient: &Principal,
+        message: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let pk = self.vetkd_ibe_public_key(namespace).await?;
+        let dpk = DerivedPublicKey::deserialize(&pk).map_err(|err| format!("{err:?}"))?;
+        Ok(ibe_encrypt(&dpk, recipient, message))
+    }
+
+    /// Decrypts the message encrypted to the caller in the namespace, `recipient` should be the caller.
+    async fn ibe_decrypt_from(
+        &self,
+        namespace: &str,
+        recipient: &Principal,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let (vk, _) = self.ibe_vetkey(namespace, recipient).await?;
+        ibe_decrypt(&vk, ciphertext)
+    }
+
+    /// Encrypts the payload with a random DEK wrapped under the KEK derived from the vetKey of the setting,
+    /// the kid of the wrapped DEK is the setting key. See [`crate::dek::seal`].
+    /// It creates the setting if `path.version` is 0, otherwise updates its payload on that version.
+    async fn put_encrypted_setting(
+        &self,
+        path: &SettingPath,
+        payload: &[u8],
+    ) -> Result<UpdateSettingOutput, String> {
+        let (vk, _) = self.vetkey(path).await?;
+        let kek = vetkey_kek(&vk, &path.key)?;
+        self.put_encrypted_setting_with_kek(path, payload, &kek, path.key.to_vec())
+            .await
+    }
+
+    /// Fetches the setting and decrypts its payload with the KEK derived from the vetKey of the setting.
+    /// It fails if the DEK was not wrapped with that KEK, the kid should be the setting key.
+    async fn get_encrypted_setting(
+        &self,
+        path: &SettingPath,
+    ) -> Result<(Vec<u8>, SettingInfo), String> {
+        let (vk, _) = self.vetkey(path).await?;
+        let kek = vetkey_kek(&vk, &path.key)?;
+        self.get_encrypted_setting_with_kek(path, &kek, &path.key)
+            .await
+    }
+
+    /// Same as [`CoseSDK::put_encrypted_setting`], with the given KEK and kid.
+    async fn put_encrypted_setting_with_kek(
+        &self,
+        path: &SettingPath,
+        payload: &[u8],
+        kek: &[u8; 32],
+        kid: Vec<u8>,
+    ) -> Result<UpdateSettingOutput, String> {
+        let (payload, dek) = seal(path, payload, kek, kid)?;
+        if path.version == 0 {
+            self.setting_create(
+                path,
+                &CreateSettingInput {
+                    payload: Some(payload),
+                    dek: Some(dek),
+                    ..Default::default()
+                },
+            )
+            .await
+        } else {
+            self.setting_update_payload(
+                path,
+                &UpdateSettingPayloadInput {
+                    payload: Some(payload),
+                    dek: Some(dek),
+                    ..Default::default()
+                },
+            )
+            .await
+        }
+    }
+
+    /// Same as [`CoseSDK::get_encrypted_setting`], with the given KEK and expected kid.
+    async fn get_encrypted_setting_with_kek(
+        &self,
+        path: &SettingPath,
+        kek: &[u8; 32],
+        kid: &[u8],
+    ) -> Result<(Vec<u8>, SettingInfo), String> {
+        let info = self.setting_get(path).await?;
+        let payload = info
+            .payload
+            .as_ref()
+            .ok_or_else(|| "setting has no payload".to_string())?;
+        let dek = info
+            .dek
+            .as_ref()
+            .ok_or_else(|| "setting is not encrypted, it has no DEK".to_string())?;
+        let plain = open(path, payload, dek, kek, kid)?;
+        Ok((plain, info))
+    }
+
     async fn namespace_get_fixed_identity(
         &self,
         namespace: &str,
@@ -357,7 +767,7 @@ pub trait CoseSDK: CanisterCaller + Sized {
         namespace: &str,
         user_owned: bool,
         subject: Option<Principal>,
-    ) -> Result<NamespaceInfo, String> {
+    ) -> Result<Vec<(Principal, ByteBuf)>, String> {
         self.canister_query(
             self.canister(),
             "namespace_list_setting_keys",
@@ -367,12 +777,114 @@ pub trait CoseSDK: CanisterCaller + Sized {
         .map_err(format_error)?
     }
 
+    async fn namespace_list_settings(
+        &self,
+        input: &ListSettingsInput,
+    ) -> Result<ListSettingsOutput, String> {
+        self.canister_query(self.canister(), "namespace_list_settings", (input,))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// Returns a stream of setting pages that walks all pages from `input.start_after`.
+    fn namespace_list_settings_stream(
+        &self,
+        input: ListSettingsInput,
+    ) -> BoxStream<'_, Result<ListSettingsOutput, String>>
+    where
+        Self: Sync,
+    {
+        stream::try_unfold(Some(input), move |input| async move {
+            let mut input = match input {
+                Some(input) => input,
+                None => return Ok(None),
+            };
+            let output = self.namespace_list_settings(&input).await?;
+            let next = output.next_start_after.clone().map(|start_after| {
+                input.start_after = Some(start_after);
+                input
+            });
+            Ok(Some((output, next)))
+        })
+        .boxed()
+    }
+
+    async fn namespace_search_settings(
+        &self,
+        input: &SearchSettingsInput,
+    ) -> Result<SearchSettingsOutput, String> {
+        self.canister_query(self.canister(), "namespace_search_settings", (input,))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn namespace_changes_since(
+        &self,
+        namespace: &str,
+        cursor: u64,
+        limit: Option<u32>,
+    ) -> Result<SettingChangesOutput, String> {
+        self.canister_query(
+            self.canister(),
+            "namespace_changes_since",
+            (namespace, cursor, limit),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    /// Returns a stream of setting changes in the namespace after the `cursor` seq.
+    /// It polls the change log and awaits `sleep()` whenever there are no new changes,
+    /// so the caller controls the polling interval with its own runtime,
+    /// e.g. `|| tokio::time::sleep(Duration::from_secs(3))`.
+    /// The stream ends after the first error. An error starting with [`CURSOR_TOO_OLD`] means that
+    /// changes after the cursor have been dropped from the change log, the caller should resync the namespace
+    /// and watch again from the cursor 0.
+    fn watch<'a, S, F>(
+        &'a self,
+        namespace: &'a str,
+        cursor: u64,
+        sleep: S,
+    ) -> BoxStream<'a, Result<SettingChange, String>>
+    where
+        Self: Sync,
+        S: Fn() -> F + Send + 'a,
+        F: Future<Output = ()> + Send + 'a,
+    {
+        stream::try_unfold(
+            (cursor, VecDeque::new(), sleep),
+            move |(mut cursor, mut pending, sleep)| async move {
+                loop {
+                    if let Some(change) = pending.pop_front() {
+                        return Ok(Some((change, (cursor, pending, sleep))));
+                    }
+                    let output = self
+                        .namespace_changes_since(namespace, cursor, None)
+                        .await?;
+                    let caught_up = output.next_cursor == cursor;
+                    cursor = output.next_cursor;
+                    pending.extend(output.changes);
+                    if caught_up {
+                        sleep().await;
+                    }
+                }
+            },
+        )
+        .boxed()
+    }
+
     async fn namespace_update_info(&self, args: &UpdateNamespaceInput) -> Result<(), String> {
         self.canister_update(self.canister(), "namespace_update_info", (args,))
             .await
             .map_err(format_error)?
     }
 
+    async fn namespace_recompute_usage(&self, namespace: &str) -> Result<NamespaceInfo, String> {
+        self.canister_update(self.canister(), "namespace_recompute_usage", (namespace,))
+            .await
+            .map_err(format_error)?
+    }
+
     async fn namespace_delete(&self, namespace: &str) -> Result<(), String> {
         self.canister_update(self.canister(), "namespace_delete", (namespace,))
             .await
@@ -447,6 +959,235 @@ pub trait CoseSDK: CanisterCaller + Sized {
             .map_err(format_error)?
     }
 
+    async fn namespace_add_group_members(
+        &self,
+        namespace: &str,
+        name: &str,
+        members: &BTreeSet<Principal>,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "namespace_add_group_members",
+            (namespace, name, members),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn namespace_remove_group_members(
+        &self,
+        namespace: &str,
+        name: &str,
+        members: &BTreeSet<Principal>,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "namespace_remove_group_members",
+            (namespace, name, members),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    /// role should be "manager", "auditor" or "user"
+    async fn namespace_add_role_groups(
+        &self,
+        namespace: &str,
+        role: &str,
+        groups: &BTreeSet<String>,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "namespace_add_role_groups",
+            (namespace, role, groups),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn namespace_remove_role_groups(
+        &self,
+        namespace: &str,
+        role: &str,
+        groups: &BTreeSet<String>,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "namespace_remove_role_groups",
+            (namespace, role, groups),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    /// an empty policy restores the role model
+    async fn namespace_update_policy(
+        &self,
+        namespace: &str,
+        policy: &[PolicyRule],
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "namespace_update_policy",
+            (namespace, policy),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn namespace_update_signing_policy(
+        &self,
+        namespace: &str,
+        policy: Option<&SigningPolicy>,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "namespace_update_signing_policy",
+            (namespace, policy),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn namespace_enable_approval(
+        &self,
+        namespace: &str,
+        policy: &ApprovalPolicy,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "namespace_enable_approval",
+            (namespace, policy),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    /// the change executes at once if the proposer's approval reaches the threshold
+    async fn namespace_propose_change(
+        &self,
+        namespace: &str,
+        change: &NamespaceChange,
+    ) -> Result<ProposalInfo, String> {
+        self.canister_update(
+            self.canister(),
+            "namespace_propose_change",
+            (namespace, change),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn namespace_approve_change(
+        &self,
+        namespace: &str,
+        id: u64,
+    ) -> Result<ProposalInfo, String> {
+        self.canister_update(self.canister(), "namespace_approve_change", (namespace, id))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn namespace_list_proposals(&self, namespace: &str) -> Result<Vec<ProposalInfo>, String> {
+        self.canister_query(self.canister(), "namespace_list_proposals", (namespace,))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn namespace_export(
+        &self,
+        input: &ExportNamespaceInput,
+    ) -> Result<NamespaceExportPage, String> {
+        self.canister_update(self.canister(), "namespace_export", (input,))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// Exports all pages of the namespace, they should be imported in order.
+    fn namespace_export_stream(
+        &self,
+        namespace: String,
+    ) -> BoxStream<'_, Result<NamespaceExportPage, String>>
+    where
+        Self: Sync,
+    {
+        let input = ExportNamespaceInput {
+            ns: namespace,
+            cursor: None,
+        };
+        stream::try_unfold(Some(input), move |input| async move {
+            let mut input = match input {
+                Some(input) => input,
+                None => return Ok(None),
+            };
+            let page = self.namespace_export(&input).await?;
+            let next = page.next_cursor.clone().map(|cursor| {
+                input.cursor = Some(cursor);
+                input
+            });
+            Ok(Some((page, next)))
+        })
+        .boxed()
+    }
+
+    /// the caller of agent should be canister controller
+    async fn namespace_import(
+        &self,
+        input: &ImportNamespaceInput,
+    ) -> Result<NamespaceInfo, String> {
+        self.canister_update(self.canister(), "namespace_import", (input,))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// the caller of agent should be canister controller.
+    /// Returns false if imported settings remain to be removed, call it again until it returns true.
+    async fn namespace_abort_import(&self, namespace: &str) -> Result<bool, String> {
+        self.canister_update(self.canister(), "namespace_abort_import", (namespace,))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn namespace_rotate_kek(&self, namespace: &str) -> Result<u32, String> {
+        self.canister_update(self.canister(), "namespace_rotate_kek", (namespace,))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// the caller of agent should be canister manager.
+    /// The settings are moved in batches, call it again while `moving_from` of the result is set.
+    async fn namespace_rename(
+        &self,
+        namespace: &str,
+        new_name: &str,
+    ) -> Result<NamespaceInfo, String> {
+        self.canister_update(self.canister(), "namespace_rename", (namespace, new_name))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// the caller of agent should be canister manager.
+    /// The settings are copied in batches, call it again while `moving_from` of the result is set,
+    /// each batch returns its own re-encryption work list.
+    async fn namespace_clone(
+        &self,
+        namespace: &str,
+        new_name: &str,
+    ) -> Result<CloneNamespaceOutput, String> {
+        self.canister_update(self.canister(), "namespace_clone", (namespace, new_name))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn namespace_check_permission(
+        &self,
+        input: &CheckPermissionInput,
+    ) -> Result<CheckPermissionOutput, String> {
+        self.canister_query(self.canister(), "namespace_check_permission", (input,))
+            .await
+            .map_err(format_error)?
+    }
+
     async fn namespace_is_member(
         &self,
         namespace: &str,
@@ -480,13 +1221,142 @@ pub trait CoseSDK: CanisterCaller + Sized {
             .map_err(format_error)?
     }
 
-    async fn setting_get_archived_payload(
+    /// Returns the setting info with its DEK but without the payload.
+    async fn setting_get_dek(&self, path: &SettingPath) -> Result<SettingInfo, String> {
+        self.canister_query(self.canister(), "setting_get_dek", (path,))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn setting_get_archived_payload(
+        &self,
+        path: &SettingPath,
+    ) -> Result<SettingArchivedPayload, String> {
+        self.canister_query(self.canister(), "setting_get_archived_payload", (path,))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn setting_get_chunk(
+        &self,
+        path: &SettingPath,
+        chunk_index: u32,
+    ) -> Result<SettingChunkOutput, String> {
+        self.canister_query(self.canister(), "setting_get_chunk", (path, chunk_index))
+            .await
+            .map_err(format_error)?
+    }
+
+    /// Verifies the setting downloaded by `setting_get_chunked` with its assembled payload and DEK.
+    /// It does nothing by default, [`Client`] verifies them with the certificate and witness.
+    fn verify_chunked_setting(
+        &self,
+        _path: &SettingPath,
+        _info: &SettingInfo,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Downloads the setting with its payload in chunks.
+    /// It should be used when the payload may exceed the query response limit.
+    async fn setting_get_chunked(&self, path: &SettingPath) -> Result<SettingInfo, String> {
+        let first = self.setting_get_chunk(path, 0).await?;
+        let mut path = path.clone();
+        path.version = first.version;
+
+        let mut payload = Vec::with_capacity(first.payload_size as usize);
+        payload.extend_from_slice(&first.chunk);
+        for i in 1..first.chunks() {
+            let output = self.setting_get_chunk(&path, i).await?;
+            payload.extend_from_slice(&output.chunk);
+        }
+        if payload.len() as u64 != first.payload_size {
+            return Err(format!(
+                "payload size mismatch, expected {}, got {}",
+                first.payload_size,
+                payload.len()
+            ));
+        }
+
+        // the witness of setting_get_dek proves the payload hash
+        let mut info = self.setting_get_dek(&path).await?;
+        if info.version != first.version {
+            return Err("version mismatch".to_string());
+        }
+        info.payload = Some(payload.into());
+        info.dek = first.dek;
+        self.verify_chunked_setting(&path, &info)?;
+        Ok(info)
+    }
+
+    async fn setting_upload_begin(
+        &self,
+        path: &SettingPath,
+        payload_size: u64,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "setting_upload_begin",
+            (path, payload_size),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn setting_upload_chunk(
+        &self,
+        path: &SettingPath,
+        chunk_index: u32,
+        chunk: &[u8],
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "setting_upload_chunk",
+            (path, chunk_index, ByteBuf::from(chunk)),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    /// Uploads the payload in chunks and returns its sha256 hash,
+    /// which should be used as `payload_sha256` in `setting_create` or `setting_update_payload`.
+    async fn setting_upload_payload(
+        &self,
+        path: &SettingPath,
+        payload: &[u8],
+    ) -> Result<ByteArray<32>, String> {
+        self.setting_upload_begin(path, payload.len() as u64)
+            .await?;
+        for (i, chunk) in payload.chunks(CHUNK_SIZE as usize).enumerate() {
+            self.setting_upload_chunk(path, i as u32, chunk).await?;
+        }
+        Ok(sha256(payload).into())
+    }
+
+    /// Creates the setting, uploading the payload in chunks if it is larger than [`CHUNK_SIZE`].
+    async fn setting_create_chunked(
+        &self,
+        path: &SettingPath,
+        input: CreateSettingInput,
+    ) -> Result<CreateSettingOutput, String> {
+        let mut input = input;
+        if let Some(payload) = input.payload.take_if(|p| p.len() > CHUNK_SIZE as usize) {
+            input.payload_sha256 = Some(self.setting_upload_payload(path, &payload).await?);
+        }
+        self.setting_create(path, &input).await
+    }
+
+    /// Updates the setting payload, uploading it in chunks if it is larger than [`CHUNK_SIZE`].
+    async fn setting_update_payload_chunked(
         &self,
         path: &SettingPath,
-    ) -> Result<SettingArchivedPayload, String> {
-        self.canister_query(self.canister(), "setting_get_archived_payload", (path,))
-            .await
-            .map_err(format_error)?
+        input: UpdateSettingPayloadInput,
+    ) -> Result<UpdateSettingOutput, String> {
+        let mut input = input;
+        if let Some(payload) = input.payload.take_if(|p| p.len() > CHUNK_SIZE as usize) {
+            input.payload_sha256 = Some(self.setting_upload_payload(path, &payload).await?);
+        }
+        self.setting_update_payload(path, &input).await
     }
 
     async fn setting_create(
@@ -519,6 +1389,15 @@ pub trait CoseSDK: CanisterCaller + Sized {
             .map_err(format_error)?
     }
 
+    async fn setting_batch(
+        &self,
+        input: &SettingBatchInput,
+    ) -> Result<Vec<Option<UpdateSettingOutput>>, String> {
+        self.canister_update(self.canister(), "setting_batch", (input,))
+            .await
+            .map_err(format_error)?
+    }
+
     async fn setting_add_readers(
         &self,
         path: &SettingPath,
@@ -529,6 +1408,31 @@ pub trait CoseSDK: CanisterCaller + Sized {
             .map_err(format_error)?
     }
 
+    /// grants the readers to read the setting until `expires_at`, a unix timestamp in milliseconds
+    async fn setting_grant_readers(
+        &self,
+        path: &SettingPath,
+        args: &BTreeSet<Principal>,
+        expires_at: u64,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "setting_grant_readers",
+            (path, args, expires_at),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn setting_get_reader_grants(
+        &self,
+        path: &SettingPath,
+    ) -> Result<BTreeMap<Principal, u64>, String> {
+        self.canister_query(self.canister(), "setting_get_reader_grants", (path,))
+            .await
+            .map_err(format_error)?
+    }
+
     async fn setting_remove_readers(
         &self,
         path: &SettingPath,
@@ -539,6 +1443,51 @@ pub trait CoseSDK: CanisterCaller + Sized {
             .map_err(format_error)?
     }
 
+    async fn setting_add_reader_groups(
+        &self,
+        path: &SettingPath,
+        groups: &BTreeSet<String>,
+    ) -> Result<(), String> {
+        self.canister_update(self.canister(), "setting_add_reader_groups", (path, groups))
+            .await
+            .map_err(format_error)?
+    }
+
+    async fn setting_remove_reader_groups(
+        &self,
+        path: &SettingPath,
+        groups: &BTreeSet<String>,
+    ) -> Result<(), String> {
+        self.canister_update(
+            self.canister(),
+            "setting_remove_reader_groups",
+            (path, groups),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn setting_restore_version(
+        &self,
+        path: &SettingPath,
+        version: u32,
+        force: bool,
+    ) -> Result<UpdateSettingOutput, String> {
+        self.canister_update(
+            self.canister(),
+            "setting_restore_version",
+            (path, version, force),
+        )
+        .await
+        .map_err(format_error)?
+    }
+
+    async fn setting_prune_archived(&self, path: &SettingPath) -> Result<u32, String> {
+        self.canister_update(self.canister(), "setting_prune_archived", (path,))
+            .await
+            .map_err(format_error)?
+    }
+
     async fn setting_delete(&self, path: &SettingPath) -> Result<(), String> {
         self.canister_update(self.canister(), "setting_delete", (path,))
             .await
@@ -547,15 +1496,18 @@ pub trait CoseSDK: CanisterCaller + Sized {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use bytes::Bytes;
     use candid::{decode_args, encode_args, encode_one};
+    use futures::TryStreamExt;
     use http::{Response, StatusCode};
     use ic_agent::{agent::HttpService, AgentError};
     use ic_auth_types::{ByteBufB64, Delegation};
     use ic_cdk_management_canister::{VetKDCurve, VetKDKeyId};
-    use ic_cose_types::cose::{cose_aes256_key, ecdh::ecdh_x25519, encrypt0::cose_encrypt0, iana};
+    use ic_cose_types::cose::{
+        cose_aes256_key, ecdh::ecdh_x25519, encrypt0::cose_encrypt0, iana, kek_key_id,
+    };
     use ic_transport_types::{QueryResponse, ReplyResponse};
     use std::{
         collections::{BTreeMap, VecDeque},
@@ -564,23 +1516,23 @@ mod tests {
     };
 
     #[derive(Debug, PartialEq, Eq)]
-    enum CallKind {
+    pub(crate) enum CallKind {
         Query,
         Update,
     }
 
     #[derive(Debug)]
-    struct CallRecord {
-        kind: CallKind,
-        canister: Principal,
-        method: String,
-        args: Vec<u8>,
+    pub(crate) struct CallRecord {
+        pub(crate) kind: CallKind,
+        pub(crate) canister: Principal,
+        pub(crate) method: String,
+        pub(crate) args: Vec<u8>,
     }
 
     type MockResponses = Arc<Mutex<VecDeque<Result<Vec<u8>, String>>>>;
 
     #[derive(Clone)]
-    struct MockCose {
+    pub(crate) struct MockCose {
         canister: Principal,
         calls: Arc<Mutex<Vec<CallRecord>>>,
         responses: MockResponses,
@@ -595,7 +1547,7 @@ mod tests {
     }
 
     impl MockCose {
-        fn new() -> Self {
+        pub(crate) fn new() -> Self {
             Self {
                 canister: Principal::management_canister(),
                 calls: Arc::new(Mutex::new(Vec::new())),
@@ -604,21 +1556,21 @@ mod tests {
             }
         }
 
-        fn respond<T: CandidType>(&self, value: T) {
+        pub(crate) fn respond<T: CandidType>(&self, value: T) {
             self.responses
                 .lock()
                 .unwrap()
                 .push_back(Ok(encode_one(Ok::<T, String>(value)).unwrap()));
         }
 
-        fn respond_err(&self, err: &str) {
+        pub(crate) fn respond_err(&self, err: &str) {
             self.responses
                 .lock()
                 .unwrap()
                 .push_back(Err(err.to_string()));
         }
 
-        fn calls(&self) -> MutexGuard<'_, Vec<CallRecord>> {
+        pub(crate) fn calls(&self) -> MutexGuard<'_, Vec<CallRecord>> {
             self.calls.lock().unwrap()
         }
 
@@ -628,14 +1580,17 @@ mod tests {
 
         fn next_response(&self, method: &str, args: &[u8]) -> Result<Vec<u8>, BoxError> {
             if method == "ecdh_cose_encrypted_key" {
-                let (path, ecdh): (SettingPath, ECDHInput) = decode_args(args)?;
+                let (path, ecdh, kek_version): (SettingPath, ECDHInput, Option<u32>) =
+                    decode_args(args)?;
                 let subject = path.subject.expect("test path has subject");
                 let server_secret = [8u8; 32];
                 let (shared_secret, server_public) = ecdh_x25519(server_secret, *ecdh.public_key);
                 let cose_key = match *self.ecdh_mode.lock().unwrap() {
-                    EcdhMode::Valid => cose_aes256_key([9u8; 32], b"kid".to_vec())
-                        .to_vec()
-                        .unwrap(),
+                    EcdhMode::Valid => {
+                        cose_aes256_key([9u8; 32], kek_key_id(&path.key, kek_version.unwrap_or(0)))
+                            .to_vec()
+                            .unwrap()
+                    }
                     EcdhMode::InvalidCoseKey => vec![1, 2, 3],
                     EcdhMode::ShortSecret => {
                         let mut key = CoseKey::new();
@@ -746,6 +1701,7 @@ mod tests {
             schnorr_ed25519_public_key: Some(public_key_output()),
             schnorr_secp256k1_public_key: Some(public_key_output()),
             governance_canister: Some(Principal::management_canister()),
+            gas_policy: GasPolicy::default(),
         }
     }
 
@@ -765,6 +1721,26 @@ mod tests {
             gas_balance: 100,
             fixed_id_names: BTreeMap::from([("fixed".to_string(), principals())]),
             session_expires_in_ms: 86_400_000,
+            retain_versions: 0,
+            retain_archived_ms: 0,
+            gas_used: 0,
+            gas_exempt: false,
+            usage: BTreeMap::new(),
+            max_bytes_total: 0,
+            max_settings: 0,
+            settings_total: 1,
+            rate_limit: None,
+            groups: BTreeMap::new(),
+            manager_groups: BTreeSet::new(),
+            auditor_groups: BTreeSet::new(),
+            user_groups: BTreeSet::new(),
+            policy: vec![],
+            signing_policy: None,
+            approval_policy: None,
+            alias: None,
+            kek_version: 0,
+            gas_credit: 0,
+            moving_from: None,
         }
     }
 
@@ -789,7 +1765,7 @@ mod tests {
             .serialize()
     }
 
-    fn setting_info() -> SettingInfo {
+    pub(crate) fn setting_info() -> SettingInfo {
         SettingInfo {
             key: ByteBuf::from(vec![1]),
             subject: Principal::management_canister(),
@@ -802,6 +1778,11 @@ mod tests {
             tags: BTreeMap::from([("tag".to_string(), "value".to_string())]),
             dek: Some(ByteBuf::from(vec![7])),
             payload: Some(ByteBuf::from(vec![8])),
+            expires_at: None,
+            restored_from: None,
+            certificate: None,
+            witness: None,
+            reader_groups: BTreeSet::new(),
         }
     }
 
@@ -1034,6 +2015,13 @@ mod tests {
         respond_unit!(sdk.admin_remove_auditors(&managers));
         respond_unit!(sdk.admin_add_allowed_apis(&apis));
         respond_unit!(sdk.admin_remove_allowed_apis(&apis));
+        respond_unit!(sdk.admin_set_gas_exempt("namespace_1", true));
+        respond_unit!(sdk.admin_set_gas_policy(&GasPolicy::default()));
+        respond_unit!(sdk.admin_set_export_source(&Principal::management_canister(), None));
+        respond_unit!(sdk.admin_add_group_members("g", &managers));
+        respond_unit!(sdk.admin_remove_group_members("g", &managers));
+        sdk.respond(BTreeMap::from([("g".to_string(), managers.clone())]));
+        assert_eq!(sdk.admin_list_groups().await.unwrap()["g"], managers);
 
         sdk.respond(namespace_info());
         assert_eq!(
@@ -1092,6 +2080,12 @@ mod tests {
             sdk.get_cose_encrypted_key(&path).await.unwrap(),
             ByteArray::from([9u8; 32])
         );
+        assert_eq!(
+            sdk.get_versioned_cose_encrypted_key(&path, Some(2))
+                .await
+                .unwrap(),
+            (ByteArray::from([9u8; 32]), 2)
+        );
 
         sdk.respond(ByteBuf::from(vec![4]));
         assert_eq!(
@@ -1112,6 +2106,25 @@ mod tests {
         sdk.respond(ByteBuf::from(vec![4, 5, 6]));
         assert!(!sdk.vetkey(&path).await.unwrap_err().is_empty());
 
+        let recipient = Principal::from_slice(&[1, 2, 3]);
+        sdk.respond(ByteBuf::from(derived_public_key_bytes()));
+        let ciphertext = sdk
+            .ibe_encrypt_to("namespace_1", &recipient, b"hello")
+            .await
+            .unwrap();
+        assert!(crate::vetkeys::IbeCiphertext::deserialize(&ciphertext).is_ok());
+        assert!(sdk
+            .calls()
+            .iter()
+            .any(|call| call.kind == CallKind::Update && call.method == "vetkd_ibe_public_key"));
+        sdk.respond(ByteBuf::from(derived_public_key_bytes()));
+        sdk.respond(ByteBuf::from(vec![4, 5, 6]));
+        assert!(!sdk
+            .ibe_decrypt_from("namespace_1", &recipient, &ciphertext)
+            .await
+            .unwrap_err()
+            .is_empty());
+
         sdk.respond(Principal::management_canister());
         assert_eq!(
             sdk.namespace_get_fixed_identity("namespace_1", "fixed")
@@ -1151,7 +2164,10 @@ mod tests {
         );
         sdk.respond(namespace_info());
         sdk.namespace_get_info("namespace_1").await.unwrap();
-        sdk.respond(namespace_info());
+        sdk.respond(vec![(
+            Principal::management_canister(),
+            ByteBuf::from(vec![1]),
+        )]);
         sdk.namespace_list_setting_keys(
             "namespace_1",
             true,
@@ -1159,7 +2175,32 @@ mod tests {
         )
         .await
         .unwrap();
+        sdk.respond(SearchSettingsOutput {
+            settings: vec![setting_info()],
+            next_cursor: None,
+        });
+        assert_eq!(
+            sdk.namespace_search_settings(&SearchSettingsInput {
+                ns: "namespace_1".to_string(),
+                tag: "env".to_string(),
+                value: "prod".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .settings
+            .len(),
+            1
+        );
         respond_unit!(sdk.namespace_update_info(&update_namespace));
+        sdk.respond(namespace_info());
+        assert_eq!(
+            sdk.namespace_recompute_usage("namespace_1")
+                .await
+                .unwrap()
+                .settings_total,
+            1
+        );
         respond_unit!(sdk.namespace_delete("namespace_1"));
         respond_unit!(sdk.namespace_add_managers("namespace_1", &managers));
         respond_unit!(sdk.namespace_remove_managers("namespace_1", &managers));
@@ -1167,6 +2208,61 @@ mod tests {
         respond_unit!(sdk.namespace_remove_auditors("namespace_1", &managers));
         respond_unit!(sdk.namespace_add_users("namespace_1", &managers));
         respond_unit!(sdk.namespace_remove_users("namespace_1", &managers));
+        let groups = BTreeSet::from(["g".to_string()]);
+        respond_unit!(sdk.namespace_add_group_members("namespace_1", "g", &managers));
+        respond_unit!(sdk.namespace_remove_group_members("namespace_1", "g", &managers));
+        respond_unit!(sdk.namespace_add_role_groups("namespace_1", "user", &groups));
+        respond_unit!(sdk.namespace_remove_role_groups("namespace_1", "user", &groups));
+        respond_unit!(sdk.namespace_update_policy("namespace_1", &[]));
+        respond_unit!(sdk.namespace_update_signing_policy("namespace_1", None));
+        let approval = ApprovalPolicy {
+            threshold: 2,
+            expires_in_ms: 1000,
+        };
+        respond_unit!(sdk.namespace_enable_approval("namespace_1", &approval));
+        let proposal = ProposalInfo {
+            id: 1,
+            change: NamespaceChange::Delete,
+            description: "variant { Delete }".to_string(),
+            proposer: Principal::management_canister(),
+            approvers: managers.clone(),
+            created_at: 1,
+            expires_at: 1001,
+            executed: false,
+        };
+        sdk.respond(proposal.clone());
+        assert_eq!(
+            sdk.namespace_propose_change("namespace_1", &NamespaceChange::Delete)
+                .await
+                .unwrap(),
+            proposal
+        );
+        sdk.respond(proposal.clone());
+        assert_eq!(
+            sdk.namespace_approve_change("namespace_1", 1)
+                .await
+                .unwrap(),
+            proposal
+        );
+        sdk.respond(vec![proposal.clone()]);
+        assert_eq!(
+            sdk.namespace_list_proposals("namespace_1").await.unwrap(),
+            vec![proposal]
+        );
+        sdk.respond(CheckPermissionOutput {
+            allowed: true,
+            rule: None,
+        });
+        assert!(
+            sdk.namespace_check_permission(&CheckPermissionInput {
+                ns: "namespace_1".to_string(),
+                action: "sign".to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+            .allowed
+        );
         sdk.respond(true);
         assert!(sdk
             .namespace_is_member("namespace_1", "manager", &Principal::management_canister())
@@ -1203,7 +2299,7 @@ mod tests {
                 .version,
             3
         );
-        sdk.respond(create_output);
+        sdk.respond(create_output.clone());
         assert_eq!(
             sdk.setting_update_payload(&path, &update_payload)
                 .await
@@ -1211,8 +2307,44 @@ mod tests {
                 .version,
             3
         );
+        sdk.respond(vec![Some(create_output.clone()), None]);
+        assert_eq!(
+            sdk.setting_batch(&SettingBatchInput {
+                ns: path.ns.clone(),
+                ops: vec![
+                    SettingBatchOp::UpdatePayload {
+                        path: path.clone(),
+                        input: update_payload.clone(),
+                    },
+                    SettingBatchOp::Delete { path: path.clone() },
+                ],
+            })
+            .await
+            .unwrap()
+            .len(),
+            2
+        );
         respond_unit!(sdk.setting_add_readers(&path, &managers));
         respond_unit!(sdk.setting_remove_readers(&path, &managers));
+        let reader_groups = BTreeSet::from(["canister:g".to_string()]);
+        respond_unit!(sdk.setting_add_reader_groups(&path, &reader_groups));
+        respond_unit!(sdk.setting_remove_reader_groups(&path, &reader_groups));
+        respond_unit!(sdk.setting_grant_readers(&path, &managers, 1000));
+        sdk.respond(BTreeMap::from([(
+            Principal::management_canister(),
+            1000u64,
+        )]));
+        assert_eq!(sdk.setting_get_reader_grants(&path).await.unwrap().len(), 1);
+        sdk.respond(create_output);
+        assert_eq!(
+            sdk.setting_restore_version(&path, 1, false)
+                .await
+                .unwrap()
+                .version,
+            3
+        );
+        sdk.respond(2u32);
+        assert_eq!(sdk.setting_prune_archived(&path).await.unwrap(), 2);
         respond_unit!(sdk.setting_delete(&path));
 
         let calls = sdk.calls();
@@ -1231,6 +2363,390 @@ mod tests {
         assert_eq!(input.unwrap().ns, "namespace_1");
     }
 
+    #[tokio::test]
+    async fn cose_sdk_chunked_helpers_split_and_reassemble_payload() {
+        let sdk = MockCose::new();
+        let path = setting_path();
+        let payload: Vec<u8> = (0..CHUNK_SIZE * 2 + 1).map(|i| i as u8).collect();
+        let create_output = CreateSettingOutput {
+            created_at: 1,
+            updated_at: 1,
+            version: 1,
+        };
+
+        for _ in 0..4 {
+            sdk.respond(());
+        }
+        sdk.respond(create_output.clone());
+        sdk.setting_create_chunked(
+            &path,
+            CreateSettingInput {
+                payload: Some(ByteBuf::from(payload.clone())),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        sdk.respond(create_output);
+        sdk.setting_update_payload_chunked(
+            &path,
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from(vec![1])),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        {
+            let calls = sdk.calls();
+            let methods: Vec<&str> = calls.iter().map(|call| call.method.as_str()).collect();
+            assert_eq!(
+                methods,
+                vec![
+                    "setting_upload_begin",
+                    "setting_upload_chunk",
+                    "setting_upload_chunk",
+                    "setting_upload_chunk",
+                    "setting_create",
+                    "setting_update_payload",
+                ]
+            );
+            let (_, size): (SettingPath, u64) = decode_args(&calls[0].args).unwrap();
+            assert_eq!(size, payload.len() as u64);
+            let (_, index, chunk): (SettingPath, u32, ByteBuf) =
+                decode_args(&calls[3].args).unwrap();
+            assert_eq!(index, 2);
+            assert_eq!(chunk.as_slice(), &payload[CHUNK_SIZE as usize * 2..]);
+            let (_, input): (SettingPath, CreateSettingInput) =
+                decode_args(&calls[4].args).unwrap();
+            assert!(input.payload.is_none());
+            assert_eq!(
+                input.payload_sha256,
+                Some(ByteArray::from(sha256(&payload)))
+            );
+            let (_, input): (SettingPath, UpdateSettingPayloadInput) =
+                decode_args(&calls[5].args).unwrap();
+            assert_eq!(input.payload, Some(ByteBuf::from(vec![1])));
+            assert!(input.payload_sha256.is_none());
+        }
+
+        let sdk = MockCose::new();
+        for (i, chunk) in payload.chunks(CHUNK_SIZE as usize).enumerate() {
+            sdk.respond(SettingChunkOutput {
+                version: 2,
+                payload_size: payload.len() as u64,
+                chunk_index: i as u32,
+                chunk: ByteBuf::from(chunk),
+                dek: None,
+            });
+        }
+        sdk.respond(SettingInfo {
+            version: 2,
+            dek: None,
+            payload: None,
+            ..setting_info()
+        });
+        let info = sdk.setting_get_chunked(&path).await.unwrap();
+        assert_eq!(info.version, 2);
+        assert_eq!(info.payload.unwrap().as_slice(), payload.as_slice());
+        let calls = sdk.calls();
+        let (path, index): (SettingPath, u32) = decode_args(&calls[2].args).unwrap();
+        assert_eq!(path.version, 2);
+        assert_eq!(index, 2);
+        assert_eq!(calls[3].method, "setting_get_dek");
+        assert!(calls[..4].iter().all(|call| call.kind == CallKind::Query));
+    }
+
+    #[tokio::test]
+    async fn cose_sdk_encrypted_setting_roundtrips_with_kek() {
+        let sdk = MockCose::new();
+        let kek = [5u8; 32];
+        let mut path = SettingPath {
+            ns: "namespace_1".to_string(),
+            key: ByteBuf::from(vec![1]),
+            ..Default::default()
+        };
+        let output = CreateSettingOutput {
+            created_at: 1,
+            updated_at: 1,
+            version: 1,
+        };
+        sdk.respond(output.clone());
+        let res = sdk
+            .put_encrypted_setting_with_kek(&path, b"secret", &kek, path.key.to_vec())
+            .await
+            .unwrap();
+        assert_eq!(res.version, 1);
+
+        let mut info = setting_info();
+        {
+            let calls = sdk.calls();
+            assert_eq!(calls[0].method, "setting_create");
+            let (_, input): (SettingPath, CreateSettingInput) =
+                decode_args(&calls[0].args).unwrap();
+            assert_ne!(input.payload.as_ref().unwrap().as_slice(), b"secret");
+            info.payload = input.payload;
+            info.dek = input.dek;
+        }
+
+        sdk.respond(info.clone());
+        let (plain, got) = sdk
+            .get_encrypted_setting_with_kek(&path, &kek, &path.key)
+            .await
+            .unwrap();
+        assert_eq!(plain, b"secret".to_vec());
+        assert_eq!(got.version, info.version);
+
+        sdk.respond(info.clone());
+        let err = sdk
+            .get_encrypted_setting_with_kek(&path, &kek, b"other")
+            .await
+            .unwrap_err();
+        assert!(err.contains("kid mismatch"), "{}", err);
+
+        path.version = 1;
+        sdk.respond(output);
+        sdk.put_encrypted_setting_with_kek(&path, b"secret 2", &kek, path.key.to_vec())
+            .await
+            .unwrap();
+        let calls = sdk.calls();
+        assert_eq!(calls[3].method, "setting_update_payload");
+        let (p, input): (SettingPath, UpdateSettingPayloadInput) =
+            decode_args(&calls[3].args).unwrap();
+        assert_eq!(p.version, 1);
+        assert!(input.dek.is_some());
+    }
+
+    #[tokio::test]
+    async fn cose_sdk_list_settings_stream_walks_all_pages() {
+        let sdk = MockCose::new();
+        let subject = Principal::management_canister();
+        sdk.respond(ListSettingsOutput {
+            keys: vec![(subject, ByteBuf::from(vec![1]))],
+            next_start_after: Some((subject, ByteBuf::from(vec![1]))),
+            ..Default::default()
+        });
+        sdk.respond(ListSettingsOutput {
+            keys: vec![(subject, ByteBuf::from(vec![2]))],
+            next_start_after: None,
+            ..Default::default()
+        });
+
+        let pages: Vec<ListSettingsOutput> = sdk
+            .namespace_list_settings_stream(ListSettingsInput {
+                ns: "namespace_1".to_string(),
+                limit: Some(1),
+                ..Default::default()
+            })
+            .try_collect()
+            .await
+            .unwrap();
+        let keys: Vec<u8> = pages
+            .iter()
+            .flat_map(|p| p.keys.iter().map(|k| k.1[0]))
+            .collect();
+        assert_eq!(keys, vec![1, 2]);
+
+        let calls = sdk.calls();
+        assert_eq!(calls.len(), 2);
+        assert!(calls.iter().all(|call| call.kind == CallKind::Query));
+        let (input,): (ListSettingsInput,) = decode_args(&calls[0].args).unwrap();
+        assert!(input.start_after.is_none());
+        let (input,): (ListSettingsInput,) = decode_args(&calls[1].args).unwrap();
+        assert_eq!(input.start_after, Some((subject, ByteBuf::from(vec![1]))));
+    }
+
+    #[tokio::test]
+    async fn cose_sdk_export_stream_follows_cursors() {
+        let sdk = MockCose::new();
+        let page = |bundle: u8, next_cursor: Option<u8>| NamespaceExportPage {
+            bundle: ByteBuf::from(vec![bundle]),
+            signature: ByteBuf::from(vec![0; 64]),
+            public_key: ByteBuf::from(vec![0; 32]),
+            next_cursor: next_cursor.map(|c| ByteBuf::from(vec![c])),
+        };
+        sdk.respond(page(1, Some(9)));
+        sdk.respond(page(2, None));
+
+        let pages: Vec<NamespaceExportPage> = sdk
+            .namespace_export_stream("namespace_1".to_string())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(pages, vec![page(1, Some(9)), page(2, None)]);
+
+        {
+            let calls = sdk.calls();
+            assert_eq!(calls.len(), 2);
+            assert!(calls.iter().all(|call| call.kind == CallKind::Update));
+            let (input,): (ExportNamespaceInput,) = decode_args(&calls[0].args).unwrap();
+            assert!(input.cursor.is_none());
+            let (input,): (ExportNamespaceInput,) = decode_args(&calls[1].args).unwrap();
+            assert_eq!(input.cursor, Some(ByteBuf::from(vec![9])));
+        }
+
+        sdk.respond(namespace_info());
+        let info = sdk
+            .namespace_import(&ImportNamespaceInput {
+                name: "namespace_2".to_string(),
+                bundle: pages[0].bundle.clone(),
+                signature: pages[0].signature.clone(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(info, namespace_info());
+    }
+
+    #[tokio::test]
+    async fn cose_sdk_watch_polls_changes_and_sleeps_when_caught_up() {
+        let sdk = MockCose::new();
+        let change = |seq: u64| SettingChange {
+            seq,
+            kind: SettingChangeKind::UpdatePayload,
+            user_owned: false,
+            subject: Principal::management_canister(),
+            key: ByteBuf::from(vec![1]),
+            version: seq as u32 + 1,
+            changed_at: seq,
+        };
+        sdk.respond(SettingChangesOutput {
+            changes: vec![change(1), change(2)],
+            next_cursor: 2,
+        });
+        sdk.respond(SettingChangesOutput {
+            changes: vec![],
+            next_cursor: 2,
+        });
+        sdk.respond(SettingChangesOutput {
+            changes: vec![change(3)],
+            next_cursor: 3,
+        });
+
+        let sleeps = std::sync::atomic::AtomicUsize::new(0);
+        let changes: Vec<SettingChange> = sdk
+            .watch("namespace_1", 0, || {
+                sleeps.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                async {}
+            })
+            .take(3)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            changes.iter().map(|c| c.seq).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(sleeps.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let calls = sdk.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls
+            .iter()
+            .all(|call| call.method == "namespace_changes_since"));
+        let (_, cursor, _): (String, u64, Option<u32>) = decode_args(&calls[0].args).unwrap();
+        assert_eq!(cursor, 0);
+        let (_, cursor, _): (String, u64, Option<u32>) = decode_args(&calls[2].args).unwrap();
+        assert_eq!(cursor, 2);
+    }
+
+    #[test]
+    fn verify_certificate_time_rejects_old_certificates() {
+        use ic_agent::hash_tree::{label, leaf};
+
+        // 1_000_000_000_000 nanoseconds in LEB128
+        let tree: HashTree<Vec<u8>> = label("time", leaf(vec![0x80, 0xa0, 0x94, 0xa5, 0x8d, 0x1d]));
+        let max_age = Duration::from_secs(60);
+        let time = Duration::from_secs(1000);
+        assert!(verify_certificate_time(&tree, max_age, time).is_ok());
+        assert!(verify_certificate_time(&tree, max_age, time + max_age).is_ok());
+        // certificates from the future are allowed
+        assert!(verify_certificate_time(&tree, max_age, Duration::from_secs(1)).is_ok());
+        assert_eq!(
+            verify_certificate_time(&tree, max_age, time + max_age + Duration::from_nanos(1))
+                .unwrap_err(),
+            "certificate is older than 60s"
+        );
+
+        let tree: HashTree<Vec<u8>> = label("time", leaf(vec![0x80]));
+        assert_eq!(
+            verify_certificate_time(&tree, max_age, time).unwrap_err(),
+            "invalid time in certificate"
+        );
+        let tree: HashTree<Vec<u8>> = label("other", leaf(vec![0]));
+        assert_eq!(
+            verify_certificate_time(&tree, max_age, time).unwrap_err(),
+            "time not found in certificate"
+        );
+    }
+
+    #[test]
+    fn verify_setting_witness_checks_certified_hashes() {
+        use ic_agent::hash_tree::{fork, label, leaf, pruned};
+
+        let path = setting_path();
+        let mut info = setting_info();
+        info.key = path.key.clone();
+        let key = certified_setting_key(&path.ns, path.user_owned, &info.subject, &info.key);
+        let witness = |meta: [u8; 32], payload: HashTree<Vec<u8>>| -> HashTree<Vec<u8>> {
+            fork(
+                label(
+                    LABEL_SETTINGS,
+                    label(
+                        key.clone(),
+                        fork(label(LABEL_SETTING_META, leaf(meta.to_vec())), payload),
+                    ),
+                ),
+                pruned([9u8; 32]),
+            )
+        };
+
+        let tree = witness(
+            info.meta_hash(),
+            label(LABEL_SETTING_PAYLOAD, leaf(info.payload_hash().to_vec())),
+        );
+        let certified_data = tree.digest();
+        info.witness = Some(ByteBuf::from(cbor2::to_vec(&tree).unwrap()));
+        assert!(verify_setting_witness(&certified_data, &path, &info, true).is_ok());
+        assert_eq!(
+            verify_setting_witness(&[0u8; 32], &path, &info, true).unwrap_err(),
+            "witness does not match the certified data"
+        );
+        let mut other = info.clone();
+        other.key = ByteBuf::from(vec![0]);
+        assert_eq!(
+            verify_setting_witness(&certified_data, &path, &other, true).unwrap_err(),
+            "setting does not match the path"
+        );
+        other.key = info.key.clone();
+        other.payload = Some(ByteBuf::from(vec![0]));
+        assert_eq!(
+            verify_setting_witness(&certified_data, &path, &other, true).unwrap_err(),
+            "setting payload is not certified"
+        );
+        other.desc = "other".to_string();
+        assert_eq!(
+            verify_setting_witness(&certified_data, &path, &other, false).unwrap_err(),
+            "setting meta is not certified"
+        );
+
+        // the payload hash is pruned in the witness of setting info
+        let tree = witness(info.meta_hash(), pruned([8u8; 32]));
+        let certified_data = tree.digest();
+        info.witness = Some(ByteBuf::from(cbor2::to_vec(&tree).unwrap()));
+        assert!(verify_setting_witness(&certified_data, &path, &info, false).is_ok());
+        assert_eq!(
+            verify_setting_witness(&certified_data, &path, &info, true).unwrap_err(),
+            "setting payload is not certified"
+        );
+        info.witness = None;
+        assert_eq!(
+            verify_setting_witness(&certified_data, &path, &info, false).unwrap_err(),
+            "setting is not certified"
+        );
+    }
+
     #[tokio::test]
     async fn cose_sdk_maps_caller_errors_and_checks_required_subject() {
         let sdk = MockCose::new();
@@ -0,0 +1,135 @@
+use ic_cose_types::{
+    cose::{
+        encrypt0::{cose_decrypt0, cose_encrypt0, decrypt, try_decode_encrypt0},
+        format_error,
+    },
+    types::SettingPath,
+};
+use serde_bytes::ByteBuf;
+
+use crate::rand_bytes;
+
+/// Returns the additional authenticated data of a wrapped DEK and of the payload encrypted with it,
+/// the setting key. It binds them to their setting so that they cannot be moved to another key.
+pub fn dek_aad(path: &SettingPath) -> &[u8] {
+    path.key.as_slice()
+}
+
+/// Returns the kid recorded in the wrapped DEK, empty if there is none.
+pub fn dek_kid(wrapped: &[u8]) -> Result<Vec<u8>, String> {
+    let e0 = try_decode_encrypt0(wrapped)?;
+    let kid = e0.unprotected.kid().map_err(format_error)?;
+    Ok(kid.unwrap_or_default().to_vec())
+}
+
+/// Wraps the DEK as a COSE_Encrypt0 under the KEK, recording the kid of the KEK.
+pub fn wrap_dek(
+    path: &SettingPath,
+    dek: &[u8; 32],
+    kek: &[u8; 32],
+    kid: Vec<u8>,
+) -> Result<ByteBuf, String> {
+    let nonce: [u8; 12] = rand_bytes();
+    let wrapped = cose_encrypt0(dek, kek, dek_aad(path), &nonce, Some(kid))?;
+    Ok(ByteBuf::from(wrapped))
+}
+
+/// Unwraps the DEK with the KEK, the kid recorded in the wrapped DEK should be the expected one.
+pub fn unwrap_dek(
+    path: &SettingPath,
+    wrapped: &[u8],
+    kek: &[u8; 32],
+    kid: &[u8],
+) -> Result<[u8; 32], String> {
+    let e0 = try_decode_encrypt0(wrapped)?;
+    let got = e0.unprotected.kid().map_err(format_error)?;
+    if got != Some(kid) {
+        return Err(format!(
+            "kid mismatch, expected {:?}, got {:?}",
+            String::from_utf8_lossy(kid),
+            got.map(String::from_utf8_lossy)
+        ));
+    }
+    let dek = decrypt(&e0, kek, dek_aad(path))?;
+    dek.try_into()
+        .map_err(|val: Vec<u8>| format!("invalid DEK, expected 32 bytes, got {}", val.len()))
+}
+
+/// Encrypts the payload as a COSE_Encrypt0 with the DEK.
+pub fn encrypt_payload(
+    path: &SettingPath,
+    payload: &[u8],
+    dek: &[u8; 32],
+) -> Result<ByteBuf, String> {
+    let nonce: [u8; 12] = rand_bytes();
+    let encrypted = cose_encrypt0(payload, dek, dek_aad(path), &nonce, None)?;
+    Ok(ByteBuf::from(encrypted))
+}
+
+/// Decrypts the COSE_Encrypt0 payload with the DEK.
+pub fn decrypt_payload(
+    path: &SettingPath,
+    payload: &[u8],
+    dek: &[u8; 32],
+) -> Result<Vec<u8>, String> {
+    cose_decrypt0(payload, dek, dek_aad(path))
+}
+
+/// Encrypts the payload with a random DEK and wraps the DEK under the KEK.
+/// It returns the encrypted payload and the wrapped DEK.
+pub fn seal(
+    path: &SettingPath,
+    payload: &[u8],
+    kek: &[u8; 32],
+    kid: Vec<u8>,
+) -> Result<(ByteBuf, ByteBuf), String> {
+    let dek: [u8; 32] = rand_bytes();
+    let payload = encrypt_payload(path, payload, &dek)?;
+    let dek = wrap_dek(path, &dek, kek, kid)?;
+    Ok((payload, dek))
+}
+
+/// Reverses [`seal`], the kid recorded in the wrapped DEK should be the expected one.
+pub fn open(
+    path: &SettingPath,
+    payload: &[u8],
+    wrapped: &[u8],
+    kek: &[u8; 32],
+    kid: &[u8],
+) -> Result<Vec<u8>, String> {
+    let dek = unwrap_dek(path, wrapped, kek, kid)?;
+    decrypt_payload(path, payload, &dek)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(key: &str) -> SettingPath {
+        SettingPath {
+            ns: "namespace_1".to_string(),
+            key: ByteBuf::from(key.as_bytes()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn seal_and_open_verify_kid_and_key() {
+        let kek = [9u8; 32];
+        let p = path("config");
+        let (payload, dek) = seal(&p, b"hello", &kek, b"config".to_vec()).unwrap();
+        assert_ne!(payload.as_slice(), b"hello");
+        try_decode_encrypt0(&payload).unwrap();
+        assert_eq!(dek_kid(&dek).unwrap(), b"config".to_vec());
+        assert_eq!(
+            open(&p, &payload, &dek, &kek, b"config").unwrap(),
+            b"hello".to_vec()
+        );
+
+        let err = open(&p, &payload, &dek, &kek, b"other").unwrap_err();
+        assert!(err.contains("kid mismatch"), "{}", err);
+        assert!(open(&p, &payload, &dek, &[8u8; 32], b"config").is_err());
+        // bound to the setting key
+        assert!(open(&path("other"), &payload, &dek, &kek, b"config").is_err());
+    }
+}
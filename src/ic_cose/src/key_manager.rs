@@ -0,0 +1,205 @@
+use async_trait::async_trait;
+use candid::Principal;
+use ic_cose_types::{
+    cose::{kdf::try_hkdf256, kek_key_id, kek_key_version},
+    types::SettingPath,
+};
+use serde_bytes::ByteBuf;
+use std::{collections::BTreeMap, sync::RwLock};
+use zeroize::Zeroizing;
+
+use crate::{client::CoseSDK, dek, rand_bytes, rewrap::KekProvider};
+
+type KekCacheKey = (String, bool, Principal, Vec<u8>, u32);
+
+/// Manages KEKs combined from the partial KEKs derived by the canister with `ecdh_cose_encrypted_key`
+/// and a local secret that never leaves the client. Neither the canister nor the client alone can derive the KEK.
+///
+/// KEKs are cached by setting path and KEK version, and zeroized when they are dropped.
+/// The DEKs wrapped by it record the KEK version in the kid, see [`kek_key_id`].
+pub struct KeyManager<S> {
+    sdk: S,
+    local_secret: Zeroizing<[u8; 32]>,
+    cache: RwLock<BTreeMap<KekCacheKey, Zeroizing<[u8; 32]>>>,
+}
+
+impl<S: CoseSDK + Sync> KeyManager<S> {
+    pub fn new(sdk: S, local_secret: [u8; 32]) -> Self {
+        Self {
+            sdk,
+            local_secret: Zeroizing::new(local_secret),
+            cache: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn sdk(&self) -> &S {
+        &self.sdk
+    }
+
+    /// Removes and zeroizes all cached KEKs.
+    pub fn clear_cache(&self) {
+        self.cache.write().unwrap().clear();
+    }
+
+    /// Returns the KEK of the setting for the KEK version and its kid, the current version if None.
+    /// The current version is always fetched from the canister so that a KEK rotation is picked up.
+    pub async fn kek(
+        &self,
+        path: &SettingPath,
+        kek_version: Option<u32>,
+    ) -> Result<(Zeroizing<[u8; 32]>, Vec<u8>), String> {
+        let subject = path
+            .subject
+            .ok_or_else(|| "subject is required for KeyManager".to_string())?;
+        let cache_key = |version: u32| {
+            (
+                path.ns.clone(),
+                path.user_owned,
+                subject,
+                path.key.to_vec(),
+                version,
+            )
+        };
+
+        if let Some(version) = kek_version {
+            if let Some(kek) = self.cache.read().unwrap().get(&cache_key(version)) {
+                return Ok((kek.clone(), kek_key_id(&path.key, version)));
+            }
+        }
+
+        let (partial, version) = self
+            .sdk
+            .get_versioned_cose_encrypted_key(path, kek_version)
+            .await?;
+        let partial = Zeroizing::new(partial.into_array());
+        let kid = kek_key_id(&path.key, version);
+        let kek = Zeroizing::new(try_hkdf256::<32>(
+            partial.as_slice(),
+            Some(self.local_secret.as_slice()),
+            &kid,
+        )?);
+        self.cache
+            .write()
+            .unwrap()
+            .insert(cache_key(version), kek.clone());
+        Ok((kek, kid))
+    }
+
+    /// Wraps the DEK under the current KEK of the setting.
+    pub async fn encrypt_dek(&self, path: &SettingPath, key: &[u8; 32]) -> Result<ByteBuf, String> {
+        let (kek, kid) = self.kek(path, None).await?;
+        dek::wrap_dek(path, key, &kek, kid)
+    }
+
+    /// Unwraps the DEK with the KEK version recorded in its kid.
+    pub async fn decrypt_dek(
+        &self,
+        path: &SettingPath,
+        wrapped: &[u8],
+    ) -> Result<Zeroizing<[u8; 32]>, String> {
+        let kid = dek::dek_kid(wrapped)?;
+        let version = kek_key_version(&path.key, &kid)
+            .ok_or_else(|| "kid mismatch, the DEK belongs to another setting".to_string())?;
+        let (kek, kid) = self.kek(path, Some(version)).await?;
+        Ok(Zeroizing::new(dek::unwrap_dek(path, wrapped, &kek, &kid)?))
+    }
+
+    /// Encrypts the payload with a random DEK wrapped under the current KEK of the setting.
+    /// It returns the encrypted payload and the wrapped DEK, as `payload` and `dek` of
+    /// `CreateSettingInput` or `UpdateSettingPayloadInput`.
+    pub async fn encrypt_payload(
+        &self,
+        path: &SettingPath,
+        payload: &[u8],
+    ) -> Result<(ByteBuf, ByteBuf), String> {
+        let key = Zeroizing::new(rand_bytes::<32>());
+        let wrapped = self.encrypt_dek(path, &key).await?;
+        let payload = dek::encrypt_payload(path, payload, &key)?;
+        Ok((payload, wrapped))
+    }
+
+    /// Decrypts the payload of a setting with its wrapped DEK.
+    pub async fn decrypt_payload(
+        &self,
+        path: &SettingPath,
+        payload: &[u8],
+        wrapped: &[u8],
+    ) -> Result<Vec<u8>, String> {
+        let key = self.decrypt_dek(path, wrapped).await?;
+        dek::decrypt_payload(path, payload, &key)
+    }
+}
+
+#[async_trait]
+impl<S: CoseSDK + Sync + Send> KekProvider for KeyManager<S> {
+    async fn unwrap_kek(&self, path: &SettingPath, kid: &[u8]) -> Result<[u8; 32], String> {
+        let version = kek_key_version(&path.key, kid)
+            .ok_or_else(|| "kid mismatch, the DEK belongs to another setting".to_string())?;
+        let (kek, _) = self.kek(path, Some(version)).await?;
+        Ok(*kek)
+    }
+
+    async fn wrap_kek(&self, path: &SettingPath) -> Result<([u8; 32], Vec<u8>), String> {
+        let (kek, kid) = self.kek(path, None).await?;
+        Ok((*kek, kid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::MockCose;
+    use ic_cose_types::cose::encrypt0::try_decode_encrypt0;
+
+    #[tokio::test]
+    async fn key_manager_combines_and_caches_keks() {
+        // the mock canister returns the partial KEK [9; 32] of the requested KEK version
+        let km = KeyManager::new(MockCose::new(), [3u8; 32]);
+        let path = SettingPath {
+            ns: "namespace_1".to_string(),
+            subject: Some(Principal::management_canister()),
+            key: ByteBuf::from(vec![1]),
+            ..Default::default()
+        };
+
+        let (payload, wrapped) = km.encrypt_payload(&path, b"hello").await.unwrap();
+        try_decode_encrypt0(&payload).unwrap();
+        assert_eq!(dek::dek_kid(&wrapped).unwrap(), vec![1]);
+        let (kek, kid) = km.kek(&path, Some(0)).await.unwrap();
+        assert_eq!(kid, vec![1]);
+        assert_eq!(
+            *kek,
+            try_hkdf256::<32>(&[9u8; 32], Some(&[3u8; 32]), &[1]).unwrap()
+        );
+        assert_eq!(
+            km.decrypt_payload(&path, &payload, &wrapped).await.unwrap(),
+            b"hello".to_vec()
+        );
+        assert_eq!(km.sdk().calls().len(), 1);
+
+        // an older KEK version is derived once and then served from the cache
+        let (kek2, kid2) = km.kek(&path, Some(2)).await.unwrap();
+        assert_eq!(kid2, kek_key_id(&path.key, 2));
+        assert_ne!(*kek2, *kek);
+        km.kek(&path, Some(2)).await.unwrap();
+        assert_eq!(km.sdk().calls().len(), 2);
+
+        // a DEK of another setting is rejected
+        let mut other = path.clone();
+        other.key = ByteBuf::from(vec![2]);
+        let err = km.decrypt_dek(&other, &wrapped).await.unwrap_err();
+        assert!(err.contains("kid mismatch"), "{}", err);
+
+        // a different local secret cannot decrypt
+        let km2 = KeyManager::new(MockCose::new(), [4u8; 32]);
+        assert!(km2
+            .decrypt_payload(&path, &payload, &wrapped)
+            .await
+            .is_err());
+
+        km.clear_cache();
+        assert!(km.cache.read().unwrap().is_empty());
+        km.decrypt_payload(&path, &payload, &wrapped).await.unwrap();
+        assert_eq!(km.sdk().calls().len(), 3);
+    }
+}
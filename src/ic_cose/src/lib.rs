@@ -2,6 +2,9 @@ use rand::Rng;
 
 pub mod agent;
 pub mod client;
+pub mod dek;
+pub mod key_manager;
+pub mod rewrap;
 pub mod vetkeys;
 
 pub fn rand_bytes<const N: usize>() -> [u8; N] {
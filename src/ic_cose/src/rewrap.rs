@@ -0,0 +1,340 @@
+use async_trait::async_trait;
+use candid::Principal;
+use ic_cose_types::{
+    cose::{
+        encrypt0::{cose_encrypt0, decrypt, try_decode_encrypt0},
+        format_error, kek_key_id, kek_key_version,
+    },
+    types::{
+        setting::{ListSettingsInput, SettingInfo, UpdateSettingPayloadInput, MAX_LIST_LIMIT},
+        SettingPath,
+    },
+};
+use serde_bytes::ByteBuf;
+
+use crate::{client::CoseSDK, dek::dek_aad, rand_bytes, vetkeys::vetkey_kek};
+
+/// Provides the KEKs to unwrap and re-wrap DEKs of settings.
+#[async_trait]
+pub trait KekProvider: Send + Sync {
+    /// Returns the KEK that wrapped the DEK, identified by the kid of the DEK's COSE_Encrypt0.
+    async fn unwrap_kek(&self, path: &SettingPath, kid: &[u8]) -> Result<[u8; 32], String>;
+
+    /// Returns the KEK to re-wrap the DEK with, and the kid to record in the COSE_Encrypt0.
+    async fn wrap_kek(&self, path: &SettingPath) -> Result<([u8; 32], Vec<u8>), String>;
+}
+
+/// KEKs from `ecdh_cose_encrypted_key`, the KEK version is recorded in the kid.
+/// DEKs are re-wrapped with the current KEK version of the namespace.
+pub struct CoseKek<'a, S>(pub &'a S);
+
+#[async_trait]
+impl<S: CoseSDK + Sync> KekProvider for CoseKek<'_, S> {
+    async fn unwrap_kek(&self, path: &SettingPath, kid: &[u8]) -> Result<[u8; 32], String> {
+        let version = kek_key_version(&path.key, kid)
+            .ok_or_else(|| "kid mismatch, the DEK belongs to another setting".to_string())?;
+        let (kek, _) = self
+            .0
+            .get_versioned_cose_encrypted_key(path, Some(version))
+            .await?;
+        Ok(*kek)
+    }
+
+    async fn wrap_kek(&self, path: &SettingPath) -> Result<([u8; 32], Vec<u8>), String> {
+        let (kek, version) = self.0.get_versioned_cose_encrypted_key(path, None).await?;
+        Ok((*kek, kek_key_id(&path.key, version)))
+    }
+}
+
+/// KEKs derived from vetKeys, the kid is the setting key.
+/// DEKs of settings that moved from `from_subject` are unwrapped with its vetKey.
+pub struct VetKeyKek<'a, S> {
+    pub sdk: &'a S,
+    pub from_subject: Option<Principal>,
+}
+
+#[async_trait]
+impl<S: CoseSDK + Sync> KekProvider for VetKeyKek<'_, S> {
+    async fn unwrap_kek(&self, path: &SettingPath, _kid: &[u8]) -> Result<[u8; 32], String> {
+        let mut path = path.clone();
+        if self.from_subject.is_some() {
+            path.subject = self.from_subject;
+        }
+        let (vk, _) = self.sdk.vetkey(&path).await?;
+        vetkey_kek(&vk, &path.key)
+    }
+
+    async fn wrap_kek(&self, path: &SettingPath) -> Result<([u8; 32], Vec<u8>), String> {
+        let (vk, _) = self.sdk.vetkey(path).await?;
+        Ok((vetkey_kek(&vk, &path.key)?, path.key.to_vec()))
+    }
+}
+
+/// A resumable job that re-wraps the DEKs of settings in a namespace,
+/// e.g. after a KEK rotation or after settings moved between subjects.
+#[derive(Clone, Debug, Default)]
+pub struct RewrapJob {
+    pub namespace: String,
+    pub user_owned: bool,
+    pub subject: Option<Principal>,                // default to caller
+    pub start_after: Option<(Principal, ByteBuf)>, // cursor of a previous report to resume from
+    pub limit: Option<usize>,                      // max number of settings to process in a run
+}
+
+/// Progress report of a [`RewrapJob`] run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RewrapReport {
+    pub rewrapped: u64,
+    pub skipped: u64, // settings without DEK or already wrapped with the new KEK
+    pub failed: Vec<(Principal, ByteBuf, String)>, // retried by resuming from `cursor`
+    pub cursor: Option<(Principal, ByteBuf)>, // the last setting before the first failed one
+    pub done: bool,   // true if all settings were processed without failures
+}
+
+impl RewrapJob {
+    /// Walks the pages of setting keys after `start_after`, fetches each DEK, unwraps it with the old KEK,
+    /// re-wraps it with the new KEK and submits it with `setting_update_payload`.
+    /// The update is compare-and-swap on the fetched version, a conflict is reported as failed.
+    /// The cursor does not move past a failed setting, so resuming from it retries the failed settings,
+    /// and the settings re-wrapped after them are skipped.
+    pub async fn run<S, K>(&self, sdk: &S, kek: &K) -> Result<RewrapReport, String>
+    where
+        S: CoseSDK + Sync,
+        K: KekProvider,
+    {
+        let mut report = RewrapReport {
+            cursor: self.start_after.clone(),
+            ..Default::default()
+        };
+        let mut input = ListSettingsInput {
+            ns: self.namespace.clone(),
+            user_owned: self.user_owned,
+            subject: self.subject,
+            start_after: self.start_after.clone(),
+            limit: None,
+            with_header: false,
+        };
+        let limit = self.limit.unwrap_or(usize::MAX);
+        let mut processed = 0usize;
+        while processed < limit {
+            input.limit = Some((limit - processed).min(MAX_LIST_LIMIT as usize) as u32);
+            let page = sdk.namespace_list_settings(&input).await?;
+            for (subject, key) in page.keys {
+                processed += 1;
+                let path = SettingPath {
+                    ns: self.namespace.clone(),
+                    user_owned: self.user_owned,
+                    subject: Some(subject),
+                    key: key.clone(),
+                    version: 0,
+                };
+                match rewrap_setting(sdk, kek, path).await {
+                    Ok(true) => report.rewrapped += 1,
+                    Ok(false) => report.skipped += 1,
+                    Err(err) => report.failed.push((subject, key.clone(), err)),
+                }
+                if report.failed.is_empty() {
+                    report.cursor = Some((subject, key));
+                }
+            }
+            match page.next_start_after {
+                Some(start_after) => input.start_after = Some(start_after),
+                None => {
+                    report.done = report.failed.is_empty();
+                    break;
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Re-wraps the DEK of the setting, it returns false if there is nothing to re-wrap.
+async fn rewrap_setting<S, K>(sdk: &S, kek: &K, mut path: SettingPath) -> Result<bool, String>
+where
+    S: CoseSDK + Sync,
+    K: KekProvider,
+{
+    // the payload is not fetched, it may exceed the response limit
+    let info: SettingInfo = sdk.setting_get_dek(&path).await?;
+    let dek = match info.dek {
+        Some(dek) => dek,
+        None => return Ok(false),
+    };
+    path.version = info.version;
+
+    let e0 = try_decode_encrypt0(&dek)?;
+    let kid = e0
+        .unprotected
+        .kid()
+        .map_err(format_error)?
+        .unwrap_or_default()
+        .to_vec();
+    let aad = dek_aad(&path);
+    let (new_kek, new_kid) = kek.wrap_kek(&path).await?;
+    if kid == new_kid && decrypt(&e0, &new_kek, aad).is_ok() {
+        return Ok(false);
+    }
+
+    let old_kek = kek.unwrap_kek(&path, &kid).await?;
+    let key = decrypt(&e0, &old_kek, aad)?;
+    let nonce: [u8; 12] = rand_bytes();
+    let dek = cose_encrypt0(&key, &new_kek, aad, &nonce, Some(new_kid))?;
+    sdk.setting_update_payload(
+        &path,
+        &UpdateSettingPayloadInput {
+            dek: Some(ByteBuf::from(dek)),
+            ..Default::default()
+        },
+    )
+    .await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::tests::{setting_info, CallKind, MockCose};
+    use candid::decode_args;
+    use ic_cose_types::cose::encrypt0::cose_decrypt0;
+    use ic_cose_types::types::setting::{ListSettingsOutput, UpdateSettingOutput};
+
+    struct StaticKek;
+
+    const OLD_KEK: [u8; 32] = [1; 32];
+    const NEW_KEK: [u8; 32] = [2; 32];
+
+    #[async_trait]
+    impl KekProvider for StaticKek {
+        async fn unwrap_kek(&self, _path: &SettingPath, kid: &[u8]) -> Result<[u8; 32], String> {
+            match kid {
+                b"old" => Ok(OLD_KEK),
+                _ => Err("unknown kid".to_string()),
+            }
+        }
+
+        async fn wrap_kek(&self, _path: &SettingPath) -> Result<([u8; 32], Vec<u8>), String> {
+            Ok((NEW_KEK, b"new".to_vec()))
+        }
+    }
+
+    fn wrapped_info(key: u8, kek: Option<(&[u8; 32], &[u8])>) -> SettingInfo {
+        let mut info = setting_info();
+        info.key = ByteBuf::from(vec![key]);
+        info.version = 3;
+        info.dek = kek.map(|(kek, kid)| {
+            let dek = cose_encrypt0(&[7; 32], kek, &[key], &[0; 12], Some(kid.to_vec())).unwrap();
+            ByteBuf::from(dek)
+        });
+        info
+    }
+
+    #[tokio::test]
+    async fn rewrap_job_resumes_and_reports_progress() {
+        let sdk = MockCose::new();
+        let subject = Principal::management_canister();
+        let keys: Vec<(Principal, ByteBuf)> = (1..=5u8)
+            .map(|key| (subject, ByteBuf::from(vec![key])))
+            .collect();
+
+        sdk.respond(ListSettingsOutput {
+            keys: keys[1..4].to_vec(),
+            next_start_after: Some(keys[3].clone()),
+            ..Default::default()
+        });
+        sdk.respond(wrapped_info(2, Some((&OLD_KEK, b"old"))));
+        sdk.respond(UpdateSettingOutput {
+            created_at: 1,
+            updated_at: 3,
+            version: 4,
+        });
+        sdk.respond(wrapped_info(3, None));
+        sdk.respond(wrapped_info(4, Some((&NEW_KEK, b"new"))));
+        let job = RewrapJob {
+            namespace: "namespace_1".to_string(),
+            start_after: Some(keys[0].clone()),
+            limit: Some(3),
+            ..Default::default()
+        };
+        let report = job.run(&sdk, &StaticKek).await.unwrap();
+        assert_eq!(
+            report,
+            RewrapReport {
+                rewrapped: 1,
+                skipped: 2,
+                failed: vec![],
+                cursor: Some(keys[3].clone()),
+                done: false,
+            }
+        );
+
+        {
+            let calls = sdk.calls();
+            let methods: Vec<&str> = calls.iter().map(|c| c.method.as_str()).collect();
+            assert_eq!(
+                methods,
+                vec![
+                    "namespace_list_settings",
+                    "setting_get_dek",
+                    "setting_update_payload",
+                    "setting_get_dek",
+                    "setting_get_dek",
+                ]
+            );
+            let (input,): (ListSettingsInput,) = decode_args(&calls[0].args).unwrap();
+            assert_eq!(input.start_after, Some(keys[0].clone()));
+            assert_eq!(input.limit, Some(3));
+            assert_eq!(calls[2].kind, CallKind::Update);
+            let (path, input): (SettingPath, UpdateSettingPayloadInput) =
+                decode_args(&calls[2].args).unwrap();
+            assert_eq!(path.key, ByteBuf::from(vec![2]));
+            assert_eq!(path.version, 3);
+            assert!(input.payload.is_none());
+            let dek = input.dek.unwrap();
+            let e0 = try_decode_encrypt0(&dek).unwrap();
+            assert_eq!(e0.unprotected.kid().unwrap(), Some(&b"new"[..]));
+            assert_eq!(cose_decrypt0(&dek, &NEW_KEK, &[2]).unwrap(), vec![7; 32]);
+        }
+
+        sdk.respond(ListSettingsOutput {
+            keys: keys[4..].to_vec(),
+            ..Default::default()
+        });
+        sdk.respond(wrapped_info(5, Some((&OLD_KEK, b"old"))));
+        sdk.respond_err("Conflict: version mismatch");
+        let job = RewrapJob {
+            start_after: report.cursor,
+            limit: None,
+            ..job
+        };
+        let report = job.run(&sdk, &StaticKek).await.unwrap();
+        assert_eq!(report.rewrapped, 0);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].1, ByteBuf::from(vec![5]));
+        // the failed setting is retried from the cursor
+        assert_eq!(report.cursor, Some(keys[3].clone()));
+        assert!(!report.done);
+
+        sdk.respond(ListSettingsOutput {
+            keys: keys[4..].to_vec(),
+            ..Default::default()
+        });
+        sdk.respond(wrapped_info(5, Some((&OLD_KEK, b"old"))));
+        sdk.respond(UpdateSettingOutput {
+            created_at: 1,
+            updated_at: 5,
+            version: 4,
+        });
+        let report = RewrapJob {
+            start_after: report.cursor,
+            ..job
+        }
+        .run(&sdk, &StaticKek)
+        .await
+        .unwrap();
+        assert_eq!(report.rewrapped, 1);
+        assert!(report.failed.is_empty());
+        assert_eq!(report.cursor, Some(keys[4].clone()));
+        assert!(report.done);
+    }
+}
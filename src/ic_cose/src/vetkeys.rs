@@ -3,3 +3,28 @@ pub use ic_vetkeys::{
     EncryptedVetKey, IbeCiphertext, IbeIdentity, IbeSeed, MasterPublicKey, TransportSecretKey,
     VetKey,
 };
+
+use candid::Principal;
+use ic_cose_types::cose::kdf::try_derive_a256gcm_key;
+
+use crate::rand_bytes;
+
+/// Derives a 256-bit KEK for AES-256-GCM from the vetKey, with the key id as the HKDF salt.
+pub fn vetkey_kek(vk: &VetKey, key_id: &[u8]) -> Result<[u8; 32], String> {
+    try_derive_a256gcm_key(vk.signature_bytes(), Some(key_id))
+}
+
+/// Encrypts the message to the recipient with identity-based encryption,
+/// under the namespace-level public key from `vetkd_ibe_public_key`.
+pub fn ibe_encrypt(dpk: &DerivedPublicKey, recipient: &Principal, message: &[u8]) -> Vec<u8> {
+    let seed: [u8; 32] = rand_bytes();
+    let seed = IbeSeed::from_bytes(&seed).expect("32 bytes IBE seed");
+    let identity = IbeIdentity::from_principal(recipient);
+    IbeCiphertext::encrypt(dpk, &identity, message, &seed).serialize()
+}
+
+/// Decrypts the IBE ciphertext with the recipient's vetKey from `vetkd_ibe_encrypted_key`.
+pub fn ibe_decrypt(vk: &VetKey, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    let ciphertext = IbeCiphertext::deserialize(ciphertext)?;
+    ciphertext.decrypt(vk)
+}
@@ -1,12 +1,13 @@
-use candid::{pretty::candid::value::pp_value, CandidType, IDLValue, Principal};
-use ic_cose_types::validate_principals;
+use candid::Principal;
 use ic_cose_types::{
-    types::namespace::{CreateNamespaceInput, NamespaceInfo},
+    types::namespace::{CreateNamespaceInput, GasPolicy, NamespaceInfo},
     MILLISECONDS,
 };
-use std::collections::BTreeSet;
+use ic_cose_types::{validate_principals, validate_str};
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, BTreeSet};
 
-use crate::{is_controller, store};
+use crate::{is_controller, is_controller_or_manager, pretty_format, store};
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_managers(args: BTreeSet<Principal>) -> Result<(), String> {
@@ -60,6 +61,85 @@ fn admin_remove_allowed_apis(args: BTreeSet<String>) -> Result<(), String> {
     })
 }
 
+/// Adds members to the canister-level group, the group is created if it does not exist.
+/// Namespace roles and setting readers reference it by "canister:name".
+#[ic_cdk::update(guard = "is_controller_or_manager")]
+fn admin_add_group_members(name: String, members: BTreeSet<Principal>) -> Result<(), String> {
+    validate_str(&name)?;
+    validate_principals(&members)?;
+    store::state::with_mut(|s| {
+        s.groups.entry(name).or_default().extend(members);
+        Ok(())
+    })
+}
+
+/// Removes members from the canister-level group, the group is removed if it becomes empty.
+#[ic_cdk::update(guard = "is_controller_or_manager")]
+fn admin_remove_group_members(name: String, members: BTreeSet<Principal>) -> Result<(), String> {
+    validate_str(&name)?;
+    validate_principals(&members)?;
+    store::state::with_mut(|s| {
+        let group = s
+            .groups
+            .get_mut(&name)
+            .ok_or_else(|| format!("NotFound: group {} not found", name))?;
+        group.retain(|p| !members.contains(p));
+        if group.is_empty() {
+            s.groups.remove(&name);
+        }
+        Ok(())
+    })
+}
+
+#[ic_cdk::query]
+fn admin_list_groups() -> Result<BTreeMap<String, BTreeSet<Principal>>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    store::state::with(|s| {
+        if !s.managers.contains(&caller) && !s.auditors.contains(&caller) {
+            Err("no permission".to_string())?;
+        }
+        Ok(s.groups.clone())
+    })
+}
+
+/// Sets the canister-level gas metering policy. Metering is not enforced by default,
+/// so that namespaces without gas keep working until it is enabled.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_gas_policy(policy: GasPolicy) -> Result<(), String> {
+    store::state::with_mut(|s| {
+        s.gas_policy = policy;
+        Ok(())
+    })
+}
+
+/// Trusts the Ed25519 export public key of the canister for `namespace_import`, or removes it if None.
+/// The key is `public_key` of the canister's `namespace_export` pages, and should be checked out of band.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_export_source(canister: Principal, public_key: Option<ByteBuf>) -> Result<(), String> {
+    if let Some(ref pk) = public_key {
+        ic_ed25519::PublicKey::deserialize_raw(pk).map_err(|_| "invalid Ed25519 public key")?;
+    }
+    store::state::with_mut(|s| {
+        match public_key {
+            Some(pk) => s.export_sources.insert(canister, pk),
+            None => s.export_sources.remove(&canister),
+        };
+        Ok(())
+    })
+}
+
+/// Exempts the namespace from gas metering, or reverts it.
+/// Usage counters are still updated for exempt namespaces.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_gas_exempt(namespace: String, exempt: bool) -> Result<(), String> {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::with_mut(namespace, |ns| {
+        ns.gas_exempt = exempt;
+        ns.updated_at = now_ms;
+        Ok(())
+    })
+}
+
 #[ic_cdk::update]
 async fn admin_create_namespace(args: CreateNamespaceInput) -> Result<NamespaceInfo, String> {
     store::state::allowed_api("admin_create_namespace")?;
@@ -156,13 +236,3 @@ fn validate_admin_remove_allowed_apis(_args: BTreeSet<String>) -> Result<(), Str
 fn validate2_admin_remove_allowed_apis(args: BTreeSet<String>) -> Result<String, String> {
     pretty_format(&args)
 }
-
-fn pretty_format<T>(data: &T) -> Result<String, String>
-where
-    T: CandidType,
-{
-    let val = IDLValue::try_from_candid_type(data).map_err(|err| format!("{err:?}"))?;
-    let doc = pp_value(7, &val);
-
-    Ok(format!("{}", doc.pretty(120)))
-}
@@ -1,6 +1,7 @@
 use ic_cose_types::{
     cose::{
-        cose_aes256_key, ecdh::try_ecdh_x25519, encrypt0::cose_encrypt0, format_error, mac3_256,
+        cose_aes256_key, ecdh::try_ecdh_x25519, encrypt0::cose_encrypt0, format_error, kek_key_id,
+        mac3_256,
     },
     types::{
         ECDHInput, ECDHOutput, PublicKeyInput, PublicKeyOutput, SchnorrAlgorithm, SettingPath,
@@ -31,7 +32,15 @@ async fn ecdsa_sign(input: SignInput) -> Result<ByteBuf, String> {
     store::state::allowed_api("ecdsa_sign")?;
 
     let caller = ic_cdk::api::msg_caller();
-    store::ns::ecdsa_sign_with(&caller, input.ns, input.derivation_path, input.message).await
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::ecdsa_sign_with(
+        &caller,
+        input.ns,
+        input.derivation_path,
+        input.message,
+        now_ms,
+    )
+    .await
 }
 
 #[ic_cdk::query]
@@ -64,12 +73,14 @@ async fn schnorr_sign(algorithm: SchnorrAlgorithm, input: SignInput) -> Result<B
     store::state::allowed_api("schnorr_sign")?;
 
     let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
     store::ns::schnorr_sign_with(
         &caller,
         algorithm,
         input.ns,
         input.derivation_path,
         input.message,
+        now_ms,
     )
     .await
 }
@@ -87,12 +98,14 @@ async fn schnorr_sign_identity(
     store::ns::sign_identity(&caller, input.ns, input.audience, now_ms, algorithm).await
 }
 
-/// ecdh_encrypted_cose_key returns a permanent partial KEK encrypted with ECDH.
-/// It should be used with a local partial key to derive a full KEK.
+/// ecdh_encrypted_cose_key returns a partial KEK of the KEK version encrypted with ECDH,
+/// the current version if None. It should be used with a local partial key to derive a full KEK.
+/// The kid of the returned COSE key records the KEK version.
 #[ic_cdk::update(guard = "is_authenticated")]
 async fn ecdh_cose_encrypted_key(
     path: SettingPath,
     ecdh: ECDHInput,
+    kek_version: Option<u32>,
 ) -> Result<ECDHOutput<ByteBuf>, String> {
     store::state::allowed_api("ecdh_cose_encrypted_key")?;
     path.validate()?;
@@ -100,17 +113,21 @@ async fn ecdh_cose_encrypted_key(
     let caller = ic_cdk::api::msg_caller();
     let key_id = path.key.clone();
     let spk = store::SettingPathKey::from_path(path, caller);
-    if !store::ns::has_kek_permission(&caller, &spk) {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    if !store::ns::has_kek_permission(&caller, &spk, "derive_kek", now_ms) {
         Err(format!(
             "ecdh_cose_encrypted_key: {} has no permission for {}",
             caller.to_text(),
             spk
         ))?;
     }
+    let version = store::ns::kek_version(&spk.0, kek_version)?;
+    store::ns::check_rate_limit(&spk.0, &caller, "ecdh_cose_encrypted_key", now_ms)?;
+    store::ns::charge_gas(&spk.0, "ecdh_cose_encrypted_key")?;
 
     let aad = spk.2.as_slice();
-    let kek = store::ns::inner_derive_kek(&spk, &key_id)?;
-    let kek = cose_aes256_key(kek, key_id.into_vec());
+    let kek = store::ns::inner_derive_kek(&spk, &key_id, version)?;
+    let kek = cose_aes256_key(kek, kek_key_id(&key_id, version));
     let kek = kek.to_vec().map_err(format_error)?;
 
     let secret_key: [u8; 32] = rand_bytes().await?;
@@ -139,6 +156,7 @@ async fn vetkd_public_key(path: SettingPath) -> Result<ByteBuf, String> {
         }
         Ok(())
     })?;
+    store::ns::charge_gas(&path.ns, "vetkd_public_key")?;
 
     let spk = store::SettingPathKey::from_path(path, caller);
     let pk = store::ns::inner_vetkd_public_key(&spk).await?;
@@ -156,13 +174,16 @@ async fn vetkd_encrypted_key(
     let caller = ic_cdk::api::msg_caller();
     let key_id = path.key.clone();
     let spk = store::SettingPathKey::from_path(path, caller);
-    if !store::ns::has_kek_permission(&caller, &spk) {
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    if !store::ns::has_kek_permission(&caller, &spk, "vetkd", now_ms) {
         Err(format!(
             "vetkd_encrypted_key: {} has no permission for {}",
             caller.to_text(),
             spk
         ))?;
     }
+    store::ns::check_rate_limit(&spk.0, &caller, "vetkd_encrypted_key", now_ms)?;
+    store::ns::charge_gas(&spk.0, "vetkd_encrypted_key")?;
 
     let ek = store::ns::inner_vetkd_encrypted_key(
         &spk,
@@ -172,3 +193,43 @@ async fn vetkd_encrypted_key(
     .await?;
     Ok(ByteBuf::from(ek))
 }
+
+#[ic_cdk::update]
+async fn vetkd_ibe_public_key(namespace: String) -> Result<ByteBuf, String> {
+    store::state::allowed_api("vetkd_ibe_public_key")?;
+    store::ns::with(&namespace, |_| Ok(()))?;
+    store::state::ensure_vetkd_public_key().await?;
+    let pk = store::ns::ibe_public_key(&namespace)?;
+    Ok(ByteBuf::from(pk))
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn vetkd_ibe_encrypted_key(
+    namespace: String,
+    transport_public_key: ByteArray<48>,
+) -> Result<ByteBuf, String> {
+    store::state::allowed_api("vetkd_ibe_encrypted_key")?;
+
+    let caller = ic_cdk::api::msg_caller();
+    store::ns::with(&namespace, |ns| {
+        if !ns.can_read_namespace(&caller) {
+            Err(format!(
+                "vetkd_ibe_encrypted_key: {} has no permission for {}",
+                caller.to_text(),
+                namespace
+            ))?;
+        }
+        Ok(())
+    })?;
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::check_rate_limit(&namespace, &caller, "vetkd_ibe_encrypted_key", now_ms)?;
+    store::ns::charge_gas(&namespace, "vetkd_ibe_encrypted_key")?;
+
+    let ek = store::ns::inner_vetkd_ibe_encrypted_key(
+        &namespace,
+        &caller,
+        transport_public_key.into_array().into(),
+    )
+    .await?;
+    Ok(ByteBuf::from(ek))
+}
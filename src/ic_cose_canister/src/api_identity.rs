@@ -48,10 +48,10 @@ fn namespace_add_delegator(input: NamespaceDelegatorsInput) -> Result<BTreeSet<P
         if !ns.can_write_namespace(&caller) {
             return Err("no permission".to_string());
         }
-        let name = input.name.to_ascii_lowercase();
-        let delegators = ns.fixed_id_names.entry(name).or_default();
-        delegators.extend(input.delegators);
-        Ok(delegators.clone())
+        if ns.requires_approval() {
+            return Err(store::ns::APPROVAL_REQUIRED.to_string());
+        }
+        Ok(ns.add_delegators(&input.name, input.delegators))
     })
 }
 
@@ -65,13 +65,10 @@ fn namespace_remove_delegator(input: NamespaceDelegatorsInput) -> Result<(), Str
         if !ns.can_write_namespace(&caller) {
             return Err("no permission".to_string());
         }
-        let name = input.name.to_ascii_lowercase();
-        if let Some(delegators) = ns.fixed_id_names.get_mut(&name) {
-            delegators.retain(|v| !input.delegators.contains(v));
-            if delegators.is_empty() {
-                ns.fixed_id_names.remove(&name);
-            }
+        if ns.requires_approval() {
+            return Err(store::ns::APPROVAL_REQUIRED.to_string());
         }
+        ns.remove_delegators(&input.name, &input.delegators);
         Ok(())
     })
 }
@@ -104,6 +101,7 @@ fn namespace_sign_delegation(input: SignDelegationInput) -> Result<SignInRespons
     if session_expires_in_ms == 0 {
         return Err("delegation is disabled".to_string());
     }
+    store::ns::charge_gas(&input.ns, "namespace_sign_delegation")?;
     let expiration = (now_ms + session_expires_in_ms) * MILLISECONDS;
     let delegation_hash = delegation_signature_msg(input.pubkey.as_slice(), expiration, None);
     store::state::add_signature(user_key.seed.as_slice(), delegation_hash.as_slice());
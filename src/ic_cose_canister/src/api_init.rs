@@ -1,9 +1,15 @@
 use candid::{CandidType, Principal};
+use ic_cose_types::MILLISECONDS;
 use serde::Deserialize;
 use std::{collections::BTreeSet, time::Duration};
 
 use crate::store;
 
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 10);
+const PURGE_BATCH_SIZE: usize = 100;
+const PRUNE_BATCH_SIZE: usize = 1000;
+const CERTIFY_BATCH_SIZE: usize = 100;
+
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum InstallArgs {
     Init(InitArgs),
@@ -49,6 +55,9 @@ fn init(args: Option<InstallArgs>) {
                     1_000_000_000_000
                 };
                 s.governance_canister = args.governance_canister;
+                // no settings to index or to certify
+                s.tags_indexed = true;
+                s.certified_built = true;
             });
         }
         InstallArgs::Upgrade(_) => {
@@ -59,6 +68,27 @@ fn init(args: Option<InstallArgs>) {
     }
 
     ic_cdk_timers::set_timer(Duration::from_secs(0), store::state::init_public_key());
+    init_timers();
+}
+
+// timers are not persisted across upgrades, so they are set on both init and post_upgrade.
+fn init_timers() {
+    ic_cdk_timers::set_timer_interval(PURGE_INTERVAL, || async {
+        let now_ms = ic_cdk::api::time() / MILLISECONDS;
+        store::ns::purge_expired_settings(now_ms, PURGE_BATCH_SIZE);
+        store::ns::prune_archived_settings(now_ms, PRUNE_BATCH_SIZE);
+        store::ns::purge_expired_reader_grants(now_ms, PURGE_BATCH_SIZE);
+        store::ns::decay_rate_limits(now_ms);
+    });
+}
+
+// hashes the settings into the certified store after an upgrade, one batch per timer call
+fn build_certified_settings() {
+    if !store::ns::build_certified_settings(CERTIFY_BATCH_SIZE) {
+        ic_cdk_timers::set_timer(Duration::from_secs(0), async {
+            build_certified_settings();
+        });
+    }
 }
 
 #[ic_cdk::pre_upgrade]
@@ -97,4 +127,14 @@ fn post_upgrade(args: Option<InstallArgs>) {
         }
         _ => {}
     }
+
+    // the vetKD key name may have changed
+    ic_cdk_timers::set_timer(
+        Duration::from_secs(0),
+        store::state::init_vetkd_public_key(),
+    );
+    ic_cdk_timers::set_timer(Duration::from_secs(0), async {
+        build_certified_settings();
+    });
+    init_timers();
 }
@@ -1,12 +1,19 @@
 use candid::Principal;
 use ic_cose_types::{
-    types::{namespace::*, state::StateInfo},
-    validate_principals, MILLISECONDS,
+    types::{
+        namespace::*,
+        setting::{
+            ListSettingsInput, ListSettingsOutput, SearchSettingsInput, SearchSettingsOutput,
+            SettingChangesOutput,
+        },
+        state::StateInfo,
+    },
+    validate_principals, validate_str, MILLISECONDS,
 };
 use serde_bytes::ByteBuf;
 use std::collections::BTreeSet;
 
-use crate::{is_authenticated, is_controller_or_manager, store};
+use crate::{is_authenticated, is_controller, is_controller_or_manager, pretty_format, store};
 
 #[ic_cdk::query]
 fn state_get_info() -> Result<StateInfo, String> {
@@ -42,6 +49,36 @@ fn namespace_list_setting_keys(
     })
 }
 
+#[ic_cdk::query]
+fn namespace_list_settings(input: ListSettingsInput) -> Result<ListSettingsOutput, String> {
+    input.validate()?;
+    let caller = ic_cdk::api::msg_caller();
+    store::ns::list_settings(&caller, input)
+}
+
+#[ic_cdk::query]
+fn namespace_search_settings(input: SearchSettingsInput) -> Result<SearchSettingsOutput, String> {
+    input.validate()?;
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::search_settings(&caller, input, now_ms)
+}
+
+/// Returns setting changes in the namespace after the `cursor` seq, 0 to start from the beginning.
+#[ic_cdk::query]
+fn namespace_changes_since(
+    namespace: String,
+    cursor: u64,
+    limit: Option<u32>,
+) -> Result<SettingChangesOutput, String> {
+    if limit == Some(0) {
+        Err("limit should be greater than 0".to_string())?;
+    }
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::changes_since(&caller, namespace, cursor, limit, now_ms)
+}
+
 #[ic_cdk::update(guard = "is_authenticated")]
 fn namespace_update_info(args: UpdateNamespaceInput) -> Result<(), String> {
     store::state::allowed_api("namespace_update_info")?;
@@ -52,6 +89,15 @@ fn namespace_update_info(args: UpdateNamespaceInput) -> Result<(), String> {
     store::ns::update_namespace_info(&caller, args, now_ms)
 }
 
+/// Recomputes the settings count and the payload bytes total of the namespace
+/// from the stored settings. It scans all settings of the namespace,
+/// so only controllers and canister managers can call it.
+#[ic_cdk::update(guard = "is_controller_or_manager")]
+fn namespace_recompute_usage(namespace: String) -> Result<NamespaceInfo, String> {
+    store::state::allowed_api("namespace_recompute_usage")?;
+    store::ns::recompute_usage(namespace)
+}
+
 #[ic_cdk::update(guard = "is_authenticated")]
 fn namespace_delete(namespace: String) -> Result<(), String> {
     store::state::allowed_api("namespace_delete")?;
@@ -67,14 +113,12 @@ fn namespace_add_managers(namespace: String, args: BTreeSet<Principal>) -> Resul
 
     let caller = ic_cdk::api::msg_caller();
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::ns::with_mut(namespace, |ns| {
-        if !ns.can_write_namespace(&caller) {
-            Err("no permission".to_string())?;
-        }
-        ns.managers.extend(args);
-        ns.updated_at = now_ms;
-        Ok(())
-    })
+    store::ns::apply_change(
+        &caller,
+        namespace,
+        NamespaceChange::AddManagers(args),
+        now_ms,
+    )
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
@@ -84,14 +128,12 @@ fn namespace_remove_managers(namespace: String, args: BTreeSet<Principal>) -> Re
 
     let caller = ic_cdk::api::msg_caller();
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::ns::with_mut(namespace, |ns| {
-        if !ns.can_write_namespace(&caller) {
-            Err("no permission".to_string())?;
-        }
-        ns.managers.retain(|p| !args.contains(p));
-        ns.updated_at = now_ms;
-        Ok(())
-    })
+    store::ns::apply_change(
+        &caller,
+        namespace,
+        NamespaceChange::RemoveManagers(args),
+        now_ms,
+    )
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
@@ -162,6 +204,270 @@ fn namespace_remove_users(namespace: String, args: BTreeSet<Principal>) -> Resul
     })
 }
 
+/// Adds members to the namespace-level group, the group is created if it does not exist.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_add_group_members(
+    namespace: String,
+    name: String,
+    members: BTreeSet<Principal>,
+) -> Result<(), String> {
+    store::state::allowed_api("namespace_add_group_members")?;
+    validate_str(&name)?;
+    validate_principals(&members)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::apply_change(
+        &caller,
+        namespace,
+        NamespaceChange::AddGroupMembers(name, members),
+        now_ms,
+    )
+}
+
+/// Removes members from the namespace-level group, the group is removed if it becomes empty.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_remove_group_members(
+    namespace: String,
+    name: String,
+    members: BTreeSet<Principal>,
+) -> Result<(), String> {
+    store::state::allowed_api("namespace_remove_group_members")?;
+    validate_str(&name)?;
+    validate_principals(&members)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::apply_change(
+        &caller,
+        namespace,
+        NamespaceChange::RemoveGroupMembers(name, members),
+        now_ms,
+    )
+}
+
+/// Adds group references to a namespace role: "manager", "auditor" or "user".
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_add_role_groups(
+    namespace: String,
+    role: String,
+    groups: BTreeSet<String>,
+) -> Result<(), String> {
+    store::state::allowed_api("namespace_add_role_groups")?;
+    validate_group_refs(&groups)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::apply_change(
+        &caller,
+        namespace,
+        NamespaceChange::AddRoleGroups(role, groups),
+        now_ms,
+    )
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_remove_role_groups(
+    namespace: String,
+    role: String,
+    groups: BTreeSet<String>,
+) -> Result<(), String> {
+    store::state::allowed_api("namespace_remove_role_groups")?;
+    validate_group_refs(&groups)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::apply_change(
+        &caller,
+        namespace,
+        NamespaceChange::RemoveRoleGroups(role, groups),
+        now_ms,
+    )
+}
+
+/// Replaces the permission policy of the namespace, an empty policy restores the role model.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_update_policy(namespace: String, policy: Vec<PolicyRule>) -> Result<(), String> {
+    store::state::allowed_api("namespace_update_policy")?;
+    validate_policy(&policy)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::apply_change(
+        &caller,
+        namespace,
+        NamespaceChange::UpdatePolicy(policy),
+        now_ms,
+    )
+}
+
+/// Sets or clears the restrictions of the namespace signing APIs.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_update_signing_policy(
+    namespace: String,
+    policy: Option<SigningPolicy>,
+) -> Result<(), String> {
+    store::state::allowed_api("namespace_update_signing_policy")?;
+    if let Some(ref policy) = policy {
+        policy.validate()?;
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::apply_change(
+        &caller,
+        namespace,
+        NamespaceChange::UpdateSigningPolicy(policy),
+        now_ms,
+    )
+}
+
+/// Enables M-of-N approval of sensitive changes. Once enabled, the approval policy
+/// can only be changed by an approved proposal.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_enable_approval(namespace: String, policy: ApprovalPolicy) -> Result<(), String> {
+    store::state::allowed_api("namespace_enable_approval")?;
+    policy.validate()?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::enable_approval_policy(&caller, namespace, policy, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_propose_change(
+    namespace: String,
+    change: NamespaceChange,
+) -> Result<ProposalInfo, String> {
+    store::state::allowed_api("namespace_propose_change")?;
+    change.validate(&namespace)?;
+    let description = pretty_format(&change)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::propose_change(&caller, namespace, change, description, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_approve_change(namespace: String, id: u64) -> Result<ProposalInfo, String> {
+    store::state::allowed_api("namespace_approve_change")?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::approve_change(&caller, namespace, id, now_ms)
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn namespace_list_proposals(namespace: String) -> Result<Vec<ProposalInfo>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::list_proposals(&caller, namespace, now_ms)
+}
+
+/// Exports a page of the namespace as a CBOR bundle signed by the canister's Ed25519 key.
+/// Data encrypted under a KEK derived in this canister must be re-wrapped after the import.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn namespace_export(input: ExportNamespaceInput) -> Result<NamespaceExportPage, String> {
+    store::state::allowed_api("namespace_export")?;
+    validate_str(&input.ns)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let cursor = match input.cursor {
+        Some(cursor) => Some(store::ns::decode_export_cursor(&cursor)?),
+        None => None,
+    };
+    let (bundle, next_cursor) = store::ns::export_namespace(
+        &caller,
+        ic_cdk::api::canister_self(),
+        input.ns.clone(),
+        cursor,
+        now_ms,
+    )?;
+    store::ns::charge_gas(&input.ns, "namespace_export")?;
+
+    let signature = store::ns::sign_export(&bundle).await?;
+    Ok(NamespaceExportPage {
+        bundle: ByteBuf::from(bundle),
+        signature,
+        public_key: store::ns::export_public_key()?,
+        next_cursor: next_cursor.as_ref().map(store::ns::encode_export_cursor),
+    })
+}
+
+/// Imports an export page after verifying its signature with the trusted key of the exporting canister.
+/// The pages should be imported in order.
+#[ic_cdk::update(guard = "is_controller")]
+fn namespace_import(input: ImportNamespaceInput) -> Result<NamespaceInfo, String> {
+    store::state::allowed_api("namespace_import")?;
+    input.validate()?;
+    let page = store::ns::verify_export(&input.bundle, &input.signature)?;
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::import_namespace(input.name, page, now_ms)
+}
+
+const ABORT_IMPORT_BATCH_SIZE: usize = 100;
+
+/// Aborts the import of the namespace, and removes the namespace with the settings imported so far.
+/// It returns false if settings remain to be removed, call it again until it returns true.
+#[ic_cdk::update(guard = "is_controller")]
+fn namespace_abort_import(namespace: String) -> Result<bool, String> {
+    store::state::allowed_api("namespace_abort_import")?;
+    store::ns::abort_import(namespace, ABORT_IMPORT_BATCH_SIZE)
+}
+
+const MOVE_BATCH_SIZE: usize = 100;
+
+/// Renames the namespace. The original name is kept as an alias in key derivation paths,
+/// so existing KEKs and signing keys keep working.
+/// The settings are moved in batches, call it again while `moving_from` of the result is set.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_rename(namespace: String, new_name: String) -> Result<NamespaceInfo, String> {
+    store::state::allowed_api("namespace_rename")?;
+    validate_str(&new_name)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::rename_namespace(&caller, namespace, new_name, MOVE_BATCH_SIZE, now_ms)
+}
+
+/// Clones the namespace metadata and plaintext settings into a new namespace.
+/// Encrypted settings are returned as a re-encryption work list since the KEKs differ.
+/// The settings are copied in batches, call it again while `moving_from` of the result is set.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_clone(namespace: String, new_name: String) -> Result<CloneNamespaceOutput, String> {
+    store::state::allowed_api("namespace_clone")?;
+    validate_str(&new_name)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::clone_namespace(&caller, namespace, new_name, MOVE_BATCH_SIZE, now_ms)
+}
+
+/// Bumps the KEK version of the namespace and returns the new version.
+/// DEKs wrapped with old KEKs should be re-wrapped with the new one.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn namespace_rotate_kek(namespace: String) -> Result<u32, String> {
+    store::state::allowed_api("namespace_rotate_kek")?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::rotate_kek(&caller, namespace, now_ms)
+}
+
+/// Dry-runs a permission check against the namespace policy and role model.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn namespace_check_permission(
+    input: CheckPermissionInput,
+) -> Result<CheckPermissionOutput, String> {
+    input.validate()?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::check_permission(&caller, input, now_ms)
+}
+
 #[ic_cdk::query(guard = "is_authenticated")]
 fn namespace_is_member(
     namespace: String,
@@ -174,9 +480,9 @@ fn namespace_is_member(
             Err("no permission".to_string())?;
         }
         match member_kind.as_str() {
-            "manager" => Ok(ns.managers.contains(&user)),
-            "auditor" => Ok(ns.auditors.contains(&user)),
-            "user" => Ok(ns.users.contains(&user)),
+            "manager" => Ok(ns.is_manager(&user)),
+            "auditor" => Ok(ns.is_auditor(&user)),
+            "user" => Ok(ns.is_user(&user)),
             _ => Err(format!("invalid member kind: {}", member_kind)),
         }
     })
@@ -195,11 +501,9 @@ fn namespace_top_up(namespace: String, cycles: u128) -> Result<u128, String> {
         Err("insufficient cycles".to_string())?;
     }
 
+    store::ns::with(&namespace, |_| Ok(()))?;
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::ns::with_mut(namespace, |ns| {
-        let received = ic_cdk::api::msg_cycles_accept(cycles);
-        ns.gas_balance = ns.gas_balance.saturating_add(received);
-        ns.updated_at = now_ms;
-        Ok(received)
-    })
+    let received = ic_cdk::api::msg_cycles_accept(cycles);
+    store::ns::top_up(namespace, received, now_ms)?;
+    Ok(received)
 }
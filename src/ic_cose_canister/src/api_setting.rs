@@ -1,24 +1,59 @@
 use candid::Principal;
-use ic_cose_types::{types::setting::*, validate_principals, MILLISECONDS};
-use std::collections::BTreeSet;
+use ic_cose_types::{
+    types::{namespace::validate_group_refs, setting::*},
+    validate_principals, MILLISECONDS,
+};
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, BTreeSet};
 
 use crate::{is_authenticated, store};
 
+// Attaches the certificate and the witness of the setting to a query call result,
+// so that clients can verify it without an update call.
+// Nothing is attached while the certified settings are being built after an upgrade.
+fn certify_setting_info(spk: &store::SettingPathKey, info: &mut SettingInfo, with_payload: bool) {
+    if let Some(certificate) = ic_cdk::api::data_certificate() {
+        if let Some(witness) = store::state::setting_witness(spk, with_payload) {
+            info.certificate = Some(ByteBuf::from(certificate));
+            info.witness = Some(ByteBuf::from(witness));
+        }
+    }
+}
+
 #[ic_cdk::query]
 fn setting_get_info(path: SettingPath) -> Result<SettingInfo, String> {
     path.validate()?;
     let caller = ic_cdk::api::msg_caller();
     let spk = store::SettingPathKey::from_path(path, caller);
-    store::ns::get_setting_info(caller, spk)
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let mut info = store::ns::get_setting_info(caller, spk.clone(), now_ms)?;
+    certify_setting_info(&spk, &mut info, false);
+    Ok(info)
+}
+
+// The DEK is authenticated when it is unwrapped with its KEK.
+// The witness keeps the payload hash, so that a payload downloaded in chunks can be verified with it.
+#[ic_cdk::query]
+fn setting_get_dek(path: SettingPath) -> Result<SettingInfo, String> {
+    path.validate()?;
+    let caller = ic_cdk::api::msg_caller();
+    let spk = store::SettingPathKey::from_path(path, caller);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let mut info = store::ns::get_setting_dek(caller, spk.clone(), now_ms)?;
+    certify_setting_info(&spk, &mut info, true);
+    Ok(info)
 }
 
-// Clients should execute this query with update call to make the result of execution goes through consensus.
+// The result of a query call is certified, clients should verify it with the certificate and witness.
 #[ic_cdk::query]
 fn setting_get(path: SettingPath) -> Result<SettingInfo, String> {
     path.validate()?;
     let caller = ic_cdk::api::msg_caller();
     let spk = store::SettingPathKey::from_path(path, caller);
-    store::ns::get_setting(caller, spk)
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let mut info = store::ns::get_setting(caller, spk.clone(), now_ms)?;
+    certify_setting_info(&spk, &mut info, true);
+    Ok(info)
 }
 
 #[ic_cdk::query]
@@ -26,7 +61,53 @@ fn setting_get_archived_payload(path: SettingPath) -> Result<SettingArchivedPayl
     path.validate()?;
     let caller = ic_cdk::api::msg_caller();
     let spk = store::SettingPathKey::from_path(path, caller);
-    store::ns::get_setting_archived_payload(caller, spk)
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::get_setting_archived_payload(caller, spk, now_ms)
+}
+
+/// Returns the active reader grants of the setting with their expires_at.
+#[ic_cdk::query]
+fn setting_get_reader_grants(path: SettingPath) -> Result<BTreeMap<Principal, u64>, String> {
+    path.validate()?;
+    let caller = ic_cdk::api::msg_caller();
+    let spk = store::SettingPathKey::from_path(path, caller);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::get_reader_grants(&caller, &spk, now_ms)
+}
+
+#[ic_cdk::query]
+fn setting_get_chunk(path: SettingPath, chunk_index: u32) -> Result<SettingChunkOutput, String> {
+    path.validate()?;
+    let caller = ic_cdk::api::msg_caller();
+    let spk = store::SettingPathKey::from_path(path, caller);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::get_setting_chunk(caller, spk, chunk_index, now_ms)
+}
+
+/// Starts a chunked payload upload for the setting.
+/// The payload is committed by `setting_create` or `setting_update_payload` with `payload_sha256`,
+/// and stored in chunks.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn setting_upload_begin(path: SettingPath, payload_size: u64) -> Result<(), String> {
+    store::state::allowed_api("setting_upload_begin")?;
+    path.validate()?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let subject = path.subject.unwrap_or(caller);
+    let spk = store::SettingPathKey::from_path(path, subject);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::begin_upload(&caller, &spk, payload_size, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn setting_upload_chunk(path: SettingPath, chunk_index: u32, chunk: ByteBuf) -> Result<(), String> {
+    store::state::allowed_api("setting_upload_chunk")?;
+    path.validate()?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let subject = path.subject.unwrap_or(caller);
+    let spk = store::SettingPathKey::from_path(path, subject);
+    store::ns::upload_chunk(&caller, &spk, chunk_index, &chunk)
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
@@ -42,7 +123,12 @@ fn setting_create(
     let subject = path.subject.unwrap_or(caller);
     let spk = store::SettingPathKey::from_path(path, subject);
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::ns::create_setting(caller, spk, input, now_ms)
+    if let Some(ref payload_sha256) = input.payload_sha256 {
+        store::ns::verify_upload(&caller, &spk, payload_sha256)?;
+    }
+    store::ns::with_gas(&spk.0.clone(), "setting_create", || {
+        store::ns::create_setting(caller, spk, input, now_ms)
+    })
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
@@ -58,7 +144,9 @@ fn setting_update_info(
     let subject = path.subject.unwrap_or(caller);
     let spk = store::SettingPathKey::from_path(path, subject);
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::ns::update_setting_info(caller, spk, input, now_ms)
+    store::ns::with_gas(&spk.0.clone(), "setting_update_info", || {
+        store::ns::update_setting_info(caller, spk, input, now_ms)
+    })
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
@@ -74,7 +162,42 @@ fn setting_update_payload(
     let subject = path.subject.unwrap_or(caller);
     let spk = store::SettingPathKey::from_path(path, subject);
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::ns::update_setting_payload(caller, spk, input, now_ms)
+    if let Some(ref payload_sha256) = input.payload_sha256 {
+        store::ns::verify_upload(&caller, &spk, payload_sha256)?;
+    }
+    store::ns::with_gas(&spk.0.clone(), "setting_update_payload", || {
+        store::ns::update_setting_payload(caller, spk, input, now_ms)
+    })
+}
+
+/// Applies create, update and delete operations on settings in one namespace atomically.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn setting_batch(input: SettingBatchInput) -> Result<Vec<Option<UpdateSettingOutput>>, String> {
+    store::state::allowed_api("setting_batch")?;
+    input.validate()?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let namespace = input.ns.clone();
+    let mut ops = Vec::with_capacity(input.ops.len());
+    for op in input.ops {
+        let path = op.path().clone();
+        let subject = path.subject.unwrap_or(caller);
+        let spk = store::SettingPathKey::from_path(path, subject);
+        let payload_sha256 = match op {
+            SettingBatchOp::Create { ref input, .. } => input.payload_sha256.as_ref(),
+            SettingBatchOp::UpdatePayload { ref input, .. } => input.payload_sha256.as_ref(),
+            _ => None,
+        };
+        if let Some(hash) = payload_sha256 {
+            store::ns::verify_upload(&caller, &spk, hash)?;
+        }
+        ops.push((spk, op));
+    }
+
+    store::ns::with_gas(&namespace, "setting_batch", || {
+        store::ns::batch_update_settings(caller, ops, now_ms)
+    })
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
@@ -87,11 +210,15 @@ fn setting_add_readers(path: SettingPath, input: BTreeSet<Principal>) -> Result<
     let subject = path.subject.unwrap_or(caller);
     let spk = store::SettingPathKey::from_path(path, subject);
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::ns::with_setting_mut(&caller, &spk, |setting| {
-        setting.readers.extend(input);
-        setting.updated_at = now_ms;
-        Ok(())
-    })
+    let version = store::ns::with_gas(&spk.0, "setting_add_readers", || {
+        store::ns::with_setting_mut(&caller, &spk, |setting| {
+            setting.readers.extend(input);
+            setting.updated_at = now_ms;
+            Ok(setting.version)
+        })
+    })?;
+    store::ns::record_change(&spk, SettingChangeKind::UpdateReaders, version, now_ms);
+    Ok(())
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
@@ -104,13 +231,119 @@ fn setting_remove_readers(path: SettingPath, input: BTreeSet<Principal>) -> Resu
     let subject = path.subject.unwrap_or(caller);
     let spk = store::SettingPathKey::from_path(path, subject);
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::ns::with_setting_mut(&caller, &spk, |setting| {
-        setting.readers.retain(|p| !input.contains(p));
-        setting.updated_at = now_ms;
-        Ok(())
+    let version = store::ns::with_gas(&spk.0, "setting_remove_readers", || {
+        store::ns::with_setting_mut(&caller, &spk, |setting| {
+            setting.readers.retain(|p| !input.contains(p));
+            setting.reader_grants.retain(|p, _| !input.contains(p));
+            setting.updated_at = now_ms;
+            Ok(setting.version)
+        })
+    })?;
+    store::ns::record_change(&spk, SettingChangeKind::UpdateReaders, version, now_ms);
+    Ok(())
+}
+
+/// Adds group references to the setting readers,
+/// `name` for a namespace-level group or `canister:name` for a canister-level group.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn setting_add_reader_groups(path: SettingPath, groups: BTreeSet<String>) -> Result<(), String> {
+    store::state::allowed_api("setting_add_reader_groups")?;
+    path.validate()?;
+    validate_group_refs(&groups)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let subject = path.subject.unwrap_or(caller);
+    let spk = store::SettingPathKey::from_path(path, subject);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let version = store::ns::with_gas(&spk.0, "setting_add_reader_groups", || {
+        store::ns::with_setting_mut(&caller, &spk, |setting| {
+            setting.reader_groups.extend(groups);
+            setting.updated_at = now_ms;
+            Ok(setting.version)
+        })
+    })?;
+    store::ns::record_change(&spk, SettingChangeKind::UpdateReaders, version, now_ms);
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn setting_remove_reader_groups(path: SettingPath, groups: BTreeSet<String>) -> Result<(), String> {
+    store::state::allowed_api("setting_remove_reader_groups")?;
+    path.validate()?;
+    validate_group_refs(&groups)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let subject = path.subject.unwrap_or(caller);
+    let spk = store::SettingPathKey::from_path(path, subject);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let version = store::ns::with_gas(&spk.0, "setting_remove_reader_groups", || {
+        store::ns::with_setting_mut(&caller, &spk, |setting| {
+            setting.reader_groups.retain(|g| !groups.contains(g));
+            setting.updated_at = now_ms;
+            Ok(setting.version)
+        })
+    })?;
+    store::ns::record_change(&spk, SettingChangeKind::UpdateReaders, version, now_ms);
+    Ok(())
+}
+
+/// Grants the readers to read the setting until `expires_at`, a unix timestamp in milliseconds.
+/// Expired grants are removed by a timer, and can be revoked by `setting_remove_readers`.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn setting_grant_readers(
+    path: SettingPath,
+    input: BTreeSet<Principal>,
+    expires_at: u64,
+) -> Result<(), String> {
+    store::state::allowed_api("setting_grant_readers")?;
+    path.validate()?;
+    validate_principals(&input)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let subject = path.subject.unwrap_or(caller);
+    let spk = store::SettingPathKey::from_path(path, subject);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let version = store::ns::with_gas(&spk.0, "setting_grant_readers", || {
+        store::ns::grant_readers(&caller, &spk, input, expires_at, now_ms)
+    })?;
+    store::ns::record_change(&spk, SettingChangeKind::UpdateReaders, version, now_ms);
+    Ok(())
+}
+
+/// Creates a new current version of the setting from an archived version.
+/// `path.version` should be the current version.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn setting_restore_version(
+    path: SettingPath,
+    version: u32,
+    force: bool,
+) -> Result<UpdateSettingOutput, String> {
+    store::state::allowed_api("setting_restore_version")?;
+    path.validate()?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let subject = path.subject.unwrap_or(caller);
+    let spk = store::SettingPathKey::from_path(path, subject);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::with_gas(&spk.0.clone(), "setting_restore_version", || {
+        store::ns::restore_setting_version(caller, spk, version, force, now_ms)
     })
 }
 
+/// Prunes archived versions of the setting below `path.version`,
+/// along with those out of the namespace retention policy.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn setting_prune_archived(path: SettingPath) -> Result<u32, String> {
+    store::state::allowed_api("setting_prune_archived")?;
+    path.validate()?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let subject = path.subject.unwrap_or(caller);
+    let spk = store::SettingPathKey::from_path(path, subject);
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::prune_setting_archived(&caller, &spk, now_ms)
+}
+
 #[ic_cdk::update(guard = "is_authenticated")]
 fn setting_delete(path: SettingPath) -> Result<(), String> {
     store::state::allowed_api("setting_delete")?;
@@ -119,5 +352,8 @@ fn setting_delete(path: SettingPath) -> Result<(), String> {
     let caller = ic_cdk::api::msg_caller();
     let subject = path.subject.unwrap_or(caller);
     let spk = store::SettingPathKey::from_path(path, subject);
-    store::ns::delete_setting(&caller, &spk)
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::ns::with_gas(&spk.0, "setting_delete", || {
+        store::ns::delete_setting(&caller, &spk, now_ms)
+    })
 }
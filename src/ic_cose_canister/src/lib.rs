@@ -1,11 +1,11 @@
-use candid::Principal;
+use candid::{pretty::candid::value::pp_value, CandidType, IDLValue, Principal};
 use ic_auth_types::*;
 use ic_cose_types::{
     format_error, types::namespace::*, types::setting::*, types::state::StateInfo, types::*,
     ANONYMOUS,
 };
 use serde_bytes::{ByteArray, ByteBuf};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 mod api_admin;
 mod api_cose;
@@ -57,4 +57,15 @@ async fn rand_bytes<const N: usize>() -> Result<[u8; N], String> {
     data.try_into().map_err(format_error)
 }
 
+/// Formats the Candid value for humans, used in proposal descriptions and validate2 endpoints.
+fn pretty_format<T>(data: &T) -> Result<String, String>
+where
+    T: CandidType,
+{
+    let val = IDLValue::try_from_candid_type(data).map_err(|err| format!("{err:?}"))?;
+    let doc = pp_value(7, &val);
+
+    Ok(format!("{}", doc.pretty(120)))
+}
+
 ic_cdk::export_candid!();
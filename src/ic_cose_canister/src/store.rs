@@ -5,12 +5,15 @@ use ic_canister_sig_creation::{
     DELEGATION_SIG_DOMAIN,
 };
 use ic_cdk::api::certified_data_set;
-use ic_certification::labeled_hash;
+use ic_certification::{
+    fork, fork_hash, labeled, labeled_hash, leaf, leaf_hash, pruned, AsHashTree, Hash, HashTree,
+    RbTree,
+};
 use ic_cose_types::{
     cose::{
         cwt::{scope_claim, ClaimsSet},
         encrypt0::try_decode_encrypt0,
-        format_error, mac3_256, sha256,
+        format_error, keccak256, mac3_256, sha256,
         sign1::{cose_sign1, ES256K},
     },
     types::{namespace::*, setting::*, state::StateInfo, PublicKeyOutput, SchnorrAlgorithm},
@@ -34,13 +37,26 @@ use crate::{
     ecdsa::{derive_public_key, ecdsa_public_key, sign_with_ecdsa},
     rand_bytes,
     schnorr::{derive_schnorr_public_key, schnorr_public_key, sign_with_schnorr},
-    vetkd::{vetkd_encrypted_key, vetkd_public_key},
+    vetkd::{
+        derive_vetkd_public_key, vetkd_canister_public_key, vetkd_encrypted_key, vetkd_public_key,
+    },
 };
 
 const SESSION_EXPIRES_IN_MS: u64 = 1000 * 3600 * 24; // 1 day
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+fn try_from_cbor_bytes<T>(bytes: &[u8], context: &str) -> Result<T, String>
+where
+    T: DeserializeOwned,
+{
+    let value: Value =
+        from_reader(bytes).map_err(|err| format!("failed to decode {context}: {err:?}"))?;
+    value
+        .deserialized()
+        .map_err(|err| format!("failed to deserialize {context}: {err:?}"))
+}
+
 fn from_cbor_bytes<T>(bytes: &[u8], context: &str) -> T
 where
     T: DeserializeOwned,
@@ -84,6 +100,22 @@ pub struct State {
     pub init_vector: ByteArray<32>, // should not be exposed
     #[serde(default, rename = "gov")]
     pub governance_canister: Option<Principal>,
+    #[serde(default, rename = "gr")]
+    pub groups: BTreeMap<String, BTreeSet<Principal>>, // canister-level groups, referenced by "canister:name"
+    #[serde(default, rename = "na")]
+    pub namespace_aliases: BTreeMap<String, String>, // old name of a renamed namespace -> current name
+    #[serde(default, rename = "vp")]
+    pub vetkd_public_key: Option<ByteBuf>, // canister-level vetKD public key
+    #[serde(default, rename = "gp")]
+    pub gas_policy: GasPolicy,
+    #[serde(default, rename = "es")]
+    pub export_sources: BTreeMap<Principal, ByteBuf>, // trusted exporting canister -> Ed25519 export public key
+    #[serde(default, rename = "ti")]
+    pub tags_indexed: bool, // the tag index has been built from the existing settings
+    #[serde(default, rename = "cb")]
+    pub certified_built: bool, // the hashes of all settings are in CERTIFIED_STORE
+    #[serde(default, rename = "cc")]
+    pub certified_cursor: Option<SettingPathKey>, // the last setting hashed by the build of CERTIFIED_STORE
 }
 
 impl State {
@@ -115,6 +147,7 @@ impl State {
                 None
             },
             governance_canister: self.governance_canister,
+            gas_policy: self.gas_policy.clone(),
         }
     }
 }
@@ -176,11 +209,116 @@ pub struct Namespace {
     #[serde(rename = "u")]
     pub users: BTreeSet<Principal>, // users can read and write settings they created
     #[serde(rename = "g")]
-    pub gas_balance: u128, // gas balance, TODO: https://internetcomputer.org/docs/current/developer-docs/gas-cost
+    pub gas_balance: u128, // gas balance in cycles, debited by metered calls, see `gas_cost`
     #[serde(default, rename = "f")]
     pub fixed_id_names: BTreeMap<String, BTreeSet<Principal>>, // fixed_id_name -> users
     #[serde(default, rename = "se")]
     pub session_expires_in_ms: u64, // session expires in milliseconds
+    #[serde(default, rename = "rv")]
+    pub retain_versions: u32, // number of archived versions to keep per setting, 0: keep all
+    #[serde(default, rename = "ra")]
+    pub retain_archived_ms: u64, // keep archived versions newer than this age, 0: keep all
+    #[serde(default, rename = "gu")]
+    pub gas_used: u128, // total gas cost of metered calls in cycles
+    #[serde(default, rename = "ge")]
+    pub gas_exempt: bool, // metered calls are not debited if true, set by controllers
+    #[serde(default, rename = "gc")]
+    pub gas_credit: u128, // gas used on credit when the balance is insufficient, repaid by top-ups
+    #[serde(default, rename = "us")]
+    pub usage: BTreeMap<String, u64>, // API name -> number of metered calls
+    #[serde(default, rename = "mb")]
+    pub max_bytes_total: u64, // max total payload size in bytes, 0: unlimited
+    #[serde(default, rename = "ms")]
+    pub max_settings: u64, // max number of settings, 0: unlimited
+    #[serde(default, rename = "sc")]
+    pub settings_total: u64, // number of settings
+    #[serde(default, rename = "rl")]
+    pub rate_limit: Option<RateLimit>, // rate limit of signing and key derivation calls per caller
+    #[serde(default, rename = "gr")]
+    pub groups: BTreeMap<String, BTreeSet<Principal>>, // namespace-level groups
+    #[serde(default, rename = "mg")]
+    pub manager_groups: BTreeSet<String>, // members of the groups are managers
+    #[serde(default, rename = "ag")]
+    pub auditor_groups: BTreeSet<String>, // members of the groups are auditors
+    #[serde(default, rename = "ug")]
+    pub user_groups: BTreeSet<String>, // members of the groups are users
+    #[serde(default, rename = "po")]
+    pub policy: Vec<PolicyRule>, // first matching rule decides, the role model applies if none matches
+    #[serde(default, rename = "sp")]
+    pub signing_policy: Option<SigningPolicy>, // restrictions of the signing APIs
+    #[serde(default, rename = "ap")]
+    pub approval_policy: Option<ApprovalPolicy>, // M-of-N approval of sensitive changes
+    #[serde(default, rename = "pr")]
+    pub proposals: BTreeMap<u64, Proposal>, // pending proposals of sensitive changes
+    #[serde(default, rename = "pi")]
+    pub proposal_id: u64, // id of the last proposal
+    #[serde(default, rename = "al")]
+    pub alias: Option<String>, // the original name of a renamed namespace, used in key derivation
+    #[serde(default, rename = "kv")]
+    pub kek_version: u32, // current KEK version, mixed into the KEK derivation path if > 0
+    #[serde(default, rename = "im")]
+    pub importing: Option<NamespaceImport>, // the export being imported, until its last page
+    #[serde(default, rename = "mv")]
+    pub moving: Option<NamespaceMove>, // the rename or clone into this namespace, until all settings are moved
+}
+
+/// The progress of a rename or clone, the settings are moved or copied in batches.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NamespaceMove {
+    #[serde(rename = "f")]
+    pub from: String, // the renamed or cloned namespace
+    #[serde(rename = "c")]
+    pub clone: bool, // the settings are copied and the source namespace is kept
+    #[serde(default, rename = "k")]
+    pub cursor: Option<SettingPathKey>, // the last setting copied by a clone
+}
+
+/// The progress of an import, the following pages must belong to the same export and come in order.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NamespaceImport {
+    #[serde(rename = "c")]
+    pub canister: Principal, // the exporting canister
+    #[serde(rename = "n")]
+    pub name: String, // the exported namespace name
+    #[serde(rename = "id")]
+    pub export_id: ByteBuf,
+    #[serde(rename = "p")]
+    pub next_page: u32,
+    #[serde(default, rename = "s")]
+    pub pending: Option<ExportedSetting>, // the setting whose payloads are being imported in chunks
+    #[serde(default, rename = "nc")]
+    pub next_chunk: (u32, u64), // payload version and offset of the next chunk of the pending setting
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Proposal {
+    #[serde(rename = "c")]
+    pub change: NamespaceChange,
+    #[serde(rename = "d")]
+    pub description: String,
+    #[serde(rename = "p")]
+    pub proposer: Principal,
+    #[serde(rename = "a")]
+    pub approvers: BTreeSet<Principal>,
+    #[serde(rename = "ca")]
+    pub created_at: u64, // unix timestamp in milliseconds
+    #[serde(rename = "e")]
+    pub expires_at: u64, // unix timestamp in milliseconds
+}
+
+impl Proposal {
+    pub fn into_info(self, id: u64, executed: bool) -> ProposalInfo {
+        ProposalInfo {
+            id,
+            change: self.change,
+            description: self.description,
+            proposer: self.proposer,
+            approvers: self.approvers,
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            executed,
+        }
+    }
 }
 
 pub enum NamespaceReadPermission {
@@ -206,17 +344,287 @@ impl Namespace {
             gas_balance: self.gas_balance,
             fixed_id_names: self.fixed_id_names,
             session_expires_in_ms: self.session_expires_in_ms,
+            retain_versions: self.retain_versions,
+            retain_archived_ms: self.retain_archived_ms,
+            gas_used: self.gas_used,
+            gas_exempt: self.gas_exempt,
+            usage: self.usage,
+            max_bytes_total: self.max_bytes_total,
+            max_settings: self.max_settings,
+            settings_total: self.settings_total,
+            rate_limit: self.rate_limit,
+            groups: self.groups,
+            manager_groups: self.manager_groups,
+            auditor_groups: self.auditor_groups,
+            user_groups: self.user_groups,
+            policy: self.policy,
+            signing_policy: self.signing_policy,
+            approval_policy: self.approval_policy,
+            alias: self.alias,
+            kek_version: self.kek_version,
+            gas_credit: self.gas_credit,
+            moving_from: self.moving.map(|m| m.from),
+        }
+    }
+
+    /// Returns the name used in key derivation paths, the original name if renamed.
+    pub fn derivation_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.alias.as_deref().unwrap_or(name)
+    }
+
+    /// Checks whether the caller is a member of the referenced group,
+    /// `name` for a namespace-level group or `canister:name` for a canister-level group.
+    pub fn is_group_member(&self, group: &str, caller: &Principal) -> bool {
+        match group.strip_prefix(CANISTER_GROUP_PREFIX) {
+            Some(name) => state::with(|s| s.groups.get(name).is_some_and(|m| m.contains(caller))),
+            None => self.groups.get(group).is_some_and(|m| m.contains(caller)),
+        }
+    }
+
+    /// Returns the group references of the role: "manager", "auditor" or "user".
+    pub fn role_groups_mut(&mut self, role: &str) -> Result<&mut BTreeSet<String>, String> {
+        match role {
+            "manager" => Ok(&mut self.manager_groups),
+            "auditor" => Ok(&mut self.auditor_groups),
+            "user" => Ok(&mut self.user_groups),
+            _ => Err(format!("invalid role: {}", role)),
+        }
+    }
+
+    fn has_role(
+        &self,
+        members: &BTreeSet<Principal>,
+        groups: &BTreeSet<String>,
+        caller: &Principal,
+    ) -> bool {
+        members.contains(caller) || groups.iter().any(|g| self.is_group_member(g, caller))
+    }
+
+    pub fn is_manager(&self, caller: &Principal) -> bool {
+        self.has_role(&self.managers, &self.manager_groups, caller)
+    }
+
+    pub fn is_auditor(&self, caller: &Principal) -> bool {
+        self.has_role(&self.auditors, &self.auditor_groups, caller)
+    }
+
+    pub fn is_user(&self, caller: &Principal) -> bool {
+        self.has_role(&self.users, &self.user_groups, caller)
+    }
+
+    fn is_policy_subject(&self, subject: &PolicySubject, caller: &Principal) -> bool {
+        match subject {
+            PolicySubject::Any => true,
+            PolicySubject::Role(role) => match role.as_str() {
+                "manager" => self.is_manager(caller),
+                "auditor" => self.is_auditor(caller),
+                "user" => self.is_user(caller),
+                _ => false,
+            },
+            PolicySubject::Principal(p) => p == caller,
+            PolicySubject::Group(group) => self.is_group_member(group, caller),
+        }
+    }
+
+    /// Returns the index and effect of the first policy rule that matches,
+    /// None if the role model applies.
+    pub fn policy_decision(
+        &self,
+        caller: &Principal,
+        action: &str,
+        resource: &[&[u8]],
+    ) -> Option<(u32, bool)> {
+        self.policy
+            .iter()
+            .position(|rule| {
+                rule.matches_resource(action, resource)
+                    && self.is_policy_subject(&rule.subject, caller)
+            })
+            .map(|i| (i as u32, self.policy[i].allow))
+    }
+
+    /// Checks whether the caller is a reader of the setting, by principal, active grant or group.
+    pub fn is_setting_reader(&self, setting: &Setting, caller: &Principal, now_ms: u64) -> bool {
+        setting.is_reader(caller, now_ms)
+            || setting
+                .reader_groups
+                .iter()
+                .any(|g| self.is_group_member(g, caller))
+    }
+
+    /// Checks the storage quotas against the usage after a write.
+    /// Writes that do not increase the usage are always allowed.
+    pub fn check_quota(&self, bytes_total: u64, settings_total: u64) -> Result<(), String> {
+        if self.max_bytes_total > 0
+            && bytes_total > self.max_bytes_total
+            && bytes_total > self.payload_bytes_total
+        {
+            Err(format!(
+                "payload bytes total exceeds the limit {}",
+                self.max_bytes_total
+            ))?;
+        }
+        if self.max_settings > 0
+            && settings_total > self.max_settings
+            && settings_total > self.settings_total
+        {
+            Err(format!(
+                "settings count exceeds the limit {}",
+                self.max_settings
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether the archived version should be pruned by the retention policy.
+    pub fn should_prune_archived(
+        &self,
+        version: u32,
+        current_version: u32,
+        archived_at: u64,
+        now_ms: u64,
+    ) -> bool {
+        (self.retain_versions > 0 && version.saturating_add(self.retain_versions) < current_version)
+            || (self.retain_archived_ms > 0
+                && archived_at.saturating_add(self.retain_archived_ms) < now_ms)
+    }
+
+    /// Checks whether sensitive changes of the namespace require M-of-N approval.
+    pub fn requires_approval(&self) -> bool {
+        self.approval_policy
+            .as_ref()
+            .is_some_and(|p| p.threshold > 1)
+    }
+
+    /// Checks that the managers can still reach the threshold of the approval policy.
+    fn check_approval_threshold(&self) -> Result<(), String> {
+        if let Some(ref policy) = self.approval_policy {
+            if policy.threshold as usize > self.managers.len() {
+                Err(format!(
+                    "approval threshold {} exceeds the number of managers {}",
+                    policy.threshold,
+                    self.managers.len()
+                ))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply_info_update(&mut self, input: UpdateNamespaceInput, now_ms: u64) {
+        if let Some(desc) = input.desc {
+            self.desc = desc;
+        }
+        if let Some(max_payload_size) = input.max_payload_size {
+            self.max_payload_size = max_payload_size;
+        }
+        if let Some(status) = input.status {
+            self.status = status;
+        }
+        if let Some(visibility) = input.visibility {
+            self.visibility = visibility;
+        }
+        if let Some(session_expires_in_ms) = input.session_expires_in_ms {
+            self.session_expires_in_ms = session_expires_in_ms;
+        }
+        if let Some(retain_versions) = input.retain_versions {
+            self.retain_versions = retain_versions;
+        }
+        if let Some(retain_archived_ms) = input.retain_archived_ms {
+            self.retain_archived_ms = retain_archived_ms;
+        }
+        if let Some(max_bytes_total) = input.max_bytes_total {
+            self.max_bytes_total = max_bytes_total;
+        }
+        if let Some(max_settings) = input.max_settings {
+            self.max_settings = max_settings;
+        }
+        if let Some(rate_limit) = input.rate_limit {
+            self.rate_limit = if rate_limit.capacity > 0 {
+                Some(rate_limit)
+            } else {
+                None
+            };
+        }
+        self.updated_at = now_ms;
+    }
+
+    pub fn add_delegators(
+        &mut self,
+        name: &str,
+        delegators: BTreeSet<Principal>,
+    ) -> BTreeSet<Principal> {
+        let entry = self
+            .fixed_id_names
+            .entry(name.to_ascii_lowercase())
+            .or_default();
+        entry.extend(delegators);
+        entry.clone()
+    }
+
+    pub fn remove_delegators(&mut self, name: &str, delegators: &BTreeSet<Principal>) {
+        let name = name.to_ascii_lowercase();
+        if let Some(entry) = self.fixed_id_names.get_mut(&name) {
+            entry.retain(|v| !delegators.contains(v));
+            if entry.is_empty() {
+                self.fixed_id_names.remove(&name);
+            }
         }
     }
 
+    /// Applies an approved change except `Delete`, which removes the namespace.
+    fn apply_change(&mut self, change: NamespaceChange, now_ms: u64) -> Result<(), String> {
+        match change {
+            NamespaceChange::AddManagers(args) => self.managers.extend(args),
+            NamespaceChange::RemoveManagers(args) => {
+                self.managers.retain(|p| !args.contains(p));
+                self.check_approval_threshold()?;
+            }
+            NamespaceChange::AddGroupMembers(name, members) => {
+                self.groups.entry(name).or_default().extend(members);
+            }
+            NamespaceChange::RemoveGroupMembers(name, members) => {
+                let group = self
+                    .groups
+                    .get_mut(&name)
+                    .ok_or_else(|| format!("NotFound: group {} not found", name))?;
+                group.retain(|p| !members.contains(p));
+                if group.is_empty() {
+                    self.groups.remove(&name);
+                }
+            }
+            NamespaceChange::AddRoleGroups(role, groups) => {
+                self.role_groups_mut(&role)?.extend(groups);
+            }
+            NamespaceChange::RemoveRoleGroups(role, groups) => {
+                self.role_groups_mut(&role)?.retain(|g| !groups.contains(g));
+            }
+            NamespaceChange::UpdatePolicy(policy) => self.policy = policy,
+            NamespaceChange::UpdateSigningPolicy(policy) => self.signing_policy = policy,
+            NamespaceChange::UpdateInfo(input) => self.apply_info_update(input, now_ms),
+            NamespaceChange::AddDelegator(input) => {
+                self.add_delegators(&input.name, input.delegators);
+            }
+            NamespaceChange::RemoveDelegator(input) => {
+                self.remove_delegators(&input.name, &input.delegators);
+            }
+            NamespaceChange::UpdateApprovalPolicy(policy) => {
+                self.approval_policy = policy;
+                self.check_approval_threshold()?;
+            }
+            NamespaceChange::Delete => Err("delete can not be applied to namespace".to_string())?,
+        }
+        self.updated_at = now_ms;
+        Ok(())
+    }
+
     pub fn read_permission(&self, caller: &Principal) -> NamespaceReadPermission {
         if self.visibility == 1 {
             return NamespaceReadPermission::Full;
         }
 
-        if self.managers.contains(caller) || self.auditors.contains(caller) {
+        if self.is_manager(caller) || self.is_auditor(caller) {
             NamespaceReadPermission::Full
-        } else if self.status >= 0 && self.users.contains(caller) {
+        } else if self.status >= 0 && self.is_user(caller) {
             NamespaceReadPermission::User
         } else {
             NamespaceReadPermission::None
@@ -224,7 +632,7 @@ impl Namespace {
     }
 
     pub fn can_write_namespace(&self, caller: &Principal) -> bool {
-        self.status < 1 && self.managers.contains(caller)
+        self.status < 1 && self.is_manager(caller)
     }
 
     pub fn can_read_namespace(&self, caller: &Principal) -> bool {
@@ -233,26 +641,34 @@ impl Namespace {
         }
 
         if self.status < 0 {
-            return self.managers.contains(caller) || self.auditors.contains(caller);
+            return self.is_manager(caller) || self.is_auditor(caller);
         }
 
-        self.managers.contains(caller)
-            || self.auditors.contains(caller)
-            || self.users.contains(caller)
+        self.is_manager(caller) || self.is_auditor(caller) || self.is_user(caller)
     }
 
     pub fn can_write_setting(&self, caller: &Principal, spk: &SettingPathKey) -> bool {
-        if self.status != 0 {
+        // settings are moved into the namespace by a rename or clone
+        if self.status != 0 || self.moving.is_some() {
             return false;
         }
 
+        // user owned settings can only be written by the subject
+        if spk.1 != 0 && caller != &spk.2 {
+            return false;
+        }
+
+        if let Some((_, allow)) = self.policy_decision(caller, "write", &[&spk.3]) {
+            return allow;
+        }
+
         // only managers can create server side settings for any subject
         if spk.1 == 0 {
-            return self.managers.contains(caller);
+            return self.is_manager(caller);
         }
 
         // users can create settings for themselves and update them
-        self.users.contains(caller) && caller == &spk.2
+        self.is_user(caller)
     }
 
     fn partial_can_read_setting(&self, caller: &Principal, spk: &SettingPathKey) -> Option<bool> {
@@ -261,20 +677,65 @@ impl Namespace {
         }
 
         if self.status < 0 {
-            return Some(self.managers.contains(caller) || self.auditors.contains(caller));
+            return Some(self.is_manager(caller) || self.is_auditor(caller));
+        }
+
+        if let Some((_, allow)) = self.policy_decision(caller, "read", &[&spk.3]) {
+            return Some(allow);
         }
 
-        if self.managers.contains(caller) || self.auditors.contains(caller) || caller == &spk.2 {
+        if caller == &spk.2 || self.is_manager(caller) || self.is_auditor(caller) {
             return Some(true);
         }
         None
     }
 
-    pub fn has_ns_signing_permission(&self, caller: &Principal) -> bool {
-        if self.status < 0 && !self.managers.contains(caller) {
+    pub fn has_ns_signing_permission(
+        &self,
+        caller: &Principal,
+        derivation_path: &[ByteBuf],
+    ) -> bool {
+        if self.status < 0 && !self.is_manager(caller) {
             return false;
         }
-        self.managers.contains(caller) || self.users.contains(caller)
+        let resource: Vec<&[u8]> = derivation_path.iter().map(|p| p.as_slice()).collect();
+        let allowed = match self.policy_decision(caller, "sign", &resource) {
+            Some((_, allow)) => allow,
+            None => self.is_manager(caller) || self.is_user(caller),
+        };
+        allowed && self.is_signing_path_allowed(caller, &resource)
+    }
+
+    /// Checks the derivation path against the prefixes of the signing policy
+    /// declared for the caller and its roles.
+    fn is_signing_path_allowed(&self, caller: &Principal, path: &[&[u8]]) -> bool {
+        let policy = match self.signing_policy {
+            Some(ref policy) if policy.restricts_paths() => policy,
+            _ => return true,
+        };
+        let prefixes = policy.principal_paths.get(caller).into_iter().chain(
+            policy.role_paths.iter().filter_map(|(role, prefixes)| {
+                let has_role = match role.as_str() {
+                    "manager" => self.is_manager(caller),
+                    "auditor" => self.is_auditor(caller),
+                    "user" => self.is_user(caller),
+                    _ => false,
+                };
+                has_role.then_some(prefixes)
+            }),
+        );
+        prefixes
+            .flatten()
+            .any(|prefix| has_path_prefix(prefix, path))
+    }
+
+    /// Checks the message against the message formats of the signing policy,
+    /// returns true if the message is accepted as an EIP-191 message.
+    pub fn check_signing_message(&self, message: &[u8]) -> Result<bool, String> {
+        match self.signing_policy {
+            Some(ref policy) => policy.check_message(message),
+            None => Ok(false),
+        }
     }
 }
 
@@ -318,6 +779,29 @@ pub struct Setting {
     pub payload: Option<ByteBuf>,
     #[serde(rename = "k")]
     pub dek: Option<ByteBuf>, // Data Encryption Key that encrypted by BYOK or vetKey in COSE_Encrypt0
+    #[serde(default, rename = "e")]
+    pub expires_at: Option<u64>, // unix timestamp in milliseconds
+    #[serde(default, rename = "rf")]
+    pub restored_from: Option<u32>, // the archived version that the current version was restored from
+    #[serde(default, rename = "rg")]
+    pub reader_grants: BTreeMap<Principal, u64>, // reader -> grant expires_at in milliseconds
+    #[serde(default, rename = "rr")]
+    pub reader_groups: BTreeSet<String>, // members of the groups can read the setting
+    #[serde(default, rename = "pc")]
+    pub payload_chunks: Option<ChunkedPayload>, // the payload stored in PAYLOAD_CHUNKS_STORE, `payload` is None
+}
+
+// ChunkedPayload is a payload uploaded or imported in chunks, its chunks are stored in PAYLOAD_CHUNKS_STORE
+// keyed by the setting path key with the payload version that stored them.
+// Versions that keep the payload, e.g. after updating the DEK only, refer to the same chunks.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ChunkedPayload {
+    #[serde(rename = "s")]
+    pub size: u64,
+    #[serde(rename = "h")]
+    pub sha256: ByteArray<32>,
+    #[serde(rename = "v")]
+    pub version: u32, // payload version of the chunk keys
 }
 
 impl Setting {
@@ -331,11 +815,79 @@ impl Setting {
             status: self.status,
             version: self.version,
             readers: self.readers,
+            reader_groups: self.reader_groups,
             tags: self.tags,
             dek: if with_payload { self.dek } else { None },
             payload: if with_payload { self.payload } else { None },
+            expires_at: self.expires_at,
+            restored_from: self.restored_from,
+            certificate: None,
+            witness: None,
         }
     }
+
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= now_ms)
+    }
+
+    /// Checks whether the caller is a reader, or has an active reader grant.
+    pub fn is_reader(&self, caller: &Principal, now_ms: u64) -> bool {
+        self.readers.contains(caller)
+            || self
+                .reader_grants
+                .get(caller)
+                .is_some_and(|expires_at| *expires_at > now_ms)
+    }
+
+    // size of the payload in bytes, stored inline or in chunks
+    pub fn payload_size(&self) -> u64 {
+        match self.payload_chunks {
+            Some(ref chunks) => chunks.size,
+            None => self.payload.as_ref().map(|p| p.len() as u64).unwrap_or(0),
+        }
+    }
+
+    // sha256 hash of the payload, None if there is no payload
+    pub fn payload_sha256(&self) -> Option<[u8; 32]> {
+        match self.payload_chunks {
+            Some(ref chunks) => Some(*chunks.sha256),
+            None => self.payload.as_ref().map(|p| sha256(p)),
+        }
+    }
+
+    // size of the payload and dek in bytes
+    pub fn size(&self) -> u64 {
+        let dek = self.dek.as_ref().map(|k| k.len()).unwrap_or(0);
+        self.payload_size() + dek as u64
+    }
+
+    /// Checks the compare-and-swap conditions of an update against the current setting.
+    pub fn check_cas(
+        &self,
+        expected_hash: Option<&ByteArray<32>>,
+        expected_updated_at: Option<u64>,
+    ) -> Result<(), String> {
+        let conflict = |reason: &str| {
+            SettingConflict {
+                reason: reason.to_string(),
+                version: self.version,
+                updated_at: self.updated_at,
+            }
+            .to_string()
+        };
+
+        if expected_updated_at.is_some_and(|updated_at| updated_at != self.updated_at) {
+            Err(conflict("updated_at mismatch"))?;
+        }
+        if let Some(hash) = expected_hash {
+            let payload_hash = self.payload_sha256().unwrap_or_else(|| sha256(&[]));
+            if &payload_hash != hash.as_ref() {
+                Err(conflict("payload hash mismatch"))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Storable for Setting {
@@ -423,6 +975,18 @@ pub struct SettingArchived {
     pub payload: Option<ByteBuf>,
     #[serde(rename = "k")]
     pub dek: Option<ByteBuf>,
+    #[serde(default, rename = "pc")]
+    pub payload_chunks: Option<ChunkedPayload>, // the payload stored in PAYLOAD_CHUNKS_STORE, `payload` is None
+}
+
+impl SettingArchived {
+    // size of the archived payload in bytes, the dek is a copy of the setting's dek and not counted
+    pub fn size(&self) -> u64 {
+        match self.payload_chunks {
+            Some(ref chunks) => chunks.size,
+            None => self.payload.as_ref().map(|p| p.len() as u64).unwrap_or(0),
+        }
+    }
 }
 
 impl Storable for SettingArchived {
@@ -445,105 +1009,518 @@ impl Storable for SettingArchived {
     }
 }
 
-const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
-const NSLEGACY_MEMORY_ID: MemoryId = MemoryId::new(1);
-const PAYLOADS_MEMORY_ID: MemoryId = MemoryId::new(2);
-const NAMESPACES_MEMORY_ID: MemoryId = MemoryId::new(3);
-const SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(4);
-
-thread_local! {
-    static SIGNATURES : RefCell<SignatureMap> = RefCell::new(SignatureMap::default());
-    static STATE: RefCell<State> = RefCell::new(State::default());
-    static NS: RefCell<BTreeMap<String, NamespaceLegacy>> = const { RefCell::new(BTreeMap::new()) };
-
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+/// A page of namespace export, see [`NamespaceExportPage`].
+#[derive(Deserialize, Serialize)]
+pub struct NamespaceExport {
+    #[serde(rename = "c")]
+    pub canister: Principal, // the exporting canister
+    #[serde(rename = "n")]
+    pub name: String, // the exported namespace name
+    #[serde(rename = "ea")]
+    pub exported_at: u64, // unix timestamp in milliseconds, of the first page
+    #[serde(rename = "id")]
+    pub export_id: ByteBuf, // the same in all pages of an export
+    #[serde(rename = "p")]
+    pub page: u32, // index of the page, from 0
+    #[serde(rename = "l")]
+    pub last: bool,
+    #[serde(rename = "ns")]
+    pub namespace: Option<Namespace>, // only in the first page
+    #[serde(rename = "s")]
+    pub settings: Vec<ExportedSetting>,
+    #[serde(default, rename = "ch")]
+    pub chunks: Vec<ExportedChunk>, // chunks of the payloads of the last chunked setting
+}
 
-    static STATE_STORE: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
-        StableCell::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
-            Vec::new()
-        )
-    );
+/// The cursor of the next export page.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ExportCursor {
+    #[serde(rename = "id")]
+    pub export_id: ByteBuf,
+    #[serde(rename = "ea")]
+    pub exported_at: u64,
+    #[serde(rename = "p")]
+    pub page: u32, // index of the next page
+    #[serde(rename = "k")]
+    pub spk: SettingPathKey, // the next setting
+    #[serde(default, rename = "v")]
+    pub version: u32, // payload version of the next chunk if the setting is being exported in chunks
+    #[serde(default, rename = "o")]
+    pub offset: u64, // offset of the next chunk
+}
 
-    static NSLEGACY_STORE: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
-        StableCell::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(NSLEGACY_MEMORY_ID)),
-            Vec::new()
-        )
-    );
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ExportedSetting {
+    #[serde(rename = "k")]
+    pub key: (u8, Principal, ByteBuf), // user_owned, subject, key
+    #[serde(rename = "s")]
+    pub setting: Setting,
+    #[serde(rename = "a")]
+    pub archived: Vec<(u32, SettingArchived)>,
+    // payloads of a setting larger than a page are removed from it and exported in chunks
+    // in the following pages: payload version -> (size, sha256)
+    #[serde(default, rename = "c")]
+    pub chunked: BTreeMap<u32, (u64, ByteArray<32>)>,
+}
 
-    static PAYLOADS_STORE: RefCell<StableBTreeMap<SettingPathKey, SettingArchived, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(PAYLOADS_MEMORY_ID)),
-        )
-    );
+#[derive(Deserialize, Serialize)]
+pub struct ExportedChunk {
+    #[serde(rename = "v")]
+    pub version: u32, // payload version
+    #[serde(rename = "o")]
+    pub offset: u64,
+    #[serde(rename = "d")]
+    pub data: ByteBuf,
+}
 
-    static NAMESPACES_STORE: RefCell<StableBTreeMap<String, Namespace, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(NAMESPACES_MEMORY_ID)),
-        )
-    );
+// SettingChangeLog is an entry of the namespace change log, keyed by change_log_key(namespace, seq).
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SettingChangeLog {
+    #[serde(rename = "k")]
+    pub kind: SettingChangeKind,
+    #[serde(rename = "s")]
+    pub spk: SettingPathKey, // with the version after the change
+    #[serde(rename = "c")]
+    pub changed_at: u64,
+}
 
-    static SETTINGS_STORE: RefCell<StableBTreeMap<SettingPathKey, Setting, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(SETTINGS_MEMORY_ID)),
-        )
-    );
+impl SettingChangeLog {
+    pub fn into_change(self, seq: u64) -> SettingChange {
+        SettingChange {
+            seq,
+            kind: self.kind,
+            user_owned: self.spk.1 == 1,
+            subject: self.spk.2,
+            key: self.spk.3,
+            version: self.spk.4,
+            changed_at: self.changed_at,
+        }
+    }
 }
 
-pub mod state {
-    use super::*;
+impl Storable for SettingChangeLog {
+    const BOUND: Bound = Bound::Unbounded;
 
-    pub fn with<R>(f: impl FnOnce(&State) -> R) -> R {
-        STATE.with_borrow(f)
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        to_writer(&self, &mut buf).expect("failed to encode SettingChangeLog data");
+        buf
     }
 
-    pub fn with_mut<R>(f: impl FnOnce(&mut State) -> R) -> R {
-        STATE.with_borrow_mut(f)
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        to_writer(self, &mut buf).expect("failed to encode SettingChangeLog data");
+        Cow::Owned(buf)
     }
 
-    pub fn is_controller(caller: &Principal) -> bool {
-        STATE.with_borrow(|s| s.governance_canister.as_ref() == Some(caller))
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_cbor_bytes(&bytes, "SettingChangeLog data")
     }
+}
 
-    pub fn is_manager(caller: &Principal) -> bool {
-        STATE.with_borrow(|s| s.managers.contains(caller))
+// CertifiedSetting is the value of a setting in the certified settings tree:
+// fork(labeled("meta", leaf(meta hash)), labeled("payload", leaf(payload hash)))
+// It is stored along with the setting so that the tree can be rebuilt without hashing the payloads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertifiedSetting {
+    pub meta: Hash,    // SettingInfo::meta_hash
+    pub payload: Hash, // SettingInfo::payload_hash
+}
+
+impl CertifiedSetting {
+    pub fn new(spk: &SettingPathKey, setting: Setting) -> Self {
+        let payload =
+            certified_payload_hash(setting.payload_sha256().as_ref(), setting.dek.as_ref());
+        let info = setting.into_info(spk.2, spk.3.clone(), false);
+        Self {
+            meta: info.meta_hash(),
+            payload,
+        }
     }
 
-    pub fn allowed_api(api: &str) -> Result<(), String> {
-        if with(|s| s.allowed_apis.is_empty() || s.allowed_apis.contains(api)) {
-            Ok(())
+    // the payload hash is pruned if `with_payload` is false
+    pub fn witness(&self, with_payload: bool) -> HashTree {
+        let payload = if with_payload {
+            labeled(LABEL_SETTING_PAYLOAD, leaf(self.payload.to_vec()))
         } else {
-            Err(format!("API {} not allowed", api))
-        }
+            pruned(labeled_hash(
+                LABEL_SETTING_PAYLOAD,
+                &leaf_hash(&self.payload),
+            ))
+        };
+        fork(
+            labeled(LABEL_SETTING_META, leaf(self.meta.to_vec())),
+            payload,
+        )
     }
+}
 
-    pub fn add_signature(seed: &[u8], message: &[u8]) {
-        SIGNATURES.with_borrow_mut(|sigs| {
-            let sig_inputs = CanisterSigInputs {
-                domain: DELEGATION_SIG_DOMAIN,
-                seed,
-                message,
-            };
-            sigs.add_signature(&sig_inputs);
+impl Storable for CertifiedSetting {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 64,
+        is_fixed_size: true,
+    };
 
-            certified_data_set(labeled_hash(LABEL_SIG, &sigs.root_hash()));
-        });
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
     }
 
-    pub fn get_signature(seed: &[u8], message: &[u8]) -> Result<Vec<u8>, String> {
-        SIGNATURES.with_borrow(|sigs| {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&self.meta);
+        buf.extend_from_slice(&self.payload);
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        Self {
+            meta: bytes[..32]
+                .try_into()
+                .expect("invalid CertifiedSetting data"),
+            payload: bytes[32..64]
+                .try_into()
+                .expect("invalid CertifiedSetting data"),
+        }
+    }
+}
+
+impl AsHashTree for CertifiedSetting {
+    fn root_hash(&self) -> Hash {
+        fork_hash(
+            &labeled_hash(LABEL_SETTING_META, &leaf_hash(&self.meta)),
+            &labeled_hash(LABEL_SETTING_PAYLOAD, &leaf_hash(&self.payload)),
+        )
+    }
+
+    fn as_hash_tree(&self) -> HashTree {
+        self.witness(true)
+    }
+}
+
+// TokenBucket is the rate limit state of a caller in a namespace, kept in heap memory only.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenBucket {
+    pub tokens: u32,
+    pub refilled_at: u64, // unix timestamp in milliseconds
+}
+
+impl TokenBucket {
+    pub fn new(limit: &RateLimit, now_ms: u64) -> Self {
+        Self {
+            tokens: limit.capacity,
+            refilled_at: now_ms,
+        }
+    }
+
+    pub fn refill(&mut self, limit: &RateLimit, now_ms: u64) {
+        if self.tokens >= limit.capacity || limit.refill_interval_ms == 0 {
+            self.tokens = self.tokens.min(limit.capacity);
+            self.refilled_at = now_ms;
+            return;
+        }
+
+        let refills = now_ms.saturating_sub(self.refilled_at) / limit.refill_interval_ms;
+        let tokens = (self.tokens as u64).saturating_add(refills);
+        if tokens >= limit.capacity as u64 {
+            self.tokens = limit.capacity;
+            self.refilled_at = now_ms;
+        } else {
+            self.tokens = tokens as u32;
+            // keep the remainder of the current interval
+            self.refilled_at += refills * limit.refill_interval_ms;
+        }
+    }
+
+    /// Takes a token from the bucket.
+    /// It returns the milliseconds to wait for the next token if the bucket is empty.
+    pub fn take(&mut self, limit: &RateLimit, now_ms: u64) -> Result<(), u64> {
+        self.refill(limit, now_ms);
+        if self.tokens == 0 {
+            return Err((self.refilled_at + limit.refill_interval_ms).saturating_sub(now_ms));
+        }
+        self.tokens -= 1;
+        Ok(())
+    }
+}
+
+// PayloadUpload is an in-progress chunked payload upload, its chunks are stored in UPLOAD_CHUNKS_STORE as they arrive.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PayloadUpload {
+    #[serde(rename = "u")]
+    pub uploader: Principal,
+    #[serde(rename = "sa")]
+    pub started_at: u64, // unix timestamp in milliseconds
+    #[serde(rename = "s")]
+    pub payload_size: u64,
+    #[serde(rename = "f")]
+    pub filled: BTreeSet<u32>, // indexes of the received chunks
+    #[serde(default, rename = "h")]
+    pub sha256: Option<ByteArray<32>>, // set when the received payload is verified, see `verify_upload`
+    #[serde(default, rename = "e")]
+    pub encrypt0: bool, // the verified payload is a COSE_Encrypt0 message
+}
+
+impl Storable for PayloadUpload {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        to_writer(&self, &mut buf).expect("failed to encode PayloadUpload data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        to_writer(self, &mut buf).expect("failed to encode PayloadUpload data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_cbor_bytes(&bytes, "PayloadUpload data")
+    }
+}
+
+// UploadChunkKey: (setting path key, chunk index)
+#[derive(Clone, Debug, Deserialize, Serialize, Ord, PartialOrd, Eq, PartialEq)]
+pub struct UploadChunkKey(pub SettingPathKey, pub u32);
+
+impl Storable for UploadChunkKey {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        to_writer(&self, &mut buf).expect("failed to encode UploadChunkKey data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        to_writer(self, &mut buf).expect("failed to encode UploadChunkKey data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_cbor_bytes(&bytes, "UploadChunkKey data")
+    }
+}
+
+const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const NSLEGACY_MEMORY_ID: MemoryId = MemoryId::new(1);
+const PAYLOADS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const NAMESPACES_MEMORY_ID: MemoryId = MemoryId::new(3);
+const SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const TAGS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const EXPIRES_MEMORY_ID: MemoryId = MemoryId::new(6);
+const CHANGES_MEMORY_ID: MemoryId = MemoryId::new(7);
+const GRANTS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const UPLOADS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const UPLOAD_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const CERTIFIED_MEMORY_ID: MemoryId = MemoryId::new(11);
+const PAYLOAD_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(12);
+
+// the change log keeps the latest changes of each namespace only
+const MAX_CHANGES_PER_NAMESPACE: u64 = 10_000;
+
+// limits of the pending chunked uploads
+const MAX_UPLOADS_PER_CALLER: usize = 4;
+const MAX_UPLOADS: usize = 64;
+const MAX_UPLOADS_SIZE: u64 = 512 * 1024 * 1024; // 512MiB
+
+thread_local! {
+    static SIGNATURES : RefCell<SignatureMap> = RefCell::new(SignatureMap::default());
+    static STATE: RefCell<State> = RefCell::new(State::default());
+    static NS: RefCell<BTreeMap<String, NamespaceLegacy>> = const { RefCell::new(BTreeMap::new()) };
+    // rate limit buckets: (namespace, caller) -> bucket, reset on upgrade
+    static RATE_LIMITS: RefCell<BTreeMap<(String, Principal), TokenBucket>> = const { RefCell::new(BTreeMap::new()) };
+    // certified settings tree: certified_setting_key -> setting hashes, rebuilt on upgrade
+    static CERTIFIED_SETTINGS: RefCell<RbTree<Vec<u8>, CertifiedSetting>> = const { RefCell::new(RbTree::new()) };
+    // the last archived payload key checked by the prune timer
+    static PRUNE_CURSOR: RefCell<Option<SettingPathKey>> = const { RefCell::new(None) };
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STATE_STORE: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
+            Vec::new()
+        )
+    );
+
+    static NSLEGACY_STORE: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(NSLEGACY_MEMORY_ID)),
+            Vec::new()
+        )
+    );
+
+    static PAYLOADS_STORE: RefCell<StableBTreeMap<SettingPathKey, SettingArchived, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PAYLOADS_MEMORY_ID)),
+        )
+    );
+
+    static NAMESPACES_STORE: RefCell<StableBTreeMap<String, Namespace, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(NAMESPACES_MEMORY_ID)),
+        )
+    );
+
+    static SETTINGS_STORE: RefCell<StableBTreeMap<SettingPathKey, Setting, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(SETTINGS_MEMORY_ID)),
+        )
+    );
+
+    // secondary index: tag_index_key(namespace, tag key, tag value, spk) -> spk
+    static TAGS_STORE: RefCell<StableBTreeMap<Vec<u8>, SettingPathKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TAGS_MEMORY_ID)),
+        )
+    );
+
+    // expiry index: expires_at in big-endian + spk -> spk
+    static EXPIRES_STORE: RefCell<StableBTreeMap<Vec<u8>, SettingPathKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(EXPIRES_MEMORY_ID)),
+        )
+    );
+
+    // change log: change_log_key(namespace, seq) -> change
+    static CHANGES_STORE: RefCell<StableBTreeMap<Vec<u8>, SettingChangeLog, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CHANGES_MEMORY_ID)),
+        )
+    );
+
+    // reader grant expiry index: reader_grant_index_key(spk, reader, expires_at) -> spk
+    static GRANTS_STORE: RefCell<StableBTreeMap<Vec<u8>, SettingPathKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(GRANTS_MEMORY_ID)),
+        )
+    );
+
+    // pending chunked uploads: spk with version 0 -> upload
+    static UPLOADS_STORE: RefCell<StableBTreeMap<SettingPathKey, PayloadUpload, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(UPLOADS_MEMORY_ID)),
+        )
+    );
+
+    // hashes of the settings in the certified settings tree: spk -> hashes
+    static CERTIFIED_STORE: RefCell<StableBTreeMap<SettingPathKey, CertifiedSetting, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CERTIFIED_MEMORY_ID)),
+        )
+    );
+
+    // received chunks of the pending uploads, keyed by spk with version 0
+    static UPLOAD_CHUNKS_STORE: RefCell<StableBTreeMap<UploadChunkKey, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(UPLOAD_CHUNKS_MEMORY_ID)),
+        )
+    );
+
+    // chunks of the payloads stored in chunks, keyed by spk with the payload version, see `ChunkedPayload`
+    static PAYLOAD_CHUNKS_STORE: RefCell<StableBTreeMap<UploadChunkKey, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PAYLOAD_CHUNKS_MEMORY_ID)),
+        )
+    );
+}
+
+pub mod state {
+    use super::*;
+
+    pub fn with<R>(f: impl FnOnce(&State) -> R) -> R {
+        STATE.with_borrow(f)
+    }
+
+    pub fn with_mut<R>(f: impl FnOnce(&mut State) -> R) -> R {
+        STATE.with_borrow_mut(f)
+    }
+
+    pub fn is_controller(caller: &Principal) -> bool {
+        STATE.with_borrow(|s| s.governance_canister.as_ref() == Some(caller))
+    }
+
+    pub fn is_manager(caller: &Principal) -> bool {
+        STATE.with_borrow(|s| s.managers.contains(caller))
+    }
+
+    pub fn allowed_api(api: &str) -> Result<(), String> {
+        if with(|s| s.allowed_apis.is_empty() || s.allowed_apis.contains(api)) {
+            Ok(())
+        } else {
+            Err(format!("API {} not allowed", api))
+        }
+    }
+
+    pub fn add_signature(seed: &[u8], message: &[u8]) {
+        SIGNATURES.with_borrow_mut(|sigs| {
+            let sig_inputs = CanisterSigInputs {
+                domain: DELEGATION_SIG_DOMAIN,
+                seed,
+                message,
+            };
+            sigs.add_signature(&sig_inputs);
+        });
+        update_certified_data();
+    }
+
+    pub fn get_signature(seed: &[u8], message: &[u8]) -> Result<Vec<u8>, String> {
+        SIGNATURES.with_borrow(|sigs| {
             let sig_inputs = CanisterSigInputs {
                 domain: DELEGATION_SIG_DOMAIN,
                 seed,
                 message,
             };
-            sigs.get_signature_as_cbor(&sig_inputs, None)
+            sigs.get_signature_as_cbor(&sig_inputs, Some(settings_root_hash()))
                 .map_err(|err| format!("failed to get signature: {:?}", err))
         })
     }
 
+    fn settings_root_hash() -> Hash {
+        CERTIFIED_SETTINGS.with_borrow(|t| labeled_hash(LABEL_SETTINGS, &t.root_hash()))
+    }
+
+    fn signatures_root_hash() -> Hash {
+        SIGNATURES.with_borrow(|sigs| labeled_hash(LABEL_SIG, &sigs.root_hash()))
+    }
+
+    /// Returns the root hash of the certified data,
+    /// with the settings tree on the left and the signatures tree on the right.
+    pub fn certified_data() -> Hash {
+        fork_hash(&settings_root_hash(), &signatures_root_hash())
+    }
+
+    pub fn update_certified_data() {
+        let data = certified_data();
+        // certified data can only be set in a canister
+        if cfg!(not(test)) {
+            certified_data_set(data);
+        }
+    }
+
+    /// Returns the witness of the setting in CBOR, against the certified data.
+    /// The payload hash is pruned if `with_payload` is false.
+    /// It returns None while the certified settings are being built after an upgrade.
+    pub fn setting_witness(spk: &SettingPathKey, with_payload: bool) -> Option<Vec<u8>> {
+        if !with(|s| s.certified_built) {
+            return None;
+        }
+        let key = certified_setting_key(&spk.0, spk.1 == 1, &spk.2, &spk.3);
+        let settings =
+            CERTIFIED_SETTINGS.with_borrow(|t| t.nested_witness(&key, |v| v.witness(with_payload)));
+        let tree = fork(
+            labeled(LABEL_SETTINGS, settings),
+            pruned(signatures_root_hash()),
+        );
+        let mut buf = vec![];
+        to_writer(&tree, &mut buf).expect("failed to encode witness");
+        Some(buf)
+    }
+
     pub async fn init_public_key() {
         let (ecdsa_key_name, schnorr_key_name) =
             with(|r| (r.ecdsa_key_name.clone(), r.schnorr_key_name.clone()));
@@ -583,6 +1560,36 @@ pub mod state {
             r.schnorr_secp256k1_public_key = schnorr_secp256k1_public_key;
             r.init_vector = iv.into();
         });
+
+        init_vetkd_public_key().await;
+    }
+
+    /// Retrieves the canister-level vetKD public key, it should be refreshed when the vetKD key name changes.
+    pub async fn init_vetkd_public_key() {
+        let key_name = with(|r| r.vetkd_key_name.clone());
+        let vetkd_public_key = vetkd_canister_public_key(key_name)
+            .await
+            .map_err(|err| {
+                ic_cdk::api::debug_print(format!("failed to retrieve vetKD public key: {err}"))
+            })
+            .ok();
+
+        with_mut(|r| {
+            r.vetkd_public_key = vetkd_public_key.map(ByteBuf::from);
+        });
+    }
+
+    /// Retrieves the canister-level vetKD public key again if the fetch on init or upgrade failed.
+    pub async fn ensure_vetkd_public_key() -> Result<(), String> {
+        if with(|r| r.vetkd_public_key.is_some()) {
+            return Ok(());
+        }
+        let key_name = with(|r| r.vetkd_key_name.clone());
+        let pk = vetkd_canister_public_key(key_name).await?;
+        with_mut(|r| {
+            r.vetkd_public_key = Some(ByteBuf::from(pk));
+        });
+        Ok(())
     }
 
     pub fn load() {
@@ -593,18 +1600,25 @@ pub mod state {
             });
         });
 
+        if !with(|s| s.tags_indexed) {
+            ns::rebuild_tag_index();
+            with_mut(|s| s.tags_indexed = true);
+        }
         let count = NAMESPACES_STORE.with_borrow(|r| r.len());
-        if count > 0 {
-            return; // already migrated
+        if count == 0 {
+            // not migrated yet
+            NSLEGACY_STORE.with_borrow(|r| {
+                let data = r.get();
+                if data.is_empty() {
+                    return;
+                }
+                let m: BTreeMap<String, NamespaceLegacy> = from_cbor_bytes(data, "NS_STORE data");
+                ns::migrate(m);
+            });
+        }
+        if with(|s| s.certified_built) {
+            ns::load_certified_settings();
         }
-        NSLEGACY_STORE.with_borrow(|r| {
-            let data = r.get();
-            if data.is_empty() {
-                return;
-            }
-            let m: BTreeMap<String, NamespaceLegacy> = from_cbor_bytes(data, "NS_STORE data");
-            ns::migrate(m);
-        });
     }
 
     pub fn save() {
@@ -623,6 +1637,8 @@ pub mod ns {
 
     use super::*;
 
+    const IBE_DERIVATION: &[u8] = b"COSE_IBE";
+
     pub fn migrate(m: BTreeMap<String, NamespaceLegacy>) {
         if m.is_empty() {
             return;
@@ -645,14 +1661,18 @@ pub mod ns {
                         gas_balance: ns.gas_balance,
                         fixed_id_names: ns.fixed_id_names,
                         session_expires_in_ms: ns.session_expires_in_ms,
+                        settings_total: (ns.settings.len() + ns.user_settings.len()) as u64,
+                        ..Default::default()
                     };
                     r.insert(name.clone(), nns);
                     for (k, setting) in ns.settings {
                         let spk = SettingPathKey(name.clone(), 0, k.0, k.1, 0);
+                        index_tags(&spk, &BTreeMap::new(), &setting.tags);
                         rs.insert(spk, setting);
                     }
                     for (k, setting) in ns.user_settings {
                         let spk = SettingPathKey(name.clone(), 1, k.0, k.1, 0);
+                        index_tags(&spk, &BTreeMap::new(), &setting.tags);
                         rs.insert(spk, setting);
                     }
                 }
@@ -660,872 +1680,6494 @@ pub mod ns {
         });
     }
 
-    pub fn namespace_count() -> u64 {
-        NAMESPACES_STORE.with_borrow(|r| r.len())
+    // tag_index_prefix: namespace \0 tag key \0 tag value
+    // Namespace names and tag keys are validated by `validate_str`, so they never contain \0.
+    fn tag_index_prefix(namespace: &str, tag: &str, value: &str) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(namespace.len() + tag.len() + value.len() + 2);
+        buf.extend_from_slice(namespace.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(tag.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(value.as_bytes());
+        buf
     }
 
-    const MAX_KEY: [u8; 64] = [255u8; 64];
-    pub fn list_setting_keys(
-        namespace: &str,
-        user_owned: bool,
-        subject: Option<Principal>,
-    ) -> Vec<(Principal, ByteBuf)> {
-        SETTINGS_STORE.with_borrow(|r| {
-            let range = if let Some(subject) = subject {
-                ops::Range {
-                    start: &SettingPathKey(
-                        namespace.to_owned(),
-                        if user_owned { 1 } else { 0 },
-                        subject,
-                        ByteBuf::new(),
-                        0,
-                    ),
-                    end: &SettingPathKey(
-                        namespace.to_owned(),
-                        if user_owned { 1 } else { 0 },
-                        subject,
-                        ByteBuf::from(MAX_KEY.as_ref()),
-                        0,
-                    ),
-                }
-            } else {
-                ops::Range {
-                    start: &SettingPathKey(
-                        namespace.to_owned(),
-                        if user_owned { 1 } else { 0 },
-                        Principal::anonymous(),
-                        ByteBuf::new(),
-                        0,
-                    ),
-                    end: &SettingPathKey(
-                        namespace.to_owned(),
-                        if user_owned { 2 } else { 1 },
-                        Principal::management_canister(),
-                        ByteBuf::new(),
-                        u32::MAX,
-                    ),
-                }
-            };
-            r.keys_range(range).map(|k| (k.2, k.3)).collect()
-        })
+    fn tag_index_key(spk: &SettingPathKey, tag: &str, value: &str) -> Vec<u8> {
+        let mut buf = tag_index_prefix(&spk.0, tag, value);
+        buf.push(0);
+        buf.extend_from_slice(&spk.to_bytes());
+        buf
     }
 
-    pub fn with<R>(
-        namespace: &String,
-        f: impl FnOnce(Namespace) -> Result<R, String>,
-    ) -> Result<R, String> {
-        NAMESPACES_STORE.with_borrow(|r| {
-            r.get(namespace)
-                .map(f)
-                .unwrap_or_else(|| Err(format!("NotFound: namespace {} not found", namespace)))
-        })
-    }
-
-    pub fn with_mut<R>(
-        namespace: String,
-        f: impl FnOnce(&mut Namespace) -> Result<R, String>,
-    ) -> Result<R, String> {
-        NAMESPACES_STORE.with_borrow_mut(|r| match r.get(&namespace) {
-            Some(mut ns) => match f(&mut ns) {
-                Ok(rt) => {
-                    r.insert(namespace, ns);
-                    Ok(rt)
+    /// Updates the tag index of the setting from the old tags to the new tags.
+    fn index_tags(
+        spk: &SettingPathKey,
+        old: &BTreeMap<String, String>,
+        new: &BTreeMap<String, String>,
+    ) {
+        let spk = spk.v0();
+        TAGS_STORE.with_borrow_mut(|r| {
+            for (k, v) in old.iter() {
+                if new.get(k) != Some(v) {
+                    r.remove(&tag_index_key(&spk, k, v));
                 }
-                Err(err) => Err(err),
-            },
-            None => Err(format!("NotFound: namespace {} not found", namespace)),
-        })
+            }
+            for (k, v) in new.iter() {
+                if old.get(k) != Some(v) {
+                    r.insert(tag_index_key(&spk, k, v), spk.clone());
+                }
+            }
+        });
     }
 
-    pub fn has_kek_permission(caller: &Principal, spk: &SettingPathKey) -> bool {
-        with(&spk.0, |ns| {
-            if ns.status < 0 && !ns.managers.contains(caller) {
-                return Ok(false);
+    /// Builds the tag index from all settings, it runs once when upgrading from a version without it.
+    pub fn rebuild_tag_index() {
+        TAGS_STORE.with_borrow_mut(|r| r.clear_new());
+        SETTINGS_STORE.with_borrow(|r| {
+            for entry in r.iter() {
+                let (spk, setting) = entry.into_pair();
+                index_tags(&spk, &BTreeMap::new(), &setting.tags);
             }
+        });
+    }
 
-            if caller == &spk.2
-                || ns.auditors.contains(caller)
-                || (spk.1 == 0 && ns.managers.contains(caller))
-            {
-                return Ok(true);
+    /// Updates the setting in the certified settings tree and its stored hashes,
+    /// or removes them if it does not exist.
+    /// While CERTIFIED_STORE is being built, only the hashes of the settings already built are updated.
+    fn certify_setting(spk: &SettingPathKey) {
+        let spk = spk.v0();
+        let (built, cursor) = state::with(|s| (s.certified_built, s.certified_cursor.clone()));
+        if !built && cursor.is_none_or(|cursor| spk > cursor) {
+            // the build hashes it later
+            return;
+        }
+
+        let key = certified_setting_key(&spk.0, spk.1 == 1, &spk.2, &spk.3);
+        let setting = SETTINGS_STORE.with_borrow(|r| r.get(&spk));
+        match setting {
+            Some(setting) => {
+                let certified = CertifiedSetting::new(&spk, setting);
+                CERTIFIED_STORE.with_borrow_mut(|r| r.insert(spk, certified.clone()));
+                if built {
+                    CERTIFIED_SETTINGS.with_borrow_mut(|t| t.insert(key, certified));
+                }
+            }
+            None => {
+                CERTIFIED_STORE.with_borrow_mut(|r| r.remove(&spk));
+                if built {
+                    CERTIFIED_SETTINGS.with_borrow_mut(|t| t.delete(&key));
+                }
             }
+        }
+        if built {
+            state::update_certified_data();
+        }
+    }
 
-            let setting = SETTINGS_STORE.with_borrow(|m| m.get(&spk.v0()));
-            Ok(setting.is_some_and(|s| s.readers.contains(caller)))
-        })
-        .unwrap_or(false)
+    /// Hashes at most `limit` settings into CERTIFIED_STORE from where the last call stopped.
+    /// It runs in timer batches after upgrading from a version without the stored hashes,
+    /// settings are not certified until all of them are hashed and the tree is loaded.
+    /// It returns true when the build has finished.
+    pub fn build_certified_settings(limit: usize) -> bool {
+        let (built, cursor) = state::with(|s| (s.certified_built, s.certified_cursor.clone()));
+        if built {
+            return true;
+        }
+
+        if cursor.is_none() {
+            CERTIFIED_STORE.with_borrow_mut(|r| r.clear_new());
+        }
+        let start = match cursor {
+            Some(cursor) => ops::Bound::Excluded(cursor),
+            None => ops::Bound::Unbounded,
+        };
+        let mut count = 0;
+        let mut last: Option<SettingPathKey> = None;
+        CERTIFIED_STORE.with_borrow_mut(|rc| {
+            SETTINGS_STORE.with_borrow(|r| {
+                for entry in r.range((start, ops::Bound::Unbounded)).take(limit) {
+                    let (spk, setting) = entry.into_pair();
+                    rc.insert(spk.clone(), CertifiedSetting::new(&spk, setting));
+                    last = Some(spk);
+                    count += 1;
+                }
+            });
+        });
+
+        if count < limit {
+            state::with_mut(|s| {
+                s.certified_built = true;
+                s.certified_cursor = None;
+            });
+            load_certified_settings();
+            return true;
+        }
+        state::with_mut(|s| s.certified_cursor = last);
+        false
     }
 
-    pub fn ecdsa_public_key(
+    /// Loads the certified settings tree from the stored hashes of the settings.
+    pub fn load_certified_settings() {
+        CERTIFIED_SETTINGS.with_borrow_mut(|t| {
+            *t = RbTree::new();
+            CERTIFIED_STORE.with_borrow(|r| {
+                for entry in r.iter() {
+                    let (spk, certified) = entry.into_pair();
+                    let key = certified_setting_key(&spk.0, spk.1 == 1, &spk.2, &spk.3);
+                    t.insert(key, certified);
+                }
+            });
+        });
+        state::update_certified_data();
+    }
+
+    pub fn search_settings(
         caller: &Principal,
-        namespace: String,
-        derivation_path: Vec<ByteBuf>,
-    ) -> Result<PublicKeyOutput, String> {
-        with(&namespace, |ns| {
-            if !ns.can_read_namespace(caller) {
+        input: SearchSettingsInput,
+        now_ms: u64,
+    ) -> Result<SearchSettingsOutput, String> {
+        with(&input.ns, |ns| {
+            let permission = ns.read_permission(caller);
+            if matches!(permission, NamespaceReadPermission::None) {
                 Err("no permission".to_string())?;
             }
 
-            state::with(|s| {
-                let pk = s.ecdsa_public_key.as_ref().ok_or("no ecdsa public key")?;
-                let mut path: Vec<Vec<u8>> = Vec::with_capacity(derivation_path.len() + 3);
-                path.push(b"COSE_ECDSA_Signing".to_vec());
-                path.push(namespace.to_bytes().to_vec());
-                path.extend(derivation_path.into_iter().map(|b| b.into_vec()));
-                derive_public_key(pk, path)
-            })
+            let mut prefix = tag_index_prefix(&input.ns, &input.tag, &input.value);
+            if !input.prefix {
+                prefix.push(0);
+            }
+            let start = match input.cursor {
+                Some(cursor) => {
+                    if !cursor.starts_with(&prefix) {
+                        Err("invalid cursor".to_string())?;
+                    }
+                    ops::Bound::Excluded(cursor.into_vec())
+                }
+                None => ops::Bound::Included(prefix.clone()),
+            };
+            let limit = input
+                .limit
+                .unwrap_or(MAX_SEARCH_LIMIT)
+                .min(MAX_SEARCH_LIMIT) as usize;
+
+            let mut output = SearchSettingsOutput {
+                settings: Vec::new(),
+                next_cursor: None,
+            };
+            TAGS_STORE.with_borrow(|r| {
+                let mut last: Option<Vec<u8>> = None;
+                for entry in r.range((start, ops::Bound::Unbounded)) {
+                    let (key, spk) = entry.into_pair();
+                    if !key.starts_with(&prefix) {
+                        break;
+                    }
+                    if output.settings.len() >= limit {
+                        output.next_cursor = last.map(ByteBuf::from);
+                        break;
+                    }
+
+                    last = Some(key);
+                    let setting = match SETTINGS_STORE.with_borrow(|rs| rs.get(&spk)) {
+                        Some(setting) if !setting.is_expired(now_ms) => setting,
+                        _ => continue,
+                    };
+                    // a tag value may contain \0, so the index key is not an exact match
+                    let matched = setting.tags.get(&input.tag).is_some_and(|v| {
+                        if input.prefix {
+                            v.starts_with(&input.value)
+                        } else {
+                            v == &input.value
+                        }
+                    });
+                    if !matched {
+                        continue;
+                    }
+                    if matches!(permission, NamespaceReadPermission::User) {
+                        let can = ns
+                            .partial_can_read_setting(caller, &spk)
+                            .unwrap_or_else(|| ns.is_setting_reader(&setting, caller, now_ms));
+                        if !can {
+                            continue;
+                        }
+                    }
+
+                    output.settings.push(setting.into_info(spk.2, spk.3, false));
+                }
+            });
+            Ok(output)
         })
     }
 
-    pub async fn ecdsa_sign_with(
-        caller: &Principal,
-        namespace: String,
-        derivation_path: Vec<ByteBuf>,
-        message: ByteBuf,
-    ) -> Result<ByteBuf, String> {
-        with(&namespace, |ns| {
-            if !ns.has_ns_signing_permission(caller) {
-                Err("no permission".to_string())?;
-            }
-            Ok(())
-        })?;
+    // change_log_key: namespace \0 seq in big-endian
+    fn change_log_key(namespace: &str, seq: u64) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(namespace.len() + 9);
+        buf.extend_from_slice(namespace.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(&seq.to_be_bytes());
+        buf
+    }
 
-        let key_name = state::with(|s| s.ecdsa_key_name.clone());
-        let mut path: Vec<Vec<u8>> = Vec::with_capacity(derivation_path.len() + 3);
-        path.push(b"COSE_ECDSA_Signing".to_vec());
-        path.push(namespace.to_bytes().to_vec());
-        path.extend(derivation_path.into_iter().map(|b| b.into_vec()));
-        let sig = sign_with_ecdsa(key_name, path, message.into_vec()).await?;
-        Ok(ByteBuf::from(sig))
+    fn change_log_seq(key: &[u8]) -> u64 {
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&key[key.len() - 8..]);
+        u64::from_be_bytes(seq)
     }
 
-    pub fn schnorr_public_key(
+    /// Appends a change of the setting to the namespace change log,
+    /// and drops the oldest change when the log is full.
+    pub fn record_change(spk: &SettingPathKey, kind: SettingChangeKind, version: u32, now_ms: u64) {
+        CHANGES_STORE.with_borrow_mut(|r| {
+            let seq = r
+                .keys_range(change_log_key(&spk.0, 0)..=change_log_key(&spk.0, u64::MAX))
+                .next_back()
+                .map(|key| change_log_seq(&key))
+                .unwrap_or(0)
+                + 1;
+            let mut spk = spk.clone();
+            spk.4 = version;
+            if seq > MAX_CHANGES_PER_NAMESPACE {
+                r.remove(&change_log_key(&spk.0, seq - MAX_CHANGES_PER_NAMESPACE));
+            }
+            r.insert(
+                change_log_key(&spk.0, seq),
+                SettingChangeLog {
+                    kind,
+                    spk,
+                    changed_at: now_ms,
+                },
+            );
+        });
+    }
+
+    /// Returns changes in the namespace after the `cursor` seq.
+    /// Changes of settings that the caller can not read are skipped,
+    /// but still advance the cursor.
+    /// It fails with [`CURSOR_TOO_OLD`] if changes after the cursor have been dropped from the log,
+    /// the cursor 0 starts from the oldest change in the log.
+    pub fn changes_since(
         caller: &Principal,
-        alg: SchnorrAlgorithm,
         namespace: String,
-        derivation_path: Vec<ByteBuf>,
-    ) -> Result<PublicKeyOutput, String> {
+        cursor: u64,
+        limit: Option<u32>,
+        now_ms: u64,
+    ) -> Result<SettingChangesOutput, String> {
         with(&namespace, |ns| {
-            if !ns.can_read_namespace(caller) {
+            let permission = ns.read_permission(caller);
+            if matches!(permission, NamespaceReadPermission::None) {
                 Err("no permission".to_string())?;
             }
 
-            state::with(|s| {
-                let pk = match alg {
-                    SchnorrAlgorithm::Bip340secp256k1 => s
-                        .schnorr_secp256k1_public_key
-                        .as_ref()
-                        .ok_or("no schnorr secp256k1 public key")?,
-                    SchnorrAlgorithm::Ed25519 => s
-                        .schnorr_ed25519_public_key
-                        .as_ref()
-                        .ok_or("no schnorr ed25519 public key")?,
-                };
-                let mut path: Vec<Vec<u8>> = Vec::with_capacity(derivation_path.len() + 3);
-                path.push(b"COSE_Schnorr_Signing".to_vec());
-                path.push(namespace.to_bytes().to_vec());
-                path.extend(derivation_path.into_iter().map(|b| b.into_vec()));
-                derive_schnorr_public_key(alg, pk, path)
-            })
+            let limit = limit.unwrap_or(MAX_CHANGES_LIMIT).min(MAX_CHANGES_LIMIT) as usize;
+            let mut output = SettingChangesOutput {
+                changes: Vec::new(),
+                next_cursor: cursor,
+            };
+            CHANGES_STORE.with_borrow(|r| {
+                if cursor > 0 {
+                    let oldest = r
+                        .keys_range(
+                            change_log_key(&namespace, 0)..=change_log_key(&namespace, u64::MAX),
+                        )
+                        .next()
+                        .map(|key| change_log_seq(&key));
+                    if let Some(oldest) = oldest.filter(|oldest| *oldest > cursor.saturating_add(1))
+                    {
+                        return Err(format!(
+                            "{}: changes after {} have been dropped, the oldest change is {}",
+                            CURSOR_TOO_OLD, cursor, oldest
+                        ));
+                    }
+                }
+
+                let range = (
+                    ops::Bound::Excluded(change_log_key(&namespace, cursor)),
+                    ops::Bound::Included(change_log_key(&namespace, u64::MAX)),
+                );
+                for entry in r.range(range).take(limit) {
+                    let (key, log) = entry.into_pair();
+                    let seq = change_log_seq(&key);
+                    output.next_cursor = seq;
+                    if matches!(permission, NamespaceReadPermission::User) {
+                        let can = ns
+                            .partial_can_read_setting(caller, &log.spk)
+                            .unwrap_or_else(|| {
+                                SETTINGS_STORE
+                                    .with_borrow(|rs| rs.get(&log.spk.v0()))
+                                    .is_some_and(|setting| {
+                                        ns.is_setting_reader(&setting, caller, now_ms)
+                                    })
+                            });
+                        if !can {
+                            continue;
+                        }
+                    }
+                    output.changes.push(log.into_change(seq));
+                }
+                Ok(())
+            })?;
+            Ok(output)
         })
     }
 
-    pub async fn schnorr_sign_with(
-        caller: &Principal,
-        alg: SchnorrAlgorithm,
-        namespace: String,
-        derivation_path: Vec<ByteBuf>,
-        message: ByteBuf,
-    ) -> Result<ByteBuf, String> {
-        with(&namespace, |ns| {
-            if !ns.has_ns_signing_permission(caller) {
-                Err("no permission".to_string())?;
+    fn expires_index_key(spk: &SettingPathKey, expires_at: u64) -> Vec<u8> {
+        let mut buf = expires_at.to_be_bytes().to_vec();
+        buf.extend_from_slice(&spk.to_bytes());
+        buf
+    }
+
+    /// Updates the expiry index of the setting from the old expires_at to the new one.
+    fn index_expires(spk: &SettingPathKey, old: Option<u64>, new: Option<u64>) {
+        if old == new {
+            return;
+        }
+        let spk = spk.v0();
+        EXPIRES_STORE.with_borrow_mut(|r| {
+            if let Some(expires_at) = old {
+                r.remove(&expires_index_key(&spk, expires_at));
             }
-            Ok(())
-        })?;
+            if let Some(expires_at) = new {
+                r.insert(expires_index_key(&spk, expires_at), spk.clone());
+            }
+        });
+    }
 
-        let key_name = state::with(|s| s.schnorr_key_name.clone());
-        let mut path: Vec<Vec<u8>> = Vec::with_capacity(derivation_path.len() + 3);
-        path.push(b"COSE_Schnorr_Signing".to_vec());
-        path.push(namespace.to_bytes().to_vec());
-        path.extend(derivation_path.into_iter().map(|b| b.into_vec()));
-        let sig = sign_with_schnorr(key_name, alg, path, message.into_vec()).await?;
-        Ok(ByteBuf::from(sig))
+    // reader_grant_index_key: expires_at in big-endian + reader length + reader + spk
+    fn reader_grant_index_key(
+        spk: &SettingPathKey,
+        reader: &Principal,
+        expires_at: u64,
+    ) -> Vec<u8> {
+        let reader = reader.as_slice();
+        let mut buf = expires_at.to_be_bytes().to_vec();
+        buf.push(reader.len() as u8);
+        buf.extend_from_slice(reader);
+        buf.extend_from_slice(&spk.to_bytes());
+        buf
     }
 
-    const CWT_EXPIRATION_SECONDS: i64 = 3600;
-    pub async fn sign_identity(
+    fn reader_grant_from_index_key(key: &[u8]) -> (u64, Principal) {
+        let expires_at = u64::from_be_bytes(key[..8].try_into().expect("invalid grant index key"));
+        let len = key[8] as usize;
+        (expires_at, Principal::from_slice(&key[9..9 + len]))
+    }
+
+    /// Grants the readers to read the setting until `expires_at`.
+    /// An existing grant of a reader is replaced.
+    pub fn grant_readers(
         caller: &Principal,
-        namespace: String,
-        audience: String,
+        spk: &SettingPathKey,
+        readers: BTreeSet<Principal>,
+        expires_at: u64,
         now_ms: u64,
-        algorithm: SchnorrAlgorithm,
-    ) -> Result<ByteBuf, String> {
-        let permission = with(&namespace, |ns| {
-            if ns.managers.contains(caller) {
-                Ok(format!("Namespace.*:{}", namespace))
-            } else if ns.users.contains(caller) {
-                if ns.auditors.contains(caller) {
-                    Ok(format!(
-                        "Namespace.Read:{} Namespace.*.SubjectedSetting:{}",
-                        namespace, namespace
-                    ))
-                } else {
-                    Ok(format!(
-                        "Namespace.Read.Info:{} Namespace.*.SubjectedSetting:{}",
-                        namespace, namespace
-                    ))
+    ) -> Result<u32, String> {
+        if expires_at <= now_ms {
+            Err("expires_at should be in the future".to_string())?;
+        }
+
+        let spk = spk.clone();
+        with_setting_mut(caller, &spk, |setting| {
+            GRANTS_STORE.with_borrow_mut(|r| {
+                for reader in readers {
+                    // the index entry of a replaced grant is removed by the purge timer
+                    r.insert(
+                        reader_grant_index_key(&spk.v0(), &reader, expires_at),
+                        spk.v0(),
+                    );
+                    setting.reader_grants.insert(reader, expires_at);
                 }
-            } else if ns.auditors.contains(caller) {
-                Ok(format!("Namespace.Read:{}", namespace))
-            } else {
-                Err("no permission".to_string())
+            });
+            setting.updated_at = now_ms;
+            Ok(setting.version)
+        })
+    }
+
+    /// Returns the active reader grants of the setting with their expires_at.
+    pub fn get_reader_grants(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        now_ms: u64,
+    ) -> Result<BTreeMap<Principal, u64>, String> {
+        let setting = try_get_setting(caller, spk, now_ms)
+            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", spk))?;
+        Ok(setting
+            .reader_grants
+            .into_iter()
+            .filter(|(_, expires_at)| *expires_at > now_ms)
+            .collect())
+    }
+
+    /// Removes at most `limit` expired reader grants.
+    /// It returns the number of checked index entries.
+    pub fn purge_expired_reader_grants(now_ms: u64, limit: usize) -> usize {
+        let expired: Vec<(Vec<u8>, SettingPathKey)> = GRANTS_STORE.with_borrow(|r| {
+            r.range(..(now_ms.saturating_add(1)).to_be_bytes().to_vec())
+                .take(limit)
+                .map(|entry| entry.into_pair())
+                .collect()
+        });
+
+        let count = expired.len();
+        for (key, spk) in expired {
+            GRANTS_STORE.with_borrow_mut(|r| r.remove(&key));
+            let (expires_at, reader) = reader_grant_from_index_key(&key);
+            SETTINGS_STORE.with_borrow_mut(|r| {
+                if let Some(mut setting) = r.get(&spk) {
+                    // the grant may have been replaced or revoked
+                    if setting.reader_grants.get(&reader) == Some(&expires_at) {
+                        setting.reader_grants.remove(&reader);
+                        r.insert(spk, setting);
+                    }
+                }
+            });
+        }
+        count
+    }
+
+    // 0 means never expires
+    fn check_expires_at(expires_at: Option<u64>, now_ms: u64) -> Result<Option<u64>, String> {
+        match expires_at {
+            None | Some(0) => Ok(None),
+            Some(expires_at) if expires_at <= now_ms => {
+                Err("expires_at should be in the future".to_string())
             }
-        })?;
+            Some(expires_at) => Ok(Some(expires_at)),
+        }
+    }
 
-        let key_name = state::with(|s| s.schnorr_key_name.clone());
-        let now_sec = (now_ms / 1000) as i64;
-        let cwt_id: [u8; 16] = rand_bytes().await?;
-        let claims = ClaimsSet {
-            issuer: Some(ic_cdk::api::canister_self().to_text()),
-            subject: Some(caller.to_text()),
-            audience: Some(audience),
-            expiration: Some((now_sec + CWT_EXPIRATION_SECONDS) as u64),
-            not_before: Some(now_sec as u64),
-            issued_at: Some(now_sec as u64),
-            cwt_id: Some(cwt_id.into()),
-            extra: scope_claim(permission),
-        };
-        let payload = claims.to_vec().map_err(format_error)?;
-        let alg = match algorithm {
-            SchnorrAlgorithm::Ed25519 => EdDSA,
-            SchnorrAlgorithm::Bip340secp256k1 => ES256K,
-        };
-        let mut sign1 = cose_sign1(payload, alg, None)?;
-        let mut tbs_data = sign1
-            .prepare_signature(None, None, Some(caller.as_slice()))
-            .map_err(format_error)?;
-        if algorithm == SchnorrAlgorithm::Bip340secp256k1 {
-            tbs_data = sha256(&tbs_data).into();
+    // archived versions of the setting in [from_version, to_version)
+    fn archived_range(
+        spk: &SettingPathKey,
+        from_version: u32,
+        to_version: u32,
+    ) -> ops::Range<SettingPathKey> {
+        ops::Range {
+            start: SettingPathKey(spk.0.clone(), spk.1, spk.2, spk.3.clone(), from_version),
+            end: SettingPathKey(spk.0.clone(), spk.1, spk.2, spk.3.clone(), to_version),
         }
-        let sig = sign_with_schnorr(key_name, algorithm, vec![], tbs_data).await?;
-        sign1.set_signature(sig).map_err(format_error)?;
-        let token = sign1.to_vec().map_err(format_error)?;
-        Ok(ByteBuf::from(token))
     }
 
-    pub fn inner_derive_kek(spk: &SettingPathKey, key_id: &[u8]) -> Result<[u8; 32], String> {
-        state::with(|s| {
-            let pk = s
-                .schnorr_secp256k1_public_key
-                .as_ref()
-                .ok_or("no schnorr secp256k1 public key")?;
-
-            let derivation_path = vec![
-                b"COSE_Symmetric_Key".to_vec(),
-                s.init_vector.to_vec(),
-                spk.2.to_bytes().to_vec(),
-                vec![spk.1],
-                spk.0.to_bytes().to_vec(),
-            ];
-            let pk =
-                derive_schnorr_public_key(SchnorrAlgorithm::Bip340secp256k1, pk, derivation_path)?;
-            Ok(mac3_256(&pk.public_key, key_id))
-        })
+    /// Removes the setting with its archived payloads and index entries.
+    /// It returns the size in bytes of the removed payloads.
+    fn remove_setting(spk: &SettingPathKey, setting: &Setting) -> u64 {
+        let spk = spk.v0();
+        SETTINGS_STORE.with_borrow_mut(|r| r.remove(&spk));
+        certify_setting(&spk);
+        index_tags(&spk, &setting.tags, &BTreeMap::new());
+        index_expires(&spk, setting.expires_at, None);
+
+        let mut size = setting.size();
+        if setting.version > 1 {
+            PAYLOADS_STORE.with_borrow_mut(|r| {
+                let keys: Vec<SettingPathKey> = r
+                    .keys_range(archived_range(&spk, 1, setting.version))
+                    .collect();
+                for pk in keys {
+                    if let Some(archived) = r.remove(&pk) {
+                        size += archived.size();
+                    }
+                }
+            });
+        }
+        PAYLOAD_CHUNKS_STORE.with_borrow_mut(|r| {
+            let keys: Vec<UploadChunkKey> = r.keys_range(setting_chunks_range(&spk)).collect();
+            for key in keys {
+                r.remove(&key);
+            }
+        });
+        size
     }
 
-    pub async fn inner_vetkd_public_key(spk: &SettingPathKey) -> Result<Vec<u8>, String> {
-        let key_name = state::with(|r| r.vetkd_key_name.clone());
+    /// Finds archived versions of the setting that are below `before_version`
+    /// or out of the namespace retention policy.
+    /// It returns their keys and their size in bytes.
+    fn prunable_archived(
+        ns: &Namespace,
+        spk: &SettingPathKey,
+        current_version: u32,
+        before_version: u32,
+        now_ms: u64,
+    ) -> (Vec<SettingPathKey>, u64) {
+        if before_version <= 1 && ns.retain_versions == 0 && ns.retain_archived_ms == 0 {
+            return (vec![], 0);
+        }
 
-        vetkd_public_key(
-            key_name,
-            &[
-                b"COSE_Symmetric_Key",
-                spk.2.to_bytes().as_ref(),
-                &[spk.1],
-                spk.0.to_bytes().as_ref(),
-            ],
-        )
-        .await
+        PAYLOADS_STORE.with_borrow(|r| {
+            let mut size = 0u64;
+            let pruned: Vec<SettingPathKey> = r
+                .range(archived_range(spk, 1, current_version))
+                .filter_map(|entry| {
+                    let (pk, archived) = entry.into_pair();
+                    if pk.4 < before_version
+                        || ns.should_prune_archived(
+                            pk.4,
+                            current_version,
+                            archived.archived_at,
+                            now_ms,
+                        )
+                    {
+                        size += archived.size();
+                        Some(pk)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+            (pruned, size)
+        })
     }
 
-    pub async fn inner_vetkd_encrypted_key(
+    /// Removes archived versions of the setting that are below `before_version`
+    /// or out of the namespace retention policy.
+    /// It returns the number of removed versions and their size in bytes.
+    fn prune_archived(
+        ns: &Namespace,
         spk: &SettingPathKey,
-        key_id: Vec<u8>,
-        transport_public_key: Vec<u8>,
-    ) -> Result<Vec<u8>, String> {
-        let key_name = state::with(|r| r.vetkd_key_name.clone());
-
-        vetkd_encrypted_key(
-            key_name,
-            &[
-                b"COSE_Symmetric_Key",
-                spk.2.to_bytes().as_ref(),
-                &[spk.1],
-                spk.0.to_bytes().as_ref(),
-            ],
-            key_id,
-            transport_public_key,
-        )
-        .await
+        current_version: u32,
+        before_version: u32,
+        now_ms: u64,
+    ) -> (u32, u64) {
+        let (pruned, size) = prunable_archived(ns, spk, current_version, before_version, now_ms);
+        let chunks: BTreeSet<u32> = PAYLOADS_STORE.with_borrow_mut(|r| {
+            pruned
+                .iter()
+                .filter_map(|pk| r.remove(pk).and_then(|a| a.payload_chunks))
+                .map(|c| c.version)
+                .collect()
+        });
+        for version in chunks {
+            release_payload_chunks(spk, version);
+        }
+        (pruned.len() as u32, size)
     }
 
-    pub fn get_namespace(caller: &Principal, namespace: String) -> Result<NamespaceInfo, String> {
-        with(&namespace, |ns| {
-            if !ns.can_read_namespace(caller) {
-                Err("no permission".to_string())?;
-            }
-            Ok(ns.into_info(namespace.clone()))
+    // size in bytes of the archived versions of the setting below `version`
+    fn archived_size(spk: &SettingPathKey, version: u32) -> u64 {
+        if version <= 1 {
+            return 0;
+        }
+        PAYLOADS_STORE.with_borrow(|r| {
+            r.range(archived_range(spk, 1, version))
+                .map(|entry| entry.value().size())
+                .sum()
         })
     }
 
-    pub fn list_namespaces(prev: Option<String>, take: usize) -> Vec<NamespaceInfo> {
-        NAMESPACES_STORE.with_borrow(|r| {
-            let mut res = Vec::with_capacity(take);
-            match prev {
-                Some(p) => {
-                    for e in r.range(ops::RangeTo { end: p }).rev() {
-                        res.push(e.value().into_info(e.key().clone()));
-                        if res.len() >= take {
-                            break;
-                        }
-                    }
-                }
-                None => {
-                    for e in r.iter().rev() {
-                        res.push(e.value().into_info(e.key().clone()));
-                        if res.len() >= take {
-                            break;
-                        }
-                    }
-                }
-            };
-            res
+    /// Recomputes the number of settings and the total payload size of the namespace
+    /// from the stored settings and archived versions, to repair counters that have drifted.
+    pub fn recompute_usage(namespace: String) -> Result<NamespaceInfo, String> {
+        let start = SettingPathKey(
+            namespace.clone(),
+            0,
+            Principal::management_canister(),
+            ByteBuf::new(),
+            0,
+        );
+        let end = SettingPathKey(
+            namespace.clone(),
+            u8::MAX,
+            Principal::management_canister(),
+            ByteBuf::new(),
+            0,
+        );
+        with_mut(namespace.clone(), |ns| {
+            let mut settings_total = 0u64;
+            let mut bytes_total = SETTINGS_STORE.with_borrow(|r| {
+                r.range(start.clone()..end.clone())
+                    .map(|entry| {
+                        settings_total += 1;
+                        entry.value().size()
+                    })
+                    .sum::<u64>()
+            });
+            bytes_total += PAYLOADS_STORE.with_borrow(|r| {
+                r.range(start..end)
+                    .map(|entry| entry.value().size())
+                    .sum::<u64>()
+            });
+            ns.settings_total = settings_total;
+            ns.payload_bytes_total = bytes_total;
+            Ok(ns.clone().into_info(namespace.clone()))
         })
     }
 
-    pub async fn create_namespace(
+    /// Prunes archived versions of the setting, and credits their bytes back to the namespace.
+    /// Archived versions below `spk.4` are pruned along with those out of the retention policy.
+    pub fn prune_setting_archived(
         caller: &Principal,
-        input: CreateNamespaceInput,
+        spk: &SettingPathKey,
         now_ms: u64,
-    ) -> Result<NamespaceInfo, String> {
-        if !state::with(|s| s.managers.contains(caller)) {
-            Err("no permission".to_string())?;
+    ) -> Result<u32, String> {
+        with_mut(spk.0.clone(), |ns| {
+            if !ns.can_write_setting(caller, spk) {
+                Err("no permission".to_string())?;
+            }
+            let setting = SETTINGS_STORE
+                .with_borrow(|r| r.get(&spk.v0()))
+                .ok_or_else(|| format!("NotFound: setting {} not found", spk))?;
+            if spk.4 > setting.version {
+                Err("version mismatch".to_string())?;
+            }
+
+            let (count, size) = prune_archived(ns, &spk.v0(), setting.version, spk.4, now_ms);
+            ns.payload_bytes_total = ns.payload_bytes_total.saturating_sub(size);
+            Ok(count)
+        })
+    }
+
+    /// Checks at most `limit` archived payloads from where the last call stopped,
+    /// prunes those out of the retention policy and credits their bytes back to namespaces.
+    /// It returns the number of pruned versions.
+    pub fn prune_archived_settings(now_ms: u64, limit: usize) -> usize {
+        let cursor = PRUNE_CURSOR.with_borrow(|c| c.clone());
+        let entries: Vec<(SettingPathKey, SettingArchived)> = PAYLOADS_STORE.with_borrow(|r| {
+            let start = match cursor {
+                Some(cursor) => ops::Bound::Excluded(cursor),
+                None => ops::Bound::Unbounded,
+            };
+            r.range((start, ops::Bound::Unbounded))
+                .take(limit)
+                .map(|entry| entry.into_pair())
+                .collect()
+        });
+        // start over from the beginning when reaching the end
+        let next = if entries.len() < limit {
+            None
+        } else {
+            entries.last().map(|(pk, _)| pk.clone())
+        };
+        PRUNE_CURSOR.with_borrow_mut(|c| *c = next);
+
+        let mut namespaces: BTreeMap<String, Option<Namespace>> = BTreeMap::new();
+        let mut credits: BTreeMap<String, u64> = BTreeMap::new();
+        let mut pruned = 0;
+        for (pk, archived) in entries {
+            let ns = namespaces
+                .entry(pk.0.clone())
+                .or_insert_with(|| NAMESPACES_STORE.with_borrow(|r| r.get(&pk.0)));
+            let ns = match ns {
+                Some(ns) if ns.retain_versions > 0 || ns.retain_archived_ms > 0 => ns,
+                _ => continue,
+            };
+            let current_version = match SETTINGS_STORE.with_borrow(|r| r.get(&pk.v0())) {
+                Some(setting) => setting.version,
+                None => continue,
+            };
+            if ns.should_prune_archived(pk.4, current_version, archived.archived_at, now_ms) {
+                PAYLOADS_STORE.with_borrow_mut(|r| r.remove(&pk));
+                if let Some(ref chunks) = archived.payload_chunks {
+                    release_payload_chunks(&pk, chunks.version);
+                }
+                *credits.entry(pk.0.clone()).or_default() += archived.size();
+                pruned += 1;
+            }
         }
 
-        NAMESPACES_STORE.with_borrow_mut(|r| {
-            if r.contains_key(&input.name) {
-                Err(format!("namespace {} already exists", input.name))?;
+        for (name, size) in credits {
+            let _ = with_mut(name, |ns| {
+                ns.payload_bytes_total = ns.payload_bytes_total.saturating_sub(size);
+                Ok(())
+            });
+        }
+        pruned
+    }
+
+    /// Purges at most `limit` expired settings, and credits their bytes back to namespaces.
+    /// It returns the number of purged settings.
+    pub fn purge_expired_settings(now_ms: u64, limit: usize) -> usize {
+        let expired: Vec<(Vec<u8>, SettingPathKey)> = EXPIRES_STORE.with_borrow(|r| {
+            r.range(..(now_ms.saturating_add(1)).to_be_bytes().to_vec())
+                .take(limit)
+                .map(|entry| entry.into_pair())
+                .collect()
+        });
+
+        let count = expired.len();
+        for (key, spk) in expired {
+            match SETTINGS_STORE.with_borrow(|r| r.get(&spk)) {
+                Some(setting) if setting.is_expired(now_ms) => {
+                    let size = remove_setting(&spk, &setting);
+                    record_change(&spk, SettingChangeKind::Delete, setting.version, now_ms);
+                    let _ = with_mut(spk.0.clone(), |ns| {
+                        ns.payload_bytes_total = ns.payload_bytes_total.saturating_sub(size);
+                        ns.settings_total = ns.settings_total.saturating_sub(1);
+                        Ok(())
+                    });
+                }
+                _ => {
+                    // stale index entry
+                    EXPIRES_STORE.with_borrow_mut(|r| r.remove(&key));
+                }
             }
-            let ns = Namespace {
-                desc: input.desc.unwrap_or_default(),
-                created_at: now_ms,
-                updated_at: now_ms,
-                max_payload_size: input.max_payload_size.unwrap_or(MAX_PAYLOAD_SIZE),
-                visibility: input.visibility,
-                managers: input.managers,
-                auditors: input.auditors,
-                users: input.users,
-                session_expires_in_ms: input.session_expires_in_ms.unwrap_or(SESSION_EXPIRES_IN_MS),
-                ..Default::default()
-            };
+        }
+        count
+    }
 
-            let info = ns.clone().into_info(input.name.clone());
-            r.insert(input.name, ns);
-            Ok(info)
+    pub fn namespace_count() -> u64 {
+        NAMESPACES_STORE.with_borrow(|r| r.len())
+    }
+
+    const MAX_KEY: [u8; 64] = [255u8; 64];
+    fn setting_keys_range(
+        namespace: &str,
+        user_owned: bool,
+        subject: Option<Principal>,
+    ) -> (SettingPathKey, SettingPathKey) {
+        if let Some(subject) = subject {
+            (
+                SettingPathKey(
+                    namespace.to_owned(),
+                    if user_owned { 1 } else { 0 },
+                    subject,
+                    ByteBuf::new(),
+                    0,
+                ),
+                SettingPathKey(
+                    namespace.to_owned(),
+                    if user_owned { 1 } else { 0 },
+                    subject,
+                    ByteBuf::from(MAX_KEY.as_ref()),
+                    0,
+                ),
+            )
+        } else {
+            (
+                SettingPathKey(
+                    namespace.to_owned(),
+                    if user_owned { 1 } else { 0 },
+                    Principal::anonymous(),
+                    ByteBuf::new(),
+                    0,
+                ),
+                SettingPathKey(
+                    namespace.to_owned(),
+                    if user_owned { 2 } else { 1 },
+                    Principal::management_canister(),
+                    ByteBuf::new(),
+                    u32::MAX,
+                ),
+            )
+        }
+    }
+
+    pub fn list_setting_keys(
+        namespace: &str,
+        user_owned: bool,
+        subject: Option<Principal>,
+    ) -> Vec<(Principal, ByteBuf)> {
+        let (start, end) = setting_keys_range(namespace, user_owned, subject);
+        SETTINGS_STORE.with_borrow(|r| {
+            r.keys_range(ops::Range {
+                start: &start,
+                end: &end,
+            })
+            .map(|k| (k.2, k.3))
+            .collect()
         })
     }
 
-    pub fn update_namespace_info(
+    pub fn list_settings(
         caller: &Principal,
-        input: UpdateNamespaceInput,
-        now_ms: u64,
-    ) -> Result<(), String> {
-        with_mut(input.name, |ns| {
-            if !ns.can_write_namespace(caller) {
-                Err("no permission".to_string())?;
+        input: ListSettingsInput,
+    ) -> Result<ListSettingsOutput, String> {
+        let subject = with(&input.ns, |ns| match ns.read_permission(caller) {
+            NamespaceReadPermission::Full => Ok(input.subject),
+            NamespaceReadPermission::User
+                if input.subject.is_none() || input.subject == Some(*caller) =>
+            {
+                Ok(Some(*caller))
             }
+            _ => Err("no permission".to_string()),
+        })?;
 
-            if let Some(desc) = input.desc {
-                ns.desc = desc;
-            }
-            if let Some(max_payload_size) = input.max_payload_size {
-                ns.max_payload_size = max_payload_size;
-            }
-            if let Some(status) = input.status {
-                ns.status = status;
+        let (start, end) = setting_keys_range(&input.ns, input.user_owned, subject);
+        let start = match input.start_after {
+            Some((after_subject, after_key)) => {
+                if subject.is_some_and(|s| s != after_subject) {
+                    Err("invalid start_after".to_string())?;
+                }
+                ops::Bound::Excluded(SettingPathKey(
+                    start.0,
+                    start.1,
+                    after_subject,
+                    after_key,
+                    0,
+                ))
             }
-            if let Some(visibility) = input.visibility {
-                ns.visibility = visibility;
+            None => ops::Bound::Included(start),
+        };
+        let limit = input.limit.unwrap_or(MAX_LIST_LIMIT).min(MAX_LIST_LIMIT) as usize;
+
+        let mut output = ListSettingsOutput::default();
+        SETTINGS_STORE.with_borrow(|r| {
+            let mut iter = r.range((start, ops::Bound::Excluded(end)));
+            for entry in iter.by_ref().take(limit) {
+                let (spk, setting) = entry.into_pair();
+                if input.with_header {
+                    output.headers.push(SettingHeader {
+                        subject: spk.2,
+                        key: spk.3.clone(),
+                        version: setting.version,
+                        status: setting.status,
+                        payload_size: setting.payload_size(),
+                        tags: setting.tags,
+                        created_at: setting.created_at,
+                        updated_at: setting.updated_at,
+                        expires_at: setting.expires_at,
+                    });
+                } else {
+                    output.keys.push((spk.2, spk.3.clone()));
+                }
+                output.next_start_after = Some((spk.2, spk.3));
             }
-            if let Some(session_expires_in_ms) = input.session_expires_in_ms {
-                ns.session_expires_in_ms = session_expires_in_ms;
+            if iter.next().is_none() {
+                output.next_start_after = None;
             }
-            ns.updated_at = now_ms;
-            Ok(())
+        });
+        Ok(output)
+    }
+
+    pub fn with<R>(
+        namespace: &String,
+        f: impl FnOnce(Namespace) -> Result<R, String>,
+    ) -> Result<R, String> {
+        NAMESPACES_STORE.with_borrow(|r| {
+            r.get(namespace)
+                .map(f)
+                .unwrap_or_else(|| Err(format!("NotFound: namespace {} not found", namespace)))
         })
     }
 
-    pub fn delete_namespace(caller: &Principal, namespace: String) -> Result<(), String> {
+    pub fn with_mut<R>(
+        namespace: String,
+        f: impl FnOnce(&mut Namespace) -> Result<R, String>,
+    ) -> Result<R, String> {
         NAMESPACES_STORE.with_borrow_mut(|r| match r.get(&namespace) {
-            Some(ns) => {
-                if !ns.can_write_namespace(caller) {
-                    Err("no permission".to_string())?;
+            Some(mut ns) => match f(&mut ns) {
+                Ok(rt) => {
+                    r.insert(namespace, ns);
+                    Ok(rt)
                 }
-                SETTINGS_STORE.with_borrow(|rr| {
-                    let mut iter = rr.keys_range(ops::RangeFrom {
-                        start: &SettingPathKey(
-                            namespace.clone(),
-                            0,
-                            Principal::anonymous(),
-                            ByteBuf::new(),
-                            0,
-                        ),
-                    });
-                    if iter.next().is_some() {
-                        return Err(format!("namespace {} is not empty", namespace));
-                    }
-                    Ok(())
-                })?;
-                r.remove(&namespace);
-                Ok(())
-            }
+                Err(err) => Err(err),
+            },
             None => Err(format!("NotFound: namespace {} not found", namespace)),
         })
     }
 
-    pub fn try_get_setting(caller: &Principal, spk: &SettingPathKey) -> Option<Setting> {
-        with(&spk.0, |ns| {
-            let can = ns.partial_can_read_setting(caller, spk);
-            if can == Some(false) {
-                return Ok(None);
+    fn debit_gas(ns: &mut Namespace, api: &str) -> Result<(), String> {
+        let cost = gas_cost(api);
+        let policy = state::with(|s| s.gas_policy.clone());
+        if policy.enforced && !ns.gas_exempt {
+            if ns.gas_balance >= cost {
+                ns.gas_balance -= cost;
+            } else if ns.gas_credit.saturating_add(cost) <= policy.credit_limit {
+                ns.gas_credit += cost;
+            } else {
+                Err(format!(
+                    "insufficient gas balance, {} costs {} cycles",
+                    api, cost
+                ))?;
             }
-
-            let setting = SETTINGS_STORE.with_borrow(|m| m.get(&spk.v0()));
-            Ok(setting.filter(|s| {
-                spk.4 <= s.version && (can == Some(true) || s.readers.contains(caller))
-            }))
-        })
-        .unwrap_or(None)
+        }
+        ns.gas_used = ns.gas_used.saturating_add(cost);
+        *ns.usage.entry(api.to_string()).or_default() += 1;
+        Ok(())
     }
 
-    pub fn get_setting_info(caller: Principal, spk: SettingPathKey) -> Result<SettingInfo, String> {
-        let setting = try_get_setting(&caller, &spk)
-            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", spk))?;
+    /// Takes a token from the caller's bucket if the namespace has a rate limit.
+    /// It should be called after the caller's permission is checked.
+    pub fn check_rate_limit(
+        namespace: &str,
+        caller: &Principal,
+        api: &str,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let limit = match with(&namespace.to_string(), |ns| Ok(ns.rate_limit))? {
+            Some(limit) if limit.capacity > 0 => limit,
+            _ => return Ok(()),
+        };
 
-        Ok(setting.into_info(spk.2, spk.3, false))
+        RATE_LIMITS.with_borrow_mut(|r| {
+            r.entry((namespace.to_string(), *caller))
+                .or_insert_with(|| TokenBucket::new(&limit, now_ms))
+                .take(&limit, now_ms)
+                .map_err(|wait_ms| {
+                    format!(
+                        "rate limit exceeded for {}, retry after {} ms at {}",
+                        api,
+                        wait_ms,
+                        now_ms + wait_ms
+                    )
+                })
+        })
     }
 
-    pub fn get_setting(caller: Principal, spk: SettingPathKey) -> Result<SettingInfo, String> {
-        let setting = try_get_setting(&caller, &spk)
-            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", &spk))?;
-
-        if spk.4 != 0 && spk.4 != setting.version {
-            Err("version mismatch".to_string())?;
-        };
+    /// Removes buckets that are full by now, as well as those of namespaces without a rate limit.
+    /// It returns the number of remaining buckets.
+    pub fn decay_rate_limits(now_ms: u64) -> usize {
+        let mut limits: BTreeMap<String, Option<RateLimit>> = BTreeMap::new();
+        RATE_LIMITS.with_borrow_mut(|r| {
+            r.retain(|(namespace, _), bucket| {
+                let limit = limits.entry(namespace.clone()).or_insert_with(|| {
+                    NAMESPACES_STORE
+                        .with_borrow(|ns| ns.get(namespace))
+                        .and_then(|ns| ns.rate_limit)
+                        .filter(|limit| limit.capacity > 0)
+                });
+                match limit {
+                    Some(limit) => {
+                        bucket.refill(limit, now_ms);
+                        bucket.tokens < limit.capacity
+                    }
+                    None => false,
+                }
+            });
+            r.len()
+        })
+    }
 
-        Ok(setting.into_info(spk.2, spk.3, true))
+    /// Debits the gas cost of the API call from the namespace, and counts the call.
+    /// It should be called after the caller's permission is checked.
+    pub fn charge_gas(namespace: &str, api: &str) -> Result<(), String> {
+        with_mut(namespace.to_string(), |ns| debit_gas(ns, api))
     }
 
-    pub fn get_setting_archived_payload(
-        caller: Principal,
-        spk: SettingPathKey,
-    ) -> Result<SettingArchivedPayload, String> {
-        let setting = try_get_setting(&caller, &spk)
-            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", &spk))?;
-
-        if spk.4 == 0 || spk.4 >= setting.version {
-            Err("version mismatch".to_string())?;
-        };
-
-        let payload = PAYLOADS_STORE.with_borrow(|r| {
-            r.get(&spk)
-                .ok_or_else(|| format!("NotFound: setting {} payload not found", &spk))
-        })?;
-
-        Ok(SettingArchivedPayload {
-            version: spk.4,
-            archived_at: payload.archived_at,
-            deprecated: payload.deprecated,
-            payload: payload.payload,
-            dek: payload.dek,
+    /// Credits the received cycles to the namespace, the gas used on credit is repaid first.
+    /// It returns the gas balance.
+    pub fn top_up(namespace: String, received: u128, now_ms: u64) -> Result<u128, String> {
+        with_mut(namespace, |ns| {
+            let repaid = received.min(ns.gas_credit);
+            ns.gas_credit -= repaid;
+            ns.gas_balance = ns.gas_balance.saturating_add(received - repaid);
+            ns.updated_at = now_ms;
+            Ok(ns.gas_balance)
         })
     }
 
-    pub fn create_setting(
-        caller: Principal,
-        spk: SettingPathKey,
-        input: CreateSettingInput,
+    /// Runs a metered operation on the namespace.
+    /// It is rejected if the gas balance and the remaining credit are lower than the API cost,
+    /// and the cost is debited only when the operation succeeds.
+    pub fn with_gas<R>(
+        namespace: &str,
+        api: &str,
+        f: impl FnOnce() -> Result<R, String>,
+    ) -> Result<R, String> {
+        with(&namespace.to_string(), |mut ns| debit_gas(&mut ns, api))?;
+        let rt = f()?;
+        charge_gas(namespace, api)?;
+        Ok(rt)
+    }
+
+    /// Checks the permission of the key derivation action, "derive_kek" or "vetkd", on the setting.
+    pub fn has_kek_permission(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        action: &str,
         now_ms: u64,
-    ) -> Result<CreateSettingOutput, String> {
-        with_mut(spk.0.clone(), |ns| {
-            if !ns.can_write_setting(&caller, &spk) {
-                Err("no permission".to_string())?;
+    ) -> bool {
+        with(&spk.0, |ns| {
+            if ns.status < 0 && !ns.is_manager(caller) {
+                return Ok(false);
             }
 
-            if spk.4 != 0 {
-                Err("version mismatch".to_string())?;
+            if let Some((_, allow)) = ns.policy_decision(caller, action, &[&spk.3]) {
+                return Ok(allow);
             }
 
-            if let Some(ref payload) = input.payload {
-                if payload.len() as u64 > ns.max_payload_size {
-                    Err("payload size exceeds the limit".to_string())?;
-                }
+            if caller == &spk.2 || ns.is_auditor(caller) || (spk.1 == 0 && ns.is_manager(caller)) {
+                return Ok(true);
             }
 
-            let size = match input.dek {
-                Some(ref dek) => {
-                    // should be valid COSE encrypt0 dek
-                    try_decode_encrypt0(dek)?;
-                    // should be valid COSE encrypt0 payload
-                    if let Some(ref payload) = input.payload {
-                        if payload.len() as u64 > ns.max_payload_size {
-                            Err("payload size exceeds the limit".to_string())?;
-                        }
-                        try_decode_encrypt0(payload)?;
-                        payload.len() + dek.len()
-                    } else {
-                        dek.len()
-                    }
-                }
-                None => input
-                    .payload
-                    .as_ref()
-                    .map(|payload| payload.len())
-                    .unwrap_or(0),
-            };
-
-            let output = SETTINGS_STORE.with_borrow_mut(|m| {
-                if m.contains_key(&spk) {
-                    return Err(format!("setting {} already exists", &spk));
-                }
+            let setting = SETTINGS_STORE.with_borrow(|m| m.get(&spk.v0()));
+            Ok(setting.is_some_and(|s| ns.is_setting_reader(&s, caller, now_ms)))
+        })
+        .unwrap_or(false)
+    }
 
-                m.insert(
-                    spk.clone(),
-                    Setting {
-                        desc: input.desc.unwrap_or_default(),
-                        created_at: now_ms,
-                        updated_at: now_ms,
-                        status: input.status.unwrap_or(0),
-                        tags: input.tags.unwrap_or_default(),
-                        payload: input.payload,
-                        dek: input.dek,
-                        version: 1,
-                        ..Default::default()
-                    },
-                );
+    /// Evaluates the permission of the principal for the action without performing it.
+    /// Checking the permission of another principal requires the manager or auditor role.
+    pub fn check_permission(
+        caller: &Principal,
+        input: CheckPermissionInput,
+        now_ms: u64,
+    ) -> Result<CheckPermissionOutput, String> {
+        let principal = input.principal.unwrap_or(*caller);
+        let resource: Vec<&[u8]> = input.resource.iter().map(|r| r.as_slice()).collect();
+        let (rule, allowed) = with(&input.ns, |ns| {
+            if &principal != caller && !ns.is_manager(caller) && !ns.is_auditor(caller) {
+                Err("no permission".to_string())?;
+            }
 
-                Ok(CreateSettingOutput {
-                    created_at: now_ms,
-                    updated_at: now_ms,
-                    version: 1,
-                })
-            })?;
+            let rule = ns
+                .policy_decision(&principal, &input.action, &resource)
+                .map(|(i, _)| i);
+            let allowed = match input.action.as_str() {
+                "sign" => Some(ns.has_ns_signing_permission(&principal, &input.resource)),
+                "write" => Some(ns.can_write_setting(
+                    &principal,
+                    &SettingPathKey(
+                        input.ns.clone(),
+                        input.user_owned as u8,
+                        input.subject.unwrap_or(principal),
+                        input.resource[0].clone(),
+                        0,
+                    ),
+                )),
+                _ => None,
+            };
+            Ok((rule, allowed))
+        })?;
 
-            ns.payload_bytes_total = ns.payload_bytes_total.saturating_add(size as u64);
-            Ok(output)
-        })
+        let allowed = allowed.unwrap_or_else(|| {
+            let spk = SettingPathKey(
+                input.ns,
+                input.user_owned as u8,
+                input.subject.unwrap_or(principal),
+                input.resource[0].clone(),
+                0,
+            );
+            match input.action.as_str() {
+                "read" => try_get_setting(&principal, &spk, now_ms).is_some(),
+                action => has_kek_permission(&principal, &spk, action, now_ms),
+            }
+        });
+        Ok(CheckPermissionOutput { allowed, rule })
     }
 
-    pub fn with_setting_mut<R>(
+    pub fn ecdsa_public_key(
         caller: &Principal,
-        spk: &SettingPathKey,
-        f: impl FnOnce(&mut Setting) -> Result<R, String>,
-    ) -> Result<R, String> {
-        with(&spk.0, |ns| {
-            if !ns.can_write_setting(caller, spk) {
+        namespace: String,
+        derivation_path: Vec<ByteBuf>,
+    ) -> Result<PublicKeyOutput, String> {
+        with(&namespace, |ns| {
+            if !ns.can_read_namespace(caller) {
                 Err("no permission".to_string())?;
             }
 
-            let spkv0 = spk.v0();
-            SETTINGS_STORE.with_borrow_mut(|r| match r.get(&spkv0) {
-                Some(mut setting) => {
-                    if setting.version != spk.4 {
-                        Err("version mismatch".to_string())?;
-                    }
-                    if setting.status >= 1 {
-                        Err("readonly setting can not be updated".to_string())?;
-                    }
-
-                    match f(&mut setting) {
-                        Ok(rt) => {
-                            r.insert(spkv0.clone(), setting);
-                            Ok(rt)
-                        }
-                        Err(err) => Err(err),
-                    }
-                }
-                None => Err(format!("NotFound: setting {} not found", &spk)),
+            state::with(|s| {
+                let pk = s.ecdsa_public_key.as_ref().ok_or("no ecdsa public key")?;
+                let mut path: Vec<Vec<u8>> = Vec::with_capacity(derivation_path.len() + 3);
+                path.push(b"COSE_ECDSA_Signing".to_vec());
+                path.push(ns.derivation_name(&namespace).as_bytes().to_vec());
+                path.extend(derivation_path.into_iter().map(|b| b.into_vec()));
+                derive_public_key(pk, path)
             })
         })
     }
 
-    pub fn delete_setting(caller: &Principal, spk: &SettingPathKey) -> Result<(), String> {
-        with(&spk.0, |ns| {
-            if !ns.can_write_setting(caller, spk) {
+    pub async fn ecdsa_sign_with(
+        caller: &Principal,
+        namespace: String,
+        derivation_path: Vec<ByteBuf>,
+        message: ByteBuf,
+        now_ms: u64,
+    ) -> Result<ByteBuf, String> {
+        let (eip191, name) = with(&namespace, |ns| {
+            if !ns.has_ns_signing_permission(caller, &derivation_path) {
                 Err("no permission".to_string())?;
             }
+            let eip191 = ns.check_signing_message(&message)?;
+            Ok((eip191, ns.derivation_name(&namespace).to_string()))
+        })?;
+        check_rate_limit(&namespace, caller, "ecdsa_sign", now_ms)?;
+        charge_gas(&namespace, "ecdsa_sign")?;
 
-            let spkv0 = spk.v0();
-            SETTINGS_STORE.with_borrow_mut(|r| match r.get(&spkv0) {
-                Some(setting) => {
-                    if setting.version != spk.4 {
-                        Err("version mismatch".to_string())?;
-                    }
-                    if setting.status >= 1 {
-                        Err("readonly setting can not be deleted".to_string())?;
-                    }
+        let key_name = state::with(|s| s.ecdsa_key_name.clone());
+        let mut path: Vec<Vec<u8>> = Vec::with_capacity(derivation_path.len() + 3);
+        path.push(b"COSE_ECDSA_Signing".to_vec());
+        path.push(name.into_bytes());
+        path.extend(derivation_path.into_iter().map(|b| b.into_vec()));
+        let message_hash = if eip191 {
+            keccak256(&message).to_vec()
+        } else {
+            message.into_vec()
+        };
+        let sig = sign_with_ecdsa(key_name, path, message_hash).await?;
+        Ok(ByteBuf::from(sig))
+    }
 
-                    r.remove(&spkv0);
-                    if spk.4 > 1 {
-                        PAYLOADS_STORE.with_borrow_mut(|rr| {
-                            let mut pk = spk.clone();
-                            for v in 1..spk.4 {
-                                pk.4 = v;
-                                rr.remove(&pk);
-                            }
-                        });
-                    }
+    pub fn schnorr_public_key(
+        caller: &Principal,
+        alg: SchnorrAlgorithm,
+        namespace: String,
+        derivation_path: Vec<ByteBuf>,
+    ) -> Result<PublicKeyOutput, String> {
+        with(&namespace, |ns| {
+            if !ns.can_read_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
 
-                    Ok(())
-                }
-                None => Err(format!("NotFound: setting {} not found", &spk)),
+            state::with(|s| {
+                let pk = match alg {
+                    SchnorrAlgorithm::Bip340secp256k1 => s
+                        .schnorr_secp256k1_public_key
+                        .as_ref()
+                        .ok_or("no schnorr secp256k1 public key")?,
+                    SchnorrAlgorithm::Ed25519 => s
+                        .schnorr_ed25519_public_key
+                        .as_ref()
+                        .ok_or("no schnorr ed25519 public key")?,
+                };
+                let mut path: Vec<Vec<u8>> = Vec::with_capacity(derivation_path.len() + 3);
+                path.push(b"COSE_Schnorr_Signing".to_vec());
+                path.push(ns.derivation_name(&namespace).as_bytes().to_vec());
+                path.extend(derivation_path.into_iter().map(|b| b.into_vec()));
+                derive_schnorr_public_key(alg, pk, path)
             })
         })
     }
 
-    pub fn update_setting_payload(
-        caller: Principal,
-        spk: SettingPathKey,
-        input: UpdateSettingPayloadInput,
+    pub async fn schnorr_sign_with(
+        caller: &Principal,
+        alg: SchnorrAlgorithm,
+        namespace: String,
+        derivation_path: Vec<ByteBuf>,
+        message: ByteBuf,
         now_ms: u64,
-    ) -> Result<UpdateSettingOutput, String> {
-        with_mut(spk.0.clone(), |ns| {
-            if !ns.can_write_setting(&caller, &spk) {
+    ) -> Result<ByteBuf, String> {
+        let name = with(&namespace, |ns| {
+            if !ns.has_ns_signing_permission(caller, &derivation_path) {
                 Err("no permission".to_string())?;
             }
+            ns.check_signing_message(&message)?;
+            Ok(ns.derivation_name(&namespace).to_string())
+        })?;
+        check_rate_limit(&namespace, caller, "schnorr_sign", now_ms)?;
+        charge_gas(&namespace, "schnorr_sign")?;
+
+        let key_name = state::with(|s| s.schnorr_key_name.clone());
+        let mut path: Vec<Vec<u8>> = Vec::with_capacity(derivation_path.len() + 3);
+        path.push(b"COSE_Schnorr_Signing".to_vec());
+        path.push(name.into_bytes());
+        path.extend(derivation_path.into_iter().map(|b| b.into_vec()));
+        let sig = sign_with_schnorr(key_name, alg, path, message.into_vec()).await?;
+        Ok(ByteBuf::from(sig))
+    }
 
-            let mut size = if let Some(ref payload) = input.payload {
-                payload.len()
+    const CWT_EXPIRATION_SECONDS: i64 = 3600;
+    pub async fn sign_identity(
+        caller: &Principal,
+        namespace: String,
+        audience: String,
+        now_ms: u64,
+        algorithm: SchnorrAlgorithm,
+    ) -> Result<ByteBuf, String> {
+        let permission = with(&namespace, |ns| {
+            if ns.is_manager(caller) {
+                Ok(format!("Namespace.*:{}", namespace))
+            } else if ns.is_user(caller) {
+                if ns.is_auditor(caller) {
+                    Ok(format!(
+                        "Namespace.Read:{} Namespace.*.SubjectedSetting:{}",
+                        namespace, namespace
+                    ))
+                } else {
+                    Ok(format!(
+                        "Namespace.Read.Info:{} Namespace.*.SubjectedSetting:{}",
+                        namespace, namespace
+                    ))
+                }
+            } else if ns.is_auditor(caller) {
+                Ok(format!("Namespace.Read:{}", namespace))
             } else {
-                0
-            };
-            if size as u64 > ns.max_payload_size {
-                Err("payload size exceeds the limit".to_string())?;
-            }
-            if let Some(ref dek) = input.dek {
-                size += dek.len();
+                Err("no permission".to_string())
             }
+        })?;
+        check_rate_limit(&namespace, caller, "schnorr_sign_identity", now_ms)?;
+        charge_gas(&namespace, "schnorr_sign_identity")?;
 
-            let spkv0 = spk.v0();
-            let output = SETTINGS_STORE.with_borrow_mut(|r| match r.get(&spkv0) {
-                Some(mut setting) => {
-                    if setting.version != spk.4 {
-                        Err("version mismatch".to_string())?;
-                    }
-                    if setting.status >= 1 {
-                        Err("readonly setting can not be updated".to_string())?;
-                    }
-
-                    if setting.dek.is_some() || input.dek.is_some() {
-                        if let Some(ref payload) = input.payload {
-                            // should be valid COSE encrypt0 payload
-                            try_decode_encrypt0(payload)?;
-                        }
-                    }
-
-                    if let Some(payload) = setting.payload.as_ref() {
-                        PAYLOADS_STORE.with(|r| {
-                            r.borrow_mut().insert(
-                                spk.clone(),
-                                SettingArchived {
-                                    archived_at: now_ms,
-                                    deprecated: input.deprecate_current.unwrap_or(false),
-                                    payload: Some(payload.clone()),
-                                    dek: setting.dek.clone(),
-                                },
-                            );
-                        });
-                    }
+        let key_name = state::with(|s| s.schnorr_key_name.clone());
+        let now_sec = (now_ms / 1000) as i64;
+        let cwt_id: [u8; 16] = rand_bytes().await?;
+        let claims = ClaimsSet {
+            issuer: Some(ic_cdk::api::canister_self().to_text()),
+            subject: Some(caller.to_text()),
+            audience: Some(audience),
+            expiration: Some((now_sec + CWT_EXPIRATION_SECONDS) as u64),
+            not_before: Some(now_sec as u64),
+            issued_at: Some(now_sec as u64),
+            cwt_id: Some(cwt_id.into()),
+            extra: scope_claim(permission),
+        };
+        let payload = claims.to_vec().map_err(format_error)?;
+        let alg = match algorithm {
+            SchnorrAlgorithm::Ed25519 => EdDSA,
+            SchnorrAlgorithm::Bip340secp256k1 => ES256K,
+        };
+        let mut sign1 = cose_sign1(payload, alg, None)?;
+        let mut tbs_data = sign1
+            .prepare_signature(None, None, Some(caller.as_slice()))
+            .map_err(format_error)?;
+        if algorithm == SchnorrAlgorithm::Bip340secp256k1 {
+            tbs_data = sha256(&tbs_data).into();
+        }
+        let sig = sign_with_schnorr(key_name, algorithm, vec![], tbs_data).await?;
+        sign1.set_signature(sig).map_err(format_error)?;
+        let token = sign1.to_vec().map_err(format_error)?;
+        Ok(ByteBuf::from(token))
+    }
 
-                    setting.version = setting.version.saturating_add(1);
-                    setting.updated_at = now_ms;
-                    if let Some(status) = input.status {
-                        setting.status = status;
-                    }
-                    if let Some(payload) = input.payload {
-                        setting.payload = Some(payload);
-                    }
-                    if let Some(dek) = input.dek {
-                        setting.dek = Some(dek);
-                    }
+    /// Returns the name of the namespace used in key derivation paths,
+    /// so that keys derived before a rename keep working.
+    pub fn derivation_name(namespace: &String) -> String {
+        NAMESPACES_STORE.with_borrow(|r| {
+            r.get(namespace)
+                .and_then(|ns| ns.alias)
+                .unwrap_or_else(|| namespace.clone())
+        })
+    }
 
-                    r.insert(spkv0, setting.clone());
-                    Ok(UpdateSettingOutput {
-                        created_at: setting.created_at,
-                        updated_at: setting.updated_at,
-                        version: setting.version,
-                    })
-                }
-                None => Err(format!("NotFound: setting {} not found", &spk)),
-            })?;
+    /// Bumps the KEK version of the namespace. KEKs of old versions can still be derived,
+    /// so that DEKs can be unwrapped with the old KEK and re-wrapped with the new one.
+    pub fn rotate_kek(caller: &Principal, namespace: String, now_ms: u64) -> Result<u32, String> {
+        with_mut(namespace, |ns| {
+            if !ns.can_write_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
+            ns.kek_version = ns
+                .kek_version
+                .checked_add(1)
+                .ok_or("kek version overflow")?;
+            ns.updated_at = now_ms;
+            Ok(ns.kek_version)
+        })
+    }
 
-            ns.payload_bytes_total = ns.payload_bytes_total.saturating_add(size as u64);
-            Ok(output)
+    /// Resolves the requested KEK version, the current version if None.
+    /// Versions after the current one are not derivable.
+    pub fn kek_version(namespace: &String, version: Option<u32>) -> Result<u32, String> {
+        with(namespace, |ns| match version {
+            None => Ok(ns.kek_version),
+            Some(v) if v <= ns.kek_version => Ok(v),
+            Some(v) => Err(format!(
+                "kek version {} is not available, the current version is {}",
+                v, ns.kek_version
+            )),
         })
     }
 
-    pub fn update_setting_info(
-        caller: Principal,
-        spk: SettingPathKey,
-        input: UpdateSettingInfoInput,
-        now_ms: u64,
-    ) -> Result<UpdateSettingOutput, String> {
-        with_setting_mut(&caller, &spk, |setting| {
-            if let Some(status) = input.status {
+    pub fn inner_derive_kek(
+        spk: &SettingPathKey,
+        key_id: &[u8],
+        version: u32,
+    ) -> Result<[u8; 32], String> {
+        let name = derivation_name(&spk.0);
+        state::with(|s| {
+            let pk = s
+                .schnorr_secp256k1_public_key
+                .as_ref()
+                .ok_or("no schnorr secp256k1 public key")?;
+
+            let mut derivation_path = vec![
+                b"COSE_Symmetric_Key".to_vec(),
+                s.init_vector.to_vec(),
+                spk.2.to_bytes().to_vec(),
+                vec![spk.1],
+                name.into_bytes(),
+            ];
+            // version 0 keeps the original derivation path
+            if version > 0 {
+                derivation_path.push(version.to_be_bytes().to_vec());
+            }
+            let pk =
+                derive_schnorr_public_key(SchnorrAlgorithm::Bip340secp256k1, pk, derivation_path)?;
+            Ok(mac3_256(&pk.public_key, key_id))
+        })
+    }
+
+    pub async fn inner_vetkd_public_key(spk: &SettingPathKey) -> Result<Vec<u8>, String> {
+        let key_name = state::with(|r| r.vetkd_key_name.clone());
+        let name = derivation_name(&spk.0);
+
+        vetkd_public_key(
+            key_name,
+            &[
+                b"COSE_Symmetric_Key",
+                spk.2.to_bytes().as_ref(),
+                &[spk.1],
+                name.as_bytes(),
+            ],
+        )
+        .await
+    }
+
+    pub async fn inner_vetkd_encrypted_key(
+        spk: &SettingPathKey,
+        key_id: Vec<u8>,
+        transport_public_key: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        let key_name = state::with(|r| r.vetkd_key_name.clone());
+        let name = derivation_name(&spk.0);
+
+        vetkd_encrypted_key(
+            key_name,
+            &[
+                b"COSE_Symmetric_Key",
+                spk.2.to_bytes().as_ref(),
+                &[spk.1],
+                name.as_bytes(),
+            ],
+            key_id,
+            transport_public_key,
+        )
+        .await
+    }
+
+    /// Returns the namespace-level vetKD public key for identity-based encryption,
+    /// the IBE identity of a recipient is its principal.
+    pub fn ibe_public_key(namespace: &String) -> Result<Vec<u8>, String> {
+        with(namespace, |_| Ok(()))?;
+        let pk = state::with(|s| s.vetkd_public_key.clone()).ok_or("no vetKD public key")?;
+        let name = derivation_name(namespace);
+        derive_vetkd_public_key(&pk, &[IBE_DERIVATION, name.as_bytes()])
+    }
+
+    /// Derives the encrypted vetKey of the recipient for IBE in the namespace, see [`ibe_public_key`].
+    pub async fn inner_vetkd_ibe_encrypted_key(
+        namespace: &String,
+        recipient: &Principal,
+        transport_public_key: Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        let key_name = state::with(|r| r.vetkd_key_name.clone());
+        let name = derivation_name(namespace);
+
+        vetkd_encrypted_key(
+            key_name,
+            &[IBE_DERIVATION, name.as_bytes()],
+            recipient.as_slice().to_vec(),
+            transport_public_key,
+        )
+        .await
+    }
+
+    pub fn get_namespace(caller: &Principal, namespace: String) -> Result<NamespaceInfo, String> {
+        with(&namespace, |ns| {
+            if !ns.can_read_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
+            Ok(ns.into_info(namespace.clone()))
+        })
+    }
+
+    pub fn list_namespaces(prev: Option<String>, take: usize) -> Vec<NamespaceInfo> {
+        NAMESPACES_STORE.with_borrow(|r| {
+            let mut res = Vec::with_capacity(take);
+            match prev {
+                Some(p) => {
+                    for e in r.range(ops::RangeTo { end: p }).rev() {
+                        res.push(e.value().into_info(e.key().clone()));
+                        if res.len() >= take {
+                            break;
+                        }
+                    }
+                }
+                None => {
+                    for e in r.iter().rev() {
+                        res.push(e.value().into_info(e.key().clone()));
+                        if res.len() >= take {
+                            break;
+                        }
+                    }
+                }
+            };
+            res
+        })
+    }
+
+    pub async fn create_namespace(
+        caller: &Principal,
+        input: CreateNamespaceInput,
+        now_ms: u64,
+    ) -> Result<NamespaceInfo, String> {
+        if !state::with(|s| s.managers.contains(caller)) {
+            Err("no permission".to_string())?;
+        }
+
+        check_available_name(&input.name)?;
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            let ns = Namespace {
+                desc: input.desc.unwrap_or_default(),
+                created_at: now_ms,
+                updated_at: now_ms,
+                max_payload_size: input.max_payload_size.unwrap_or(MAX_PAYLOAD_SIZE),
+                visibility: input.visibility,
+                managers: input.managers,
+                auditors: input.auditors,
+                users: input.users,
+                session_expires_in_ms: input.session_expires_in_ms.unwrap_or(SESSION_EXPIRES_IN_MS),
+                max_bytes_total: input.max_bytes_total.unwrap_or(0),
+                max_settings: input.max_settings.unwrap_or(0),
+                ..Default::default()
+            };
+
+            let info = ns.clone().into_info(input.name.clone());
+            r.insert(input.name, ns);
+            Ok(info)
+        })
+    }
+
+    /// Checks that the name is not taken by a namespace or reserved as an alias of a renamed one.
+    fn check_available_name(name: &String) -> Result<(), String> {
+        if NAMESPACES_STORE.with_borrow(|r| r.contains_key(name)) {
+            Err(format!("namespace {} already exists", name))?;
+        }
+        if let Some(current) = state::with(|s| s.namespace_aliases.get(name).cloned()) {
+            Err(format!(
+                "namespace {} is reserved as an alias of {}",
+                name, current
+            ))?;
+        }
+        Ok(())
+    }
+
+    pub fn update_namespace_info(
+        caller: &Principal,
+        input: UpdateNamespaceInput,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        with_mut(input.name.clone(), |ns| {
+            if !ns.can_write_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
+            if ns.requires_approval() && (input.status.is_some() || input.visibility.is_some()) {
+                Err(APPROVAL_REQUIRED.to_string())?;
+            }
+
+            ns.apply_info_update(input, now_ms);
+            Ok(())
+        })
+    }
+
+    pub fn delete_namespace(caller: &Principal, namespace: String) -> Result<(), String> {
+        with(&namespace, |ns| {
+            if !ns.can_write_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
+            if ns.requires_approval() {
+                Err(APPROVAL_REQUIRED.to_string())?;
+            }
+            Ok(())
+        })?;
+        remove_empty_namespace(namespace)
+    }
+
+    fn remove_empty_namespace(namespace: String) -> Result<(), String> {
+        NAMESPACES_STORE.with_borrow_mut(|r| match r.get(&namespace) {
+            Some(_) => {
+                SETTINGS_STORE.with_borrow(|rr| {
+                    let mut iter = rr.keys_range(ops::RangeFrom {
+                        start: &SettingPathKey(
+                            namespace.clone(),
+                            0,
+                            Principal::anonymous(),
+                            ByteBuf::new(),
+                            0,
+                        ),
+                    });
+                    if iter.next().is_some_and(|spk| spk.0 == namespace) {
+                        return Err(format!("namespace {} is not empty", namespace));
+                    }
+                    Ok(())
+                })?;
+                r.remove(&namespace);
+                state::with_mut(|s| {
+                    s.namespace_aliases
+                        .retain(|_, current| current != &namespace)
+                });
+                // a namespace created later with the same name starts a new change log
+                CHANGES_STORE.with_borrow_mut(|r| {
+                    let keys: Vec<Vec<u8>> = r
+                        .keys_range(
+                            change_log_key(&namespace, 0)..=change_log_key(&namespace, u64::MAX),
+                        )
+                        .collect();
+                    for key in keys {
+                        r.remove(&key);
+                    }
+                });
+                Ok(())
+            }
+            None => Err(format!("NotFound: namespace {} not found", namespace)),
+        })
+    }
+
+    /// Applies a sensitive change without a proposal,
+    /// it is rejected if the namespace requires approval.
+    pub fn apply_change(
+        caller: &Principal,
+        namespace: String,
+        change: NamespaceChange,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        with_mut(namespace, |ns| {
+            if !ns.can_write_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
+            if ns.requires_approval() {
+                Err(APPROVAL_REQUIRED.to_string())?;
+            }
+            ns.apply_change(change, now_ms)
+        })
+    }
+
+    pub const APPROVAL_REQUIRED: &str =
+        "approval required, propose the change with namespace_propose_change";
+    const MAX_PENDING_PROPOSALS: usize = 16;
+
+    /// Enables the approval policy of the namespace. Once enabled, it can only be
+    /// changed by an approved `UpdateApprovalPolicy` proposal.
+    pub fn enable_approval_policy(
+        caller: &Principal,
+        namespace: String,
+        policy: ApprovalPolicy,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        with_mut(namespace, |ns| {
+            if !ns.can_write_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
+            if ns.approval_policy.is_some() {
+                Err(APPROVAL_REQUIRED.to_string())?;
+            }
+            ns.approval_policy = Some(policy);
+            ns.check_approval_threshold()?;
+            ns.updated_at = now_ms;
+            Ok(())
+        })
+    }
+
+    /// Proposes a sensitive change, the proposer's approval is counted.
+    /// The change executes at once if the approvals reach the threshold.
+    pub fn propose_change(
+        caller: &Principal,
+        namespace: String,
+        change: NamespaceChange,
+        description: String,
+        now_ms: u64,
+    ) -> Result<ProposalInfo, String> {
+        let (id, proposal) = with_mut(namespace.clone(), |ns| {
+            if !ns.can_write_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
+            let policy = ns
+                .approval_policy
+                .clone()
+                .ok_or_else(|| format!("approval is not enabled for namespace {}", namespace))?;
+            ns.proposals.retain(|_, p| p.expires_at > now_ms);
+            if ns.proposals.len() >= MAX_PENDING_PROPOSALS {
+                Err(format!(
+                    "pending proposals should be at most {}",
+                    MAX_PENDING_PROPOSALS
+                ))?;
+            }
+
+            ns.proposal_id += 1;
+            let proposal = Proposal {
+                change,
+                description,
+                proposer: *caller,
+                approvers: BTreeSet::from([*caller]),
+                created_at: now_ms,
+                expires_at: now_ms.saturating_add(policy.expires_in_ms),
+            };
+            ns.proposals.insert(ns.proposal_id, proposal.clone());
+            Ok((ns.proposal_id, proposal))
+        })?;
+        try_execute_proposal(namespace, id, proposal, now_ms)
+    }
+
+    /// Approves a pending proposal. An approver can approve again to retry a failed execution.
+    pub fn approve_change(
+        caller: &Principal,
+        namespace: String,
+        id: u64,
+        now_ms: u64,
+    ) -> Result<ProposalInfo, String> {
+        let proposal = with_mut(namespace.clone(), |ns| {
+            if !ns.can_write_namespace(caller) {
+                Err("no permission".to_string())?;
+            }
+            let proposal = ns
+                .proposals
+                .get_mut(&id)
+                .ok_or_else(|| format!("NotFound: proposal {} not found", id))?;
+            if proposal.expires_at <= now_ms {
+                Err(format!("proposal {} expired", id))?;
+            }
+            proposal.approvers.insert(*caller);
+            Ok(proposal.clone())
+        })?;
+        try_execute_proposal(namespace, id, proposal, now_ms)
+    }
+
+    fn try_execute_proposal(
+        namespace: String,
+        id: u64,
+        proposal: Proposal,
+        now_ms: u64,
+    ) -> Result<ProposalInfo, String> {
+        let approved = with(&namespace, |ns| {
+            let threshold = ns.approval_policy.as_ref().map_or(1, |p| p.threshold) as usize;
+            // approvals of principals that are no longer managers do not count
+            let approvals = proposal
+                .approvers
+                .iter()
+                .filter(|p| ns.is_manager(p))
+                .count();
+            Ok(approvals >= threshold)
+        })?;
+        if !approved {
+            return Ok(proposal.into_info(id, false));
+        }
+
+        match proposal.change {
+            NamespaceChange::Delete => remove_empty_namespace(namespace)?,
+            ref change => with_mut(namespace, |ns| {
+                ns.apply_change(change.clone(), now_ms)?;
+                ns.proposals.remove(&id);
+                Ok(())
+            })?,
+        }
+        Ok(proposal.into_info(id, true))
+    }
+
+    pub fn list_proposals(
+        caller: &Principal,
+        namespace: String,
+        now_ms: u64,
+    ) -> Result<Vec<ProposalInfo>, String> {
+        with(&namespace, |ns| {
+            if !ns.is_manager(caller) && !ns.is_auditor(caller) {
+                Err("no permission".to_string())?;
+            }
+            Ok(ns
+                .proposals
+                .into_iter()
+                .filter(|(_, p)| p.expires_at > now_ms)
+                .map(|(id, p)| p.into_info(id, false))
+                .collect())
+        })
+    }
+
+    const EXPORT_DERIVATION_PATH: &[u8] = b"COSE_Namespace_Export";
+
+    /// Exports a page of the namespace from the cursor, the first page if None.
+    /// It returns the CBOR encoded [`NamespaceExport`] and the cursor of the next page.
+    pub fn export_namespace(
+        caller: &Principal,
+        canister: Principal,
+        namespace: String,
+        cursor: Option<ExportCursor>,
+        now_ms: u64,
+    ) -> Result<(Vec<u8>, Option<ExportCursor>), String> {
+        with(&namespace, |ns| {
+            if !ns.can_write_namespace(caller) && !state::with(|s| s.managers.contains(caller)) {
+                Err("no permission".to_string())?;
+            }
+
+            let end = SettingPathKey(
+                namespace.clone(),
+                u8::MAX,
+                Principal::management_canister(),
+                ByteBuf::new(),
+                0,
+            );
+            let first = cursor.is_none();
+            let cursor = match cursor {
+                Some(cursor) if cursor.spk.0 == namespace && cursor.spk < end => cursor,
+                Some(_) => Err("invalid cursor".to_string())?,
+                None => ExportCursor {
+                    export_id: export_id(&canister, &namespace, now_ms),
+                    exported_at: now_ms,
+                    page: 0,
+                    spk: SettingPathKey(
+                        namespace.clone(),
+                        0,
+                        Principal::management_canister(),
+                        ByteBuf::new(),
+                        0,
+                    ),
+                    version: 0,
+                    offset: 0,
+                },
+            };
+            let start = cursor.spk.clone();
+
+            let mut size = 0u64;
+            let mut settings: Vec<ExportedSetting> = Vec::new();
+            let mut chunks: Vec<ExportedChunk> = Vec::new();
+            // the next setting, payload version and offset
+            let mut next_cursor: Option<(SettingPathKey, u32, u64)> = None;
+            SETTINGS_STORE.with_borrow(|r| {
+                if cursor.version > 0 && !r.contains_key(&cursor.spk) {
+                    Err(format!(
+                        "setting {} changed during the export, export again",
+                        cursor.spk
+                    ))?;
+                }
+
+                // a page with chunks ends with the chunked setting
+                let mut full = false;
+                for entry in r.range(start..end) {
+                    let spk = entry.key().clone();
+                    if full {
+                        next_cursor = Some((spk, 0, 0));
+                        break;
+                    }
+
+                    let setting = entry.value();
+                    if cursor.version > 0 && spk == cursor.spk {
+                        let from = (cursor.version, cursor.offset);
+                        match export_chunks(&spk, &setting, from, &mut size, &mut chunks) {
+                            Some((version, offset)) => {
+                                next_cursor = Some((spk, version, offset));
+                                break;
+                            }
+                            None => {
+                                full = true;
+                                continue;
+                            }
+                        }
+                    }
+
+                    let archived: Vec<(u32, SettingArchived)> = if setting.version > 1 {
+                        PAYLOADS_STORE.with_borrow(|rp| {
+                            rp.range(archived_range(&spk, 1, setting.version))
+                                .map(|e| (e.key().4, e.value()))
+                                .collect()
+                        })
+                    } else {
+                        Vec::new()
+                    };
+                    let item_size =
+                        setting.size() + archived.iter().map(|(_, a)| a.size()).sum::<u64>();
+                    let stored_in_chunks = setting.payload_chunks.is_some()
+                        || archived.iter().any(|(_, a)| a.payload_chunks.is_some());
+                    if item_size > MAX_EXPORT_PAGE_SIZE || stored_in_chunks {
+                        // exports the setting without payloads in a new page,
+                        // followed by the chunks of the payloads
+                        if !settings.is_empty() {
+                            next_cursor = Some((spk, 0, 0));
+                            break;
+                        }
+
+                        let mut item = ExportedSetting {
+                            key: (spk.1, spk.2, spk.3.clone()),
+                            setting: setting.clone(),
+                            archived,
+                            chunked: BTreeMap::new(),
+                        };
+                        for (version, archived) in item.archived.iter_mut() {
+                            if let Some(chunks) = archived.payload_chunks.take() {
+                                item.chunked.insert(*version, (chunks.size, chunks.sha256));
+                            } else if let Some(payload) =
+                                archived.payload.take_if(|p| !p.is_empty())
+                            {
+                                item.chunked.insert(
+                                    *version,
+                                    (payload.len() as u64, ByteArray::new(sha256(&payload))),
+                                );
+                            }
+                        }
+                        if let Some(chunks) = item.setting.payload_chunks.take() {
+                            item.chunked
+                                .insert(setting.version, (chunks.size, chunks.sha256));
+                        } else if let Some(payload) =
+                            item.setting.payload.take_if(|p| !p.is_empty())
+                        {
+                            item.chunked.insert(
+                                setting.version,
+                                (payload.len() as u64, ByteArray::new(sha256(&payload))),
+                            );
+                        }
+                        size += item.setting.size();
+                        settings.push(item);
+                        match export_chunks(&spk, &setting, (0, 0), &mut size, &mut chunks) {
+                            Some((version, offset)) => {
+                                next_cursor = Some((spk, version, offset));
+                                break;
+                            }
+                            None => {
+                                full = true;
+                                continue;
+                            }
+                        }
+                    }
+                    if size + item_size > MAX_EXPORT_PAGE_SIZE {
+                        next_cursor = Some((spk, 0, 0));
+                        break;
+                    }
+                    size += item_size;
+                    settings.push(ExportedSetting {
+                        key: (spk.1, spk.2, spk.3),
+                        setting,
+                        archived,
+                        chunked: BTreeMap::new(),
+                    });
+                }
+                Ok::<(), String>(())
+            })?;
+
+            let page = NamespaceExport {
+                canister,
+                name: namespace.clone(),
+                exported_at: cursor.exported_at,
+                export_id: cursor.export_id.clone(),
+                page: cursor.page,
+                last: next_cursor.is_none(),
+                namespace: if first { Some(ns) } else { None },
+                settings,
+                chunks,
+            };
+            let mut buf = vec![];
+            to_writer(&page, &mut buf).map_err(format_error)?;
+            Ok((
+                buf,
+                next_cursor.map(|(spk, version, offset)| ExportCursor {
+                    spk,
+                    version,
+                    offset,
+                    page: cursor.page + 1,
+                    ..cursor
+                }),
+            ))
+        })
+    }
+
+    /// Appends chunks of the non-empty payloads of the setting to the export page, in version order
+    /// from the payload version and offset, until the page is full.
+    /// It returns the payload version and offset of the next chunk, None if all chunks are exported.
+    fn export_chunks(
+        spk: &SettingPathKey,
+        setting: &Setting,
+        from: (u32, u64),
+        size: &mut u64,
+        chunks: &mut Vec<ExportedChunk>,
+    ) -> Option<(u32, u64)> {
+        let mut versions: Vec<u32> = PAYLOADS_STORE.with_borrow(|r| {
+            r.keys_range(archived_range(spk, from.0.max(1), setting.version))
+                .map(|k| k.4)
+                .collect()
+        });
+        versions.push(setting.version);
+        for version in versions {
+            let (payload, payload_chunks) = if version == setting.version {
+                (setting.payload.clone(), setting.payload_chunks.clone())
+            } else {
+                let mut pk = spk.clone();
+                pk.4 = version;
+                PAYLOADS_STORE
+                    .with_borrow(|r| r.get(&pk))
+                    .map(|a| (a.payload, a.payload_chunks))
+                    .unwrap_or_default()
+            };
+            let payload_size = match payload_chunks {
+                Some(ref chunks) => chunks.size,
+                None => payload.as_ref().map(|p| p.len() as u64).unwrap_or(0),
+            };
+            let mut offset = if version == from.0 { from.1 } else { 0 };
+            while offset < payload_size {
+                if *size + CHUNK_SIZE as u64 > MAX_EXPORT_PAGE_SIZE {
+                    return Some((version, offset));
+                }
+                // offsets are multiples of CHUNK_SIZE, so they match the stored chunks
+                let (_, data) = read_payload_chunk(
+                    spk,
+                    payload.as_ref(),
+                    payload_chunks.as_ref(),
+                    (offset / CHUNK_SIZE as u64) as u32,
+                )
+                .unwrap_or_default();
+                let end = (offset + CHUNK_SIZE as u64).min(payload_size);
+                chunks.push(ExportedChunk {
+                    version,
+                    offset,
+                    data: ByteBuf::from(data),
+                });
+                *size += end - offset;
+                offset = end;
+            }
+        }
+        None
+    }
+
+    fn export_id(canister: &Principal, namespace: &str, now_ms: u64) -> ByteBuf {
+        let mut buf = vec![];
+        to_writer(&(canister, namespace, now_ms), &mut buf).expect("failed to encode export id");
+        ByteBuf::from(&sha256(&buf)[..16])
+    }
+
+    pub fn encode_export_cursor(cursor: &ExportCursor) -> ByteBuf {
+        let mut buf = vec![];
+        to_writer(cursor, &mut buf).expect("failed to encode export cursor");
+        ByteBuf::from(buf)
+    }
+
+    pub fn decode_export_cursor(cursor: &[u8]) -> Result<ExportCursor, String> {
+        try_from_cbor_bytes(cursor, "export cursor")
+    }
+
+    /// Returns the Ed25519 public key that verifies the export pages of this canister.
+    pub fn export_public_key() -> Result<ByteBuf, String> {
+        state::with(|s| {
+            let pk = s
+                .schnorr_ed25519_public_key
+                .as_ref()
+                .ok_or("no schnorr ed25519 public key")?;
+            let pk = derive_schnorr_public_key(
+                SchnorrAlgorithm::Ed25519,
+                pk,
+                vec![EXPORT_DERIVATION_PATH.to_vec()],
+            )?;
+            Ok(pk.public_key)
+        })
+    }
+
+    pub async fn sign_export(bundle: &[u8]) -> Result<ByteBuf, String> {
+        let key_name = state::with(|s| s.schnorr_key_name.clone());
+        let sig = sign_with_schnorr(
+            key_name,
+            SchnorrAlgorithm::Ed25519,
+            vec![EXPORT_DERIVATION_PATH.to_vec()],
+            sha256(bundle).to_vec(),
+        )
+        .await?;
+        Ok(ByteBuf::from(sig))
+    }
+
+    /// Decodes an export page and verifies its signature with the trusted public key of the
+    /// exporting canister, see `admin_set_export_source`.
+    pub fn verify_export(bundle: &[u8], signature: &[u8]) -> Result<NamespaceExport, String> {
+        let page: NamespaceExport = try_from_cbor_bytes(bundle, "NamespaceExport data")?;
+        let public_key = state::with(|s| s.export_sources.get(&page.canister).cloned())
+            .ok_or_else(|| format!("untrusted export source {}", page.canister))?;
+        let pk = ic_ed25519::PublicKey::deserialize_raw(&public_key).map_err(format_error)?;
+        pk.verify_signature(&sha256(bundle), signature)
+            .map_err(|_| "invalid bundle signature".to_string())?;
+        Ok(page)
+    }
+
+    /// Imports a verified export page under the name. The first page creates the namespace,
+    /// and the following pages must belong to the same export and be imported in order.
+    /// Gas, usage counters and pending proposals of the exported namespace are not imported.
+    pub fn import_namespace(
+        name: String,
+        page: NamespaceExport,
+        now_ms: u64,
+    ) -> Result<NamespaceInfo, String> {
+        let mut ns = match page.namespace {
+            Some(mut ns) => {
+                if page.page != 0 {
+                    Err("invalid export page".to_string())?;
+                }
+                check_available_name(&name)?;
+                ns.gas_balance = 0;
+                ns.gas_credit = 0;
+                ns.gas_used = 0;
+                ns.usage.clear();
+                ns.payload_bytes_total = 0;
+                ns.settings_total = 0;
+                ns.proposals.clear();
+                ns.importing = None;
+                ns.moving = None;
+                // keys are derived from the imported name, the old name may be taken in this canister
+                ns.alias = None;
+                ns
+            }
+            None => {
+                let ns = NAMESPACES_STORE
+                    .with_borrow(|r| r.get(&name))
+                    .ok_or_else(|| format!("NotFound: namespace {} not found", name))?;
+                let import = ns
+                    .importing
+                    .as_ref()
+                    .ok_or_else(|| format!("namespace {} is not being imported", name))?;
+                if import.canister != page.canister
+                    || import.name != page.name
+                    || import.export_id != page.export_id
+                {
+                    Err(format!(
+                        "export page does not belong to the import of namespace {}",
+                        name
+                    ))?;
+                }
+                if import.next_page != page.page {
+                    Err(format!(
+                        "export page {} is out of order, expected page {}",
+                        page.page, import.next_page
+                    ))?;
+                }
+                ns
+            }
+        };
+
+        let (mut pending, mut next_chunk) = ns
+            .importing
+            .take()
+            .map(|im| (im.pending, im.next_chunk))
+            .unwrap_or_default();
+        let spk_of = |item: &ExportedSetting, version: u32| {
+            SettingPathKey(
+                name.clone(),
+                item.key.0,
+                item.key.1,
+                item.key.2.clone(),
+                version,
+            )
+        };
+
+        // a setting exported in chunks is the last setting of its page,
+        // and its chunks must be imported before other settings.
+        // Payloads stored in chunks are exported as chunks, never as references.
+        if (pending.is_some() && !page.settings.is_empty())
+            || page
+                .settings
+                .iter()
+                .rev()
+                .skip(1)
+                .any(|s| !s.chunked.is_empty())
+            || page.settings.iter().any(|s| {
+                s.setting.payload_chunks.is_some()
+                    || s.archived.iter().any(|(_, a)| a.payload_chunks.is_some())
+            })
+        {
+            Err("invalid export page".to_string())?;
+        }
+        let mut keys = BTreeSet::new();
+        SETTINGS_STORE.with_borrow(|r| {
+            for item in &page.settings {
+                if !keys.insert(&item.key) {
+                    Err("invalid export page".to_string())?;
+                }
+                let spk = spk_of(item, 0);
+                if r.contains_key(&spk) {
+                    Err(format!("setting {} already exists", spk))?;
+                }
+            }
+            Ok::<(), String>(())
+        })?;
+        let bytes: u64 = page
+            .settings
+            .iter()
+            .map(|s| {
+                s.setting.size()
+                    + s.archived.iter().map(|(_, a)| a.size()).sum::<u64>()
+                    + s.chunked.values().map(|(size, _)| size).sum::<u64>()
+            })
+            .sum();
+        ns.check_quota(
+            ns.payload_bytes_total.saturating_add(bytes),
+            ns.settings_total.saturating_add(page.settings.len() as u64),
+        )?;
+        // the chunks of the page belong to its last setting, or to the pending setting
+        let (chunked, chunked_from) = match page.settings.last() {
+            Some(item) if !item.chunked.is_empty() => {
+                (Some(item), (*item.chunked.keys().next().unwrap(), 0))
+            }
+            _ => (pending.as_ref(), next_chunk),
+        };
+        if check_import_chunks(spk_of, chunked, chunked_from, &page.chunks)? && page.last {
+            Err("invalid export page".to_string())?;
+        }
+
+        // The page is checked, an error here means the state is inconsistent.
+        // Trap to roll back the settings and chunks that have been imported.
+        let apply = || -> Result<(), String> {
+            for item in page.settings {
+                let spk = spk_of(&item, 0);
+                if let Some(version) = item.chunked.keys().next() {
+                    next_chunk = (*version, 0);
+                    pending = Some(item);
+                    continue;
+                }
+
+                let version = item.setting.version;
+                let size = insert_setting(&spk, item.setting, item.archived);
+                record_change(&spk, SettingChangeKind::Create, version, now_ms);
+
+                ns.payload_bytes_total = ns.payload_bytes_total.saturating_add(size);
+                ns.settings_total = ns.settings_total.saturating_add(1);
+            }
+
+            for chunk in page.chunks {
+                let item = pending
+                    .as_ref()
+                    .ok_or_else(|| "invalid export page".to_string())?;
+                let (version, offset) = next_chunk;
+                let pk = spk_of(item, version);
+                let (total, _) = item
+                    .chunked
+                    .get(&version)
+                    .ok_or_else(|| "invalid export page".to_string())?;
+                let len = (total - offset).min(CHUNK_SIZE as u64);
+                PAYLOAD_CHUNKS_STORE.with_borrow_mut(|r| {
+                    r.insert(
+                        UploadChunkKey(pk, (offset / CHUNK_SIZE as u64) as u32),
+                        chunk.data.into_vec(),
+                    )
+                });
+                let offset = offset + len;
+                if offset < *total {
+                    next_chunk = (version, offset);
+                    continue;
+                }
+
+                match item.chunked.range(version + 1..).next() {
+                    Some((version, _)) => next_chunk = (*version, 0),
+                    None => {
+                        let item = pending.take().expect("pending setting");
+                        let spk = spk_of(&item, 0);
+                        let version = item.setting.version;
+                        let size = insert_chunked_setting(&spk, item);
+                        record_change(&spk, SettingChangeKind::Create, version, now_ms);
+
+                        ns.payload_bytes_total = ns.payload_bytes_total.saturating_add(size);
+                        ns.settings_total = ns.settings_total.saturating_add(1);
+                        next_chunk = (0, 0);
+                    }
+                }
+            }
+            Ok(())
+        };
+        if let Err(err) = apply() {
+            ic_cdk::trap(format!("failed to import export page: {}", err));
+        }
+
+        ns.importing = if page.last {
+            None
+        } else {
+            Some(NamespaceImport {
+                canister: page.canister,
+                name: page.name,
+                export_id: page.export_id,
+                next_page: page.page + 1,
+                pending,
+                next_chunk,
+            })
+        };
+        ns.updated_at = now_ms;
+        NAMESPACES_STORE.with_borrow_mut(|r| r.insert(name.clone(), ns.clone()));
+        Ok(ns.into_info(name))
+    }
+
+    /// Aborts the import of the namespace, e.g. after the export has changed,
+    /// and removes at most `limit` imported settings.
+    /// It returns true when the namespace is removed with its staged chunks and change log,
+    /// false if settings remain to be removed by the following calls.
+    pub fn abort_import(name: String, limit: usize) -> Result<bool, String> {
+        let ns = NAMESPACES_STORE
+            .with_borrow(|r| r.get(&name))
+            .ok_or_else(|| format!("NotFound: namespace {} not found", name))?;
+        let import = ns
+            .importing
+            .ok_or_else(|| format!("namespace {} is not being imported", name))?;
+
+        let start = SettingPathKey(
+            name.clone(),
+            0,
+            Principal::management_canister(),
+            ByteBuf::new(),
+            0,
+        );
+        let end = SettingPathKey(
+            name.clone(),
+            u8::MAX,
+            Principal::management_canister(),
+            ByteBuf::new(),
+            0,
+        );
+        let settings: Vec<(SettingPathKey, Setting)> = SETTINGS_STORE.with_borrow(|r| {
+            r.range(start..end)
+                .take(limit)
+                .map(|entry| entry.into_pair())
+                .collect()
+        });
+        if settings.len() == limit {
+            NAMESPACES_STORE.with_borrow_mut(|r| {
+                let mut ns = r.get(&name).expect("namespace exists");
+                for (spk, setting) in &settings {
+                    let size = remove_setting(spk, setting);
+                    ns.payload_bytes_total = ns.payload_bytes_total.saturating_sub(size);
+                    ns.settings_total = ns.settings_total.saturating_sub(1);
+                }
+                r.insert(name, ns);
+            });
+            return Ok(false);
+        }
+
+        for (spk, setting) in &settings {
+            remove_setting(spk, setting);
+        }
+        if let Some(item) = import.pending {
+            for version in item.chunked.keys() {
+                remove_payload_chunks(&SettingPathKey(
+                    name.clone(),
+                    item.key.0,
+                    item.key.1,
+                    item.key.2.clone(),
+                    *version,
+                ));
+            }
+        }
+        remove_empty_namespace(name)?;
+        Ok(true)
+    }
+
+    /// Checks the chunks of an export page against the setting being imported in chunks,
+    /// starting from its payload version and offset, without writing them.
+    /// The hash of a payload is checked when its last chunk is received.
+    /// It returns true if the setting still waits for chunks of the following pages.
+    fn check_import_chunks(
+        spk_of: impl Fn(&ExportedSetting, u32) -> SettingPathKey,
+        mut pending: Option<&ExportedSetting>,
+        mut next_chunk: (u32, u64),
+        chunks: &[ExportedChunk],
+    ) -> Result<bool, String> {
+        // the payload received so far, with the chunks imported from the previous pages
+        let mut payload: Option<Vec<u8>> = None;
+        for chunk in chunks {
+            let item = pending.ok_or_else(|| "invalid export page".to_string())?;
+            let (version, offset) = next_chunk;
+            let pk = spk_of(item, version);
+            let (total, hash) = item
+                .chunked
+                .get(&version)
+                .ok_or_else(|| "invalid export page".to_string())?;
+            let len = (total - offset).min(CHUNK_SIZE as u64);
+            if chunk.version != version || chunk.offset != offset || chunk.data.len() as u64 != len
+            {
+                Err(format!(
+                    "invalid chunk of setting {}, expected version {} at offset {}",
+                    pk.v0(),
+                    version,
+                    offset
+                ))?;
+            }
+
+            payload
+                .get_or_insert_with(|| stored_payload_chunks(&pk, offset))
+                .extend_from_slice(&chunk.data);
+            let offset = offset + len;
+            if offset < *total {
+                next_chunk = (version, offset);
+                continue;
+            }
+
+            if &sha256(&payload.take().unwrap_or_default()) != hash.as_ref() {
+                Err(format!(
+                    "setting {} changed during the export, abort the import and export again",
+                    pk.v0()
+                ))?;
+            }
+            match item.chunked.range(version + 1..).next() {
+                Some((version, _)) => next_chunk = (*version, 0),
+                None => pending = None,
+            }
+        }
+        Ok(pending.is_some())
+    }
+
+    /// Inserts the setting imported in chunks, its payloads refer to the imported payload chunks.
+    /// It returns the size in bytes of the inserted payloads.
+    fn insert_chunked_setting(spk: &SettingPathKey, item: ExportedSetting) -> u64 {
+        let payload_chunks = |version: u32| {
+            item.chunked
+                .get(&version)
+                .map(|(size, sha256)| ChunkedPayload {
+                    size: *size,
+                    sha256: *sha256,
+                    version,
+                })
+        };
+
+        let archived = item
+            .archived
+            .into_iter()
+            .map(|(version, mut archived)| {
+                archived.payload_chunks = payload_chunks(version);
+                (version, archived)
+            })
+            .collect();
+        let mut setting = item.setting;
+        setting.payload_chunks = payload_chunks(setting.version);
+        insert_setting(spk, setting, archived)
+    }
+
+    /// Inserts the setting with its archived payloads and index entries.
+    /// It returns the size in bytes of the inserted payloads.
+    fn insert_setting(
+        spk: &SettingPathKey,
+        setting: Setting,
+        archived: Vec<(u32, SettingArchived)>,
+    ) -> u64 {
+        let spk = spk.v0();
+        let mut size = setting.size();
+        index_tags(&spk, &BTreeMap::new(), &setting.tags);
+        index_expires(&spk, None, setting.expires_at);
+        GRANTS_STORE.with_borrow_mut(|r| {
+            for (reader, expires_at) in &setting.reader_grants {
+                r.insert(
+                    reader_grant_index_key(&spk, reader, *expires_at),
+                    spk.clone(),
+                );
+            }
+        });
+        PAYLOADS_STORE.with_borrow_mut(|r| {
+            for (version, archived) in archived {
+                size += archived.size();
+                let mut pk = spk.clone();
+                pk.4 = version;
+                r.insert(pk, archived);
+            }
+        });
+        SETTINGS_STORE.with_borrow_mut(|r| r.insert(spk.clone(), setting));
+        certify_setting(&spk);
+        size
+    }
+
+    type SettingWithArchived = (SettingPathKey, Setting, Vec<(u32, SettingArchived)>);
+
+    // payload versions of the chunks that the setting and its archived versions refer to
+    fn chunk_versions(setting: &Setting, archived: &[(u32, SettingArchived)]) -> BTreeSet<u32> {
+        setting
+            .payload_chunks
+            .iter()
+            .chain(
+                archived
+                    .iter()
+                    .filter_map(|(_, a)| a.payload_chunks.as_ref()),
+            )
+            .map(|c| c.version)
+            .collect()
+    }
+
+    /// Returns at most `limit` settings of the namespace after the `cursor` key,
+    /// with their archived payloads.
+    fn namespace_settings(
+        namespace: &str,
+        cursor: Option<SettingPathKey>,
+        limit: usize,
+    ) -> Vec<SettingWithArchived> {
+        let start = match cursor {
+            Some(cursor) => ops::Bound::Excluded(cursor),
+            None => ops::Bound::Included(SettingPathKey(
+                namespace.to_string(),
+                0,
+                Principal::management_canister(),
+                ByteBuf::new(),
+                0,
+            )),
+        };
+        let end = SettingPathKey(
+            namespace.to_string(),
+            u8::MAX,
+            Principal::management_canister(),
+            ByteBuf::new(),
+            0,
+        );
+        SETTINGS_STORE.with_borrow(|r| {
+            r.range((start, ops::Bound::Excluded(end)))
+                .take(limit)
+                .map(|entry| {
+                    let (spk, setting) = entry.into_pair();
+                    let archived = if setting.version > 1 {
+                        PAYLOADS_STORE.with_borrow(|rp| {
+                            rp.range(archived_range(&spk, 1, setting.version))
+                                .map(|e| (e.key().4, e.value()))
+                                .collect()
+                        })
+                    } else {
+                        Vec::new()
+                    };
+                    (spk, setting, archived)
+                })
+                .collect()
+        })
+    }
+
+    /// Returns the namespace that continues the rename or clone of `namespace` into `new_name`,
+    /// None if it has not been started.
+    fn moving_namespace(namespace: &str, new_name: &str, clone: bool) -> Option<Namespace> {
+        NAMESPACES_STORE
+            .with_borrow(|r| r.get(&new_name.to_string()))
+            .filter(|ns| {
+                ns.moving
+                    .as_ref()
+                    .is_some_and(|m| m.from == namespace && m.clone == clone)
+            })
+    }
+
+    /// Renames the namespace and moves at most `limit` of its settings and change logs
+    /// to the new name. The rename continues with the same names until `moving_from`
+    /// of the result is None, settings can not be written in the meantime.
+    /// The original name is kept as an alias in key derivation paths,
+    /// so that KEKs, vetKeys and signing keys derived before the rename keep working.
+    /// Old names stay reserved until the namespace is deleted.
+    pub fn rename_namespace(
+        caller: &Principal,
+        namespace: String,
+        new_name: String,
+        limit: usize,
+        now_ms: u64,
+    ) -> Result<NamespaceInfo, String> {
+        if !state::with(|s| s.managers.contains(caller)) {
+            Err("no permission".to_string())?;
+        }
+        let mut ns = match moving_namespace(&namespace, &new_name, false) {
+            Some(ns) => ns,
+            None => start_rename(namespace.clone(), new_name.clone(), now_ms)?,
+        };
+
+        let settings = namespace_settings(&namespace, None, limit);
+        let mut budget = limit - settings.len();
+        for (spk, setting, archived) in settings {
+            let mut new_spk = spk.clone();
+            new_spk.0 = new_name.clone();
+            for version in chunk_versions(&setting, &archived) {
+                copy_payload_chunks(&spk, &new_spk, version);
+            }
+            remove_setting(&spk, &setting);
+            insert_setting(&new_spk, setting, archived);
+        }
+
+        // the change log is moved after the settings
+        if budget > 0 {
+            CHANGES_STORE.with_borrow_mut(|r| {
+                let changes: Vec<(Vec<u8>, SettingChangeLog)> = r
+                    .range(change_log_key(&namespace, 0)..=change_log_key(&namespace, u64::MAX))
+                    .take(budget)
+                    .map(|entry| entry.into_pair())
+                    .collect();
+                budget -= changes.len();
+                for (key, mut change) in changes {
+                    r.remove(&key);
+                    change.spk.0 = new_name.clone();
+                    r.insert(change_log_key(&new_name, change_log_seq(&key)), change);
+                }
+            });
+            if budget > 0 {
+                ns.moving = None;
+            }
+        }
+
+        NAMESPACES_STORE.with_borrow_mut(|r| r.insert(new_name.clone(), ns.clone()));
+        Ok(ns.into_info(new_name))
+    }
+
+    // moves the namespace to the new name, its settings are moved by the following batches
+    fn start_rename(namespace: String, new_name: String, now_ms: u64) -> Result<Namespace, String> {
+        if NAMESPACES_STORE.with_borrow(|r| r.contains_key(&new_name)) {
+            Err(format!("namespace {} already exists", new_name))?;
+        }
+        // a namespace can be renamed back to one of its old names
+        if let Some(current) = state::with(|s| s.namespace_aliases.get(&new_name).cloned()) {
+            if current != namespace {
+                Err(format!(
+                    "namespace {} is reserved as an alias of {}",
+                    new_name, current
+                ))?;
+            }
+        }
+        let mut ns = NAMESPACES_STORE
+            .with_borrow(|r| r.get(&namespace))
+            .ok_or_else(|| format!("NotFound: namespace {} not found", namespace))?;
+        if ns.moving.is_some() {
+            Err(format!(
+                "namespace {} is being renamed or cloned",
+                namespace
+            ))?;
+        }
+
+        if ns.alias.is_none() {
+            ns.alias = Some(namespace.clone());
+        }
+        if ns.alias.as_ref() == Some(&new_name) {
+            ns.alias = None;
+        }
+        ns.updated_at = now_ms;
+        ns.moving = Some(NamespaceMove {
+            from: namespace.clone(),
+            clone: false,
+            cursor: None,
+        });
+        state::with_mut(|s| {
+            for current in s.namespace_aliases.values_mut() {
+                if current == &namespace {
+                    *current = new_name.clone();
+                }
+            }
+            s.namespace_aliases
+                .insert(namespace.clone(), new_name.clone());
+            s.namespace_aliases.remove(&new_name);
+        });
+        NAMESPACES_STORE.with_borrow_mut(|r| r.remove(&namespace));
+        Ok(ns)
+    }
+
+    /// Clones the namespace metadata into a new namespace, and copies at most `limit`
+    /// of its plaintext settings. The clone continues with the same names until `moving_from`
+    /// of the result is None, it ends early if the source is renamed or deleted.
+    /// Encrypted settings are not cloned because the new namespace derives different KEKs,
+    /// they are returned as a work list of the batch to be re-encrypted and re-created by the caller.
+    /// Archived versions that are encrypted are not cloned either.
+    pub fn clone_namespace(
+        caller: &Principal,
+        namespace: String,
+        new_name: String,
+        limit: usize,
+        now_ms: u64,
+    ) -> Result<CloneNamespaceOutput, String> {
+        if !state::with(|s| s.managers.contains(caller)) {
+            Err("no permission".to_string())?;
+        }
+        let mut ns = match moving_namespace(&namespace, &new_name, true) {
+            Some(ns) => ns,
+            None => {
+                check_available_name(&new_name)?;
+                let mut ns = NAMESPACES_STORE
+                    .with_borrow(|r| r.get(&namespace))
+                    .ok_or_else(|| format!("NotFound: namespace {} not found", namespace))?;
+                if ns.moving.is_some() {
+                    Err(format!(
+                        "namespace {} is being renamed or cloned",
+                        namespace
+                    ))?;
+                }
+
+                ns.created_at = now_ms;
+                ns.updated_at = now_ms;
+                ns.gas_balance = 0;
+                ns.gas_credit = 0;
+                ns.gas_used = 0;
+                ns.usage.clear();
+                ns.payload_bytes_total = 0;
+                ns.settings_total = 0;
+                ns.proposals.clear();
+                ns.alias = None;
+                ns.moving = Some(NamespaceMove {
+                    from: namespace.clone(),
+                    clone: true,
+                    cursor: None,
+                });
+                ns
+            }
+        };
+
+        let cursor = ns.moving.as_ref().and_then(|m| m.cursor.clone());
+        let settings = namespace_settings(&namespace, cursor, limit);
+        let done = settings.len() < limit;
+        let mut reencrypt: Vec<SettingPath> = Vec::new();
+        for (spk, setting, archived) in settings {
+            if let Some(m) = ns.moving.as_mut() {
+                m.cursor = Some(spk.clone());
+            }
+            if setting.dek.is_some() {
+                reencrypt.push(SettingPath {
+                    ns: namespace.clone(),
+                    user_owned: spk.1 == 1,
+                    subject: Some(spk.2),
+                    key: spk.3,
+                    version: setting.version,
+                });
+                continue;
+            }
+
+            let archived: Vec<(u32, SettingArchived)> = archived
+                .into_iter()
+                .filter(|(_, a)| a.dek.is_none())
+                .collect();
+            let mut new_spk = spk.clone();
+            new_spk.0 = new_name.clone();
+            for version in chunk_versions(&setting, &archived) {
+                copy_payload_chunks(&spk, &new_spk, version);
+            }
+            let version = setting.version;
+            let size = insert_setting(&new_spk, setting, archived);
+            record_change(&new_spk, SettingChangeKind::Create, version, now_ms);
+            ns.payload_bytes_total = ns.payload_bytes_total.saturating_add(size);
+            ns.settings_total = ns.settings_total.saturating_add(1);
+        }
+        if done {
+            ns.moving = None;
+        }
+
+        NAMESPACES_STORE.with_borrow_mut(|r| r.insert(new_name.clone(), ns.clone()));
+        Ok(CloneNamespaceOutput {
+            namespace: ns.into_info(new_name),
+            reencrypt,
+        })
+    }
+
+    pub fn try_get_setting(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        now_ms: u64,
+    ) -> Option<Setting> {
+        with(&spk.0, |ns| {
+            let can = ns.partial_can_read_setting(caller, spk);
+            if can == Some(false) {
+                return Ok(None);
+            }
+
+            let setting = SETTINGS_STORE.with_borrow(|m| m.get(&spk.v0()));
+            Ok(setting.filter(|s| {
+                spk.4 <= s.version
+                    && !s.is_expired(now_ms)
+                    && (can == Some(true) || ns.is_setting_reader(s, caller, now_ms))
+            }))
+        })
+        .unwrap_or(None)
+    }
+
+    pub fn get_setting_info(
+        caller: Principal,
+        spk: SettingPathKey,
+        now_ms: u64,
+    ) -> Result<SettingInfo, String> {
+        let setting = try_get_setting(&caller, &spk, now_ms)
+            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", spk))?;
+
+        Ok(setting.into_info(spk.2, spk.3, false))
+    }
+
+    /// Returns the setting info with its DEK but without the payload,
+    /// so that the DEK of a payload over the response limit can be re-wrapped.
+    pub fn get_setting_dek(
+        caller: Principal,
+        spk: SettingPathKey,
+        now_ms: u64,
+    ) -> Result<SettingInfo, String> {
+        let setting = try_get_setting(&caller, &spk, now_ms)
+            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", spk))?;
+
+        let dek = setting.dek.clone();
+        let mut info = setting.into_info(spk.2, spk.3, false);
+        info.dek = dek;
+        Ok(info)
+    }
+
+    pub fn get_setting(
+        caller: Principal,
+        spk: SettingPathKey,
+        now_ms: u64,
+    ) -> Result<SettingInfo, String> {
+        let setting = try_get_setting(&caller, &spk, now_ms)
+            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", &spk))?;
+
+        if spk.4 != 0 && spk.4 != setting.version {
+            Err("version mismatch".to_string())?;
+        };
+
+        if setting
+            .payload
+            .as_ref()
+            .is_some_and(|p| p.len() as u64 > MAX_PAYLOAD_SIZE)
+        {
+            Err("payload size exceeds the response limit, use setting_get_chunk".to_string())?;
+        }
+
+        let payload = match setting.payload_chunks {
+            Some(ref chunks) => Some(read_chunked_payload(&spk, chunks)?),
+            None => None,
+        };
+        let mut info = setting.into_info(spk.2, spk.3, true);
+        if payload.is_some() {
+            info.payload = payload;
+        }
+        Ok(info)
+    }
+
+    pub fn get_setting_chunk(
+        caller: Principal,
+        spk: SettingPathKey,
+        chunk_index: u32,
+        now_ms: u64,
+    ) -> Result<SettingChunkOutput, String> {
+        let setting = try_get_setting(&caller, &spk, now_ms)
+            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", &spk))?;
+
+        let (version, payload, chunks, dek) = if spk.4 == 0 || spk.4 == setting.version {
+            (
+                setting.version,
+                setting.payload,
+                setting.payload_chunks,
+                setting.dek,
+            )
+        } else {
+            let archived = PAYLOADS_STORE.with_borrow(|r| {
+                r.get(&spk)
+                    .ok_or_else(|| format!("NotFound: setting {} payload not found", &spk))
+            })?;
+            (
+                spk.4,
+                archived.payload,
+                archived.payload_chunks,
+                archived.dek,
+            )
+        };
+
+        let (payload_size, chunk) =
+            read_payload_chunk(&spk, payload.as_ref(), chunks.as_ref(), chunk_index)?;
+        Ok(SettingChunkOutput {
+            version,
+            payload_size,
+            chunk_index,
+            chunk: ByteBuf::from(chunk),
+            dek,
+        })
+    }
+
+    /// Returns the offset in bytes of the chunk, None on overflow.
+    /// The offset is computed in u64 so that it cannot wrap on wasm32,
+    /// callers should check it against the payload size before converting it to usize.
+    fn chunk_offset(chunk_index: u32) -> Option<u64> {
+        (chunk_index as u64).checked_mul(CHUNK_SIZE as u64)
+    }
+
+    const UPLOAD_EXPIRES_IN_MS: u64 = 1000 * 3600; // 1 hour
+
+    pub fn begin_upload(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        payload_size: u64,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        with(&spk.0, |ns| {
+            if !ns.can_write_setting(caller, spk) {
+                Err("no permission".to_string())?;
+            }
+            if payload_size == 0 {
+                Err("payload size should be greater than 0".to_string())?;
+            }
+            if payload_size > ns.max_payload_size {
+                Err("payload size exceeds the limit".to_string())?;
+            }
+            Ok(())
+        })?;
+
+        let expired: Vec<SettingPathKey> = UPLOADS_STORE.with_borrow(|r| {
+            r.iter()
+                .filter(|e| e.value().started_at + UPLOAD_EXPIRES_IN_MS <= now_ms)
+                .map(|e| e.key().clone())
+                .collect()
+        });
+        for spk in expired {
+            remove_upload(&spk);
+        }
+
+        let spkv0 = spk.v0();
+        UPLOADS_STORE.with_borrow(|r| {
+            let mut uploads = 0usize;
+            let mut caller_uploads = 0usize;
+            let mut size = payload_size;
+            for entry in r.iter() {
+                let upload = entry.value();
+                if entry.key() == &spkv0 {
+                    if &upload.uploader != caller {
+                        Err(format!(
+                            "setting {} is being uploaded by another caller",
+                            &spkv0
+                        ))?;
+                    }
+                    // restarts the upload
+                    continue;
+                }
+                uploads += 1;
+                size = size.saturating_add(upload.payload_size);
+                if &upload.uploader == caller {
+                    caller_uploads += 1;
+                }
+            }
+            if caller_uploads >= MAX_UPLOADS_PER_CALLER {
+                Err(format!(
+                    "too many pending uploads, the limit is {} per caller",
+                    MAX_UPLOADS_PER_CALLER
+                ))?;
+            }
+            if uploads >= MAX_UPLOADS || size > MAX_UPLOADS_SIZE {
+                Err("too many pending uploads, try again later".to_string())?;
+            }
+            Ok::<(), String>(())
+        })?;
+
+        remove_upload(&spkv0);
+        UPLOADS_STORE.with_borrow_mut(|r| {
+            r.insert(
+                spkv0,
+                PayloadUpload {
+                    uploader: *caller,
+                    started_at: now_ms,
+                    payload_size,
+                    filled: BTreeSet::new(),
+                    sha256: None,
+                    encrypt0: false,
+                },
+            )
+        });
+        Ok(())
+    }
+
+    fn upload_chunks_range(spk: &SettingPathKey) -> ops::RangeInclusive<UploadChunkKey> {
+        UploadChunkKey(spk.clone(), 0)..=UploadChunkKey(spk.clone(), u32::MAX)
+    }
+
+    fn get_upload_of(caller: &Principal, spk: &SettingPathKey) -> Result<PayloadUpload, String> {
+        UPLOADS_STORE
+            .with_borrow(|r| r.get(spk))
+            .filter(|u| &u.uploader == caller)
+            .ok_or_else(|| format!("NotFound: upload of setting {} not found", spk))
+    }
+
+    /// Stores the chunk in stable memory, a received chunk can be uploaded again.
+    pub fn upload_chunk(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        chunk_index: u32,
+        chunk: &[u8],
+    ) -> Result<(), String> {
+        let spkv0 = spk.v0();
+        let mut upload = get_upload_of(caller, &spkv0)?;
+        let start = chunk_offset(chunk_index)
+            .filter(|start| *start < upload.payload_size)
+            .ok_or_else(|| format!("chunk index {} out of range", chunk_index))?;
+        let end = (start + CHUNK_SIZE as u64).min(upload.payload_size);
+        if chunk.len() as u64 != end - start {
+            Err(format!(
+                "chunk size mismatch, expected {}, got {}",
+                end - start,
+                chunk.len()
+            ))?;
+        }
+
+        UPLOAD_CHUNKS_STORE.with_borrow_mut(|r| {
+            r.insert(UploadChunkKey(spkv0.clone(), chunk_index), chunk.to_vec())
+        });
+        upload.filled.insert(chunk_index);
+        upload.sha256 = None;
+        UPLOADS_STORE.with_borrow_mut(|r| r.insert(spkv0, upload));
+        Ok(())
+    }
+
+    /// Verifies that all chunks of the upload are received and that the payload matches the sha256 hash.
+    /// A verified upload is committed by creating or updating the setting with the `payload_sha256`,
+    /// its chunks are moved to the payload chunks of the new version without being assembled.
+    pub fn verify_upload(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        payload_sha256: &[u8; 32],
+    ) -> Result<(), String> {
+        let spkv0 = spk.v0();
+        let mut upload = get_upload_of(caller, &spkv0)?;
+        if upload
+            .sha256
+            .as_ref()
+            .is_some_and(|h| h.as_ref() == payload_sha256)
+        {
+            return Ok(());
+        }
+        let chunks = upload.payload_size.div_ceil(CHUNK_SIZE as u64);
+        if upload.filled.len() as u64 != chunks {
+            Err(format!(
+                "upload is incomplete, received {} of {} chunks",
+                upload.filled.len(),
+                chunks
+            ))?;
+        }
+
+        let payload = stored_chunks(&spkv0, upload.payload_size);
+        if &sha256(&payload) != payload_sha256 {
+            Err("payload sha256 mismatch".to_string())?;
+        }
+        upload.sha256 = Some(ByteArray::new(*payload_sha256));
+        upload.encrypt0 = try_decode_encrypt0(&payload).is_ok();
+        UPLOADS_STORE.with_borrow_mut(|r| r.insert(spkv0, upload));
+        Ok(())
+    }
+
+    // Returns the upload of the caller that is verified with the payload hash.
+    fn verified_upload(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        payload_sha256: &ByteArray<32>,
+    ) -> Result<PayloadUpload, String> {
+        let spkv0 = spk.v0();
+        let upload = get_upload_of(caller, &spkv0)?;
+        if upload.sha256.as_ref() != Some(payload_sha256) {
+            Err(format!(
+                "upload of setting {} is not verified with the payload sha256",
+                spkv0
+            ))?;
+        }
+        Ok(upload)
+    }
+
+    // Moves the chunks of the verified upload to the payload chunks of the setting version,
+    // and removes the upload.
+    fn commit_upload(spk: &SettingPathKey, version: u32) -> ChunkedPayload {
+        let spkv0 = spk.v0();
+        let upload = UPLOADS_STORE
+            .with_borrow_mut(|r| r.remove(&spkv0))
+            .expect("upload should be verified");
+        let mut pk = spkv0.clone();
+        pk.4 = version;
+        UPLOAD_CHUNKS_STORE.with_borrow_mut(|r| {
+            let keys: Vec<UploadChunkKey> = r.keys_range(upload_chunks_range(&spkv0)).collect();
+            PAYLOAD_CHUNKS_STORE.with_borrow_mut(|rc| {
+                for key in keys {
+                    if let Some(chunk) = r.remove(&key) {
+                        rc.insert(UploadChunkKey(pk.clone(), key.1), chunk);
+                    }
+                }
+            });
+        });
+        ChunkedPayload {
+            size: upload.payload_size,
+            sha256: upload.sha256.expect("upload should be verified"),
+            version,
+        }
+    }
+
+    /// Removes the upload with its received chunks.
+    pub fn remove_upload(spk: &SettingPathKey) {
+        let spkv0 = spk.v0();
+        UPLOADS_STORE.with_borrow_mut(|r| r.remove(&spkv0));
+        remove_chunks(&spkv0);
+    }
+
+    /// Returns the payload assembled from the stored chunks of the key, in chunk index order.
+    fn stored_chunks(spk: &SettingPathKey, size: u64) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(size as usize);
+        UPLOAD_CHUNKS_STORE.with_borrow(|r| {
+            for entry in r.range(upload_chunks_range(spk)) {
+                payload.extend_from_slice(&entry.value());
+            }
+        });
+        payload
+    }
+
+    fn remove_chunks(spk: &SettingPathKey) {
+        UPLOAD_CHUNKS_STORE.with_borrow_mut(|r| {
+            let keys: Vec<UploadChunkKey> = r.keys_range(upload_chunks_range(spk)).collect();
+            for key in keys {
+                r.remove(&key);
+            }
+        });
+    }
+
+    // keys of the payload chunks of all versions of the setting
+    fn setting_chunks_range(spk: &SettingPathKey) -> ops::RangeInclusive<UploadChunkKey> {
+        let mut end = spk.v0();
+        end.4 = u32::MAX;
+        UploadChunkKey(spk.v0(), 0)..=UploadChunkKey(end, u32::MAX)
+    }
+
+    // Returns the size of the payload of a setting version, stored inline or in chunks,
+    // and its chunk at the index.
+    fn read_payload_chunk(
+        spk: &SettingPathKey,
+        payload: Option<&ByteBuf>,
+        chunks: Option<&ChunkedPayload>,
+        chunk_index: u32,
+    ) -> Result<(u64, Vec<u8>), String> {
+        let size = match chunks {
+            Some(chunks) => chunks.size,
+            None => payload.map(|p| p.len() as u64).unwrap_or(0),
+        };
+        let start = chunk_offset(chunk_index)
+            .filter(|start| chunk_index == 0 || *start < size)
+            .ok_or_else(|| format!("chunk index {} out of range", chunk_index))?;
+        let end = (start + CHUNK_SIZE as u64).min(size);
+        let chunk = match (chunks, payload) {
+            (Some(chunks), _) => {
+                let mut pk = spk.v0();
+                pk.4 = chunks.version;
+                PAYLOAD_CHUNKS_STORE
+                    .with_borrow(|r| r.get(&UploadChunkKey(pk, chunk_index)))
+                    .unwrap_or_default()
+            }
+            (None, Some(payload)) => payload[start as usize..end as usize].to_vec(),
+            (None, None) => Vec::new(),
+        };
+        Ok((size, chunk))
+    }
+
+    // Returns the payload assembled from its chunks, or an error if it exceeds the response limit.
+    fn read_chunked_payload(
+        spk: &SettingPathKey,
+        chunks: &ChunkedPayload,
+    ) -> Result<ByteBuf, String> {
+        if chunks.size > MAX_PAYLOAD_SIZE {
+            Err("payload size exceeds the response limit, use setting_get_chunk".to_string())?;
+        }
+        let mut pk = spk.v0();
+        pk.4 = chunks.version;
+        Ok(ByteBuf::from(stored_payload_chunks(&pk, chunks.size)))
+    }
+
+    /// Returns the payload assembled from the payload chunks of the key, in chunk index order.
+    fn stored_payload_chunks(pk: &SettingPathKey, size: u64) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(size as usize);
+        PAYLOAD_CHUNKS_STORE.with_borrow(|r| {
+            for entry in r.range(upload_chunks_range(pk)) {
+                payload.extend_from_slice(&entry.value());
+            }
+        });
+        payload
+    }
+
+    fn remove_payload_chunks(pk: &SettingPathKey) {
+        PAYLOAD_CHUNKS_STORE.with_borrow_mut(|r| {
+            let keys: Vec<UploadChunkKey> = r.keys_range(upload_chunks_range(pk)).collect();
+            for key in keys {
+                r.remove(&key);
+            }
+        });
+    }
+
+    // Removes the chunks stored by the payload version of the setting,
+    // unless the setting or one of its archived versions still refers to them.
+    fn release_payload_chunks(spk: &SettingPathKey, version: u32) {
+        let spk = spk.v0();
+        let refers = |chunks: Option<ChunkedPayload>| chunks.is_some_and(|c| c.version == version);
+        if SETTINGS_STORE
+            .with_borrow(|r| r.get(&spk))
+            .is_some_and(|s| refers(s.payload_chunks))
+        {
+            return;
+        }
+        if PAYLOADS_STORE.with_borrow(|r| {
+            r.range(archived_range(&spk, 1, u32::MAX))
+                .any(|e| refers(e.value().payload_chunks))
+        }) {
+            return;
+        }
+
+        let mut pk = spk;
+        pk.4 = version;
+        remove_payload_chunks(&pk);
+    }
+
+    // Copies the chunks stored by the payload version of the setting to another setting.
+    fn copy_payload_chunks(from: &SettingPathKey, to: &SettingPathKey, version: u32) {
+        let mut pk = from.v0();
+        pk.4 = version;
+        let mut to = to.v0();
+        to.4 = version;
+        PAYLOAD_CHUNKS_STORE.with_borrow_mut(|r| {
+            let keys: Vec<UploadChunkKey> = r.keys_range(upload_chunks_range(&pk)).collect();
+            for key in keys {
+                if let Some(chunk) = r.get(&key) {
+                    r.insert(UploadChunkKey(to.clone(), key.1), chunk);
+                }
+            }
+        });
+    }
+
+    pub fn get_setting_archived_payload(
+        caller: Principal,
+        spk: SettingPathKey,
+        now_ms: u64,
+    ) -> Result<SettingArchivedPayload, String> {
+        let setting = try_get_setting(&caller, &spk, now_ms)
+            .ok_or_else(|| format!("NotFound: setting {} not found or no permission", &spk))?;
+
+        if spk.4 == 0 || spk.4 >= setting.version {
+            Err("version mismatch".to_string())?;
+        };
+
+        let archived = PAYLOADS_STORE.with_borrow(|r| {
+            r.get(&spk)
+                .ok_or_else(|| format!("NotFound: setting {} payload not found", &spk))
+        })?;
+        let payload = match archived.payload_chunks {
+            Some(ref chunks) => Some(read_chunked_payload(&spk, chunks)?),
+            None => archived.payload,
+        };
+
+        Ok(SettingArchivedPayload {
+            version: spk.4,
+            archived_at: archived.archived_at,
+            deprecated: archived.deprecated,
+            payload,
+            dek: archived.dek,
+        })
+    }
+
+    fn check_create_setting(
+        ns: &Namespace,
+        caller: &Principal,
+        spk: &SettingPathKey,
+        input: &CreateSettingInput,
+    ) -> Result<u64, String> {
+        if !ns.can_write_setting(caller, spk) {
+            Err("no permission".to_string())?;
+        }
+
+        if spk.4 != 0 {
+            Err("version mismatch".to_string())?;
+        }
+
+        let upload = match input.payload_sha256 {
+            Some(ref hash) => Some(verified_upload(caller, spk, hash)?),
+            None => None,
+        };
+        let payload_size = match (&input.payload, &upload) {
+            (Some(payload), _) => payload.len() as u64,
+            (None, Some(upload)) => upload.payload_size,
+            (None, None) => 0,
+        };
+        if payload_size > ns.max_payload_size {
+            Err("payload size exceeds the limit".to_string())?;
+        }
+
+        let size = match input.dek {
+            Some(ref dek) => {
+                // should be valid COSE encrypt0 dek
+                try_decode_encrypt0(dek)?;
+                // should be valid COSE encrypt0 payload
+                if let Some(ref payload) = input.payload {
+                    try_decode_encrypt0(payload)?;
+                }
+                check_encrypt0_upload(upload.as_ref())?;
+                payload_size + dek.len() as u64
+            }
+            None => payload_size,
+        };
+
+        if SETTINGS_STORE.with_borrow(|m| m.contains_key(spk)) {
+            Err(format!("setting {} already exists", spk))?;
+        }
+
+        Ok(size)
+    }
+
+    fn check_encrypt0_upload(upload: Option<&PayloadUpload>) -> Result<(), String> {
+        if upload.is_some_and(|u| !u.encrypt0) {
+            Err("uploaded payload is not a valid COSE_Encrypt0 message".to_string())?;
+        }
+        Ok(())
+    }
+
+    fn check_writable_setting(
+        ns: &Namespace,
+        caller: &Principal,
+        spk: &SettingPathKey,
+    ) -> Result<Setting, String> {
+        if !ns.can_write_setting(caller, spk) {
+            Err("no permission".to_string())?;
+        }
+
+        match SETTINGS_STORE.with_borrow(|r| r.get(&spk.v0())) {
+            Some(setting) => {
+                if setting.version != spk.4 {
+                    Err("version mismatch".to_string())?;
+                }
+                Ok(setting)
+            }
+            None => Err(format!("NotFound: setting {} not found", spk)),
+        }
+    }
+
+    fn check_update_setting_payload(
+        ns: &Namespace,
+        caller: &Principal,
+        spk: &SettingPathKey,
+        input: &UpdateSettingPayloadInput,
+    ) -> Result<(Setting, u64), String> {
+        let setting = check_writable_setting(ns, caller, spk)?;
+        if setting.status >= 1 {
+            Err("readonly setting can not be updated".to_string())?;
+        }
+        setting.check_cas(input.expected_hash.as_ref(), input.expected_updated_at)?;
+
+        let upload = match input.payload_sha256 {
+            Some(ref hash) => Some(verified_upload(caller, spk, hash)?),
+            None => None,
+        };
+        let payload_size = match (&input.payload, &upload) {
+            (Some(payload), _) => payload.len() as u64,
+            (None, Some(upload)) => upload.payload_size,
+            (None, None) => setting.payload_size(),
+        };
+        if (input.payload.is_some() || upload.is_some()) && payload_size > ns.max_payload_size {
+            Err("payload size exceeds the limit".to_string())?;
+        }
+        let dek_size = match input.dek {
+            Some(ref dek) => dek.len(),
+            None => setting.dek.as_ref().map(|k| k.len()).unwrap_or(0),
+        };
+
+        if setting.dek.is_some() || input.dek.is_some() {
+            if let Some(ref payload) = input.payload {
+                // should be valid COSE encrypt0 payload
+                try_decode_encrypt0(payload)?;
+            }
+            check_encrypt0_upload(upload.as_ref())?;
+        }
+
+        // size of the payload and dek after the update
+        Ok((setting, payload_size + dek_size as u64))
+    }
+
+    // payload_bytes_total of the namespace after updating the setting's payload,
+    // the current payload is archived and archived versions out of the retention policy are pruned.
+    fn bytes_total_after_update(
+        ns: &Namespace,
+        spk: &SettingPathKey,
+        setting: &Setting,
+        next_size: u64,
+        bytes_total: u64,
+        now_ms: u64,
+    ) -> u64 {
+        let archived_size = setting.payload_size();
+        // the current version is archived at now_ms and never pruned by the update
+        let (_, pruned_size) =
+            prunable_archived(ns, spk, setting.version.saturating_add(1), 0, now_ms);
+        (bytes_total + archived_size + next_size).saturating_sub(setting.size() + pruned_size)
+    }
+
+    pub fn create_setting(
+        caller: Principal,
+        spk: SettingPathKey,
+        input: CreateSettingInput,
+        now_ms: u64,
+    ) -> Result<CreateSettingOutput, String> {
+        with_mut(spk.0.clone(), |ns| {
+            let size = check_create_setting(ns, &caller, &spk, &input)?;
+            let expires_at = check_expires_at(input.expires_at, now_ms)?;
+            ns.check_quota(
+                ns.payload_bytes_total.saturating_add(size),
+                ns.settings_total.saturating_add(1),
+            )?;
+            Ok(insert_new_setting(
+                ns, &spk, input, size, expires_at, now_ms,
+            ))
+        })
+    }
+
+    // inserts the checked setting, the quota should have been checked by the caller.
+    fn insert_new_setting(
+        ns: &mut Namespace,
+        spk: &SettingPathKey,
+        input: CreateSettingInput,
+        size: u64,
+        expires_at: Option<u64>,
+        now_ms: u64,
+    ) -> CreateSettingOutput {
+        let tags = input.tags.unwrap_or_default();
+        index_tags(spk, &BTreeMap::new(), &tags);
+        index_expires(spk, None, expires_at);
+        let payload_chunks = input.payload_sha256.map(|_| commit_upload(spk, 1));
+        SETTINGS_STORE.with_borrow_mut(|m| {
+            m.insert(
+                spk.clone(),
+                Setting {
+                    desc: input.desc.unwrap_or_default(),
+                    created_at: now_ms,
+                    updated_at: now_ms,
+                    status: input.status.unwrap_or(0),
+                    tags,
+                    payload: input.payload,
+                    dek: input.dek,
+                    version: 1,
+                    expires_at,
+                    payload_chunks,
+                    ..Default::default()
+                },
+            );
+        });
+        certify_setting(spk);
+
+        ns.payload_bytes_total = ns.payload_bytes_total.saturating_add(size);
+        ns.settings_total = ns.settings_total.saturating_add(1);
+        record_change(spk, SettingChangeKind::Create, 1, now_ms);
+        CreateSettingOutput {
+            created_at: now_ms,
+            updated_at: now_ms,
+            version: 1,
+        }
+    }
+
+    pub fn with_setting_mut<R>(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        f: impl FnOnce(&mut Setting) -> Result<R, String>,
+    ) -> Result<R, String> {
+        with(&spk.0, |ns| {
+            let mut setting = check_writable_setting(&ns, caller, spk)?;
+            if setting.status >= 1 {
+                Err("readonly setting can not be updated".to_string())?;
+            }
+
+            let rt = f(&mut setting)?;
+            SETTINGS_STORE.with_borrow_mut(|r| r.insert(spk.v0(), setting));
+            certify_setting(spk);
+            Ok(rt)
+        })
+    }
+
+    pub fn delete_setting(
+        caller: &Principal,
+        spk: &SettingPathKey,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        with_mut(spk.0.clone(), |ns| {
+            let setting = check_writable_setting(ns, caller, spk)?;
+            if setting.status >= 1 {
+                Err("readonly setting can not be deleted".to_string())?;
+            }
+
+            let size = remove_setting(spk, &setting);
+            ns.payload_bytes_total = ns.payload_bytes_total.saturating_sub(size);
+            ns.settings_total = ns.settings_total.saturating_sub(1);
+            record_change(spk, SettingChangeKind::Delete, setting.version, now_ms);
+            Ok(())
+        })
+    }
+
+    pub fn update_setting_payload(
+        caller: Principal,
+        spk: SettingPathKey,
+        input: UpdateSettingPayloadInput,
+        now_ms: u64,
+    ) -> Result<UpdateSettingOutput, String> {
+        with_mut(spk.0.clone(), |ns| {
+            let (setting, next_size) = check_update_setting_payload(ns, &caller, &spk, &input)?;
+            let bytes_total = bytes_total_after_update(
+                ns,
+                &spk,
+                &setting,
+                next_size,
+                ns.payload_bytes_total,
+                now_ms,
+            );
+            ns.check_quota(bytes_total, ns.settings_total)?;
+            Ok(replace_setting_payload(
+                ns,
+                &spk,
+                setting,
+                input,
+                bytes_total,
+                now_ms,
+            ))
+        })
+    }
+
+    // archives the current payload and replaces it with the checked one,
+    // the quota should have been checked by the caller.
+    fn replace_setting_payload(
+        ns: &mut Namespace,
+        spk: &SettingPathKey,
+        setting: Setting,
+        input: UpdateSettingPayloadInput,
+        bytes_total: u64,
+        now_ms: u64,
+    ) -> UpdateSettingOutput {
+        let mut next = setting.clone();
+        if let Some(status) = input.status {
+            next.status = status;
+        }
+        if let Some(payload) = input.payload {
+            next.payload = Some(payload);
+            next.payload_chunks = None;
+        }
+        if input.payload_sha256.is_some() {
+            next.payload = None;
+            next.payload_chunks = Some(commit_upload(spk, setting.version.saturating_add(1)));
+        }
+        if let Some(dek) = input.dek {
+            next.dek = Some(dek);
+        }
+        next.restored_from = None;
+        replace_setting_version(
+            ns,
+            spk,
+            setting,
+            next,
+            input.deprecate_current.unwrap_or(false),
+            bytes_total,
+            now_ms,
+        )
+    }
+
+    // archives the current payload of `setting` and makes `next` the new current version,
+    // the quota should have been checked by the caller.
+    fn replace_setting_version(
+        ns: &mut Namespace,
+        spk: &SettingPathKey,
+        setting: Setting,
+        mut next: Setting,
+        deprecate_current: bool,
+        bytes_total: u64,
+        now_ms: u64,
+    ) -> UpdateSettingOutput {
+        if setting.payload.is_some() || setting.payload_chunks.is_some() {
+            PAYLOADS_STORE.with_borrow_mut(|r| {
+                r.insert(
+                    spk.clone(),
+                    SettingArchived {
+                        archived_at: now_ms,
+                        deprecated: deprecate_current,
+                        payload: setting.payload,
+                        dek: setting.dek,
+                        payload_chunks: setting.payload_chunks,
+                    },
+                );
+            });
+        }
+
+        next.version = setting.version.saturating_add(1);
+        next.updated_at = now_ms;
+        let output = UpdateSettingOutput {
+            created_at: next.created_at,
+            updated_at: next.updated_at,
+            version: next.version,
+        };
+        // the new version is stored before pruning, so that the payload chunks it refers to are kept
+        SETTINGS_STORE.with_borrow_mut(|r| r.insert(spk.v0(), next));
+        // payload_bytes_total counts the current payload and dek, and archived payloads
+        prune_archived(ns, spk, output.version, 0, now_ms);
+        ns.payload_bytes_total = bytes_total;
+        certify_setting(spk);
+        record_change(
+            spk,
+            SettingChangeKind::UpdatePayload,
+            output.version,
+            now_ms,
+        );
+        output
+    }
+
+    /// Creates a new current version of the setting from the archived `version`.
+    /// Deprecated archived versions are refused unless `force` is true.
+    pub fn restore_setting_version(
+        caller: Principal,
+        spk: SettingPathKey,
+        version: u32,
+        force: bool,
+        now_ms: u64,
+    ) -> Result<UpdateSettingOutput, String> {
+        with_mut(spk.0.clone(), |ns| {
+            // checked first so that archived versions are not revealed to callers without permission
+            let setting = check_writable_setting(ns, &caller, &spk)?;
+            if setting.status >= 1 {
+                Err("readonly setting can not be updated".to_string())?;
+            }
+            if version == 0 || version >= spk.4 {
+                Err("version mismatch".to_string())?;
+            }
+
+            let mut pk = spk.clone();
+            pk.4 = version;
+            let archived = PAYLOADS_STORE
+                .with_borrow(|r| r.get(&pk))
+                .ok_or_else(|| format!("NotFound: setting {} payload not found", &pk))?;
+            if archived.deprecated && !force {
+                Err(format!("archived version {} is deprecated", version))?;
+            }
+
+            // the payload is restored with exactly the DEK it was encrypted with,
+            // or without a DEK if it was archived in plaintext
+            let next = Setting {
+                payload: archived.payload,
+                dek: archived.dek,
+                payload_chunks: archived.payload_chunks,
+                restored_from: Some(version),
+                ..setting.clone()
+            };
+            let bytes_total = bytes_total_after_update(
+                ns,
+                &spk,
+                &setting,
+                next.size(),
+                ns.payload_bytes_total,
+                now_ms,
+            );
+            ns.check_quota(bytes_total, ns.settings_total)?;
+            Ok(replace_setting_version(
+                ns,
+                &spk,
+                setting,
+                next,
+                false,
+                bytes_total,
+                now_ms,
+            ))
+        })
+    }
+
+    pub fn update_setting_info(
+        caller: Principal,
+        spk: SettingPathKey,
+        input: UpdateSettingInfoInput,
+        now_ms: u64,
+    ) -> Result<UpdateSettingOutput, String> {
+        let output = with_setting_mut(&caller, &spk, |setting| {
+            setting.check_cas(input.expected_hash.as_ref(), input.expected_updated_at)?;
+            if input.expires_at.is_some() {
+                let expires_at = check_expires_at(input.expires_at, now_ms)?;
+                index_expires(&spk, setting.expires_at, expires_at);
+                setting.expires_at = expires_at;
+            }
+            let status_changed = input.status.is_some_and(|status| status != setting.status);
+            if let Some(status) = input.status {
                 setting.status = status;
             }
-            if let Some(desc) = input.desc {
-                setting.desc = desc;
+            if let Some(desc) = input.desc {
+                setting.desc = desc;
+            }
+            if let Some(tags) = input.tags {
+                index_tags(&spk, &setting.tags, &tags);
+                setting.tags = tags;
+            }
+            setting.updated_at = now_ms;
+
+            Ok((
+                UpdateSettingOutput {
+                    created_at: setting.created_at,
+                    updated_at: setting.updated_at,
+                    version: setting.version,
+                },
+                status_changed,
+            ))
+        });
+
+        let (output, status_changed) = output?;
+        let kind = if status_changed {
+            SettingChangeKind::UpdateStatus
+        } else {
+            SettingChangeKind::UpdateInfo
+        };
+        record_change(&spk, kind, output.version, now_ms);
+        Ok(output)
+    }
+    /// Applies a batch of setting operations in one namespace, all or nothing.
+    /// Every operation is checked against the current state before any of them is applied.
+    /// It returns one output for each operation, `None` for a delete operation.
+    pub fn batch_update_settings(
+        caller: Principal,
+        ops: Vec<(SettingPathKey, SettingBatchOp)>,
+        now_ms: u64,
+    ) -> Result<Vec<Option<UpdateSettingOutput>>, String> {
+        let namespace = match ops.first() {
+            Some((spk, _)) => spk.0.clone(),
+            None => return Ok(vec![]),
+        };
+
+        with(&namespace, |ns| {
+            let mut keys: BTreeSet<SettingPathKey> = BTreeSet::new();
+            let mut bytes_total = ns.payload_bytes_total;
+            let mut settings_total = ns.settings_total;
+            for (spk, op) in ops.iter() {
+                if spk.0 != namespace {
+                    Err("all operations should be in the same namespace".to_string())?;
+                }
+                if !keys.insert(spk.v0()) {
+                    Err(format!("duplicate setting {} in batch", spk))?;
+                }
+
+                match op {
+                    SettingBatchOp::Create { input, .. } => {
+                        let size = check_create_setting(&ns, &caller, spk, input)?;
+                        check_expires_at(input.expires_at, now_ms)?;
+                        bytes_total = bytes_total.saturating_add(size);
+                        settings_total = settings_total.saturating_add(1);
+                    }
+                    SettingBatchOp::UpdatePayload { input, .. } => {
+                        let (setting, next_size) =
+                            check_update_setting_payload(&ns, &caller, spk, input)?;
+                        bytes_total = bytes_total_after_update(
+                            &ns,
+                            spk,
+                            &setting,
+                            next_size,
+                            bytes_total,
+                            now_ms,
+                        );
+                    }
+                    SettingBatchOp::UpdateInfo { input, .. } => {
+                        let setting = check_writable_setting(&ns, &caller, spk)?;
+                        if setting.status >= 1 {
+                            Err("readonly setting can not be updated".to_string())?;
+                        }
+                        setting
+                            .check_cas(input.expected_hash.as_ref(), input.expected_updated_at)?;
+                        check_expires_at(input.expires_at, now_ms)?;
+                    }
+                    SettingBatchOp::Delete { .. } => {
+                        let setting = check_writable_setting(&ns, &caller, spk)?;
+                        if setting.status >= 1 {
+                            Err("readonly setting can not be deleted".to_string())?;
+                        }
+                        bytes_total = bytes_total.saturating_sub(
+                            setting.size() + archived_size(&spk.v0(), setting.version),
+                        );
+                        settings_total = settings_total.saturating_sub(1);
+                    }
+                }
+            }
+            ns.check_quota(bytes_total, settings_total)
+        })?;
+
+        // All operations are checked, an error here means the state is inconsistent.
+        // Trap to roll back the operations that have been applied.
+        // The quota is checked on the net totals above, so creates and payload updates skip
+        // the per-operation check that could fail before a later delete frees the space.
+        let mut outputs = Vec::with_capacity(ops.len());
+        for (spk, op) in ops {
+            let rt = match op {
+                SettingBatchOp::Create { input, .. } => with_mut(spk.0.clone(), |ns| {
+                    let size = check_create_setting(ns, &caller, &spk, &input)?;
+                    let expires_at = check_expires_at(input.expires_at, now_ms)?;
+                    Ok(Some(insert_new_setting(
+                        ns, &spk, input, size, expires_at, now_ms,
+                    )))
+                }),
+                SettingBatchOp::UpdatePayload { input, .. } => with_mut(spk.0.clone(), |ns| {
+                    let (setting, next_size) =
+                        check_update_setting_payload(ns, &caller, &spk, &input)?;
+                    let bytes_total = bytes_total_after_update(
+                        ns,
+                        &spk,
+                        &setting,
+                        next_size,
+                        ns.payload_bytes_total,
+                        now_ms,
+                    );
+                    Ok(Some(replace_setting_payload(
+                        ns,
+                        &spk,
+                        setting,
+                        input,
+                        bytes_total,
+                        now_ms,
+                    )))
+                }),
+                SettingBatchOp::UpdateInfo { input, .. } => {
+                    update_setting_info(caller, spk, input, now_ms).map(Some)
+                }
+                SettingBatchOp::Delete { .. } => {
+                    delete_setting(&caller, &spk, now_ms).map(|_| None)
+                }
+            };
+            match rt {
+                Ok(output) => outputs.push(output),
+                Err(err) => ic_cdk::trap(format!("failed to apply batch operation: {}", err)),
+            }
+        }
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_certification::LookupResult;
+
+    #[test]
+    fn test_list_setting_keys() {
+        let n1 = "namespace1".to_string();
+        let n2 = "namespace2".to_string();
+        let p0 = Principal::anonymous();
+        let p1 = Principal::from_slice(&[1, 1, 1, 1]);
+        let p2 = Principal::from_slice(&[1, 1, 1, 1, 1]);
+        let p3 = Principal::from_slice(&[1, 1, 1, 1, 2]);
+        assert!(p0 > Principal::management_canister());
+        assert!(p0 < p1);
+        assert!(p1 < p2);
+        assert!(p2 < p3);
+
+        SETTINGS_STORE.with_borrow_mut(|r| {
+            for (i, n) in [n1.clone(), n2.clone()].iter().enumerate() {
+                for p in &[p0, p1, p2, p3] {
+                    r.insert(
+                        SettingPathKey(n.clone(), 0, *p, ByteBuf::from([i as u8]), 0),
+                        Setting::default(),
+                    );
+                    r.insert(
+                        SettingPathKey(n.clone(), 0, *p, ByteBuf::from(p.as_slice()), 0),
+                        Setting::default(),
+                    );
+                    r.insert(
+                        SettingPathKey(n.clone(), 1, *p, ByteBuf::from([i as u8 + 1]), 0),
+                        Setting::default(),
+                    );
+                    r.insert(
+                        SettingPathKey(n.clone(), 1, *p, ByteBuf::from(p.as_slice()), 0),
+                        Setting::default(),
+                    );
+                    r.insert(
+                        SettingPathKey(n.clone(), 2, *p, ByteBuf::from([0]), 0),
+                        Setting::default(),
+                    );
+                }
+            }
+        });
+
+        {
+            let keys = ns::list_setting_keys(&n1, false, None);
+            assert_eq!(
+                keys,
+                vec![
+                    (p0, ByteBuf::from([0])),
+                    (p0, ByteBuf::from(p0.as_slice())),
+                    (p1, ByteBuf::from([0])),
+                    (p1, ByteBuf::from(p1.as_slice())),
+                    (p2, ByteBuf::from([0])),
+                    (p2, ByteBuf::from(p2.as_slice())),
+                    (p3, ByteBuf::from([0])),
+                    (p3, ByteBuf::from(p3.as_slice())),
+                ]
+            );
+            let keys = ns::list_setting_keys(&n1, true, None);
+            assert_eq!(
+                keys,
+                vec![
+                    (p0, ByteBuf::from([1])),
+                    (p0, ByteBuf::from(p0.as_slice())),
+                    (p1, ByteBuf::from([1])),
+                    (p1, ByteBuf::from(p1.as_slice())),
+                    (p2, ByteBuf::from([1])),
+                    (p2, ByteBuf::from(p2.as_slice())),
+                    (p3, ByteBuf::from([1])),
+                    (p3, ByteBuf::from(p3.as_slice())),
+                ]
+            );
+            let keys = ns::list_setting_keys(&n1, false, Some(p1));
+            assert_eq!(
+                keys,
+                vec![(p1, ByteBuf::from([0])), (p1, ByteBuf::from(p1.as_slice())),]
+            );
+            let keys = ns::list_setting_keys(&n1, true, Some(p2));
+            assert_eq!(
+                keys,
+                vec![(p2, ByteBuf::from([1])), (p2, ByteBuf::from(p2.as_slice())),]
+            );
+        }
+
+        {
+            let keys = ns::list_setting_keys(&n2, false, None);
+            assert_eq!(
+                keys,
+                vec![
+                    (p0, ByteBuf::from([1])),
+                    (p0, ByteBuf::from(p0.as_slice())),
+                    (p1, ByteBuf::from([1])),
+                    (p1, ByteBuf::from(p1.as_slice())),
+                    (p2, ByteBuf::from([1])),
+                    (p2, ByteBuf::from(p2.as_slice())),
+                    (p3, ByteBuf::from([1])),
+                    (p3, ByteBuf::from(p3.as_slice())),
+                ]
+            );
+            let keys = ns::list_setting_keys(&n2, true, None);
+            assert_eq!(
+                keys,
+                vec![
+                    (p0, ByteBuf::from([2])),
+                    (p0, ByteBuf::from(p0.as_slice())),
+                    (p1, ByteBuf::from(p1.as_slice())),
+                    (p1, ByteBuf::from([2])),
+                    (p2, ByteBuf::from(p2.as_slice())),
+                    (p2, ByteBuf::from([2])),
+                    (p3, ByteBuf::from(p3.as_slice())),
+                    (p3, ByteBuf::from([2])),
+                ]
+            );
+            let keys = ns::list_setting_keys(&n2, false, Some(p1));
+            assert_eq!(
+                keys,
+                vec![(p1, ByteBuf::from([1])), (p1, ByteBuf::from(p1.as_slice())),]
+            );
+            let keys = ns::list_setting_keys(&n2, true, Some(p2));
+            assert_eq!(
+                keys,
+                vec![(p2, ByteBuf::from(p2.as_slice())), (p2, ByteBuf::from([2]))]
+            );
+        }
+    }
+
+    #[test]
+    fn test_list_settings() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let user = Principal::from_slice(&[1, 1, 1, 2]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "listing".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    users: BTreeSet::from([user]),
+                    ..Default::default()
+                },
+            );
+        });
+        for subject in [manager, user] {
+            for key in 1..=3u8 {
+                ns::create_setting(
+                    manager,
+                    SettingPathKey("listing".to_string(), 0, subject, ByteBuf::from([key]), 0),
+                    CreateSettingInput {
+                        payload: Some(ByteBuf::from(vec![key; key as usize])),
+                        ..Default::default()
+                    },
+                    key as u64,
+                )
+                .unwrap();
+            }
+        }
+
+        let list = |caller: &Principal, input: ListSettingsInput| {
+            let mut keys = Vec::new();
+            let mut input = ListSettingsInput {
+                ns: "listing".to_string(),
+                limit: Some(2),
+                ..input
+            };
+            loop {
+                let output = ns::list_settings(caller, input.clone()).unwrap();
+                keys.extend(output.keys);
+                keys.extend(output.headers.into_iter().map(|h| (h.subject, h.key)));
+                match output.next_start_after {
+                    Some(start_after) => input.start_after = Some(start_after),
+                    None => return keys,
+                }
             }
-            if let Some(tags) = input.tags {
-                setting.tags = tags;
+        };
+
+        let all = list(&manager, ListSettingsInput::default());
+        assert_eq!(all.len(), 6);
+        assert_eq!(all, ns::list_setting_keys("listing", false, None));
+        assert_eq!(
+            list(
+                &manager,
+                ListSettingsInput {
+                    with_header: true,
+                    ..Default::default()
+                }
+            ),
+            all
+        );
+        let user_keys = list(&user, ListSettingsInput::default());
+        assert_eq!(user_keys.len(), 3);
+        assert!(user_keys.iter().all(|k| k.0 == user));
+
+        let output = ns::list_settings(
+            &manager,
+            ListSettingsInput {
+                ns: "listing".to_string(),
+                subject: Some(user),
+                limit: Some(3),
+                with_header: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(output.keys.is_empty());
+        assert!(output.next_start_after.is_none());
+        assert_eq!(output.headers[2].payload_size, 3);
+        assert_eq!(output.headers[2].version, 1);
+        assert_eq!(output.headers[2].updated_at, 3);
+
+        assert_eq!(
+            ns::list_settings(
+                &user,
+                ListSettingsInput {
+                    ns: "listing".to_string(),
+                    start_after: Some((manager, ByteBuf::from([1]))),
+                    ..Default::default()
+                }
+            )
+            .unwrap_err(),
+            "invalid start_after"
+        );
+        assert_eq!(
+            ns::list_settings(
+                &user,
+                ListSettingsInput {
+                    ns: "listing".to_string(),
+                    subject: Some(manager),
+                    ..Default::default()
+                }
+            )
+            .unwrap_err(),
+            "no permission"
+        );
+    }
+
+    #[test]
+    fn test_setting_cas() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "cas".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+
+        let mut spk = SettingPathKey("cas".to_string(), 0, manager, ByteBuf::from([1]), 0);
+        ns::create_setting(
+            manager,
+            spk.clone(),
+            CreateSettingInput {
+                payload: Some(ByteBuf::from([1])),
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+
+        spk.4 = 1;
+        let err = ns::update_setting_info(
+            manager,
+            spk.clone(),
+            UpdateSettingInfoInput {
+                desc: Some("desc".to_string()),
+                expected_updated_at: Some(0),
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap_err();
+        let conflict: SettingConflict = err.parse().unwrap();
+        assert_eq!(conflict.reason, "updated_at mismatch");
+        assert_eq!(conflict.version, 1);
+        assert_eq!(conflict.updated_at, 1);
+
+        ns::update_setting_info(
+            manager,
+            spk.clone(),
+            UpdateSettingInfoInput {
+                desc: Some("desc".to_string()),
+                expected_hash: Some(sha256(&[1]).into()),
+                expected_updated_at: Some(1),
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap();
+
+        let input = UpdateSettingPayloadInput {
+            payload: Some(ByteBuf::from([2])),
+            expected_hash: Some(sha256(&[2]).into()),
+            ..Default::default()
+        };
+        let err = ns::update_setting_payload(manager, spk.clone(), input.clone(), 3).unwrap_err();
+        let conflict: SettingConflict = err.parse().unwrap();
+        assert_eq!(conflict.reason, "payload hash mismatch");
+        assert_eq!(conflict.version, 1);
+        assert_eq!(conflict.updated_at, 2);
+
+        let output = ns::update_setting_payload(
+            manager,
+            spk.clone(),
+            UpdateSettingPayloadInput {
+                expected_hash: Some(sha256(&[1]).into()),
+                expected_updated_at: Some(2),
+                ..input
+            },
+            3,
+        )
+        .unwrap();
+        assert_eq!(output.version, 2);
+        assert_eq!(output.updated_at, 3);
+    }
+
+    #[test]
+    fn test_batch_update_settings() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "batch".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+
+        let spk = |key: u8, version: u32| {
+            SettingPathKey(
+                "batch".to_string(),
+                0,
+                manager,
+                ByteBuf::from([key]),
+                version,
+            )
+        };
+        let path = |key: u8, version: u32| SettingPath {
+            ns: "batch".to_string(),
+            key: ByteBuf::from([key]),
+            version,
+            ..Default::default()
+        };
+        let payload_bytes_total =
+            || ns::with(&"batch".to_string(), |ns| Ok(ns.payload_bytes_total)).unwrap();
+        for key in [1, 2] {
+            ns::create_setting(
+                manager,
+                spk(key, 0),
+                CreateSettingInput {
+                    payload: Some(ByteBuf::from([key])),
+                    ..Default::default()
+                },
+                1,
+            )
+            .unwrap();
+        }
+        assert_eq!(payload_bytes_total(), 2);
+
+        let batch = |v: u32| {
+            vec![
+                (
+                    spk(3, 0),
+                    SettingBatchOp::Create {
+                        path: path(3, 0),
+                        input: CreateSettingInput {
+                            payload: Some(ByteBuf::from([3, 3])),
+                            ..Default::default()
+                        },
+                    },
+                ),
+                (
+                    spk(1, 1),
+                    SettingBatchOp::UpdatePayload {
+                        path: path(1, 1),
+                        input: UpdateSettingPayloadInput {
+                            payload: Some(ByteBuf::from([1, 1, 1])),
+                            ..Default::default()
+                        },
+                    },
+                ),
+                (spk(2, v), SettingBatchOp::Delete { path: path(2, v) }),
+            ]
+        };
+
+        assert_eq!(
+            ns::batch_update_settings(manager, batch(2), 2).unwrap_err(),
+            "version mismatch"
+        );
+        assert!(ns::try_get_setting(&manager, &spk(3, 0), 2).is_none());
+        assert_eq!(
+            ns::try_get_setting(&manager, &spk(1, 0), 2)
+                .unwrap()
+                .version,
+            1
+        );
+        assert_eq!(payload_bytes_total(), 2);
+
+        let mut ops = batch(1);
+        ops.push(ops[1].clone());
+        assert_eq!(
+            ns::batch_update_settings(manager, ops, 2).unwrap_err(),
+            format!("duplicate setting {} in batch", spk(1, 1))
+        );
+
+        let outputs = ns::batch_update_settings(manager, batch(1), 2).unwrap();
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[0].as_ref().unwrap().version, 1);
+        assert_eq!(outputs[1].as_ref().unwrap().version, 2);
+        assert!(outputs[2].is_none());
+        assert!(ns::try_get_setting(&manager, &spk(3, 0), 2).is_some());
+        assert!(ns::try_get_setting(&manager, &spk(2, 0), 2).is_none());
+        assert_eq!(payload_bytes_total(), 6);
+    }
+
+    #[test]
+    fn test_search_settings() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let user = Principal::from_slice(&[1, 1, 1, 2]);
+        let other = Principal::from_slice(&[1, 1, 1, 3]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "search".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    users: BTreeSet::from([user]),
+                    ..Default::default()
+                },
+            );
+        });
+
+        let spk =
+            |key: u8| SettingPathKey("search".to_string(), 0, manager, ByteBuf::from([key]), 0);
+        for (key, env) in [(1, "prod"), (2, "prod_eu"), (3, "dev"), (4, "prod")] {
+            ns::create_setting(
+                manager,
+                spk(key),
+                CreateSettingInput {
+                    payload: Some(ByteBuf::from([key])),
+                    tags: Some(BTreeMap::from([("env".to_string(), env.to_string())])),
+                    ..Default::default()
+                },
+                1,
+            )
+            .unwrap();
+        }
+        let search = |caller: &Principal, value: &str, prefix: bool, cursor: Option<ByteBuf>| {
+            ns::search_settings(
+                caller,
+                SearchSettingsInput {
+                    ns: "search".to_string(),
+                    tag: "env".to_string(),
+                    value: value.to_string(),
+                    prefix,
+                    cursor,
+                    limit: Some(1),
+                },
+                2,
+            )
+        };
+        let keys = |caller: &Principal, value: &str, prefix: bool| {
+            let mut keys = Vec::new();
+            let mut cursor = None;
+            loop {
+                let output = search(caller, value, prefix, cursor).unwrap();
+                for info in output.settings {
+                    assert!(info.payload.is_none());
+                    keys.push(info.key[0]);
+                }
+                cursor = output.next_cursor;
+                if cursor.is_none() {
+                    return keys;
+                }
             }
-            setting.updated_at = now_ms;
+        };
+
+        assert_eq!(keys(&manager, "prod", false), vec![1, 4]);
+        assert_eq!(keys(&manager, "prod", true), vec![1, 4, 2]);
+        assert_eq!(keys(&manager, "d", true), vec![3]);
+        assert_eq!(keys(&manager, "test", true), Vec::<u8>::new());
+        assert_eq!(
+            search(&other, "prod", false, None).unwrap_err(),
+            "no permission"
+        );
+        assert!(search(&manager, "dev", false, Some(ByteBuf::from([0]))).is_err());
+
+        // users only see the settings they can read
+        assert_eq!(keys(&user, "prod", true), Vec::<u8>::new());
+        ns::with_setting_mut(
+            &manager,
+            &SettingPathKey("search".to_string(), 0, manager, ByteBuf::from([4]), 1),
+            |s| {
+                s.readers.insert(user);
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(keys(&user, "prod", true), vec![4]);
+
+        // the index follows tag updates and deletes
+        let mut spk1 = spk(1);
+        spk1.4 = 1;
+        ns::update_setting_info(
+            manager,
+            spk1.clone(),
+            UpdateSettingInfoInput {
+                tags: Some(BTreeMap::from([("env".to_string(), "dev".to_string())])),
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap();
+        assert_eq!(keys(&manager, "prod", false), vec![4]);
+        assert_eq!(keys(&manager, "dev", false), vec![1, 3]);
+        ns::delete_setting(&manager, &spk1, 3).unwrap();
+        assert_eq!(keys(&manager, "dev", false), vec![3]);
+
+        ns::rebuild_tag_index();
+        assert_eq!(keys(&manager, "dev", false), vec![3]);
+        assert_eq!(keys(&manager, "prod", true), vec![4, 2]);
+    }
+
+    #[test]
+    fn test_setting_expiry() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "expiry".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+        let spk =
+            |key: u8| SettingPathKey("expiry".to_string(), 0, manager, ByteBuf::from([key]), 0);
+        let payload_bytes_total =
+            || ns::with(&"expiry".to_string(), |ns| Ok(ns.payload_bytes_total)).unwrap();
+
+        assert_eq!(
+            ns::create_setting(
+                manager,
+                spk(1),
+                CreateSettingInput {
+                    expires_at: Some(10),
+                    ..Default::default()
+                },
+                10,
+            )
+            .unwrap_err(),
+            "expires_at should be in the future"
+        );
+        for (key, expires_at) in [(1, Some(100)), (2, Some(200)), (3, None)] {
+            ns::create_setting(
+                manager,
+                spk(key),
+                CreateSettingInput {
+                    payload: Some(ByteBuf::from([key; 10])),
+                    tags: Some(BTreeMap::from([("env".to_string(), "dev".to_string())])),
+                    expires_at,
+                    ..Default::default()
+                },
+                10,
+            )
+            .unwrap();
+        }
+        let mut spk1 = spk(1);
+        spk1.4 = 1;
+        ns::update_setting_payload(
+            manager,
+            spk1.clone(),
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from([1; 5])),
+                ..Default::default()
+            },
+            20,
+        )
+        .unwrap();
+        assert_eq!(payload_bytes_total(), 35);
+
+        // key 3 gets an expiry, key 2 does not expire anymore
+        let mut spk2 = spk(2);
+        spk2.4 = 1;
+        ns::update_setting_info(
+            manager,
+            spk2,
+            UpdateSettingInfoInput {
+                expires_at: Some(0),
+                ..Default::default()
+            },
+            30,
+        )
+        .unwrap();
+        let mut spk3 = spk(3);
+        spk3.4 = 1;
+        ns::update_setting_info(
+            manager,
+            spk3,
+            UpdateSettingInfoInput {
+                expires_at: Some(300),
+                ..Default::default()
+            },
+            30,
+        )
+        .unwrap();
+
+        assert!(ns::try_get_setting(&manager, &spk(1), 99).is_some());
+        assert!(ns::try_get_setting(&manager, &spk(1), 100).is_none());
+        assert_eq!(
+            ns::get_setting_info(manager, spk(3), 300).unwrap_err(),
+            format!("NotFound: setting {} not found or no permission", spk(3))
+        );
+        assert_eq!(
+            ns::get_setting_info(manager, spk(3), 299)
+                .unwrap()
+                .expires_at,
+            Some(300)
+        );
+
+        assert_eq!(ns::purge_expired_settings(99, 10), 0);
+        assert_eq!(ns::purge_expired_settings(1000, 10), 2);
+        assert_eq!(ns::purge_expired_settings(1000, 10), 0);
+        assert!(ns::try_get_setting(&manager, &spk(2), 1000).is_some());
+        assert!(SETTINGS_STORE.with_borrow(|r| r.get(&spk(1)).is_none()));
+        assert!(PAYLOADS_STORE.with_borrow(|r| r.get(&spk1).is_none()));
+        assert_eq!(payload_bytes_total(), 10);
+        let keys = ns::search_settings(
+            &manager,
+            SearchSettingsInput {
+                ns: "expiry".to_string(),
+                tag: "env".to_string(),
+                value: "dev".to_string(),
+                ..Default::default()
+            },
+            1000,
+        )
+        .unwrap();
+        assert_eq!(keys.settings.len(), 1);
+    }
+
+    #[test]
+    fn test_prune_archived() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "prune".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+        let mut spk = SettingPathKey("prune".to_string(), 0, manager, ByteBuf::from([1]), 0);
+        let payload_bytes_total =
+            || ns::with(&"prune".to_string(), |ns| Ok(ns.payload_bytes_total)).unwrap();
+        let archived_versions =
+            || PAYLOADS_STORE.with_borrow(|r| r.keys().map(|k| k.4).collect::<Vec<_>>());
+
+        ns::create_setting(
+            manager,
+            spk.clone(),
+            CreateSettingInput {
+                payload: Some(ByteBuf::from([1])),
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+        // archived versions 1..=5 with 1..=5 bytes, current version 6 with 6 bytes
+        for v in 1..=5u32 {
+            spk.4 = v;
+            ns::update_setting_payload(
+                manager,
+                spk.clone(),
+                UpdateSettingPayloadInput {
+                    payload: Some(ByteBuf::from(vec![1; v as usize + 1])),
+                    ..Default::default()
+                },
+                v as u64 * 10,
+            )
+            .unwrap();
+        }
+        assert_eq!(archived_versions(), vec![1, 2, 3, 4, 5]);
+        assert_eq!(payload_bytes_total(), 21);
+
+        spk.4 = 3;
+        assert_eq!(ns::prune_setting_archived(&manager, &spk, 70).unwrap(), 2);
+        assert_eq!(archived_versions(), vec![3, 4, 5]);
+        assert_eq!(payload_bytes_total(), 18);
+
+        // keep the last 2 versions on write
+        ns::update_namespace_info(
+            &manager,
+            UpdateNamespaceInput {
+                name: "prune".to_string(),
+                retain_versions: Some(2),
+                ..Default::default()
+            },
+            70,
+        )
+        .unwrap();
+        spk.4 = 6;
+        ns::update_setting_payload(
+            manager,
+            spk.clone(),
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from([1; 7])),
+                ..Default::default()
+            },
+            80,
+        )
+        .unwrap();
+        assert_eq!(archived_versions(), vec![5, 6]);
+        assert_eq!(payload_bytes_total(), 5 + 6 + 7);
+
+        // versions older than 25ms are pruned by the timer
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            let mut ns = r.get(&"prune".to_string()).unwrap();
+            ns.retain_versions = 0;
+            ns.retain_archived_ms = 25;
+            r.insert("prune".to_string(), ns);
+        });
+        assert_eq!(ns::prune_archived_settings(100, 1), 1);
+        assert_eq!(ns::prune_archived_settings(100, 1), 0);
+        assert_eq!(ns::prune_archived_settings(100, 1), 0);
+        assert_eq!(archived_versions(), vec![6]);
+        assert_eq!(payload_bytes_total(), 6 + 7);
+
+        spk.4 = 7;
+        ns::delete_setting(&manager, &spk, 100).unwrap();
+        assert!(archived_versions().is_empty());
+        assert_eq!(payload_bytes_total(), 0);
+    }
+
+    #[test]
+    fn test_restore_setting_version() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "restore".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+        let mut spk = SettingPathKey("restore".to_string(), 0, manager, ByteBuf::from([1]), 0);
+        ns::create_setting(
+            manager,
+            spk.clone(),
+            CreateSettingInput {
+                payload: Some(ByteBuf::from([1])),
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+        for v in 1..=2u32 {
+            spk.4 = v;
+            ns::update_setting_payload(
+                manager,
+                spk.clone(),
+                UpdateSettingPayloadInput {
+                    payload: Some(ByteBuf::from([v as u8 + 1])),
+                    deprecate_current: Some(v == 2),
+                    ..Default::default()
+                },
+                v as u64 + 1,
+            )
+            .unwrap();
+        }
+
+        spk.4 = 3;
+        let outsider = Principal::from_slice(&[3, 3, 3, 3]);
+        for version in [1, 2, 9] {
+            assert_eq!(
+                ns::restore_setting_version(outsider, spk.clone(), version, false, 4).unwrap_err(),
+                "no permission"
+            );
+        }
+        assert_eq!(
+            ns::restore_setting_version(manager, spk.clone(), 3, false, 4).unwrap_err(),
+            "version mismatch"
+        );
+        assert_eq!(
+            ns::restore_setting_version(manager, spk.clone(), 2, false, 4).unwrap_err(),
+            "archived version 2 is deprecated"
+        );
+        let output = ns::restore_setting_version(manager, spk.clone(), 1, false, 4).unwrap();
+        assert_eq!(output.version, 4);
+        let info = ns::get_setting(manager, spk.v0(), 4).unwrap();
+        assert_eq!(info.payload.unwrap().as_slice(), &[1]);
+        assert_eq!(info.restored_from, Some(1));
+
+        spk.4 = 4;
+        ns::restore_setting_version(manager, spk.clone(), 2, true, 5).unwrap();
+        let info = ns::get_setting(manager, spk.v0(), 5).unwrap();
+        assert_eq!(info.payload.unwrap().as_slice(), &[2]);
+        assert_eq!(info.restored_from, Some(2));
+
+        spk.4 = 5;
+        ns::update_setting_payload(
+            manager,
+            spk.clone(),
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from([9])),
+                ..Default::default()
+            },
+            6,
+        )
+        .unwrap();
+        let info = ns::get_setting_info(manager, spk.v0(), 6).unwrap();
+        assert_eq!(info.restored_from, None);
+        SETTINGS_STORE.with_borrow_mut(|r| {
+            let mut setting = r.get(&spk.v0()).unwrap();
+            setting.dek = Some(ByteBuf::from([8]));
+            r.insert(spk.v0(), setting);
+        });
+        let info = ns::get_setting_info(manager, spk.v0(), 6).unwrap();
+        assert_eq!(info.dek, None);
+        // the DEK is returned without the payload
+        let info = ns::get_setting_dek(manager, spk.v0(), 6).unwrap();
+        assert_eq!(info.version, 6);
+        assert_eq!(info.dek, Some(ByteBuf::from([8])));
+        assert_eq!(info.payload, None);
+
+        // a plaintext version is restored without the DEK of the encrypted current version
+        spk.4 = 6;
+        ns::restore_setting_version(manager, spk.clone(), 1, false, 7).unwrap();
+        let info = ns::get_setting(manager, spk.v0(), 7).unwrap();
+        assert_eq!(info.version, 7);
+        assert_eq!(info.payload.unwrap().as_slice(), &[1]);
+        assert_eq!(info.dek, None);
+        // and the encrypted version is restored with its DEK
+        spk.4 = 7;
+        ns::restore_setting_version(manager, spk.clone(), 6, false, 8).unwrap();
+        let info = ns::get_setting(manager, spk.v0(), 8).unwrap();
+        assert_eq!(info.payload.unwrap().as_slice(), &[9]);
+        assert_eq!(info.dek, Some(ByteBuf::from([8])));
+        assert_eq!(info.restored_from, Some(6));
+    }
+
+    #[test]
+    fn test_change_log() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let user = Principal::from_slice(&[2, 2, 2, 2]);
+        let outsider = Principal::from_slice(&[3, 3, 3, 3]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "changes".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    users: BTreeSet::from([user]),
+                    ..Default::default()
+                },
+            );
+        });
+        let changes = |caller: &Principal, cursor: u64, limit: Option<u32>| {
+            let output =
+                ns::changes_since(caller, "changes".to_string(), cursor, limit, 0).unwrap();
+            let changes: Vec<(u64, SettingChangeKind, u32)> = output
+                .changes
+                .into_iter()
+                .map(|c| (c.seq, c.kind, c.version))
+                .collect();
+            (changes, output.next_cursor)
+        };
+
+        let mut s1 = SettingPathKey("changes".to_string(), 0, manager, ByteBuf::from([1]), 0);
+        let mut u1 = SettingPathKey("changes".to_string(), 1, user, ByteBuf::from([1]), 0);
+        ns::create_setting(manager, s1.clone(), CreateSettingInput::default(), 1).unwrap();
+        ns::create_setting(user, u1.clone(), CreateSettingInput::default(), 2).unwrap();
+        s1.4 = 1;
+        ns::update_setting_payload(
+            manager,
+            s1.clone(),
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from([1])),
+                ..Default::default()
+            },
+            3,
+        )
+        .unwrap();
+        s1.4 = 2;
+        ns::update_setting_info(
+            manager,
+            s1.clone(),
+            UpdateSettingInfoInput {
+                desc: Some("s1".to_string()),
+                ..Default::default()
+            },
+            4,
+        )
+        .unwrap();
+        let version = ns::with_setting_mut(&manager, &s1, |setting| {
+            setting.readers.insert(user);
+            Ok(setting.version)
+        })
+        .unwrap();
+        ns::record_change(&s1, SettingChangeKind::UpdateReaders, version, 5);
+        u1.4 = 1;
+        ns::update_setting_info(
+            user,
+            u1.clone(),
+            UpdateSettingInfoInput {
+                status: Some(1),
+                ..Default::default()
+            },
+            6,
+        )
+        .unwrap();
+
+        let all = vec![
+            (1, SettingChangeKind::Create, 1),
+            (2, SettingChangeKind::Create, 1),
+            (3, SettingChangeKind::UpdatePayload, 2),
+            (4, SettingChangeKind::UpdateInfo, 2),
+            (5, SettingChangeKind::UpdateReaders, 2),
+            (6, SettingChangeKind::UpdateStatus, 1),
+        ];
+        assert_eq!(changes(&manager, 0, None), (all.clone(), 6));
+        // the user is a reader of s1
+        assert_eq!(changes(&user, 0, None), (all.clone(), 6));
+        assert_eq!(changes(&manager, 0, Some(2)), (all[0..2].to_vec(), 2));
+        assert_eq!(changes(&manager, 2, Some(2)), (all[2..4].to_vec(), 4));
+        assert_eq!(changes(&manager, 6, None), (vec![], 6));
+        assert_eq!(
+            ns::changes_since(&outsider, "changes".to_string(), 0, None, 0).unwrap_err(),
+            "no permission"
+        );
+
+        ns::delete_setting(&manager, &s1, 7).unwrap();
+        assert_eq!(
+            changes(&manager, 6, None),
+            (vec![(7, SettingChangeKind::Delete, 2)], 7)
+        );
+        // the user can not read s1 anymore, but the cursor still advances
+        assert_eq!(
+            changes(&user, 0, None),
+            (
+                vec![
+                    (2, SettingChangeKind::Create, 1),
+                    (6, SettingChangeKind::UpdateStatus, 1)
+                ],
+                7
+            )
+        );
+
+        // the oldest changes are dropped when the log is full
+        for i in 0..MAX_CHANGES_PER_NAMESPACE {
+            ns::record_change(&u1, SettingChangeKind::UpdateInfo, 1, 8 + i);
+        }
+        let (page, next_cursor) = changes(&manager, 0, Some(1));
+        assert_eq!(page, vec![(8, SettingChangeKind::UpdateInfo, 1)]);
+        assert_eq!(next_cursor, 8);
+        // a cursor whose following changes have been dropped must resync
+        assert_eq!(changes(&manager, 7, Some(1)).1, 8);
+        assert_eq!(
+            ns::changes_since(&manager, "changes".to_string(), 6, None, 8).unwrap_err(),
+            "cursor too old: changes after 6 have been dropped, the oldest change is 8"
+        );
+
+        // the change log is purged with the namespace
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "changes_deleted".to_string(),
+                Namespace {
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+        let logs = || {
+            CHANGES_STORE.with_borrow(|r| {
+                r.iter()
+                    .filter(|entry| entry.value().spk.0 == "changes_deleted")
+                    .count()
+            })
+        };
+        let mut d1 = SettingPathKey(
+            "changes_deleted".to_string(),
+            0,
+            manager,
+            ByteBuf::from([1]),
+            0,
+        );
+        ns::create_setting(manager, d1.clone(), CreateSettingInput::default(), 9).unwrap();
+        d1.4 = 1;
+        ns::delete_setting(&manager, &d1, 10).unwrap();
+        assert_eq!(logs(), 2);
+        ns::delete_namespace(&manager, "changes_deleted".to_string()).unwrap();
+        assert_eq!(logs(), 0);
+        assert_eq!(changes(&manager, 0, Some(1)).1, 8);
+    }
+
+    #[test]
+    fn test_certified_settings() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "certified".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+        let mut spk = SettingPathKey("certified".to_string(), 0, manager, ByteBuf::from([1]), 0);
+        let key = certified_setting_key("certified", false, &manager, &spk.3);
+        state::with_mut(|s| s.certified_built = true);
+        let witness = |spk: &SettingPathKey, with_payload: bool| -> HashTree {
+            cbor2::from_slice(&state::setting_witness(spk, with_payload).unwrap()).unwrap()
+        };
+        let meta_path = [LABEL_SETTINGS, key.as_slice(), LABEL_SETTING_META];
+        let payload_path = [LABEL_SETTINGS, key.as_slice(), LABEL_SETTING_PAYLOAD];
+
+        ns::create_setting(
+            manager,
+            spk.clone(),
+            CreateSettingInput {
+                payload: Some(ByteBuf::from([1, 2, 3])),
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+        let info = ns::get_setting(manager, spk.clone(), 1).unwrap();
+        let tree = witness(&spk, true);
+        assert_eq!(tree.digest(), state::certified_data());
+        assert_eq!(
+            tree.lookup_path(meta_path),
+            LookupResult::Found(&info.meta_hash())
+        );
+        assert_eq!(
+            tree.lookup_path(payload_path),
+            LookupResult::Found(&info.payload_hash())
+        );
+
+        // the payload hash is pruned in the witness of setting info
+        let tree = witness(&spk, false);
+        assert_eq!(tree.digest(), state::certified_data());
+        assert_eq!(
+            tree.lookup_path(meta_path),
+            LookupResult::Found(&info.meta_hash())
+        );
+        assert_eq!(tree.lookup_path(payload_path), LookupResult::Unknown);
+
+        spk.4 = 1;
+        ns::update_setting_info(
+            manager,
+            spk.clone(),
+            UpdateSettingInfoInput {
+                desc: Some("certified".to_string()),
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap();
+        let updated = ns::get_setting_info(manager, spk.clone(), 2).unwrap();
+        assert_ne!(updated.meta_hash(), info.meta_hash());
+        let tree = witness(&spk, false);
+        assert_eq!(tree.digest(), state::certified_data());
+        assert_eq!(
+            tree.lookup_path(meta_path),
+            LookupResult::Found(&updated.meta_hash())
+        );
+
+        let root = state::certified_data();
+        ns::load_certified_settings();
+        assert_eq!(state::certified_data(), root);
+
+        // the tree is loaded from the stored hashes, without hashing the settings
+        let certified = CERTIFIED_STORE.with_borrow(|r| r.get(&spk.v0())).unwrap();
+        assert_eq!(
+            CertifiedSetting::from_bytes(certified.to_bytes()),
+            certified
+        );
+        CERTIFIED_STORE.with_borrow_mut(|r| {
+            r.insert(
+                spk.v0(),
+                CertifiedSetting {
+                    meta: [0u8; 32],
+                    payload: [0u8; 32],
+                },
+            )
+        });
+        ns::load_certified_settings();
+        assert_ne!(state::certified_data(), root);
+
+        // after upgrading from a version without the stored hashes, they are built in batches,
+        // and settings are not certified until the build has finished
+        state::with_mut(|s| s.certified_built = false);
+        let mut spk2 = SettingPathKey("certified".to_string(), 0, manager, ByteBuf::from([2]), 0);
+        ns::create_setting(manager, spk2.clone(), CreateSettingInput::default(), 3).unwrap();
+        assert!(state::setting_witness(&spk, false).is_none());
+        assert!(!ns::build_certified_settings(1));
+        assert_eq!(
+            CERTIFIED_STORE.with_borrow(|r| r.get(&spk.v0())),
+            Some(certified.clone())
+        );
+        assert!(CERTIFIED_STORE.with_borrow(|r| r.get(&spk2)).is_none());
+        // a setting already hashed is updated by its changes, the others by the build
+        ns::update_setting_info(
+            manager,
+            spk.clone(),
+            UpdateSettingInfoInput {
+                desc: Some("rebuilt".to_string()),
+                ..Default::default()
+            },
+            3,
+        )
+        .unwrap();
+        assert_ne!(
+            CERTIFIED_STORE.with_borrow(|r| r.get(&spk.v0())),
+            Some(certified)
+        );
+        assert!(!ns::build_certified_settings(1));
+        assert!(ns::build_certified_settings(1));
+        assert_eq!(CERTIFIED_STORE.with_borrow(|r| r.len()), 2);
+        let rebuilt = ns::get_setting_info(manager, spk.clone(), 3).unwrap();
+        let tree = witness(&spk, false);
+        assert_eq!(tree.digest(), state::certified_data());
+        assert_eq!(
+            tree.lookup_path(meta_path),
+            LookupResult::Found(&rebuilt.meta_hash())
+        );
+
+        ns::delete_setting(&manager, &spk, 4).unwrap();
+        let tree = witness(&spk, false);
+        assert_eq!(tree.digest(), state::certified_data());
+        assert_eq!(tree.lookup_path(meta_path), LookupResult::Absent);
+        assert!(CERTIFIED_STORE.with_borrow(|r| r.get(&spk.v0())).is_none());
+        spk2.4 = 1;
+        ns::delete_setting(&manager, &spk2, 4).unwrap();
+        assert!(CERTIFIED_STORE.with_borrow(|r| r.is_empty()));
+    }
+
+    #[test]
+    fn test_chunked_payload() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let other = Principal::from_slice(&[1, 1, 1, 2]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "chunked".to_string(),
+                Namespace {
+                    max_payload_size: MAX_CHUNKED_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+
+        let spk = SettingPathKey("chunked".to_string(), 0, manager, ByteBuf::from([1]), 0);
+        let payload: Vec<u8> = (0..CHUNK_SIZE * 8 + 10).map(|i| i as u8).collect();
+        let hash = sha256(&payload);
+        assert_eq!(
+            ns::begin_upload(&other, &spk, payload.len() as u64, 1).unwrap_err(),
+            "no permission"
+        );
+        assert_eq!(
+            ns::begin_upload(&manager, &spk, MAX_CHUNKED_PAYLOAD_SIZE + 1, 1).unwrap_err(),
+            "payload size exceeds the limit"
+        );
+        ns::begin_upload(&manager, &spk, payload.len() as u64, 1).unwrap();
+
+        let chunks: Vec<&[u8]> = payload.chunks(CHUNK_SIZE as usize).collect();
+        assert_eq!(chunks.len(), 9);
+        assert!(ns::upload_chunk(&other, &spk, 0, chunks[0])
+            .unwrap_err()
+            .starts_with("NotFound"));
+        assert!(ns::upload_chunk(&manager, &spk, 0, chunks[8])
+            .unwrap_err()
+            .starts_with("chunk size mismatch"));
+        assert!(ns::upload_chunk(&manager, &spk, u32::MAX, chunks[8])
+            .unwrap_err()
+            .ends_with("out of range"));
+        assert!(ns::upload_chunk(&manager, &spk, 9, chunks[8])
+            .unwrap_err()
+            .contains("out of range"));
+        for (i, chunk) in chunks.iter().enumerate().rev().skip(1) {
+            ns::upload_chunk(&manager, &spk, i as u32, chunk).unwrap();
+        }
+        assert_eq!(
+            ns::verify_upload(&manager, &spk, &hash).unwrap_err(),
+            "upload is incomplete, received 8 of 9 chunks"
+        );
+        ns::upload_chunk(&manager, &spk, 8, chunks[8]).unwrap();
+        assert_eq!(
+            ns::verify_upload(&manager, &spk, &[0u8; 32]).unwrap_err(),
+            "payload sha256 mismatch"
+        );
+        let input = CreateSettingInput {
+            payload_sha256: Some(ByteArray::new(hash)),
+            ..Default::default()
+        };
+        assert!(ns::create_setting(manager, spk.clone(), input.clone(), 1)
+            .unwrap_err()
+            .ends_with("is not verified with the payload sha256"));
+        ns::verify_upload(&manager, &spk, &hash).unwrap();
+        ns::create_setting(manager, spk.clone(), input, 1).unwrap();
+        assert!(ns::verify_upload(&manager, &spk, &hash).is_err());
+        assert!(UPLOAD_CHUNKS_STORE.with_borrow(|r| r.is_empty()));
+        // the payload is stored in chunks, not in the setting
+        let setting = SETTINGS_STORE.with_borrow(|r| r.get(&spk)).unwrap();
+        assert_eq!(setting.payload, None);
+        assert_eq!(setting.payload_size(), payload.len() as u64);
+        assert_eq!(PAYLOAD_CHUNKS_STORE.with_borrow(|r| r.len()), 9);
+
+        assert_eq!(
+            ns::get_setting(manager, spk.clone(), 1).unwrap_err(),
+            "payload size exceeds the response limit, use setting_get_chunk"
+        );
+        let mut downloaded = Vec::new();
+        for i in 0..9 {
+            let output = ns::get_setting_chunk(manager, spk.clone(), i, 1).unwrap();
+            assert_eq!(output.version, 1);
+            assert_eq!(output.payload_size, payload.len() as u64);
+            assert_eq!(output.chunks(), 9);
+            downloaded.extend_from_slice(&output.chunk);
+        }
+        assert_eq!(downloaded, payload);
+        assert!(ns::get_setting_chunk(manager, spk.clone(), u32::MAX, 1)
+            .unwrap_err()
+            .ends_with("out of range"));
+        assert!(ns::get_setting_chunk(manager, spk.clone(), 9, 1)
+            .unwrap_err()
+            .contains("out of range"));
+
+        // the chunks are archived with the version
+        let at = |version: u32| {
+            let mut spk = spk.clone();
+            spk.4 = version;
+            spk
+        };
+        ns::update_setting_payload(
+            manager,
+            at(1),
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from([1])),
+                expected_hash: Some(ByteArray::new(hash)),
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap();
+        let output = ns::get_setting_chunk(manager, at(1), 8, 2).unwrap();
+        assert_eq!(output.version, 1);
+        assert_eq!(output.chunk.as_slice(), chunks[8]);
+        assert_eq!(
+            ns::get_setting(manager, spk.clone(), 2).unwrap().payload,
+            Some(ByteBuf::from([1]))
+        );
+
+        // a restored version refers to the same chunks, they are kept when the archived one is pruned
+        ns::restore_setting_version(manager, at(2), 1, false, 3).unwrap();
+        assert_eq!(PAYLOAD_CHUNKS_STORE.with_borrow(|r| r.len()), 9);
+        assert_eq!(ns::prune_setting_archived(&manager, &at(3), 3).unwrap(), 2);
+        assert_eq!(PAYLOAD_CHUNKS_STORE.with_borrow(|r| r.len()), 9);
+        let output = ns::get_setting_chunk(manager, spk.clone(), 0, 3).unwrap();
+        assert_eq!(output.version, 3);
+        assert_eq!(output.payload_size, payload.len() as u64);
+        assert_eq!(output.chunk.as_slice(), chunks[0]);
+
+        ns::delete_setting(&manager, &at(3), 4).unwrap();
+        assert!(PAYLOAD_CHUNKS_STORE.with_borrow(|r| r.is_empty()));
+    }
+
+    #[test]
+    fn test_upload_limits() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "uploads".to_string(),
+                Namespace {
+                    max_payload_size: MAX_CHUNKED_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
+            );
+        });
+        let spk =
+            |key: u8| SettingPathKey("uploads".to_string(), 0, manager, ByteBuf::from([key]), 0);
+
+        for key in 1..=MAX_UPLOADS_PER_CALLER as u8 {
+            ns::begin_upload(&manager, &spk(key), CHUNK_SIZE as u64 + 1, 1).unwrap();
+        }
+        ns::upload_chunk(&manager, &spk(1), 1, &[1]).unwrap();
+        assert_eq!(UPLOAD_CHUNKS_STORE.with_borrow(|r| r.len()), 1);
+        assert_eq!(
+            ns::begin_upload(&manager, &spk(9), 1, 2).unwrap_err(),
+            format!(
+                "too many pending uploads, the limit is {} per caller",
+                MAX_UPLOADS_PER_CALLER
+            )
+        );
+
+        // restarting an upload drops the received chunks
+        ns::begin_upload(&manager, &spk(1), 1, 2).unwrap();
+        assert!(UPLOAD_CHUNKS_STORE.with_borrow(|r| r.is_empty()));
+        assert!(ns::upload_chunk(&manager, &spk(1), 1, &[1])
+            .unwrap_err()
+            .ends_with("out of range"));
+
+        // expired uploads are removed
+        ns::upload_chunk(&manager, &spk(1), 0, &[1]).unwrap();
+        ns::begin_upload(&manager, &spk(9), 1, 2 + 3600 * 1000).unwrap();
+        assert_eq!(UPLOADS_STORE.with_borrow(|r| r.len()), 1);
+        assert!(UPLOAD_CHUNKS_STORE.with_borrow(|r| r.is_empty()));
+
+        let size = MAX_UPLOADS_SIZE / 2;
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            let mut ns = r.get(&"uploads".to_string()).unwrap();
+            ns.max_payload_size = size;
+            r.insert("uploads".to_string(), ns);
+        });
+        ns::begin_upload(&manager, &spk(1), size, 3).unwrap();
+        assert_eq!(
+            ns::begin_upload(&manager, &spk(3), size, 3).unwrap_err(),
+            "too many pending uploads, try again later"
+        );
+    }
+
+    #[test]
+    fn test_gas_metering() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "gas".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    gas_balance: GAS_COST_SETTING_WRITE * 2 + 1,
+                    ..Default::default()
+                },
+            );
+        });
+        let gas = || ns::with(&"gas".to_string(), Ok).unwrap();
+
+        // metering is not enforced by default, calls are only counted
+        ns::charge_gas("gas", "ecdsa_sign").unwrap();
+        let ns1 = gas();
+        assert_eq!(ns1.gas_balance, GAS_COST_SETTING_WRITE * 2 + 1);
+        assert_eq!(ns1.gas_used, GAS_COST_THRESHOLD_KEY);
+        assert_eq!(ns1.usage.get("ecdsa_sign"), Some(&1));
+
+        state::with_mut(|s| {
+            s.gas_policy = GasPolicy {
+                enforced: true,
+                credit_limit: GAS_COST_SETTING_WRITE,
+            }
+        });
+        let s1 = SettingPathKey("gas".to_string(), 0, manager, ByteBuf::from([1]), 0);
+        ns::with_gas("gas", "setting_create", || {
+            ns::create_setting(manager, s1.clone(), CreateSettingInput::default(), 1)
+        })
+        .unwrap();
+        let ns1 = gas();
+        assert_eq!(ns1.gas_balance, GAS_COST_SETTING_WRITE + 1);
+        assert_eq!(
+            ns1.gas_used,
+            GAS_COST_THRESHOLD_KEY + GAS_COST_SETTING_WRITE
+        );
+        assert_eq!(ns1.usage.get("setting_create"), Some(&1));
+
+        // failed operations are not charged
+        assert!(ns::with_gas("gas", "setting_create", || {
+            ns::create_setting(manager, s1.clone(), CreateSettingInput::default(), 1)
+        })
+        .unwrap_err()
+        .ends_with("already exists"));
+        assert_eq!(gas().gas_balance, GAS_COST_SETTING_WRITE + 1);
+
+        // unmetered APIs and deletes are counted but not charged
+        ns::charge_gas("gas", "namespace_get_info").unwrap();
+        ns::charge_gas("gas", "setting_delete").unwrap();
+        let ns1 = gas();
+        assert_eq!(ns1.gas_balance, GAS_COST_SETTING_WRITE + 1);
+        assert_eq!(ns1.usage.get("namespace_get_info"), Some(&1));
+        assert_eq!(ns1.usage.get("setting_delete"), Some(&1));
+
+        ns::charge_gas("gas", "setting_update_payload").unwrap();
+        assert_eq!(gas().gas_balance, 1);
+        // the credit covers a call when the balance is insufficient
+        ns::charge_gas("gas", "setting_update_payload").unwrap();
+        let ns1 = gas();
+        assert_eq!(ns1.gas_balance, 1);
+        assert_eq!(ns1.gas_credit, GAS_COST_SETTING_WRITE);
+        let mut called = false;
+        assert_eq!(
+            ns::with_gas("gas", "setting_update_payload", || {
+                called = true;
+                Ok(())
+            })
+            .unwrap_err(),
+            format!(
+                "insufficient gas balance, setting_update_payload costs {} cycles",
+                GAS_COST_SETTING_WRITE
+            )
+        );
+        assert!(!called);
+        assert!(ns::charge_gas("gas", "ecdsa_sign").is_err());
+        assert!(ns::charge_gas("unknown", "ecdsa_sign")
+            .unwrap_err()
+            .starts_with("NotFound"));
+
+        // top-ups repay the credit first
+        let balance = ns::top_up("gas".to_string(), GAS_COST_SETTING_WRITE + 10, 2).unwrap();
+        assert_eq!(balance, 11);
+        assert_eq!(gas().gas_credit, 0);
+
+        ns::with_mut("gas".to_string(), |ns| {
+            ns.gas_exempt = true;
+            Ok(())
+        })
+        .unwrap();
+        ns::charge_gas("gas", "ecdsa_sign").unwrap();
+        let ns1 = gas();
+        assert_eq!(ns1.gas_balance, 11);
+        assert_eq!(
+            ns1.gas_used,
+            GAS_COST_SETTING_WRITE * 3 + GAS_COST_THRESHOLD_KEY * 2
+        );
+        assert_eq!(ns1.usage.get("ecdsa_sign"), Some(&2));
+    }
+
+    #[test]
+    fn test_storage_quota() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "quota".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    retain_versions: 1,
+                    max_bytes_total: 20,
+                    max_settings: 2,
+                    ..Default::default()
+                },
+            );
+        });
+
+        let spk = |key: u8, version: u32| {
+            SettingPathKey(
+                "quota".to_string(),
+                0,
+                manager,
+                ByteBuf::from([key]),
+                version,
+            )
+        };
+        let path = |key: u8, version: u32| SettingPath {
+            ns: "quota".to_string(),
+            key: ByteBuf::from([key]),
+            version,
+            ..Default::default()
+        };
+        let usage = || {
+            ns::with(&"quota".to_string(), |ns| {
+                Ok((ns.payload_bytes_total, ns.settings_total))
+            })
+            .unwrap()
+        };
+        let create = |key: u8, size: usize| {
+            ns::create_setting(
+                manager,
+                spk(key, 0),
+                CreateSettingInput {
+                    payload: Some(ByteBuf::from(vec![key; size])),
+                    ..Default::default()
+                },
+                1,
+            )
+        };
+        let update = |key: u8, version: u32, size: usize| {
+            ns::update_setting_payload(
+                manager,
+                spk(key, version),
+                UpdateSettingPayloadInput {
+                    payload: Some(ByteBuf::from(vec![key; size])),
+                    ..Default::default()
+                },
+                2,
+            )
+        };
+
+        create(1, 10).unwrap();
+        assert_eq!(
+            create(2, 11).unwrap_err(),
+            "payload bytes total exceeds the limit 20"
+        );
+        create(2, 5).unwrap();
+        assert_eq!(usage(), (15, 2));
+        assert_eq!(
+            create(3, 0).unwrap_err(),
+            "settings count exceeds the limit 2"
+        );
+
+        // the current payload is archived
+        assert_eq!(
+            update(1, 1, 6).unwrap_err(),
+            "payload bytes total exceeds the limit 20"
+        );
+        assert!(PAYLOADS_STORE.with_borrow(|r| r.get(&spk(1, 1)).is_none()));
+        update(1, 1, 4).unwrap();
+        assert_eq!(usage(), (19, 2));
+        // version 1 is pruned by the retention policy
+        update(1, 2, 4).unwrap();
+        assert_eq!(usage(), (13, 2));
+
+        let batch = |size: usize| {
+            vec![
+                (spk(2, 1), SettingBatchOp::Delete { path: path(2, 1) }),
+                (
+                    spk(3, 0),
+                    SettingBatchOp::Create {
+                        path: path(3, 0),
+                        input: CreateSettingInput {
+                            payload: Some(ByteBuf::from(vec![3; size])),
+                            ..Default::default()
+                        },
+                    },
+                ),
+            ]
+        };
+        assert_eq!(
+            ns::batch_update_settings(manager, batch(13), 3).unwrap_err(),
+            "payload bytes total exceeds the limit 20"
+        );
+        ns::batch_update_settings(manager, batch(9), 3).unwrap();
+        assert_eq!(usage(), (17, 2));
+
+        // the quota is checked on the net totals, a create can come before the delete that frees the space
+        let swap = |create: u8, size: usize, delete: u8| {
+            vec![
+                (
+                    spk(create, 0),
+                    SettingBatchOp::Create {
+                        path: path(create, 0),
+                        input: CreateSettingInput {
+                            payload: Some(ByteBuf::from(vec![create; size])),
+                            ..Default::default()
+                        },
+                    },
+                ),
+                (
+                    spk(delete, 1),
+                    SettingBatchOp::Delete {
+                        path: path(delete, 1),
+                    },
+                ),
+            ]
+        };
+        ns::batch_update_settings(manager, swap(4, 1, 3), 3).unwrap();
+        assert_eq!(usage(), (9, 2));
+        ns::batch_update_settings(manager, swap(3, 9, 4), 3).unwrap();
+        assert_eq!(usage(), (17, 2));
+
+        ns::delete_setting(&manager, &spk(1, 3), 4).unwrap();
+        assert_eq!(usage(), (9, 1));
+
+        // counters have drifted
+        ns::with_mut("quota".to_string(), |ns| {
+            ns.max_bytes_total = 5;
+            ns.payload_bytes_total = 1000;
+            ns.settings_total = 0;
+            Ok(())
+        })
+        .unwrap();
+        let info = ns::recompute_usage("quota".to_string()).unwrap();
+        assert_eq!((info.payload_bytes_total, info.settings_total), (9, 1));
+        assert_eq!(usage(), (9, 1));
+        assert_eq!(
+            update(3, 1, 2).unwrap_err(),
+            "payload bytes total exceeds the limit 5"
+        );
+        ns::delete_setting(&manager, &spk(3, 1), 5).unwrap();
+        assert_eq!(usage(), (0, 0));
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit {
+            capacity: 2,
+            refill_interval_ms: 100,
+        };
+        let mut bucket = TokenBucket::new(&limit, 0);
+        assert_eq!(bucket.take(&limit, 0), Ok(()));
+        assert_eq!(bucket.take(&limit, 10), Ok(()));
+        assert_eq!(bucket.take(&limit, 50), Err(50));
+        assert_eq!(bucket.take(&limit, 150), Ok(()));
+        // the remainder of the interval is kept
+        assert_eq!(bucket.take(&limit, 160), Err(40));
+        bucket.refill(&limit, 1000);
+        assert_eq!(
+            bucket,
+            TokenBucket {
+                tokens: 2,
+                refilled_at: 1000
+            }
+        );
+
+        let user = Principal::from_slice(&[2, 2, 2, 2]);
+        let other = Principal::from_slice(&[2, 2, 2, 3]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "rate".to_string(),
+                Namespace {
+                    managers: BTreeSet::from([user]),
+                    users: BTreeSet::from([user, other]),
+                    ..Default::default()
+                },
+            );
+        });
+        for _ in 0..10 {
+            ns::check_rate_limit("rate", &user, "ecdsa_sign", 0).unwrap();
+        }
 
-            Ok(UpdateSettingOutput {
-                created_at: setting.created_at,
-                updated_at: setting.updated_at,
-                version: setting.version,
-            })
+        ns::with_mut("rate".to_string(), |ns| {
+            ns.rate_limit = Some(limit.clone());
+            Ok(())
         })
+        .unwrap();
+        ns::check_rate_limit("rate", &user, "ecdsa_sign", 0).unwrap();
+        ns::check_rate_limit("rate", &user, "schnorr_sign", 0).unwrap();
+        assert_eq!(
+            ns::check_rate_limit("rate", &user, "ecdsa_sign", 30).unwrap_err(),
+            "rate limit exceeded for ecdsa_sign, retry after 70 ms at 100"
+        );
+        // buckets are per caller
+        ns::check_rate_limit("rate", &other, "ecdsa_sign", 30).unwrap();
+        assert_eq!(ns::decay_rate_limits(30), 2);
+        assert_eq!(ns::decay_rate_limits(300), 0);
+        ns::check_rate_limit("rate", &user, "ecdsa_sign", 300).unwrap();
+        ns::check_rate_limit("rate", &user, "ecdsa_sign", 300).unwrap();
+        assert!(ns::check_rate_limit("rate", &user, "ecdsa_sign", 300).is_err());
+
+        // capacity 0 removes the rate limit
+        ns::update_namespace_info(
+            &user,
+            UpdateNamespaceInput {
+                name: "rate".to_string(),
+                rate_limit: Some(RateLimit::default()),
+                ..Default::default()
+            },
+            400,
+        )
+        .unwrap();
+        assert!(ns::with(&"rate".to_string(), |ns| Ok(ns.rate_limit))
+            .unwrap()
+            .is_none());
+        ns::check_rate_limit("rate", &user, "ecdsa_sign", 300).unwrap();
+        assert_eq!(ns::decay_rate_limits(300), 0);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn test_reader_grants() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let user = Principal::from_slice(&[2, 2, 2, 2]);
+        let contractor = Principal::from_slice(&[3, 3, 3, 3]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "grants".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    users: BTreeSet::from([user, contractor]),
+                    ..Default::default()
+                },
+            );
+        });
+
+        let spk = SettingPathKey("grants".to_string(), 0, manager, ByteBuf::from([1]), 0);
+        ns::create_setting(manager, spk.clone(), CreateSettingInput::default(), 1).unwrap();
+        assert!(ns::try_get_setting(&contractor, &spk, 1).is_none());
+        assert!(!ns::has_kek_permission(&contractor, &spk, "derive_kek", 1));
+
+        assert_eq!(
+            ns::grant_readers(&manager, &spk, BTreeSet::from([contractor]), 1, 1).unwrap_err(),
+            "expires_at should be in the future"
+        );
+        assert_eq!(
+            ns::grant_readers(&user, &spk, BTreeSet::from([contractor]), 100, 1).unwrap_err(),
+            "no permission"
+        );
+        let mut spk1 = spk.clone();
+        spk1.4 = 1;
+        ns::grant_readers(&manager, &spk1, BTreeSet::from([contractor]), 100, 1).unwrap();
+        ns::grant_readers(&manager, &spk1, BTreeSet::from([user]), 50, 1).unwrap();
+        assert!(ns::try_get_setting(&contractor, &spk, 99).is_some());
+        assert!(ns::has_kek_permission(&contractor, &spk, "derive_kek", 99));
+        assert!(ns::try_get_setting(&contractor, &spk, 100).is_none());
+        assert!(!ns::has_kek_permission(
+            &contractor,
+            &spk,
+            "derive_kek",
+            100
+        ));
+        assert_eq!(
+            ns::get_reader_grants(&contractor, &spk, 10).unwrap(),
+            BTreeMap::from([(user, 50), (contractor, 100)])
+        );
+        assert_eq!(
+            ns::get_reader_grants(&contractor, &spk, 60).unwrap(),
+            BTreeMap::from([(contractor, 100)])
+        );
+
+        // the grant is extended, the old index entry is stale
+        ns::grant_readers(&manager, &spk1, BTreeSet::from([contractor]), 200, 2).unwrap();
+        assert_eq!(ns::purge_expired_reader_grants(99, 10), 1);
+        assert_eq!(ns::purge_expired_reader_grants(150, 10), 1);
+        let setting = SETTINGS_STORE.with_borrow(|r| r.get(&spk)).unwrap();
+        assert_eq!(setting.reader_grants, BTreeMap::from([(contractor, 200)]));
+        assert!(ns::try_get_setting(&contractor, &spk, 150).is_some());
+        assert_eq!(ns::purge_expired_reader_grants(300, 10), 1);
+        assert_eq!(ns::purge_expired_reader_grants(300, 10), 0);
+        let setting = SETTINGS_STORE.with_borrow(|r| r.get(&spk)).unwrap();
+        assert!(setting.reader_grants.is_empty());
+        assert!(ns::get_reader_grants(&contractor, &spk, 150).is_err());
+    }
 
     #[test]
-    fn test_list_setting_keys() {
-        let n1 = "namespace1".to_string();
-        let n2 = "namespace2".to_string();
-        let p0 = Principal::anonymous();
-        let p1 = Principal::from_slice(&[1, 1, 1, 1]);
-        let p2 = Principal::from_slice(&[1, 1, 1, 1, 1]);
-        let p3 = Principal::from_slice(&[1, 1, 1, 1, 2]);
-        assert!(p0 > Principal::management_canister());
-        assert!(p0 < p1);
-        assert!(p1 < p2);
-        assert!(p2 < p3);
+    fn test_principal_groups() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let member = Principal::from_slice(&[2, 2, 2, 2]);
+        let operator = Principal::from_slice(&[3, 3, 3, 3]);
+        let mut namespace = Namespace {
+            max_payload_size: MAX_PAYLOAD_SIZE,
+            managers: BTreeSet::from([manager]),
+            groups: BTreeMap::from([("team".to_string(), BTreeSet::from([member]))]),
+            ..Default::default()
+        };
+        assert_eq!(
+            namespace.role_groups_mut("x").unwrap_err(),
+            "invalid role: x"
+        );
+
+        let user_spk = SettingPathKey("groups".to_string(), 1, member, ByteBuf::from([1]), 0);
+        assert!(!namespace.is_user(&member));
+        assert!(!namespace.can_write_setting(&member, &user_spk));
+        namespace
+            .role_groups_mut("user")
+            .unwrap()
+            .insert("team".to_string());
+        assert!(namespace.is_user(&member));
+        assert!(namespace.can_write_setting(&member, &user_spk));
+
+        // canister level groups are resolved at check time
+        namespace
+            .role_groups_mut("manager")
+            .unwrap()
+            .insert("canister:ops".to_string());
+        assert!(!namespace.is_manager(&operator));
+        state::with_mut(|s| {
+            s.groups
+                .insert("ops".to_string(), BTreeSet::from([operator]));
+        });
+        assert!(namespace.is_manager(&operator));
+        assert!(!namespace.is_manager(&member));
 
-        SETTINGS_STORE.with_borrow_mut(|r| {
-            for (i, n) in [n1.clone(), n2.clone()].iter().enumerate() {
-                for p in &[p0, p1, p2, p3] {
-                    r.insert(
-                        SettingPathKey(n.clone(), 0, *p, ByteBuf::from([i as u8]), 0),
-                        Setting::default(),
-                    );
-                    r.insert(
-                        SettingPathKey(n.clone(), 0, *p, ByteBuf::from(p.as_slice()), 0),
-                        Setting::default(),
-                    );
-                    r.insert(
-                        SettingPathKey(n.clone(), 1, *p, ByteBuf::from([i as u8 + 1]), 0),
-                        Setting::default(),
-                    );
-                    r.insert(
-                        SettingPathKey(n.clone(), 1, *p, ByteBuf::from(p.as_slice()), 0),
-                        Setting::default(),
-                    );
-                    r.insert(
-                        SettingPathKey(n.clone(), 2, *p, ByteBuf::from([0]), 0),
-                        Setting::default(),
-                    );
-                }
-            }
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert("groups".to_string(), namespace);
         });
+        let outsider = Principal::from_slice(&[4, 4, 4, 4]);
+        let spk = SettingPathKey("groups".to_string(), 0, manager, ByteBuf::from([2]), 0);
+        ns::create_setting(manager, spk.clone(), CreateSettingInput::default(), 1).unwrap();
+        ns::with_mut("groups".to_string(), |ns| {
+            ns.groups
+                .insert("readers".to_string(), BTreeSet::from([outsider]));
+            Ok(())
+        })
+        .unwrap();
+        assert!(ns::try_get_setting(&outsider, &spk, 1).is_none());
+        assert!(!ns::has_kek_permission(&outsider, &spk, "derive_kek", 1));
+
+        let mut spk1 = spk.clone();
+        spk1.4 = 1;
+        ns::with_setting_mut(&manager, &spk1, |setting| {
+            setting.reader_groups.insert("readers".to_string());
+            Ok(())
+        })
+        .unwrap();
+        assert!(ns::try_get_setting(&outsider, &spk, 1).is_some());
+        assert!(ns::has_kek_permission(&outsider, &spk, "derive_kek", 1));
 
-        {
-            let keys = ns::list_setting_keys(&n1, false, None);
-            assert_eq!(
-                keys,
-                vec![
-                    (p0, ByteBuf::from([0])),
-                    (p0, ByteBuf::from(p0.as_slice())),
-                    (p1, ByteBuf::from([0])),
-                    (p1, ByteBuf::from(p1.as_slice())),
-                    (p2, ByteBuf::from([0])),
-                    (p2, ByteBuf::from(p2.as_slice())),
-                    (p3, ByteBuf::from([0])),
-                    (p3, ByteBuf::from(p3.as_slice())),
-                ]
+        // removing the member revokes access through the group
+        ns::with_mut("groups".to_string(), |ns| {
+            ns.groups.remove("readers");
+            Ok(())
+        })
+        .unwrap();
+        assert!(ns::try_get_setting(&outsider, &spk, 1).is_none());
+    }
+
+    #[test]
+    fn test_namespace_policy() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let user = Principal::from_slice(&[2, 2, 2, 2]);
+        let contractor = Principal::from_slice(&[3, 3, 3, 3]);
+        let rule = |subject, action: &str, prefix: &str, allow| PolicyRule {
+            subject,
+            actions: BTreeSet::from([action.to_string()]),
+            prefix: vec![ByteBuf::from(prefix)],
+            allow,
+        };
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "policy".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    users: BTreeSet::from([user]),
+                    groups: BTreeMap::from([("writers".to_string(), BTreeSet::from([user]))]),
+                    policy: vec![
+                        rule(
+                            PolicySubject::Role("user".to_string()),
+                            "sign",
+                            "admin",
+                            false,
+                        ),
+                        rule(
+                            PolicySubject::Principal(contractor),
+                            "read",
+                            "public/",
+                            true,
+                        ),
+                        rule(
+                            PolicySubject::Group("writers".to_string()),
+                            "write",
+                            "shared/",
+                            true,
+                        ),
+                        rule(PolicySubject::Any, "vetkd", "", false),
+                    ],
+                    ..Default::default()
+                },
             );
-            let keys = ns::list_setting_keys(&n1, true, None);
-            assert_eq!(
-                keys,
-                vec![
-                    (p0, ByteBuf::from([1])),
-                    (p0, ByteBuf::from(p0.as_slice())),
-                    (p1, ByteBuf::from([1])),
-                    (p1, ByteBuf::from(p1.as_slice())),
-                    (p2, ByteBuf::from([1])),
-                    (p2, ByteBuf::from(p2.as_slice())),
-                    (p3, ByteBuf::from([1])),
-                    (p3, ByteBuf::from(p3.as_slice())),
-                ]
+        });
+
+        let namespace = NAMESPACES_STORE.with_borrow(|r| r.get(&"policy".to_string()).unwrap());
+        assert!(!namespace.has_ns_signing_permission(&user, &[ByteBuf::from("admin_1")]));
+        assert!(namespace.has_ns_signing_permission(&user, &[ByteBuf::from("wallet")]));
+        assert!(namespace.has_ns_signing_permission(&manager, &[ByteBuf::from("admin_1")]));
+        assert!(!namespace.has_ns_signing_permission(&contractor, &[]));
+
+        let spk =
+            |key: &str| SettingPathKey("policy".to_string(), 0, manager, ByteBuf::from(key), 0);
+        assert!(namespace.can_write_setting(&user, &spk("shared/1")));
+        assert!(!namespace.can_write_setting(&user, &spk("private")));
+        ns::create_setting(manager, spk("public/1"), CreateSettingInput::default(), 1).unwrap();
+        ns::create_setting(manager, spk("private"), CreateSettingInput::default(), 1).unwrap();
+        assert!(ns::try_get_setting(&contractor, &spk("public/1"), 1).is_some());
+        assert!(ns::try_get_setting(&contractor, &spk("private"), 1).is_none());
+        assert!(ns::has_kek_permission(
+            &manager,
+            &spk("private"),
+            "derive_kek",
+            1
+        ));
+        assert!(!ns::has_kek_permission(
+            &manager,
+            &spk("private"),
+            "vetkd",
+            1
+        ));
+
+        let input = CheckPermissionInput {
+            ns: "policy".to_string(),
+            action: "read".to_string(),
+            principal: Some(contractor),
+            subject: Some(manager),
+            resource: vec![ByteBuf::from("public/1")],
+            ..Default::default()
+        };
+        assert_eq!(
+            ns::check_permission(&user, input.clone(), 1).unwrap_err(),
+            "no permission"
+        );
+        assert_eq!(
+            ns::check_permission(&manager, input.clone(), 1).unwrap(),
+            CheckPermissionOutput {
+                allowed: true,
+                rule: Some(1),
+            }
+        );
+        assert_eq!(
+            ns::check_permission(
+                &user,
+                CheckPermissionInput {
+                    ns: "policy".to_string(),
+                    action: "sign".to_string(),
+                    resource: vec![ByteBuf::from("wallet")],
+                    ..Default::default()
+                },
+                1
+            )
+            .unwrap(),
+            CheckPermissionOutput {
+                allowed: true,
+                rule: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_signing_policy() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let user = Principal::from_slice(&[2, 2, 2, 2]);
+        let path = |segments: &[&str]| -> Vec<ByteBuf> {
+            segments.iter().map(|s| ByteBuf::from(*s)).collect()
+        };
+        let mut namespace = Namespace {
+            managers: BTreeSet::from([manager]),
+            users: BTreeSet::from([user]),
+            ..Default::default()
+        };
+        assert!(namespace.has_ns_signing_permission(&user, &path(&["treasury"])));
+        assert_eq!(namespace.check_signing_message(&[0u8; 32]), Ok(false));
+
+        namespace.signing_policy = Some(SigningPolicy {
+            role_paths: BTreeMap::from([("user".to_string(), vec![path(&["wallet"])])]),
+            principal_paths: BTreeMap::from([(manager, vec![path(&["treasury", "eth"])])]),
+            message_formats: BTreeSet::from(["eip191".to_string()]),
+        });
+        assert!(!namespace.has_ns_signing_permission(&user, &path(&["treasury"])));
+        assert!(!namespace.has_ns_signing_permission(&user, &[]));
+        assert!(namespace.has_ns_signing_permission(&user, &path(&["wallet_1", "0"])));
+        assert!(namespace.has_ns_signing_permission(&manager, &path(&["treasury", "eth"])));
+        assert!(!namespace.has_ns_signing_permission(&manager, &path(&["treasury", "btc"])));
+        assert!(!namespace.has_ns_signing_permission(&manager, &path(&["wallet"])));
+
+        let mut message = EIP191_PREFIX.to_vec();
+        message.extend_from_slice(b"2hi");
+        assert_eq!(namespace.check_signing_message(&message), Ok(true));
+        assert!(namespace.check_signing_message(&[0u8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_namespace_approval() {
+        let m1 = Principal::from_slice(&[1, 1, 1, 1]);
+        let m2 = Principal::from_slice(&[2, 2, 2, 2]);
+        let m3 = Principal::from_slice(&[3, 3, 3, 3]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "approval".to_string(),
+                Namespace {
+                    managers: BTreeSet::from([m1, m2, m3]),
+                    ..Default::default()
+                },
             );
-            let keys = ns::list_setting_keys(&n1, false, Some(p1));
-            assert_eq!(
-                keys,
-                vec![(p1, ByteBuf::from([0])), (p1, ByteBuf::from(p1.as_slice())),]
+        });
+        let get = || NAMESPACES_STORE.with_borrow(|r| r.get(&"approval".to_string()).unwrap());
+
+        let policy = ApprovalPolicy {
+            threshold: 4,
+            expires_in_ms: 1000,
+        };
+        assert_eq!(
+            ns::propose_change(
+                &m1,
+                "approval".to_string(),
+                NamespaceChange::Delete,
+                "".to_string(),
+                1
+            )
+            .unwrap_err(),
+            "approval is not enabled for namespace approval"
+        );
+        assert_eq!(
+            ns::enable_approval_policy(&m1, "approval".to_string(), policy, 1).unwrap_err(),
+            "approval threshold 4 exceeds the number of managers 3"
+        );
+        let policy = ApprovalPolicy {
+            threshold: 2,
+            expires_in_ms: 1000,
+        };
+        ns::enable_approval_policy(&m1, "approval".to_string(), policy.clone(), 1).unwrap();
+        assert_eq!(
+            ns::enable_approval_policy(&m1, "approval".to_string(), policy, 1).unwrap_err(),
+            ns::APPROVAL_REQUIRED
+        );
+        assert_eq!(
+            ns::delete_namespace(&m1, "approval".to_string()).unwrap_err(),
+            ns::APPROVAL_REQUIRED
+        );
+        assert_eq!(
+            ns::update_namespace_info(
+                &m1,
+                UpdateNamespaceInput {
+                    name: "approval".to_string(),
+                    status: Some(1),
+                    ..Default::default()
+                },
+                1
+            )
+            .unwrap_err(),
+            ns::APPROVAL_REQUIRED
+        );
+
+        let remove_m3 = NamespaceChange::RemoveManagers(BTreeSet::from([m3]));
+        let info = ns::propose_change(
+            &m1,
+            "approval".to_string(),
+            remove_m3.clone(),
+            "".to_string(),
+            1,
+        )
+        .unwrap();
+        assert_eq!(info.id, 1);
+        assert!(!info.executed);
+        assert_eq!(
+            ns::list_proposals(&m2, "approval".to_string(), 1)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(ns::list_proposals(&m2, "approval".to_string(), 1001)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            ns::approve_change(&m2, "approval".to_string(), 1, 1001).unwrap_err(),
+            "proposal 1 expired"
+        );
+        assert_eq!(
+            ns::approve_change(&m2, "approval".to_string(), 2, 1).unwrap_err(),
+            "NotFound: proposal 2 not found"
+        );
+        // an approval of the proposer again does not reach the threshold
+        assert!(
+            !ns::approve_change(&m1, "approval".to_string(), 1, 2)
+                .unwrap()
+                .executed
+        );
+        assert!(
+            ns::approve_change(&m2, "approval".to_string(), 1, 2)
+                .unwrap()
+                .executed
+        );
+        let namespace = get();
+        assert_eq!(namespace.managers, BTreeSet::from([m1, m2]));
+        assert!(namespace.proposals.is_empty());
+
+        // removing another manager would make the threshold unreachable
+        let remove_m2 = NamespaceChange::RemoveManagers(BTreeSet::from([m2]));
+        ns::propose_change(&m1, "approval".to_string(), remove_m2, "".to_string(), 3).unwrap();
+        assert_eq!(
+            ns::approve_change(&m2, "approval".to_string(), 2, 3).unwrap_err(),
+            "approval threshold 2 exceeds the number of managers 1"
+        );
+        assert_eq!(get().managers, BTreeSet::from([m1, m2]));
+
+        let info = ns::propose_change(
+            &m1,
+            "approval".to_string(),
+            NamespaceChange::UpdateApprovalPolicy(None),
+            "".to_string(),
+            3,
+        )
+        .unwrap();
+        assert_eq!(info.approvers, BTreeSet::from([m1]));
+        assert!(
+            ns::approve_change(&m2, "approval".to_string(), 3, 4)
+                .unwrap()
+                .executed
+        );
+        assert!(!get().requires_approval());
+        ns::delete_namespace(&m1, "approval".to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_namespace_approval_guards_manager_role() {
+        let m1 = Principal::from_slice(&[1, 1, 1, 1]);
+        let m2 = Principal::from_slice(&[2, 2, 2, 2]);
+        let puppet = Principal::from_slice(&[6, 6, 6, 6]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "approval_guard".to_string(),
+                Namespace {
+                    managers: BTreeSet::from([m1, m2]),
+                    groups: BTreeMap::from([("admins".to_string(), BTreeSet::from([m2]))]),
+                    ..Default::default()
+                },
             );
-            let keys = ns::list_setting_keys(&n1, true, Some(p2));
+        });
+        let name = || "approval_guard".to_string();
+        let get = || NAMESPACES_STORE.with_borrow(|r| r.get(&name()).unwrap());
+        let policy = ApprovalPolicy {
+            threshold: 2,
+            expires_in_ms: 1000,
+        };
+        ns::enable_approval_policy(&m1, name(), policy, 1).unwrap();
+
+        // a single manager cannot grant the manager role to sock puppets
+        let puppets = BTreeSet::from([puppet]);
+        for change in [
+            NamespaceChange::AddManagers(puppets.clone()),
+            NamespaceChange::AddGroupMembers("admins".to_string(), puppets.clone()),
+            NamespaceChange::RemoveGroupMembers("admins".to_string(), BTreeSet::from([m2])),
+            NamespaceChange::AddRoleGroups(
+                "manager".to_string(),
+                BTreeSet::from(["admins".to_string()]),
+            ),
+            NamespaceChange::RemoveRoleGroups(
+                "manager".to_string(),
+                BTreeSet::from(["admins".to_string()]),
+            ),
+            NamespaceChange::UpdatePolicy(vec![]),
+            // nor lift the signing restrictions
+            NamespaceChange::UpdateSigningPolicy(None),
+        ] {
             assert_eq!(
-                keys,
-                vec![(p2, ByteBuf::from([1])), (p2, ByteBuf::from(p2.as_slice())),]
+                ns::apply_change(&m1, name(), change, 2).unwrap_err(),
+                ns::APPROVAL_REQUIRED
             );
         }
+        assert_eq!(get().managers, BTreeSet::from([m1, m2]));
+
+        // a proposal of a single manager is not executed, and puppets cannot approve it
+        let info = ns::propose_change(
+            &m1,
+            name(),
+            NamespaceChange::AddManagers(puppets.clone()),
+            "".to_string(),
+            2,
+        )
+        .unwrap();
+        assert!(!info.executed);
+        assert_eq!(
+            ns::approve_change(&puppet, name(), info.id, 2).unwrap_err(),
+            "no permission"
+        );
+        assert!(!get().is_manager(&puppet));
+        assert!(
+            ns::approve_change(&m2, name(), info.id, 3)
+                .unwrap()
+                .executed
+        );
+        assert!(get().is_manager(&puppet));
+
+        // without approval the changes apply directly
+        ns::propose_change(
+            &m1,
+            name(),
+            NamespaceChange::UpdateApprovalPolicy(None),
+            "".to_string(),
+            4,
+        )
+        .unwrap();
+        ns::approve_change(&m2, name(), info.id + 1, 4).unwrap();
+        ns::apply_change(
+            &m1,
+            name(),
+            NamespaceChange::AddGroupMembers("admins".to_string(), puppets),
+            5,
+        )
+        .unwrap();
+        assert!(get().groups["admins"].contains(&puppet));
+    }
 
-        {
-            let keys = ns::list_setting_keys(&n2, false, None);
-            assert_eq!(
-                keys,
-                vec![
-                    (p0, ByteBuf::from([1])),
-                    (p0, ByteBuf::from(p0.as_slice())),
-                    (p1, ByteBuf::from([1])),
-                    (p1, ByteBuf::from(p1.as_slice())),
-                    (p2, ByteBuf::from([1])),
-                    (p2, ByteBuf::from(p2.as_slice())),
-                    (p3, ByteBuf::from([1])),
-                    (p3, ByteBuf::from(p3.as_slice())),
-                ]
+    #[test]
+    fn test_namespace_export_import() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let canister = Principal::from_slice(&[9, 9, 9, 9]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "export".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    gas_balance: 100,
+                    ..Default::default()
+                },
             );
-            let keys = ns::list_setting_keys(&n2, true, None);
+        });
+        let spk = |ns: &str, key: u8, version: u32| {
+            SettingPathKey(ns.to_string(), 0, manager, ByteBuf::from([key]), version)
+        };
+        let size = MAX_EXPORT_PAGE_SIZE as usize / 2;
+        for key in 1..=2u8 {
+            ns::create_setting(
+                manager,
+                spk("export", key, 0),
+                CreateSettingInput {
+                    payload: Some(ByteBuf::from(vec![key; size])),
+                    tags: Some(BTreeMap::from([("k".to_string(), "v".to_string())])),
+                    ..Default::default()
+                },
+                1,
+            )
+            .unwrap();
+        }
+        ns::update_setting_payload(
+            manager,
+            spk("export", 1, 1),
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from(vec![3; 10])),
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap();
+
+        assert_eq!(
+            ns::export_namespace(&canister, canister, "export".to_string(), None, 3).unwrap_err(),
+            "no permission"
+        );
+        assert_eq!(
+            ns::export_namespace(
+                &manager,
+                canister,
+                "export".to_string(),
+                Some(ExportCursor {
+                    export_id: ByteBuf::from([1]),
+                    exported_at: 3,
+                    page: 1,
+                    spk: spk("other", 1, 0),
+                    version: 0,
+                    offset: 0,
+                }),
+                3
+            )
+            .unwrap_err(),
+            "invalid cursor"
+        );
+        let export = |now_ms: u64| {
+            let (page1, cursor) =
+                ns::export_namespace(&manager, canister, "export".to_string(), None, now_ms)
+                    .unwrap();
+            let cursor = cursor.unwrap();
+            assert_eq!(cursor.page, 1);
             assert_eq!(
-                keys,
-                vec![
-                    (p0, ByteBuf::from([2])),
-                    (p0, ByteBuf::from(p0.as_slice())),
-                    (p1, ByteBuf::from(p1.as_slice())),
-                    (p1, ByteBuf::from([2])),
-                    (p2, ByteBuf::from(p2.as_slice())),
-                    (p2, ByteBuf::from([2])),
-                    (p3, ByteBuf::from(p3.as_slice())),
-                    (p3, ByteBuf::from([2])),
-                ]
+                ns::decode_export_cursor(&ns::encode_export_cursor(&cursor)).unwrap(),
+                cursor
             );
-            let keys = ns::list_setting_keys(&n2, false, Some(p1));
-            assert_eq!(
-                keys,
-                vec![(p1, ByteBuf::from([1])), (p1, ByteBuf::from(p1.as_slice())),]
+            let (page2, next) = ns::export_namespace(
+                &manager,
+                canister,
+                "export".to_string(),
+                Some(cursor.clone()),
+                now_ms + 1,
+            )
+            .unwrap();
+            assert!(next.is_none());
+            (page1, page2, cursor)
+        };
+        let (page1, page2, cursor) = export(3);
+
+        let sk = ic_ed25519::PrivateKey::generate_from_seed(&[7; 32]);
+        let sig1 = sk.sign_message(&sha256(&page1));
+        let verify =
+            |bundle: &[u8], sig: &[u8]| ns::verify_export(bundle, sig).map(|page| page.name);
+        assert_eq!(
+            verify(&page1, &sig1).unwrap_err(),
+            format!("untrusted export source {}", canister)
+        );
+        // a key trusted for another canister does not verify the pages
+        state::with_mut(|s| {
+            s.export_sources.insert(
+                Principal::anonymous(),
+                ByteBuf::from(sk.public_key().serialize_raw()),
+            )
+        });
+        assert!(verify(&page1, &sig1).is_err());
+        state::with_mut(|s| {
+            s.export_sources.insert(
+                canister,
+                ByteBuf::from(
+                    ic_ed25519::PrivateKey::generate_from_seed(&[8; 32])
+                        .public_key()
+                        .serialize_raw(),
+                ),
+            )
+        });
+        assert_eq!(
+            verify(&page1, &sig1).unwrap_err(),
+            "invalid bundle signature"
+        );
+        state::with_mut(|s| {
+            s.export_sources
+                .insert(canister, ByteBuf::from(sk.public_key().serialize_raw()))
+        });
+        assert_eq!(
+            verify(&page2, &sig1).unwrap_err(),
+            "invalid bundle signature"
+        );
+        assert!(verify(b"bad", &sig1).is_err());
+        assert_eq!(verify(&page1, &sig1).unwrap(), "export");
+        let import = |name: &str, bundle: &[u8]| {
+            let sig = sk.sign_message(&sha256(bundle));
+            ns::import_namespace(
+                name.to_string(),
+                ns::verify_export(bundle, &sig).unwrap(),
+                4,
+            )
+        };
+
+        assert_eq!(
+            import("imported", &page2).unwrap_err(),
+            "NotFound: namespace imported not found"
+        );
+        // a page without the namespace cannot add settings to a namespace that is not being imported
+        assert_eq!(
+            import("export", &page2).unwrap_err(),
+            "namespace export is not being imported"
+        );
+        let info = import("imported", &page1).unwrap();
+        assert_eq!(info.gas_balance, 0);
+        assert_eq!(info.settings_total, 1);
+        assert_eq!(info.payload_bytes_total, size as u64 + 10);
+        assert_eq!(
+            import("imported", &page1).unwrap_err(),
+            "namespace imported already exists"
+        );
+
+        // pages of another export, or out of order, are rejected
+        let (_, other_page2, _) = export(5);
+        assert_eq!(
+            import("imported", &other_page2).unwrap_err(),
+            "export page does not belong to the import of namespace imported"
+        );
+        let (page3, _) = ns::export_namespace(
+            &manager,
+            canister,
+            "export".to_string(),
+            Some(ExportCursor { page: 2, ..cursor }),
+            4,
+        )
+        .unwrap();
+        assert_eq!(
+            import("imported", &page3).unwrap_err(),
+            "export page 2 is out of order, expected page 1"
+        );
+
+        let info = import("imported", &page2).unwrap();
+        assert_eq!(info.settings_total, 2);
+        assert_eq!(info.payload_bytes_total, 2 * size as u64 + 10);
+        assert_eq!(
+            import("imported", &page2).unwrap_err(),
+            "namespace imported is not being imported"
+        );
+
+        let setting = ns::try_get_setting(&manager, &spk("imported", 1, 0), 4).unwrap();
+        assert_eq!(setting.version, 2);
+        assert_eq!(setting.payload.unwrap().as_slice(), &[3; 10]);
+        let archived = PAYLOADS_STORE
+            .with_borrow(|r| r.get(&spk("imported", 1, 1)))
+            .unwrap();
+        assert_eq!(archived.size(), size as u64);
+        let found = ns::search_settings(
+            &manager,
+            SearchSettingsInput {
+                ns: "imported".to_string(),
+                tag: "k".to_string(),
+                value: "v".to_string(),
+                ..Default::default()
+            },
+            4,
+        )
+        .unwrap();
+        assert_eq!(found.settings.len(), 2);
+
+        // the quotas of the imported namespace are checked
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            let mut ns = r.get(&"export".to_string()).unwrap();
+            ns.max_settings = 1;
+            r.insert("export".to_string(), ns);
+        });
+        let (page1, page2, _) = export(7);
+        import("imported2", &page1).unwrap();
+        assert_eq!(
+            import("imported2", &page2).unwrap_err(),
+            "settings count exceeds the limit 1"
+        );
+
+        // a renamed namespace does not keep its original name for key derivation
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            let mut ns = r.get(&"export".to_string()).unwrap();
+            ns.alias = Some("original".to_string());
+            r.insert("export".to_string(), ns);
+        });
+        assert_eq!(ns::derivation_name(&"export".to_string()), "original");
+        let (page1, _, _) = export(9);
+        import("imported3", &page1).unwrap();
+        let imported = NAMESPACES_STORE
+            .with_borrow(|r| r.get(&"imported3".to_string()))
+            .unwrap();
+        assert_eq!(imported.alias, None);
+        assert_eq!(ns::derivation_name(&"imported3".to_string()), "imported3");
+    }
+
+    #[test]
+    fn test_namespace_export_chunked() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let canister = Principal::from_slice(&[9, 9, 9, 9]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "export".to_string(),
+                Namespace {
+                    max_payload_size: MAX_CHUNKED_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    ..Default::default()
+                },
             );
-            let keys = ns::list_setting_keys(&n2, true, Some(p2));
+        });
+        let spk = |ns: &str, key: u8, version: u32| {
+            SettingPathKey(ns.to_string(), 0, manager, ByteBuf::from([key]), version)
+        };
+        let large: Vec<u8> = (0..3_000_000u32).map(|i| i as u8).collect();
+        for (key, payload) in [(0u8, vec![0; 10]), (1, large.clone()), (2, vec![2; 10])] {
+            ns::create_setting(
+                manager,
+                spk("export", key, 0),
+                CreateSettingInput {
+                    payload: Some(ByteBuf::from(payload)),
+                    ..Default::default()
+                },
+                1,
+            )
+            .unwrap();
+        }
+        ns::update_setting_payload(
+            manager,
+            spk("export", 1, 1),
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from(large[..1_000_000].to_vec())),
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap();
+
+        let mut pages = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) =
+                ns::export_namespace(&manager, canister, "export".to_string(), cursor, 3).unwrap();
+            assert!(page.len() as u64 <= MAX_EXPORT_PAGE_SIZE + 1024);
+            pages.push(page);
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+        // the first setting, the large setting in 3 pages of chunks, the last setting
+        assert_eq!(pages.len(), 5);
+
+        // the export fails if the setting being exported in chunks is removed
+        let (_, cursor) =
+            ns::export_namespace(&manager, canister, "export".to_string(), None, 4).unwrap();
+        let (_, cursor) =
+            ns::export_namespace(&manager, canister, "export".to_string(), cursor, 4).unwrap();
+        let cursor = cursor.unwrap();
+        assert_eq!(cursor.version, 1);
+        assert!(cursor.offset > 0);
+        let sk = ic_ed25519::PrivateKey::generate_from_seed(&[7; 32]);
+        state::with_mut(|s| {
+            s.export_sources
+                .insert(canister, ByteBuf::from(sk.public_key().serialize_raw()))
+        });
+        let import = |name: &str, bundle: &[u8]| {
+            let sig = sk.sign_message(&sha256(bundle));
+            ns::import_namespace(
+                name.to_string(),
+                ns::verify_export(bundle, &sig).unwrap(),
+                5,
+            )
+        };
+
+        for page in &pages[..3] {
+            import("imported", page).unwrap();
+        }
+        let info = ns::with(&"imported".to_string(), |ns| Ok(ns.clone())).unwrap();
+        assert_eq!(info.settings_total, 1);
+        let importing = info.importing.unwrap();
+        assert!(importing.pending.is_some());
+        assert!(!PAYLOAD_CHUNKS_STORE.with_borrow(|r| r.is_empty()));
+        // a page is checked before any write, a corrupted chunk leaves the import unchanged
+        let chunks = PAYLOAD_CHUNKS_STORE.with_borrow(|r| r.len());
+        let mut corrupted: NamespaceExport = from_cbor_bytes(&pages[3], "NamespaceExport data");
+        let data = &mut corrupted.chunks.last_mut().unwrap().data;
+        data[0] = data[0].wrapping_add(1);
+        let mut bundle = vec![];
+        to_writer(&corrupted, &mut bundle).unwrap();
+        assert_eq!(
+            import("imported", &bundle).unwrap_err(),
+            format!(
+                "setting {} changed during the export, abort the import and export again",
+                spk("imported", 1, 0)
+            )
+        );
+        let info = ns::with(&"imported".to_string(), |ns| Ok(ns.clone())).unwrap();
+        assert_eq!(info.importing.unwrap().next_page, 3);
+        assert_eq!(PAYLOAD_CHUNKS_STORE.with_borrow(|r| r.len()), chunks);
+
+        // an aborted import removes the imported settings in batches, then the namespace
+        let changes = CHANGES_STORE.with_borrow(|r| r.len());
+        for page in &pages[..3] {
+            import("aborted", page).unwrap();
+        }
+        assert!(!ns::abort_import("aborted".to_string(), 1).unwrap());
+        let info = ns::with(&"aborted".to_string(), |ns| Ok(ns.clone())).unwrap();
+        assert_eq!(info.settings_total, 0);
+        assert!(ns::abort_import("aborted".to_string(), 1).unwrap());
+        assert!(ns::with(&"aborted".to_string(), |ns| Ok(ns.clone())).is_err());
+        assert_eq!(PAYLOAD_CHUNKS_STORE.with_borrow(|r| r.len()), chunks);
+        assert_eq!(CHANGES_STORE.with_borrow(|r| r.len()), changes);
+        assert_eq!(
+            ns::abort_import("imported2".to_string(), 1).unwrap_err(),
+            "NotFound: namespace imported2 not found"
+        );
+        // chunks cannot be imported out of order
+        assert_eq!(
+            import("imported", &pages[4]).unwrap_err(),
+            "export page 4 is out of order, expected page 3"
+        );
+        for page in &pages[3..] {
+            import("imported", page).unwrap();
+        }
+        let info = ns::with(&"imported".to_string(), |ns| Ok(ns.clone())).unwrap();
+        assert!(info.importing.is_none());
+        assert_eq!(info.settings_total, 3);
+        assert_eq!(info.payload_bytes_total, 4_000_000 + 20);
+        assert!(UPLOAD_CHUNKS_STORE.with_borrow(|r| r.is_empty()));
+
+        // the payloads imported in chunks are stored in chunks
+        let setting = ns::try_get_setting(&manager, &spk("imported", 1, 0), 5).unwrap();
+        assert_eq!(setting.version, 2);
+        assert_eq!(setting.payload, None);
+        let info = ns::get_setting(manager, spk("imported", 1, 0), 5).unwrap();
+        assert_eq!(info.payload.unwrap().as_slice(), &large[..1_000_000]);
+        let archived = PAYLOADS_STORE
+            .with_borrow(|r| r.get(&spk("imported", 1, 1)))
+            .unwrap();
+        assert_eq!(archived.payload, None);
+        assert_eq!(archived.size(), large.len() as u64);
+        let mut downloaded = Vec::new();
+        for i in 0..large.len().div_ceil(CHUNK_SIZE as usize) as u32 {
+            let output = ns::get_setting_chunk(manager, spk("imported", 1, 1), i, 5).unwrap();
+            downloaded.extend_from_slice(&output.chunk);
+        }
+        assert_eq!(downloaded, large);
+
+        ns::delete_setting(&manager, &spk("export", 1, 2), 6).unwrap();
+        assert_eq!(
+            ns::export_namespace(&manager, canister, "export".to_string(), Some(cursor), 6)
+                .unwrap_err(),
+            format!(
+                "setting {} changed during the export, export again",
+                spk("export", 1, 0)
+            )
+        );
+    }
+
+    #[test]
+    fn test_namespace_rename_clone() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "typo".to_string(),
+                Namespace {
+                    max_payload_size: MAX_PAYLOAD_SIZE,
+                    managers: BTreeSet::from([manager]),
+                    gas_balance: 100,
+                    ..Default::default()
+                },
+            );
+        });
+        let spk = |ns: &str, key: u8, version: u32| {
+            SettingPathKey(ns.to_string(), 0, manager, ByteBuf::from([key]), version)
+        };
+        ns::create_setting(
+            manager,
+            spk("typo", 1, 0),
+            CreateSettingInput {
+                payload: Some(ByteBuf::from(vec![1; 10])),
+                tags: Some(BTreeMap::from([("k".to_string(), "v".to_string())])),
+                ..Default::default()
+            },
+            1,
+        )
+        .unwrap();
+        ns::update_setting_payload(
+            manager,
+            spk("typo", 1, 1),
+            UpdateSettingPayloadInput {
+                payload: Some(ByteBuf::from(vec![2; 10])),
+                ..Default::default()
+            },
+            2,
+        )
+        .unwrap();
+        SETTINGS_STORE.with_borrow_mut(|r| {
+            r.insert(
+                spk("typo", 2, 0),
+                Setting {
+                    version: 3,
+                    payload: Some(ByteBuf::from(vec![3; 10])),
+                    dek: Some(ByteBuf::from(vec![4; 10])),
+                    ..Default::default()
+                },
+            )
+        });
+        ns::with_mut("typo".to_string(), |ns| {
+            ns.settings_total += 1;
+            ns.payload_bytes_total += 20;
+            Ok(())
+        })
+        .unwrap();
+
+        let clone = || ns::clone_namespace(&manager, "typo".to_string(), "copy".to_string(), 1, 3);
+        assert_eq!(clone().unwrap_err(), "no permission");
+        state::with_mut(|s| s.managers.insert(manager));
+
+        // the settings are copied one per batch
+        let output = clone().unwrap();
+        assert_eq!(output.namespace.gas_balance, 0);
+        assert_eq!(output.namespace.moving_from, Some("typo".to_string()));
+        assert!(output.reencrypt.is_empty());
+        // settings can not be written until the clone is done
+        assert!(
+            ns::create_setting(manager, spk("copy", 3, 0), CreateSettingInput::default(), 3)
+                .is_err()
+        );
+        let output = clone().unwrap();
+        assert_eq!(output.namespace.moving_from, Some("typo".to_string()));
+        assert_eq!(
+            output.reencrypt,
+            vec![SettingPath {
+                ns: "typo".to_string(),
+                user_owned: false,
+                subject: Some(manager),
+                key: ByteBuf::from([2]),
+                version: 3,
+            }]
+        );
+        let output = clone().unwrap();
+        assert_eq!(output.namespace.moving_from, None);
+        assert!(output.reencrypt.is_empty());
+        assert_eq!(output.namespace.settings_total, 1);
+        assert_eq!(output.namespace.payload_bytes_total, 20);
+        let setting = ns::try_get_setting(&manager, &spk("copy", 1, 0), 3).unwrap();
+        assert_eq!(setting.version, 2);
+        assert!(ns::try_get_setting(&manager, &spk("copy", 2, 0), 3).is_none());
+        assert_eq!(ns::derivation_name(&"copy".to_string()), "copy");
+        assert_eq!(clone().unwrap_err(), "namespace copy already exists");
+
+        // the settings and then the change log are moved one per batch
+        let rename =
+            || ns::rename_namespace(&manager, "typo".to_string(), "fixed".to_string(), 1, 4);
+        let info = rename().unwrap();
+        assert_eq!(info.moving_from, Some("typo".to_string()));
+        assert!(ns::get_namespace(&manager, "typo".to_string()).is_err());
+        assert!(SETTINGS_STORE.with_borrow(|r| r.get(&spk("typo", 2, 0)).is_some()));
+        assert!(ns::create_setting(
+            manager,
+            spk("fixed", 3, 0),
+            CreateSettingInput::default(),
+            4
+        )
+        .is_err());
+        let mut batches = 1;
+        let info = loop {
+            let info = rename().unwrap();
+            batches += 1;
+            if info.moving_from.is_none() {
+                break info;
+            }
+        };
+        assert_eq!(batches, 5);
+        assert_eq!(info.name, "fixed");
+        assert_eq!(info.alias, Some("typo".to_string()));
+        assert_eq!(info.settings_total, 2);
+        assert_eq!(ns::derivation_name(&"fixed".to_string()), "typo");
+        assert!(ns::get_namespace(&manager, "typo".to_string()).is_err());
+        assert!(SETTINGS_STORE.with_borrow(|r| r.get(&spk("typo", 1, 0)).is_none()));
+        let setting = ns::try_get_setting(&manager, &spk("fixed", 1, 0), 4).unwrap();
+        assert_eq!(setting.payload.unwrap().as_slice(), &[2; 10]);
+        assert!(PAYLOADS_STORE.with_borrow(|r| r.get(&spk("fixed", 1, 1)).is_some()));
+        let found = ns::search_settings(
+            &manager,
+            SearchSettingsInput {
+                ns: "fixed".to_string(),
+                tag: "k".to_string(),
+                value: "v".to_string(),
+                ..Default::default()
+            },
+            4,
+        )
+        .unwrap();
+        assert_eq!(found.settings.len(), 1);
+        let changes = ns::changes_since(&manager, "fixed".to_string(), 0, None, 4).unwrap();
+        assert_eq!(changes.changes.len(), 2);
+
+        assert_eq!(
+            ns::clone_namespace(&manager, "fixed".to_string(), "typo".to_string(), 100, 5)
+                .unwrap_err(),
+            "namespace typo is reserved as an alias of fixed"
+        );
+        assert_eq!(
+            ns::rename_namespace(&manager, "copy".to_string(), "typo".to_string(), 100, 5)
+                .unwrap_err(),
+            "namespace typo is reserved as an alias of fixed"
+        );
+
+        let info =
+            ns::rename_namespace(&manager, "fixed".to_string(), "fixed2".to_string(), 100, 5)
+                .unwrap();
+        assert_eq!(info.alias, Some("typo".to_string()));
+        state::with(|s| {
+            assert_eq!(s.namespace_aliases.get("typo"), Some(&"fixed2".to_string()));
             assert_eq!(
-                keys,
-                vec![(p2, ByteBuf::from(p2.as_slice())), (p2, ByteBuf::from([2]))]
+                s.namespace_aliases.get("fixed"),
+                Some(&"fixed2".to_string())
+            );
+        });
+
+        let info = ns::rename_namespace(&manager, "fixed2".to_string(), "typo".to_string(), 100, 6)
+            .unwrap();
+        assert_eq!(info.alias, None);
+        assert_eq!(ns::derivation_name(&"typo".to_string()), "typo");
+        state::with(|s| {
+            assert_eq!(s.namespace_aliases.get("typo"), None);
+            assert_eq!(s.namespace_aliases.get("fixed2"), Some(&"typo".to_string()));
+        });
+    }
+
+    #[test]
+    fn test_kek_rotation() {
+        let manager = Principal::from_slice(&[1, 1, 1, 1]);
+        let user = Principal::from_slice(&[2, 2, 2, 2]);
+        NAMESPACES_STORE.with_borrow_mut(|r| {
+            r.insert(
+                "kek".to_string(),
+                Namespace {
+                    managers: BTreeSet::from([manager]),
+                    users: BTreeSet::from([user]),
+                    ..Default::default()
+                },
             );
+        });
+        let namespace = "kek".to_string();
+        assert_eq!(ns::kek_version(&namespace, None).unwrap(), 0);
+        assert_eq!(
+            ns::rotate_kek(&user, namespace.clone(), 1).unwrap_err(),
+            "no permission"
+        );
+        assert_eq!(ns::rotate_kek(&manager, namespace.clone(), 1).unwrap(), 1);
+        assert_eq!(ns::rotate_kek(&manager, namespace.clone(), 2).unwrap(), 2);
+
+        assert_eq!(ns::kek_version(&namespace, None).unwrap(), 2);
+        assert_eq!(ns::kek_version(&namespace, Some(0)).unwrap(), 0);
+        assert_eq!(ns::kek_version(&namespace, Some(1)).unwrap(), 1);
+        assert_eq!(
+            ns::kek_version(&namespace, Some(3)).unwrap_err(),
+            "kek version 3 is not available, the current version is 2"
+        );
+        let info = ns::get_namespace(&manager, namespace).unwrap();
+        assert_eq!(info.kek_version, 2);
+        assert_eq!(info.updated_at, 2);
+    }
+
+    #[test]
+    fn test_ibe_public_key() {
+        use ic_cdk_management_canister::{VetKDCurve, VetKDKeyId};
+        use ic_vetkeys::{DerivedPublicKey, MasterPublicKey};
+
+        for name in ["ibe_1", "ibe_2", "ibe_renamed"] {
+            NAMESPACES_STORE.with_borrow_mut(|r| {
+                r.insert(
+                    name.to_string(),
+                    Namespace {
+                        alias: (name == "ibe_renamed").then(|| "ibe_1".to_string()),
+                        ..Default::default()
+                    },
+                );
+            });
         }
+        assert_eq!(
+            ns::ibe_public_key(&"ibe_1".to_string()).unwrap_err(),
+            "no vetKD public key"
+        );
+
+        let key_id = VetKDKeyId {
+            curve: VetKDCurve::Bls12_381_G2,
+            name: "test_key_1".to_string(),
+        };
+        let canister_key = MasterPublicKey::for_pocketic_key(&key_id)
+            .unwrap()
+            .derive_canister_key(Principal::management_canister().as_slice());
+        state::with_mut(|s| s.vetkd_public_key = Some(canister_key.serialize().into()));
+
+        let pk1 = ns::ibe_public_key(&"ibe_1".to_string()).unwrap();
+        assert!(DerivedPublicKey::deserialize(&pk1).is_ok());
+        assert_ne!(pk1, canister_key.serialize());
+        assert_ne!(pk1, ns::ibe_public_key(&"ibe_2".to_string()).unwrap());
+        // a renamed namespace keeps its key
+        assert_eq!(pk1, ns::ibe_public_key(&"ibe_renamed".to_string()).unwrap());
+        assert_eq!(
+            ns::ibe_public_key(&"unknown".to_string()).unwrap_err(),
+            "NotFound: namespace unknown not found"
+        );
     }
 }
@@ -1,5 +1,6 @@
 use ic_cdk_management_canister as mgt;
 use ic_cose_types::format_error;
+use ic_vetkeys::DerivedPublicKey;
 use sha3::Digest;
 
 pub async fn vetkd_public_key(
@@ -18,6 +19,33 @@ pub async fn vetkd_public_key(
     Ok(res.public_key)
 }
 
+/// Returns the canister-level vetKD public key, with an empty context.
+/// Public keys of derivation paths can be derived from it offline, see [`derive_vetkd_public_key`].
+pub async fn vetkd_canister_public_key(key_name: String) -> Result<Vec<u8>, String> {
+    let args = mgt::VetKDPublicKeyArgs {
+        canister_id: None,
+        context: vec![],
+        key_id: mgt::VetKDKeyId {
+            curve: mgt::VetKDCurve::Bls12_381_G2,
+            name: key_name,
+        },
+    };
+    let res = mgt::vetkd_public_key(&args).await.map_err(format_error)?;
+    Ok(res.public_key)
+}
+
+/// Derives the same public key as [`vetkd_public_key`] of the derivation path,
+/// from the canister-level vetKD public key.
+pub fn derive_vetkd_public_key(
+    canister_public_key: &[u8],
+    derivation_path: &[&[u8]],
+) -> Result<Vec<u8>, String> {
+    let pk = DerivedPublicKey::deserialize(canister_public_key)
+        .map_err(|err| format!("invalid vetKD public key: {err:?}"))?;
+    let pk = pk.derive_sub_key(&derivation_path_to_context(derivation_path));
+    Ok(pk.serialize())
+}
+
 pub async fn vetkd_encrypted_key(
     key_name: String,
     derivation_path: &[&[u8]],
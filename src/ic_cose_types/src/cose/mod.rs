@@ -96,6 +96,33 @@ pub fn skip_prefix<'a>(tag: &[u8], data: &'a [u8]) -> &'a [u8] {
     }
 }
 
+/// Separator between the key id and the big-endian KEK version in a versioned kid.
+pub const KEK_VERSION_SEPARATOR: &[u8] = b"#v";
+
+/// Returns the kid of a KEK, the key id with the KEK version appended.
+/// Version 0 is the original KEK and its kid is the key id.
+pub fn kek_key_id(key_id: &[u8], version: u32) -> Vec<u8> {
+    let mut kid = key_id.to_vec();
+    if version > 0 {
+        kid.extend_from_slice(KEK_VERSION_SEPARATOR);
+        kid.extend_from_slice(&version.to_be_bytes());
+    }
+    kid
+}
+
+/// Returns the KEK version of a kid made by [`kek_key_id`] for the key id,
+/// None if the kid was not made for the key id.
+/// The version is parsed after the key id only, a key id that ends like a version is kept as is.
+pub fn kek_key_version(key_id: &[u8], kid: &[u8]) -> Option<u32> {
+    let suffix = kid.strip_prefix(key_id)?;
+    if suffix.is_empty() {
+        return Some(0);
+    }
+    let version = suffix.strip_prefix(KEK_VERSION_SEPARATOR)?;
+    let version = u32::from_be_bytes(version.try_into().ok()?);
+    (version > 0).then_some(version)
+}
+
 pub fn cose_aes256_key(secret: [u8; 32], key_id: Vec<u8>) -> CoseKey {
     let mut key = CoseKey::new();
     key.set_kty(iana::KeyTypeSymmetric)
@@ -219,6 +246,26 @@ mod test {
         assert_eq!(key.alg().unwrap(), Some(Label::Int(iana::AlgorithmA256GCM)));
     }
 
+    #[test]
+    fn kek_key_id_roundtrips() {
+        assert_eq!(kek_key_id(b"key", 0), b"key".to_vec());
+        assert_eq!(kek_key_version(b"key", b"key"), Some(0));
+
+        let kid = kek_key_id(b"key", 3);
+        assert_eq!(kid, b"key#v\x00\x00\x00\x03".to_vec());
+        assert_eq!(kek_key_version(b"key", &kid), Some(3));
+        assert_eq!(kek_key_version(b"ey", &kid[1..]), Some(3));
+        assert_eq!(kek_key_version(b"other", &kid), None);
+        assert_eq!(kek_key_version(b"key", b"key#v\x00\x00\x00\x00"), None);
+        assert_eq!(kek_key_version(b"key", b"key#v\x00\x03"), None);
+
+        // a key id that ends like a version is not misparsed
+        let key_id = b"config#v\x01\x02\x03\x04";
+        assert_eq!(kek_key_version(key_id, key_id), Some(0));
+        assert_eq!(kek_key_version(key_id, &kek_key_id(key_id, 2)), Some(2));
+        assert_eq!(kek_key_version(b"config", key_id), Some(0x01020304));
+    }
+
     #[test]
     fn get_cose_key_secret_works() {
         let secret = vec![1u8; 32];
@@ -1,20 +1,281 @@
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use std::collections::{BTreeMap, BTreeSet};
 
-use super::validate_desc;
+use super::{setting::SettingPath, validate_desc};
 use crate::{validate_principals, validate_principals_not_anonymous, validate_str};
 
 pub const MAX_PAYLOAD_SIZE: u64 = 2_000_000; // 2MB
+/// Upper bound of a namespace's `max_payload_size`.
+/// Payloads larger than [`MAX_PAYLOAD_SIZE`] must be uploaded and downloaded in chunks.
+pub const MAX_CHUNKED_PAYLOAD_SIZE: u64 = 32 * 1024 * 1024; // 32MiB
+
+/// Gas cost of threshold signing and vetKD key derivation, in cycles.
+/// It covers the fee of the management canister on the fiduciary subnet.
+pub const GAS_COST_THRESHOLD_KEY: u128 = 30_000_000_000;
+/// Gas cost of a call that derives keys or signs in the canister, in cycles.
+pub const GAS_COST_DERIVATION: u128 = 100_000_000;
+/// Gas cost of a setting write, in cycles.
+pub const GAS_COST_SETTING_WRITE: u128 = 10_000_000;
+
+/// Returns the gas cost in cycles of a namespace API call, 0 for calls that are not metered.
+/// The cost is debited from the namespace's `gas_balance`.
+pub fn gas_cost(api: &str) -> u128 {
+    match api {
+        "ecdsa_sign"
+        | "schnorr_sign"
+        | "schnorr_sign_identity"
+        | "vetkd_encrypted_key"
+        | "vetkd_ibe_encrypted_key"
+        | "namespace_export" => GAS_COST_THRESHOLD_KEY,
+        "vetkd_public_key" | "ecdh_cose_encrypted_key" | "namespace_sign_delegation" => {
+            GAS_COST_DERIVATION
+        }
+        "setting_create"
+        | "setting_update_info"
+        | "setting_update_payload"
+        | "setting_add_readers"
+        | "setting_remove_readers"
+        | "setting_grant_readers"
+        | "setting_add_reader_groups"
+        | "setting_remove_reader_groups"
+        | "setting_restore_version" => GAS_COST_SETTING_WRITE,
+        "setting_batch" => GAS_COST_SETTING_WRITE * 4,
+        _ => 0,
+    }
+}
+
+/// Canister-level gas metering policy, set by controllers.
+/// Metered calls are only counted if it is not enforced.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct GasPolicy {
+    pub enforced: bool,
+    // gas in cycles a namespace can use on credit when its balance is insufficient, repaid by top-ups
+    pub credit_limit: u128,
+}
+
+/// Token bucket limit of the signing and key derivation calls of each caller in a namespace.
+/// A caller can make `capacity` calls in a burst, and one more call every `refill_interval_ms`.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RateLimit {
+    pub capacity: u32,           // max number of calls in a burst, 0: unlimited
+    pub refill_interval_ms: u64, // one call is refilled every interval
+}
+
+impl RateLimit {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.capacity > 0 && self.refill_interval_ms == 0 {
+            Err("refill_interval_ms should be greater than 0".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Prefix of a group reference to a canister-level group.
+/// A reference without the prefix refers to a group in the namespace.
+pub const CANISTER_GROUP_PREFIX: &str = "canister:";
+
+/// Validates a group reference, `name` or `canister:name`.
+pub fn validate_group_ref(group: &str) -> Result<(), String> {
+    validate_str(group.strip_prefix(CANISTER_GROUP_PREFIX).unwrap_or(group))
+}
+
+/// Validates a non-empty set of group references.
+pub fn validate_group_refs(groups: &BTreeSet<String>) -> Result<(), String> {
+    if groups.is_empty() {
+        Err("groups cannot be empty".to_string())?;
+    }
+    for group in groups {
+        validate_group_ref(group)?;
+    }
+    Ok(())
+}
+
+/// Actions that can be controlled by a namespace policy.
+pub const POLICY_ACTIONS: [&str; 5] = ["read", "write", "sign", "derive_kek", "vetkd"];
+/// Max number of rules in a namespace policy.
+pub const MAX_POLICY_RULES: usize = 64;
+
+/// The principals that a policy rule applies to.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum PolicySubject {
+    Any,
+    Role(String), // "manager", "auditor" or "user"
+    Principal(Principal),
+    Group(String), // `name` or `canister:name`
+}
+
+/// A rule of a namespace policy.
+/// The resource of setting actions is `[key]`, and of "sign" is the derivation path.
+/// A rule matches a resource if the resource starts with the `prefix` segments,
+/// where the last segment of the prefix only needs to be a byte prefix of the resource's segment.
+/// An empty prefix matches all resources.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PolicyRule {
+    pub subject: PolicySubject,
+    pub actions: BTreeSet<String>, // see POLICY_ACTIONS
+    pub prefix: Vec<ByteBuf>,
+    pub allow: bool,
+}
+
+impl PolicyRule {
+    pub fn validate(&self) -> Result<(), String> {
+        match &self.subject {
+            PolicySubject::Any | PolicySubject::Principal(_) => {}
+            PolicySubject::Role(role) => {
+                if !["manager", "auditor", "user"].contains(&role.as_str()) {
+                    Err(format!("invalid role: {}", role))?;
+                }
+            }
+            PolicySubject::Group(group) => validate_group_ref(group)?,
+        }
+        if self.actions.is_empty() {
+            Err("actions cannot be empty".to_string())?;
+        }
+        for action in &self.actions {
+            if !POLICY_ACTIONS.contains(&action.as_str()) {
+                Err(format!("invalid action: {}", action))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether the rule applies to the action and resource.
+    pub fn matches_resource(&self, action: &str, resource: &[&[u8]]) -> bool {
+        self.actions.contains(action) && has_path_prefix(&self.prefix, resource)
+    }
+}
+
+/// Checks whether the path starts with the prefix segments,
+/// where the last segment of the prefix only needs to be a byte prefix of the path's segment.
+pub fn has_path_prefix(prefix: &[ByteBuf], path: &[&[u8]]) -> bool {
+    if prefix.len() > path.len() {
+        return false;
+    }
+    let n = prefix.len();
+    prefix.iter().enumerate().all(|(i, p)| {
+        if i + 1 == n {
+            path[i].starts_with(p)
+        } else {
+            path[i] == p.as_slice()
+        }
+    })
+}
+
+pub fn validate_policy(rules: &[PolicyRule]) -> Result<(), String> {
+    if rules.len() > MAX_POLICY_RULES {
+        Err(format!(
+            "policy rules should be at most {}",
+            MAX_POLICY_RULES
+        ))?;
+    }
+    for rule in rules {
+        rule.validate()?;
+    }
+    Ok(())
+}
+
+/// Message formats that the signing APIs can be restricted to.
+/// "raw": any message, a 32-byte hash for ECDSA.
+/// "eip191": an EIP-191 personal message, ECDSA signs its Keccak-256 hash.
+pub const SIGNING_MESSAGE_FORMATS: [&str; 2] = ["raw", "eip191"];
+pub const EIP191_PREFIX: &[u8] = b"\x19Ethereum Signed Message:\n";
+
+/// Returns the payload of an EIP-191 personal message, None if the message is not one.
+pub fn eip191_payload(message: &[u8]) -> Option<&[u8]> {
+    let rest = message.strip_prefix(EIP191_PREFIX)?;
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    let len: usize = std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()?;
+    let payload = &rest[digits..];
+    (payload.len() == len).then_some(payload)
+}
+
+/// Restrictions of the namespace signing APIs, on top of the signing permission.
+/// If any derivation path prefix is declared, a caller can only sign with the derivation paths
+/// that match a prefix of its roles or of itself, see [`has_path_prefix`].
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SigningPolicy {
+    pub role_paths: BTreeMap<String, Vec<Vec<ByteBuf>>>, // "manager", "auditor" or "user" -> derivation path prefixes
+    pub principal_paths: BTreeMap<Principal, Vec<Vec<ByteBuf>>>, // principal -> derivation path prefixes
+    pub message_formats: BTreeSet<String>, // see SIGNING_MESSAGE_FORMATS, empty: any message
+}
+
+impl SigningPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        for role in self.role_paths.keys() {
+            if !["manager", "auditor", "user"].contains(&role.as_str()) {
+                Err(format!("invalid role: {}", role))?;
+            }
+        }
+        for format in &self.message_formats {
+            if !SIGNING_MESSAGE_FORMATS.contains(&format.as_str()) {
+                Err(format!("invalid message format: {}", format))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn restricts_paths(&self) -> bool {
+        !self.role_paths.is_empty() || !self.principal_paths.is_empty()
+    }
+
+    /// Checks the message against the allowed formats,
+    /// returns true if the message is accepted as an EIP-191 message.
+    pub fn check_message(&self, message: &[u8]) -> Result<bool, String> {
+        if self.message_formats.is_empty() {
+            return Ok(false);
+        }
+        if self.message_formats.contains("eip191") && eip191_payload(message).is_some() {
+            return Ok(true);
+        }
+        if self.message_formats.contains("raw") {
+            return Ok(false);
+        }
+        Err(format!(
+            "message format is not allowed, expected one of {:?}",
+            self.message_formats
+        ))
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CheckPermissionInput {
+    pub ns: String,
+    pub action: String,               // see POLICY_ACTIONS
+    pub principal: Option<Principal>, // default to the caller
+    pub user_owned: bool,             // for setting actions
+    pub subject: Option<Principal>,   // for setting actions, default to the principal
+    pub resource: Vec<ByteBuf>,       // [key] for setting actions, derivation path for "sign"
+}
+
+impl CheckPermissionInput {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_str(&self.ns)?;
+        if !POLICY_ACTIONS.contains(&self.action.as_str()) {
+            Err(format!("invalid action: {}", self.action))?;
+        }
+        if self.action != "sign" && self.resource.len() != 1 {
+            Err("resource should be [key] for setting actions".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CheckPermissionOutput {
+    pub allowed: bool,
+    pub rule: Option<u32>, // index of the matched policy rule, None: decided by the role model
+}
 
 fn validate_max_payload_size(max_payload_size: u64) -> Result<(), String> {
     if max_payload_size == 0 {
         Err("max_payload_size should be greater than 0".to_string())?;
     }
-    if max_payload_size > MAX_PAYLOAD_SIZE {
+    if max_payload_size > MAX_CHUNKED_PAYLOAD_SIZE {
         Err(format!(
             "max_payload_size should be less than or equal to {}",
-            MAX_PAYLOAD_SIZE
+            MAX_CHUNKED_PAYLOAD_SIZE
         ))?;
     }
     Ok(())
@@ -43,6 +304,26 @@ pub struct NamespaceInfo {
     pub gas_balance: u128,             // cycles
     pub fixed_id_names: BTreeMap<String, BTreeSet<Principal>>, // fixed identity names
     pub session_expires_in_ms: u64,    // session expiration in milliseconds for fixed identity
+    pub retain_versions: u32, // number of archived versions to keep per setting, 0: keep all
+    pub retain_archived_ms: u64, // keep archived versions newer than this age in milliseconds, 0: keep all
+    pub gas_used: u128,          // total gas cost of metered calls in cycles
+    pub gas_exempt: bool,        // metered calls are not debited if true
+    pub usage: BTreeMap<String, u64>, // API name -> number of metered calls
+    pub max_bytes_total: u64,    // max total payload size in bytes, 0: unlimited
+    pub max_settings: u64,       // max number of settings, 0: unlimited
+    pub settings_total: u64,     // number of settings
+    pub rate_limit: Option<RateLimit>, // rate limit of signing and key derivation calls per caller
+    pub groups: BTreeMap<String, BTreeSet<Principal>>, // namespace-level groups
+    pub manager_groups: BTreeSet<String>, // members of the groups are managers
+    pub auditor_groups: BTreeSet<String>, // members of the groups are auditors
+    pub user_groups: BTreeSet<String>, // members of the groups are users
+    pub policy: Vec<PolicyRule>, // first matching rule decides, the role model applies if none matches
+    pub signing_policy: Option<SigningPolicy>, // restrictions of the signing APIs
+    pub approval_policy: Option<ApprovalPolicy>, // M-of-N approval of sensitive changes
+    pub alias: Option<String>,   // the original name of a renamed namespace, used in key derivation
+    pub kek_version: u32,        // current KEK version, 0 is the original KEK
+    pub gas_credit: u128,        // gas in cycles used on credit, repaid by top-ups
+    pub moving_from: Option<String>, // the namespace being renamed or cloned into this one, None when done
 }
 
 #[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
@@ -55,6 +336,8 @@ pub struct CreateNamespaceInput {
     pub auditors: BTreeSet<Principal>, // auditors can read all settings
     pub users: BTreeSet<Principal>,    // users can read and write settings they created
     pub session_expires_in_ms: Option<u64>, // session expiration in milliseconds for fixed identity, default to 1 day
+    pub max_bytes_total: Option<u64>,       // max total payload size in bytes, 0: unlimited
+    pub max_settings: Option<u64>,          // max number of settings, 0: unlimited
 }
 
 impl CreateNamespaceInput {
@@ -74,7 +357,7 @@ impl CreateNamespaceInput {
     }
 }
 
-#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct UpdateNamespaceInput {
     pub name: String,
     pub desc: Option<String>,
@@ -82,6 +365,11 @@ pub struct UpdateNamespaceInput {
     pub status: Option<i8>,
     pub visibility: Option<u8>, // 0: private; 1: public
     pub session_expires_in_ms: Option<u64>,
+    pub retain_versions: Option<u32>, // 0: keep all archived versions
+    pub retain_archived_ms: Option<u64>, // 0: keep all archived versions
+    pub max_bytes_total: Option<u64>, // 0: unlimited
+    pub max_settings: Option<u64>,    // 0: unlimited
+    pub rate_limit: Option<RateLimit>, // capacity 0: unlimited
 }
 
 impl UpdateNamespaceInput {
@@ -103,11 +391,14 @@ impl UpdateNamespaceInput {
         if let Some(visibility) = self.visibility {
             validate_visibility(visibility)?;
         }
+        if let Some(ref rate_limit) = self.rate_limit {
+            rate_limit.validate()?;
+        }
         Ok(())
     }
 }
 
-#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct NamespaceDelegatorsInput {
     pub ns: String,
     pub name: String,
@@ -123,6 +414,146 @@ impl NamespaceDelegatorsInput {
     }
 }
 
+/// M-of-N approval of sensitive namespace changes by the namespace managers.
+/// A change executes once `threshold` managers, including the proposer, approved it.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ApprovalPolicy {
+    pub threshold: u32,     // number of approvals required
+    pub expires_in_ms: u64, // a proposal expires if it is not executed in time
+}
+
+impl ApprovalPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 {
+            Err("threshold should be greater than 0".to_string())?;
+        }
+        if self.expires_in_ms == 0 {
+            Err("expires_in_ms should be greater than 0".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Sensitive namespace changes that require approval if the namespace has an approval policy.
+/// Changes of managers, groups, role groups and the policy can grant the manager role,
+/// so they all require approval.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum NamespaceChange {
+    AddManagers(BTreeSet<Principal>),
+    RemoveManagers(BTreeSet<Principal>),
+    AddGroupMembers(String, BTreeSet<Principal>), // (group name, members)
+    RemoveGroupMembers(String, BTreeSet<Principal>),
+    AddRoleGroups(String, BTreeSet<String>), // (role, group references)
+    RemoveRoleGroups(String, BTreeSet<String>),
+    UpdatePolicy(Vec<PolicyRule>),
+    UpdateSigningPolicy(Option<SigningPolicy>),
+    UpdateInfo(UpdateNamespaceInput), // changes of status or visibility require approval
+    AddDelegator(NamespaceDelegatorsInput),
+    RemoveDelegator(NamespaceDelegatorsInput),
+    UpdateApprovalPolicy(Option<ApprovalPolicy>),
+    Delete,
+}
+
+impl NamespaceChange {
+    pub fn validate(&self, namespace: &str) -> Result<(), String> {
+        let target = match self {
+            NamespaceChange::AddManagers(args) | NamespaceChange::RemoveManagers(args) => {
+                return validate_principals(args)
+            }
+            NamespaceChange::AddGroupMembers(name, members)
+            | NamespaceChange::RemoveGroupMembers(name, members) => {
+                validate_str(name)?;
+                return validate_principals(members);
+            }
+            NamespaceChange::AddRoleGroups(role, groups)
+            | NamespaceChange::RemoveRoleGroups(role, groups) => {
+                if !matches!(role.as_str(), "manager" | "auditor" | "user") {
+                    Err(format!("invalid role: {}", role))?;
+                }
+                return validate_group_refs(groups);
+            }
+            NamespaceChange::UpdatePolicy(rules) => return validate_policy(rules),
+            NamespaceChange::UpdateSigningPolicy(Some(policy)) => return policy.validate(),
+            NamespaceChange::UpdateInfo(input) => {
+                input.validate()?;
+                &input.name
+            }
+            NamespaceChange::AddDelegator(input) | NamespaceChange::RemoveDelegator(input) => {
+                input.validate()?;
+                &input.ns
+            }
+            NamespaceChange::UpdateApprovalPolicy(Some(policy)) => return policy.validate(),
+            NamespaceChange::UpdateSigningPolicy(None)
+            | NamespaceChange::UpdateApprovalPolicy(None)
+            | NamespaceChange::Delete => return Ok(()),
+        };
+        if target != namespace {
+            Err(format!("namespace mismatch, expected {}", namespace))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct ProposalInfo {
+    pub id: u64,
+    pub change: NamespaceChange,
+    pub description: String, // human readable description of the change
+    pub proposer: Principal,
+    pub approvers: BTreeSet<Principal>,
+    pub created_at: u64, // unix timestamp in milliseconds
+    pub expires_at: u64, // unix timestamp in milliseconds
+    pub executed: bool,
+}
+
+/// Max size in bytes of the payloads in a page of namespace export.
+/// The payloads of a larger setting are exported in chunks of [`super::setting::CHUNK_SIZE`] in the following pages.
+pub const MAX_EXPORT_PAGE_SIZE: u64 = 1_500_000;
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ExportNamespaceInput {
+    pub ns: String,
+    pub cursor: Option<ByteBuf>, // next_cursor of the previous page, None for the first page
+}
+
+/// A page of namespace export. The first page contains the namespace,
+/// and every page contains a batch of settings with their archived versions.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct NamespaceExportPage {
+    pub bundle: ByteBuf,              // CBOR encoded page
+    pub signature: ByteBuf,           // Ed25519 signature of the SHA-256 hash of the bundle
+    pub public_key: ByteBuf,          // Ed25519 public key of the exporting canister
+    pub next_cursor: Option<ByteBuf>, // None if it is the last page
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImportNamespaceInput {
+    pub name: String, // name of the imported namespace, can differ from the exported one
+    pub bundle: ByteBuf, // bundle of an export page, imported in order
+    pub signature: ByteBuf, // signature of the export page, verified with the trusted key of the exporting canister
+}
+
+impl ImportNamespaceInput {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_str(&self.name)?;
+        if self.bundle.is_empty() {
+            Err("bundle cannot be empty".to_string())?;
+        }
+        if self.signature.len() != 64 {
+            Err("signature should be 64 bytes".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct CloneNamespaceOutput {
+    pub namespace: NamespaceInfo,
+    // encrypted settings of the source namespace that were not cloned by this batch,
+    // they should be decrypted with the source KEK and re-created in the cloned namespace
+    pub reencrypt: Vec<SettingPath>,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -183,11 +614,14 @@ mod test {
 
         let mut input = create_namespace_input();
         input.max_payload_size = Some(MAX_PAYLOAD_SIZE + 1);
+        assert!(input.validate().is_ok());
+
+        input.max_payload_size = Some(MAX_CHUNKED_PAYLOAD_SIZE + 1);
         assert_eq!(
             input.validate().unwrap_err(),
             format!(
                 "max_payload_size should be less than or equal to {}",
-                MAX_PAYLOAD_SIZE
+                MAX_CHUNKED_PAYLOAD_SIZE
             )
         );
     }
@@ -215,6 +649,18 @@ mod test {
         input.visibility = Some(3);
         assert_eq!(input.validate().unwrap_err(), "visibility should be 0 or 1");
 
+        input.visibility = None;
+        input.rate_limit = Some(RateLimit {
+            capacity: 10,
+            refill_interval_ms: 0,
+        });
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "refill_interval_ms should be greater than 0"
+        );
+        input.rate_limit = Some(RateLimit::default());
+        assert!(input.validate().is_ok());
+
         input.visibility = None;
         input.max_payload_size = Some(0);
         assert_eq!(
@@ -223,11 +669,14 @@ mod test {
         );
 
         input.max_payload_size = Some(MAX_PAYLOAD_SIZE + 1);
+        assert!(input.validate().is_ok());
+
+        input.max_payload_size = Some(MAX_CHUNKED_PAYLOAD_SIZE + 1);
         assert_eq!(
             input.validate().unwrap_err(),
             format!(
                 "max_payload_size should be less than or equal to {}",
-                MAX_PAYLOAD_SIZE
+                MAX_CHUNKED_PAYLOAD_SIZE
             )
         );
     }
@@ -260,6 +709,182 @@ mod test {
         );
     }
 
+    #[test]
+    fn gas_cost_covers_metered_apis() {
+        assert_eq!(gas_cost("ecdsa_sign"), GAS_COST_THRESHOLD_KEY);
+        assert_eq!(gas_cost("vetkd_encrypted_key"), GAS_COST_THRESHOLD_KEY);
+        assert_eq!(gas_cost("vetkd_ibe_encrypted_key"), GAS_COST_THRESHOLD_KEY);
+        assert_eq!(gas_cost("ecdh_cose_encrypted_key"), GAS_COST_DERIVATION);
+        assert_eq!(gas_cost("setting_create"), GAS_COST_SETTING_WRITE);
+        assert!(gas_cost("setting_batch") > GAS_COST_SETTING_WRITE);
+        assert_eq!(gas_cost("namespace_get_info"), 0);
+        // deletes are never charged, so that a namespace without gas can free its data
+        assert_eq!(gas_cost("setting_delete"), 0);
+    }
+
+    #[test]
+    fn group_refs_validate() {
+        assert!(validate_group_ref("group_1").is_ok());
+        assert!(validate_group_ref("canister:group_1").is_ok());
+        assert_eq!(validate_group_ref("canister:").unwrap_err(), "empty string");
+        assert_eq!(
+            validate_group_ref("ns:group_1").unwrap_err(),
+            "invalid character: :"
+        );
+        assert_eq!(
+            validate_group_refs(&BTreeSet::new()).unwrap_err(),
+            "groups cannot be empty"
+        );
+        assert!(validate_group_refs(&BTreeSet::from(["group_1".to_string()])).is_ok());
+    }
+
+    #[test]
+    fn policy_rules_validate_and_match() {
+        let mut rule = PolicyRule {
+            subject: PolicySubject::Role("user".to_string()),
+            actions: BTreeSet::from(["sign".to_string()]),
+            prefix: vec![ByteBuf::from("wallet"), ByteBuf::from("eth")],
+            allow: true,
+        };
+        assert!(rule.validate().is_ok());
+        assert!(rule.matches_resource("sign", &[b"wallet", b"eth"]));
+        assert!(rule.matches_resource("sign", &[b"wallet", b"eth_1", b"0"]));
+        assert!(!rule.matches_resource("sign", &[b"wallet_1", b"eth"]));
+        assert!(!rule.matches_resource("sign", &[b"wallet"]));
+        assert!(!rule.matches_resource("read", &[b"wallet", b"eth"]));
+
+        rule.prefix.clear();
+        assert!(rule.matches_resource("sign", &[]));
+
+        rule.subject = PolicySubject::Role("owner".to_string());
+        assert_eq!(rule.validate().unwrap_err(), "invalid role: owner");
+        rule.subject = PolicySubject::Group("canister:".to_string());
+        assert_eq!(rule.validate().unwrap_err(), "empty string");
+        rule.subject = PolicySubject::Any;
+        rule.actions.insert("delete".to_string());
+        assert_eq!(rule.validate().unwrap_err(), "invalid action: delete");
+        rule.actions.clear();
+        assert_eq!(rule.validate().unwrap_err(), "actions cannot be empty");
+
+        rule.actions.insert("read".to_string());
+        assert!(validate_policy(&vec![rule.clone(); MAX_POLICY_RULES]).is_ok());
+        assert_eq!(
+            validate_policy(&vec![rule; MAX_POLICY_RULES + 1]).unwrap_err(),
+            format!("policy rules should be at most {}", MAX_POLICY_RULES)
+        );
+
+        let mut input = CheckPermissionInput {
+            ns: "namespace_1".to_string(),
+            action: "write".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "resource should be [key] for setting actions"
+        );
+        input.resource.push(ByteBuf::from("key"));
+        assert!(input.validate().is_ok());
+        input.action = "sign".to_string();
+        input.resource.clear();
+        assert!(input.validate().is_ok());
+        input.action = "admin".to_string();
+        assert_eq!(input.validate().unwrap_err(), "invalid action: admin");
+    }
+
+    #[test]
+    fn signing_policy_checks_messages() {
+        let mut message = EIP191_PREFIX.to_vec();
+        message.extend_from_slice(b"5hello");
+        assert_eq!(eip191_payload(&message), Some(&b"hello"[..]));
+        assert_eq!(eip191_payload(&message[..message.len() - 1]), None);
+        assert_eq!(eip191_payload(&EIP191_PREFIX[1..]), None);
+        assert_eq!(eip191_payload(EIP191_PREFIX), None);
+
+        let mut policy = SigningPolicy::default();
+        assert!(policy.validate().is_ok());
+        assert!(!policy.restricts_paths());
+        assert_eq!(policy.check_message(&message), Ok(false));
+        assert_eq!(policy.check_message(&[0u8; 32]), Ok(false));
+
+        policy.message_formats.insert("eip191".to_string());
+        assert_eq!(policy.check_message(&message), Ok(true));
+        assert_eq!(
+            policy.check_message(&[0u8; 32]).unwrap_err(),
+            "message format is not allowed, expected one of {\"eip191\"}"
+        );
+        policy.message_formats.insert("raw".to_string());
+        assert_eq!(policy.check_message(&[0u8; 32]), Ok(false));
+
+        policy.message_formats.insert("tx".to_string());
+        assert_eq!(policy.validate().unwrap_err(), "invalid message format: tx");
+        policy.message_formats.remove("tx");
+        policy.role_paths.insert("owner".to_string(), vec![vec![]]);
+        assert_eq!(policy.validate().unwrap_err(), "invalid role: owner");
+        assert!(policy.restricts_paths());
+    }
+
+    #[test]
+    fn namespace_change_validate() {
+        let policy = ApprovalPolicy {
+            threshold: 0,
+            expires_in_ms: 1000,
+        };
+        assert_eq!(
+            policy.validate().unwrap_err(),
+            "threshold should be greater than 0"
+        );
+        let change = NamespaceChange::UpdateApprovalPolicy(Some(policy));
+        assert_eq!(
+            change.validate("ns").unwrap_err(),
+            "threshold should be greater than 0"
+        );
+        assert!(NamespaceChange::UpdateApprovalPolicy(None)
+            .validate("ns")
+            .is_ok());
+        assert!(NamespaceChange::Delete.validate("ns").is_ok());
+        assert_eq!(
+            NamespaceChange::RemoveManagers(BTreeSet::new())
+                .validate("ns")
+                .unwrap_err(),
+            "principals cannot be empty"
+        );
+
+        let change = NamespaceChange::UpdateInfo(UpdateNamespaceInput {
+            name: "ns".to_string(),
+            status: Some(1),
+            ..Default::default()
+        });
+        assert!(change.validate("ns").is_ok());
+        assert_eq!(
+            change.validate("ns2").unwrap_err(),
+            "namespace mismatch, expected ns2"
+        );
+        let change = NamespaceChange::RemoveDelegator(NamespaceDelegatorsInput {
+            ns: "ns".to_string(),
+            name: "id".to_string(),
+            delegators: principal_set(),
+        });
+        assert!(change.validate("ns").is_ok());
+        assert!(!encode_one(change).unwrap().is_empty());
+    }
+
+    #[test]
+    fn import_namespace_validate() {
+        let mut input = ImportNamespaceInput {
+            name: "namespace_1".to_string(),
+            bundle: ByteBuf::from(vec![1]),
+            signature: ByteBuf::from(vec![0; 64]),
+        };
+        assert!(input.validate().is_ok());
+        input.signature = ByteBuf::new();
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "signature should be 64 bytes"
+        );
+        input.bundle = ByteBuf::new();
+        assert_eq!(input.validate().unwrap_err(), "bundle cannot be empty");
+    }
+
     #[test]
     fn namespace_info_derived_traits_work() {
         let info = NamespaceInfo {
@@ -277,6 +902,37 @@ mod test {
             gas_balance: 100,
             fixed_id_names: BTreeMap::from([("fixed".to_string(), principal_set())]),
             session_expires_in_ms: 1000,
+            retain_versions: 3,
+            retain_archived_ms: 0,
+            gas_used: 30,
+            gas_exempt: false,
+            usage: BTreeMap::from([("ecdsa_sign".to_string(), 1)]),
+            max_bytes_total: 1024 * 1024,
+            max_settings: 100,
+            settings_total: 1,
+            rate_limit: Some(RateLimit {
+                capacity: 10,
+                refill_interval_ms: 1000,
+            }),
+            groups: BTreeMap::from([("group_1".to_string(), principal_set())]),
+            manager_groups: BTreeSet::from(["group_1".to_string()]),
+            auditor_groups: BTreeSet::new(),
+            user_groups: BTreeSet::from(["canister:group_2".to_string()]),
+            policy: vec![PolicyRule {
+                subject: PolicySubject::Role("user".to_string()),
+                actions: BTreeSet::from(["sign".to_string()]),
+                prefix: vec![ByteBuf::from("wallet")],
+                allow: true,
+            }],
+            signing_policy: Some(SigningPolicy::default()),
+            approval_policy: Some(ApprovalPolicy {
+                threshold: 2,
+                expires_in_ms: 3600 * 1000,
+            }),
+            alias: Some("namespace_0".to_string()),
+            kek_version: 1,
+            gas_credit: 0,
+            moving_from: None,
         };
         assert_eq!(info.clone(), info);
         assert!(!format!("{info:?}").is_empty());
@@ -1,17 +1,39 @@
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
-use serde_bytes::ByteBuf;
-use std::collections::{BTreeMap, BTreeSet};
+use serde_bytes::{ByteArray, ByteBuf};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    str::FromStr,
+};
 
 use super::validate_desc;
-use crate::validate_str;
+use crate::{cose::sha256, to_cbor_bytes, validate_str};
 
+/// Size of a payload chunk for chunked uploads and downloads.
 pub const CHUNK_SIZE: u32 = 256 * 1024;
 pub const MAX_DEK_SIZE: u64 = 3 * 1024;
 /// Maximum number of tags per setting.
 pub const MAX_TAGS: usize = 32;
 /// Maximum byte length of a tag value.
 pub const MAX_TAG_VALUE_SIZE: usize = 256;
+/// Maximum number of operations in a setting batch.
+pub const MAX_BATCH_OPS: usize = 32;
+/// Maximum number of settings in a search page.
+pub const MAX_SEARCH_LIMIT: u32 = 100;
+/// Maximum number of settings in a listing page.
+pub const MAX_LIST_LIMIT: u32 = 1000;
+/// Maximum number of changes in a change log page.
+pub const MAX_CHANGES_LIMIT: u32 = 1000;
+/// Prefix of the error returned for a cursor whose following changes have been dropped from the change log.
+/// The client should resync the namespace and then watch from the cursor 0.
+pub const CURSOR_TOO_OLD: &str = "cursor too old";
+/// Label of the settings subtree in the canister's certified data.
+pub const LABEL_SETTINGS: &[u8] = b"settings";
+/// Label of the metadata hash of a setting in the certified settings tree.
+pub const LABEL_SETTING_META: &[u8] = b"meta";
+/// Label of the payload hash of a setting in the certified settings tree.
+pub const LABEL_SETTING_PAYLOAD: &[u8] = b"payload";
 
 fn validate_tags(tags: &BTreeMap<String, String>) -> Result<(), String> {
     if tags.len() > MAX_TAGS {
@@ -39,9 +61,62 @@ pub struct SettingInfo {
     pub status: i8,      // -1: archived; 0: readable and writable; 1: readonly
     pub version: u32,
     pub readers: BTreeSet<Principal>, // readers can read the setting
+    pub reader_groups: BTreeSet<String>, // members of the groups can read the setting
     pub tags: BTreeMap<String, String>, // tags for query
     pub dek: Option<ByteBuf>, // Data Encryption Key encrypted by BYOK or vetKey in COSE_Encrypt0
     pub payload: Option<ByteBuf>, // encrypted or plain payload
+    pub expires_at: Option<u64>, // unix timestamp in milliseconds, the setting is purged after it
+    pub restored_from: Option<u32>, // the archived version that the current version was restored from
+    pub certificate: Option<ByteBuf>, // IC certificate in CBOR, only returned by query calls
+    pub witness: Option<ByteBuf>, // hash tree in CBOR that proves the setting is in the certified data
+}
+
+impl SettingInfo {
+    /// Returns the hash of the setting metadata in the certified settings tree.
+    /// It covers all fields except `dek`, `payload`, `certificate` and `witness`.
+    pub fn meta_hash(&self) -> [u8; 32] {
+        sha256(&to_cbor_bytes(&(
+            &self.key,
+            &self.subject,
+            &self.desc,
+            self.created_at,
+            self.updated_at,
+            self.status,
+            self.version,
+            &self.readers,
+            &self.tags,
+            self.expires_at,
+            self.restored_from,
+            &self.reader_groups,
+        )))
+    }
+
+    /// Returns the hash of the setting payload and dek in the certified settings tree.
+    pub fn payload_hash(&self) -> [u8; 32] {
+        let payload_sha256 = self.payload.as_ref().map(|p| sha256(p));
+        certified_payload_hash(payload_sha256.as_ref(), self.dek.as_ref())
+    }
+}
+
+/// Returns the hash of a setting payload and dek in the certified settings tree.
+/// The payload is covered by its sha256 hash,
+/// so that a payload stored in chunks can be certified without reading it.
+pub fn certified_payload_hash(
+    payload_sha256: Option<&[u8; 32]>,
+    dek: Option<&ByteBuf>,
+) -> [u8; 32] {
+    let payload_sha256 = payload_sha256.map(|h| serde_bytes::Bytes::new(h));
+    sha256(&to_cbor_bytes(&(payload_sha256, dek)))
+}
+
+/// Returns the key of the setting in the certified settings tree.
+pub fn certified_setting_key(
+    ns: &str,
+    user_owned: bool,
+    subject: &Principal,
+    key: &ByteBuf,
+) -> Vec<u8> {
+    to_cbor_bytes(&(ns, if user_owned { 1u8 } else { 0u8 }, subject, key))
 }
 
 #[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
@@ -73,6 +148,8 @@ pub struct CreateSettingInput {
     pub status: Option<i8>,
     pub tags: Option<BTreeMap<String, String>>,
     pub dek: Option<ByteBuf>,
+    pub payload_sha256: Option<ByteArray<32>>, // use the payload uploaded in chunks with this sha256 hash
+    pub expires_at: Option<u64>, // unix timestamp in milliseconds, 0 means never expires
 }
 
 impl CreateSettingInput {
@@ -82,6 +159,9 @@ impl CreateSettingInput {
                 Err("status should be 0 or 1".to_string())?;
             }
         }
+        if self.payload.is_some() && self.payload_sha256.is_some() {
+            Err("payload and payload_sha256 should not be provided together".to_string())?;
+        }
         if let Some(ref desc) = self.desc {
             validate_desc(desc)?;
         }
@@ -109,6 +189,9 @@ pub struct UpdateSettingInfoInput {
     pub desc: Option<String>,
     pub status: Option<i8>,
     pub tags: Option<BTreeMap<String, String>>,
+    pub expected_hash: Option<ByteArray<32>>, // sha256 hash of the current payload for compare-and-swap
+    pub expected_updated_at: Option<u64>,     // current updated_at for compare-and-swap
+    pub expires_at: Option<u64>, // unix timestamp in milliseconds, 0 means never expires
 }
 
 impl UpdateSettingInfoInput {
@@ -134,6 +217,9 @@ pub struct UpdateSettingPayloadInput {
     pub status: Option<i8>,
     pub deprecate_current: Option<bool>, // deprecate the current version
     pub dek: Option<ByteBuf>,
+    pub payload_sha256: Option<ByteArray<32>>, // use the payload uploaded in chunks with this sha256 hash
+    pub expected_hash: Option<ByteArray<32>>, // sha256 hash of the current payload for compare-and-swap
+    pub expected_updated_at: Option<u64>,     // current updated_at for compare-and-swap
 }
 
 impl UpdateSettingPayloadInput {
@@ -143,9 +229,12 @@ impl UpdateSettingPayloadInput {
                 Err("status should be -1, 0 or 1".to_string())?;
             }
         }
-        if self.payload.is_none() && self.dek.is_none() {
+        if self.payload.is_none() && self.dek.is_none() && self.payload_sha256.is_none() {
             Err("payload or dek should be provided".to_string())?;
         }
+        if self.payload.is_some() && self.payload_sha256.is_some() {
+            Err("payload and payload_sha256 should not be provided together".to_string())?;
+        }
         if let Some(ref dek) = self.dek {
             if dek.len() > MAX_DEK_SIZE as usize {
                 Err("DEK size exceeds the limit".to_string())?;
@@ -157,6 +246,44 @@ impl UpdateSettingPayloadInput {
 
 pub type UpdateSettingOutput = CreateSettingOutput;
 
+/// The error returned when the compare-and-swap conditions of an update are not met.
+///
+/// It is carried in the `Err` string as
+/// `Conflict: {reason}, version: {version}, updated_at: {updated_at}`
+/// and can be parsed back with [`str::parse`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SettingConflict {
+    pub reason: String,
+    pub version: u32,    // current version of the setting
+    pub updated_at: u64, // current updated_at of the setting
+}
+
+impl fmt::Display for SettingConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Conflict: {}, version: {}, updated_at: {}",
+            self.reason, self.version, self.updated_at
+        )
+    }
+}
+
+impl FromStr for SettingConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("invalid conflict error: {}", s);
+        let rest = s.strip_prefix("Conflict: ").ok_or_else(err)?;
+        let (rest, updated_at) = rest.rsplit_once(", updated_at: ").ok_or_else(err)?;
+        let (reason, version) = rest.rsplit_once(", version: ").ok_or_else(err)?;
+        Ok(SettingConflict {
+            reason: reason.to_string(),
+            version: version.parse().map_err(|_| err())?,
+            updated_at: updated_at.parse().map_err(|_| err())?,
+        })
+    }
+}
+
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
 pub struct SettingArchivedPayload {
     pub version: u32,
@@ -166,6 +293,201 @@ pub struct SettingArchivedPayload {
     pub dek: Option<ByteBuf>, // exist if the payload is encrypted
 }
 
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct SettingChunkOutput {
+    pub version: u32,
+    pub payload_size: u64, // total payload size in bytes
+    pub chunk_index: u32,
+    pub chunk: ByteBuf,
+    pub dek: Option<ByteBuf>, // exist if the payload is encrypted
+}
+
+impl SettingChunkOutput {
+    /// Returns the number of chunks of the payload.
+    pub fn chunks(&self) -> u32 {
+        self.payload_size.div_ceil(CHUNK_SIZE as u64) as u32
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub enum SettingBatchOp {
+    Create {
+        path: SettingPath,
+        input: CreateSettingInput,
+    },
+    UpdatePayload {
+        path: SettingPath,
+        input: UpdateSettingPayloadInput,
+    },
+    UpdateInfo {
+        path: SettingPath,
+        input: UpdateSettingInfoInput,
+    },
+    Delete {
+        path: SettingPath,
+    },
+}
+
+impl SettingBatchOp {
+    pub fn path(&self) -> &SettingPath {
+        match self {
+            SettingBatchOp::Create { path, .. } => path,
+            SettingBatchOp::UpdatePayload { path, .. } => path,
+            SettingBatchOp::UpdateInfo { path, .. } => path,
+            SettingBatchOp::Delete { path } => path,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            SettingBatchOp::Create { path, input } => {
+                path.validate()?;
+                input.validate()
+            }
+            SettingBatchOp::UpdatePayload { path, input } => {
+                path.validate()?;
+                input.validate()
+            }
+            SettingBatchOp::UpdateInfo { path, input } => {
+                path.validate()?;
+                input.validate()
+            }
+            SettingBatchOp::Delete { path } => path.validate(),
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct SettingBatchInput {
+    pub ns: String,
+    pub ops: Vec<SettingBatchOp>,
+}
+
+impl SettingBatchInput {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_str(&self.ns)?;
+        if self.ops.is_empty() {
+            Err("ops should not be empty".to_string())?;
+        }
+        if self.ops.len() > MAX_BATCH_OPS {
+            Err(format!("ops count exceeds the limit {}", MAX_BATCH_OPS))?;
+        }
+        for op in self.ops.iter() {
+            if op.path().ns != self.ns {
+                Err(format!(
+                    "operation namespace {} does not match {}",
+                    op.path().ns,
+                    self.ns
+                ))?;
+            }
+            op.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SearchSettingsInput {
+    pub ns: String,
+    pub tag: String,             // tag key
+    pub value: String,           // tag value, or the prefix of tag values if `prefix` is true
+    pub prefix: bool,            // match tag values by prefix
+    pub cursor: Option<ByteBuf>, // `next_cursor` of the previous page
+    pub limit: Option<u32>,      // default to MAX_SEARCH_LIMIT
+}
+
+impl SearchSettingsInput {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_str(&self.ns)?;
+        validate_str(&self.tag)?;
+        if self.value.len() > MAX_TAG_VALUE_SIZE {
+            Err(format!(
+                "tag value length exceeds the limit {}",
+                MAX_TAG_VALUE_SIZE
+            ))?;
+        }
+        if self.limit == Some(0) {
+            Err("limit should be greater than 0".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct SearchSettingsOutput {
+    pub settings: Vec<SettingInfo>,   // settings without payload and dek
+    pub next_cursor: Option<ByteBuf>, // None if there are no more results
+}
+
+/// Setting metadata without payload, readers and dek.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SettingHeader {
+    pub subject: Principal,
+    pub key: ByteBuf,
+    pub version: u32,
+    pub status: i8,
+    pub tags: BTreeMap<String, String>,
+    pub created_at: u64,         // unix timestamp in milliseconds
+    pub updated_at: u64,         // unix timestamp in milliseconds
+    pub payload_size: u64,       // size of the current payload in bytes
+    pub expires_at: Option<u64>, // unix timestamp in milliseconds
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ListSettingsInput {
+    pub ns: String,
+    pub user_owned: bool,
+    pub subject: Option<Principal>,
+    pub start_after: Option<(Principal, ByteBuf)>, // (subject, key) of the last setting of the previous page
+    pub limit: Option<u32>,                        // default to MAX_LIST_LIMIT
+    pub with_header: bool,                         // return headers instead of keys
+}
+
+impl ListSettingsInput {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_str(&self.ns)?;
+        if self.limit == Some(0) {
+            Err("limit should be greater than 0".to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ListSettingsOutput {
+    pub keys: Vec<(Principal, ByteBuf)>, // empty if `with_header` is true
+    pub headers: Vec<SettingHeader>,     // empty if `with_header` is false
+    pub next_start_after: Option<(Principal, ByteBuf)>, // None if there are no more settings
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum SettingChangeKind {
+    Create,
+    UpdatePayload,
+    UpdateInfo,
+    UpdateStatus,
+    UpdateReaders,
+    Delete,
+}
+
+/// An entry of the namespace change log.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SettingChange {
+    pub seq: u64, // monotonically increasing in the namespace, starting from 1
+    pub kind: SettingChangeKind,
+    pub user_owned: bool,
+    pub subject: Principal,
+    pub key: ByteBuf,
+    pub version: u32, // version of the setting after the change, or the deleted version
+    pub changed_at: u64, // unix timestamp in milliseconds
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SettingChangesOutput {
+    pub changes: Vec<SettingChange>,
+    pub next_cursor: u64, // seq of the last scanned change, used as the cursor of the next page
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -213,6 +535,16 @@ mod test {
         assert!(!crate::to_cbor_bytes(&input).is_empty());
         assert_eq!(input.validate().unwrap_err(), "status should be 0 or 1");
 
+        let input = CreateSettingInput {
+            payload: Some(ByteBuf::from(vec![1])),
+            payload_sha256: Some([0u8; 32].into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "payload and payload_sha256 should not be provided together"
+        );
+
         let input = CreateSettingInput {
             tags: Some(BTreeMap::from([(
                 "Invalid".to_string(),
@@ -327,6 +659,22 @@ mod test {
         assert!(!crate::to_cbor_bytes(&input).is_empty());
         assert!(input.validate().is_ok());
 
+        let input = UpdateSettingPayloadInput {
+            payload_sha256: Some([0u8; 32].into()),
+            ..Default::default()
+        };
+        assert!(input.validate().is_ok());
+
+        let input = UpdateSettingPayloadInput {
+            payload: Some(ByteBuf::new()),
+            payload_sha256: Some([0u8; 32].into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "payload and payload_sha256 should not be provided together"
+        );
+
         let input = UpdateSettingPayloadInput {
             payload: Some(ByteBuf::new()),
             status: Some(2),
@@ -341,6 +689,168 @@ mod test {
         assert_eq!(input.validate().unwrap_err(), "DEK size exceeds the limit");
     }
 
+    #[test]
+    fn setting_conflict_roundtrips_through_error_string() {
+        let conflict = SettingConflict {
+            reason: "payload hash mismatch".to_string(),
+            version: 3,
+            updated_at: 1_700_000_000_000,
+        };
+        let err = conflict.to_string();
+        assert_eq!(
+            err,
+            "Conflict: payload hash mismatch, version: 3, updated_at: 1700000000000"
+        );
+        assert_eq!(err.parse::<SettingConflict>().unwrap(), conflict);
+
+        assert_eq!(
+            "NotFound: setting".parse::<SettingConflict>().unwrap_err(),
+            "invalid conflict error: NotFound: setting"
+        );
+        assert!("Conflict: x, version: a, updated_at: 1"
+            .parse::<SettingConflict>()
+            .is_err());
+    }
+
+    #[test]
+    fn setting_batch_input_validate_checks_ops() {
+        let path = SettingPath {
+            ns: "namespace_1".to_string(),
+            key: ByteBuf::from(vec![1]),
+            ..Default::default()
+        };
+        let mut input = SettingBatchInput {
+            ns: "namespace_1".to_string(),
+            ops: vec![],
+        };
+        assert_eq!(input.validate().unwrap_err(), "ops should not be empty");
+
+        input.ops = vec![
+            SettingBatchOp::Create {
+                path: path.clone(),
+                input: CreateSettingInput::default(),
+            },
+            SettingBatchOp::UpdateInfo {
+                path: path.clone(),
+                input: UpdateSettingInfoInput::default(),
+            },
+            SettingBatchOp::Delete { path: path.clone() },
+        ];
+        assert!(input.validate().is_ok());
+        assert_eq!(input.ops[2].path(), &path);
+        assert!(!encode_one(input.clone()).unwrap().is_empty());
+
+        input.ops.push(SettingBatchOp::UpdatePayload {
+            path: path.clone(),
+            input: UpdateSettingPayloadInput::default(),
+        });
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "payload or dek should be provided"
+        );
+
+        input.ops.pop();
+        input.ops.push(SettingBatchOp::Delete {
+            path: SettingPath {
+                ns: "namespace_2".to_string(),
+                ..path.clone()
+            },
+        });
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "operation namespace namespace_2 does not match namespace_1"
+        );
+
+        input.ops = vec![SettingBatchOp::Delete { path }; MAX_BATCH_OPS + 1];
+        assert!(input.validate().is_err());
+    }
+
+    #[test]
+    fn search_settings_input_validate_checks_tag_and_limit() {
+        let mut input = SearchSettingsInput {
+            ns: "namespace_1".to_string(),
+            tag: "env".to_string(),
+            value: "prod".to_string(),
+            ..Default::default()
+        };
+        assert!(input.validate().is_ok());
+
+        input.limit = Some(0);
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "limit should be greater than 0"
+        );
+        input.limit = Some(MAX_SEARCH_LIMIT);
+        input.value = "v".repeat(MAX_TAG_VALUE_SIZE + 1);
+        assert!(input.validate().is_err());
+        input.value = String::new();
+        input.prefix = true;
+        assert!(input.validate().is_ok());
+        input.tag = "Env".to_string();
+        assert_eq!(input.validate().unwrap_err(), "invalid character: E");
+    }
+
+    #[test]
+    fn list_settings_input_validate_checks_namespace_and_limit() {
+        let mut input = ListSettingsInput {
+            ns: "namespace_1".to_string(),
+            ..Default::default()
+        };
+        assert!(input.validate().is_ok());
+        input.limit = Some(0);
+        assert_eq!(
+            input.validate().unwrap_err(),
+            "limit should be greater than 0"
+        );
+        input.limit = Some(MAX_LIST_LIMIT + 1);
+        assert!(input.validate().is_ok());
+        input.ns = String::new();
+        assert!(input.validate().is_err());
+    }
+
+    #[test]
+    fn setting_info_certified_hashes_cover_expected_fields() {
+        let info = SettingInfo {
+            key: ByteBuf::from(vec![1]),
+            subject: Principal::management_canister(),
+            desc: "desc".to_string(),
+            created_at: 1,
+            updated_at: 2,
+            status: 0,
+            version: 3,
+            readers: BTreeSet::new(),
+            reader_groups: BTreeSet::new(),
+            tags: BTreeMap::new(),
+            dek: None,
+            payload: Some(ByteBuf::from(vec![4])),
+            expires_at: None,
+            restored_from: None,
+            certificate: None,
+            witness: None,
+        };
+        let meta_hash = info.meta_hash();
+        let payload_hash = info.payload_hash();
+
+        let mut other = info.clone();
+        other.payload = None;
+        other.certificate = Some(ByteBuf::from(vec![5]));
+        other.witness = Some(ByteBuf::from(vec![6]));
+        assert_eq!(other.meta_hash(), meta_hash);
+        assert_ne!(other.payload_hash(), payload_hash);
+
+        other.readers.insert(Principal::anonymous());
+        assert_ne!(other.meta_hash(), meta_hash);
+        let readers_hash = other.meta_hash();
+        other.reader_groups.insert("group_1".to_string());
+        assert_ne!(other.meta_hash(), readers_hash);
+
+        let subject = Principal::management_canister();
+        assert_ne!(
+            certified_setting_key("namespace_1", false, &subject, &info.key),
+            certified_setting_key("namespace_1", true, &subject, &info.key)
+        );
+    }
+
     #[test]
     fn setting_data_types_are_constructible() {
         let info = SettingInfo {
@@ -352,9 +862,14 @@ mod test {
             status: 0,
             version: 3,
             readers: BTreeSet::from([Principal::management_canister()]),
+            reader_groups: BTreeSet::from(["canister:group_1".to_string()]),
             tags: BTreeMap::from([("tag".to_string(), "value".to_string())]),
             dek: Some(ByteBuf::from(vec![4])),
             payload: Some(ByteBuf::from(vec![5])),
+            expires_at: None,
+            restored_from: Some(2),
+            certificate: None,
+            witness: None,
         };
         assert_eq!(info.version, 3);
         assert_eq!(info.clone(), info);
@@ -384,5 +899,34 @@ mod test {
         assert!(!format!("{:?}", archived.clone()).is_empty());
         assert!(!encode_one(archived.clone()).unwrap().is_empty());
         assert!(!crate::to_cbor_bytes(&archived).is_empty());
+
+        let chunk = SettingChunkOutput {
+            version: 3,
+            payload_size: CHUNK_SIZE as u64 * 2 + 1,
+            chunk_index: 0,
+            chunk: ByteBuf::from(vec![7]),
+            dek: None,
+        };
+        assert_eq!(chunk.chunks(), 3);
+        assert!(!format!("{:?}", chunk.clone()).is_empty());
+        assert!(!encode_one(chunk.clone()).unwrap().is_empty());
+        assert!(!crate::to_cbor_bytes(&chunk).is_empty());
+
+        let changes = SettingChangesOutput {
+            changes: vec![SettingChange {
+                seq: 1,
+                kind: SettingChangeKind::UpdateReaders,
+                user_owned: false,
+                subject: Principal::anonymous(),
+                key: ByteBuf::from(vec![8]),
+                version: 2,
+                changed_at: 5,
+            }],
+            next_cursor: 1,
+        };
+        assert_eq!(changes.changes[0].kind, SettingChangeKind::UpdateReaders);
+        assert!(!format!("{:?}", changes.clone()).is_empty());
+        assert!(!encode_one(changes.clone()).unwrap().is_empty());
+        assert!(!crate::to_cbor_bytes(&changes).is_empty());
     }
 }
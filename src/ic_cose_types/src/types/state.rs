@@ -2,7 +2,7 @@ use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeSet;
 
-use super::PublicKeyOutput;
+use super::{namespace::GasPolicy, PublicKeyOutput};
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub struct StateInfo {
@@ -21,6 +21,7 @@ pub struct StateInfo {
     pub schnorr_ed25519_public_key: Option<PublicKeyOutput>,
     pub schnorr_secp256k1_public_key: Option<PublicKeyOutput>,
     pub governance_canister: Option<Principal>,
+    pub gas_policy: GasPolicy,
 }
 
 #[cfg(test)]
@@ -49,6 +50,7 @@ mod tests {
             schnorr_ed25519_public_key: None,
             schnorr_secp256k1_public_key: None,
             governance_canister: Some(Principal::management_canister()),
+            gas_policy: GasPolicy::default(),
         };
         assert_eq!(state.name, "ic_cose");
         assert_eq!(state.namespace_total, 1);